@@ -1,17 +1,162 @@
-use crate::{utils, Account, Error};
-use alloy_primitives::{Address, U256};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use crate::{
+    utils::{self, HashAlgo},
+    Account, Block, BlockHeader, Error, SealedBlock, Transaction, Transactions,
+};
+use alloy_primitives::{Address, B256, U256};
+use k256::ecdsa::SigningKey;
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Blocks before a block reward can be spent, the reward of block `n` is spendable by the
+/// transactions of block `n + maturity` onwards
+///
+/// With 0 the transactions of the rewarded block may already spend it. The default of 1 makes
+/// it spendable from the next block on
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
+#[serde(transparent)]
+pub struct CoinbaseMaturity(pub u64);
+
+impl CoinbaseMaturity {
+    /// Blocks whose reward the transactions of block `number` can't spend yet, `number`
+    /// itself included unless the maturity is 0
+    pub fn immature_blocks(&self, number: u64) -> std::ops::RangeInclusive<u64> {
+        (number + 1).saturating_sub(self.0)..=number
+    }
+}
+
+impl Default for CoinbaseMaturity {
+    fn default() -> Self {
+        Self(1)
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[non_exhaustive]
 pub struct ChainSpec {
     /// Id of the chain
     chain_id: u64,
     /// Preallocations for accounts
     accounts: HashMap<Address, Account>,
+    /// Reserve every account has to keep, transfers may only go below it by emptying the
+    /// account completely, which removes it from the state
+    #[serde(default)]
+    min_balance: Option<u128>,
+    /// When block rewards become spendable
+    #[serde(default)]
+    coinbase_maturity: CoinbaseMaturity,
+    /// Hash of the transactions, blocks and addresses of the chain, nodes only talk to peers
+    /// that hash with the same one
+    #[serde(default)]
+    hash_algo: HashAlgo,
+    /// Block hashes pinned by the operator, by block number. The node refuses to start with, or
+    /// to produce, a chain that contradicts any of them
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    checkpoints: BTreeMap<u64, B256>,
+    /// Coinbases that may seal the blocks a node imports from other nodes. Without any, a
+    /// node only imports blocks sealed for its own coinbase
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    producers: BTreeSet<Address>,
+    /// Seeds of the accounts added with [ChainSpec::with_dev_account], only meant for
+    /// development chains since anyone can derive their keys
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "dev_seeds"
+    )]
+    dev_seeds: BTreeMap<u64, Address>,
+}
+
+/// Rejects seeds that aren't valid keys when the spec is read, so the keys derived from them
+/// later can't fail
+fn dev_seeds<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<u64, Address>, D::Error> {
+    let seeds = BTreeMap::<u64, Address>::deserialize(deserializer)?;
+    if let Some(seed) = seeds
+        .keys()
+        .find(|seed| utils::u256_to_signing_key(&U256::from(**seed)).is_err())
+    {
+        return Err(de::Error::custom(format!(
+            "dev seed {seed} is not a valid key"
+        )));
+    }
+    Ok(seeds)
 }
 
 impl ChainSpec {
+    /// Creates an empty spec, use the `with_*` methods to fill it in
+    pub fn new(chain_id: u64) -> Self {
+        Self {
+            chain_id,
+            accounts: HashMap::new(),
+            min_balance: None,
+            coinbase_maturity: CoinbaseMaturity::default(),
+            hash_algo: HashAlgo::default(),
+            checkpoints: BTreeMap::new(),
+            producers: BTreeSet::new(),
+            dev_seeds: BTreeMap::new(),
+        }
+    }
+
+    /// Preallocates `account` to `addr`
+    pub fn with_account(mut self, addr: Address, account: Account) -> Self {
+        self.accounts.insert(addr, account);
+        self
+    }
+
+    /// Preallocates `account` to the address of the key derived from `seed`, the key is
+    /// handed back by [ChainSpec::dev_accounts]
+    ///
+    /// # Panics
+    ///
+    /// If `seed` is 0, which isn't a valid key
+    pub fn with_dev_account(mut self, seed: u64, account: Account) -> Self {
+        let key = utils::u256_to_signing_key(&U256::from(seed)).expect("seed 0 is not a valid key");
+        let addr = utils::addr(&key, self.hash_algo);
+        self.dev_seeds.insert(seed, addr);
+        self.with_account(addr, account)
+    }
+
+    pub fn with_min_balance(mut self, min_balance: u128) -> Self {
+        self.min_balance = Some(min_balance);
+        self
+    }
+
+    pub fn with_coinbase_maturity(mut self, coinbase_maturity: CoinbaseMaturity) -> Self {
+        self.coinbase_maturity = coinbase_maturity;
+        self
+    }
+
+    /// Hashes the chain with `algo`, the dev accounts already added move to the addresses
+    /// their keys have under it
+    pub fn with_hash_algo(mut self, algo: HashAlgo) -> Self {
+        self.hash_algo = algo;
+        for (seed, addr) in self.dev_seeds.iter_mut() {
+            // Checked when the spec is read, a seed without a key has no address to move to
+            let Ok(key) = utils::u256_to_signing_key(&U256::from(*seed)) else {
+                continue;
+            };
+            let moved = utils::addr(&key, algo);
+            if let Some(account) = self.accounts.remove(addr) {
+                self.accounts.insert(moved, account);
+            }
+            *addr = moved;
+        }
+        self
+    }
+
+    /// Pins block `number` to `hash`
+    pub fn with_checkpoint(mut self, number: u64, hash: B256) -> Self {
+        self.checkpoints.insert(number, hash);
+        self
+    }
+
+    /// Lets blocks sealed for `coinbase` be imported, see [ChainSpec::producers]
+    pub fn with_producer(mut self, coinbase: Address) -> Self {
+        self.producers.insert(coinbase);
+        self
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>, Error> {
         serde_json::to_vec(self).map_err(|e| e.into())
     }
@@ -20,34 +165,160 @@ impl ChainSpec {
         serde_json::from_slice(data).map_err(|e| e.into())
     }
 
+    pub fn chain_id(&self) -> u64 {
+        self.chain_id
+    }
+
+    /// Commits to the chain id and the preallocations, so specs that share an id but start
+    /// from a different state are told apart
+    pub fn genesis_hash(&self) -> B256 {
+        let mut accounts: Vec<_> = self.accounts.iter().collect();
+        accounts.sort_by_key(|(address, _)| **address);
+
+        let mut data = self.chain_id.to_be_bytes().to_vec();
+        for (address, account) in accounts {
+            data.extend_from_slice(address.as_slice());
+            data.extend_from_slice(&account.balance().to_be_bytes());
+            data.extend_from_slice(&account.nonce().to_be_bytes());
+        }
+        utils::sha3(data)
+    }
+
+    /// Block 0 of the chain, sealed with [ChainSpec::genesis_hash] instead of a proof of work,
+    /// so it is the same on every node started from this spec
+    pub fn genesis_block(&self) -> SealedBlock {
+        let header = BlockHeader {
+            number: 0,
+            parent_hash: B256::ZERO,
+            ..Default::default()
+        };
+        Block::new(header, Transactions::default()).seal(self.genesis_hash())
+    }
+
     pub fn iter_accounts(&self) -> std::collections::hash_map::Iter<'_, Address, Account> {
         self.accounts.iter()
     }
-}
 
-impl Default for ChainSpec {
-    fn default() -> Self {
-        let account1 = Account::new(100_000_000, 0);
-        let account2 = Account::new(100_000_000, 0);
-        let account3 = Account::new(100_000_000, 0);
+    pub fn min_balance(&self) -> Option<u128> {
+        self.min_balance
+    }
 
-        let pk1 = utils::u256_to_signing_key(&U256::from(1)).unwrap();
-        let pk2 = utils::u256_to_signing_key(&U256::from(2)).unwrap();
-        let pk3 = utils::u256_to_signing_key(&U256::from(3)).unwrap();
+    pub fn coinbase_maturity(&self) -> CoinbaseMaturity {
+        self.coinbase_maturity
+    }
 
-        let addr1 = utils::addr(&pk1);
-        let addr2 = utils::addr(&pk2);
-        let addr3 = utils::addr(&pk3);
+    pub fn hash_algo(&self) -> HashAlgo {
+        self.hash_algo
+    }
 
-        let mut map = HashMap::new();
-        map.insert(addr1, account1);
-        map.insert(addr2, account2);
-        map.insert(addr3, account3);
+    pub fn checkpoints(&self) -> &BTreeMap<u64, B256> {
+        &self.checkpoints
+    }
 
-        Self {
-            chain_id: 1,
-            accounts: map,
+    /// Coinbases whose blocks other nodes import, the rewards of imported blocks only ever
+    /// go to one of them
+    pub fn producers(&self) -> &BTreeSet<Address> {
+        &self.producers
+    }
+
+    /// Keys of the accounts added with [ChainSpec::with_dev_account], ordered by seed
+    ///
+    /// Fails for specs without any, accounts added by address can't be signed for
+    pub fn dev_accounts(&self) -> Result<Vec<TestAccount>, Error> {
+        if self.dev_seeds.is_empty() {
+            return Err(Error::NoDevAccounts(self.accounts.len()));
         }
+
+        self.dev_seeds
+            .keys()
+            .map(|seed| {
+                Ok(TestAccount::new(*seed)?
+                    .with_chain_id(self.chain_id)
+                    .with_hash_algo(self.hash_algo))
+            })
+            .collect()
+    }
+}
+
+/// Funded account of a development chain along with its key, keeping track of its nonce
+#[derive(Debug, Clone)]
+pub struct TestAccount {
+    pub seed: u64,
+    pub address: Address,
+    key: SigningKey,
+    /// Nonce of the next transaction sent with [TestAccount::transfer]
+    nonce: u64,
+    /// Chain the transactions are signed for
+    chain_id: u64,
+    /// Hash the address is derived and the transactions are signed with
+    hash_algo: HashAlgo,
+}
+
+impl TestAccount {
+    pub fn new(seed: u64) -> Result<Self, Error> {
+        let key = utils::u256_to_signing_key(&U256::from(seed))?;
+        Ok(Self {
+            seed,
+            address: utils::addr(&key, HashAlgo::default()),
+            key,
+            nonce: 0,
+            chain_id: 0,
+            hash_algo: HashAlgo::default(),
+        })
+    }
+
+    /// Signs the transactions for `chain_id`, accounts of [ChainSpec::dev_accounts] already
+    /// are for the chain of the spec
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = chain_id;
+        self
+    }
+
+    /// Derives the address and signs with `algo`, accounts of [ChainSpec::dev_accounts]
+    /// already use the one of the spec
+    pub fn with_hash_algo(mut self, algo: HashAlgo) -> Self {
+        self.address = utils::addr(&self.key, algo);
+        self.hash_algo = algo;
+        self
+    }
+
+    /// Continues from `nonce`, for accounts that already sent transactions
+    pub fn with_nonce(mut self, nonce: u64) -> Self {
+        self.nonce = nonce;
+        self
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Signs a transfer with the next nonce
+    pub fn transfer(&mut self, to: Address, value: u128) -> Transaction {
+        let tx = self.sign(to, value, self.nonce);
+        self.nonce += 1;
+        tx
+    }
+
+    /// Signs a transfer with any nonce, leaving the tracked one alone
+    pub fn sign(&self, to: Address, value: u128, nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            from: self.address,
+            to,
+            nonce,
+            value,
+            ..Default::default()
+        };
+        utils::sign_transaction(&mut tx, self.chain_id, &self.key, self.hash_algo);
+        tx
+    }
+}
+
+impl Default for ChainSpec {
+    fn default() -> Self {
+        Self::new(1)
+            .with_dev_account(1, Account::new(100_000_000, 0))
+            .with_dev_account(2, Account::new(100_000_000, 0))
+            .with_dev_account(3, Account::new(100_000_000, 0))
     }
 }
 
@@ -57,6 +328,25 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_genesis_hash() {
+        let spec = ChainSpec::new(1)
+            .with_account(Address::repeat_byte(1), Account::new(100, 0))
+            .with_account(Address::repeat_byte(2), Account::new(200, 0));
+
+        // Independent of the order the accounts were added in
+        let reordered = ChainSpec::new(1)
+            .with_account(Address::repeat_byte(2), Account::new(200, 0))
+            .with_account(Address::repeat_byte(1), Account::new(100, 0));
+        assert_eq!(spec.genesis_hash(), reordered.genesis_hash());
+
+        assert_ne!(spec.genesis_hash(), ChainSpec::new(1).genesis_hash());
+        assert_ne!(
+            ChainSpec::new(1).genesis_hash(),
+            ChainSpec::new(2).genesis_hash()
+        );
+    }
+
     #[test]
     fn test_serialize_of_chainspec() {
         let mut map = HashMap::new();
@@ -73,6 +363,12 @@ mod tests {
         let spec = ChainSpec {
             accounts: map,
             chain_id: 1,
+            min_balance: Some(100),
+            coinbase_maturity: CoinbaseMaturity(10),
+            hash_algo: HashAlgo::Sha3,
+            checkpoints: BTreeMap::from([(10, B256::repeat_byte(1))]),
+            producers: BTreeSet::from([Address::repeat_byte(2)]),
+            dev_seeds: BTreeMap::new(),
         };
 
         let serialized = spec.serialize().unwrap();
@@ -81,4 +377,70 @@ mod tests {
 
         assert_eq!(spec, deserialized);
     }
+
+    #[test]
+    fn test_dev_accounts() {
+        let spec = ChainSpec::default();
+        let accounts = spec.dev_accounts().unwrap();
+        assert_eq!(accounts.len(), 3);
+        for account in &accounts {
+            let funded = spec
+                .iter_accounts()
+                .any(|(addr, _)| *addr == account.address);
+            assert!(funded);
+        }
+
+        // Survives a round trip through the spec file
+        let spec = ChainSpec::deserialize(&spec.serialize().unwrap()).unwrap();
+        assert_eq!(spec.dev_accounts().unwrap()[0].address, accounts[0].address);
+
+        let external = ChainSpec::new(1).with_account(Address::ZERO, Account::new(1, 0));
+        assert!(matches!(
+            external.dev_accounts(),
+            Err(Error::NoDevAccounts(1))
+        ));
+    }
+
+    #[test]
+    fn test_dev_accounts_follow_the_hash_algo() {
+        let keccak = ChainSpec::default().dev_accounts().unwrap();
+        let spec = ChainSpec::default().with_hash_algo(HashAlgo::Sha3);
+        assert_eq!(spec.iter_accounts().count(), 3);
+
+        for (keccak, sha3) in keccak.iter().zip(spec.dev_accounts().unwrap()) {
+            assert_ne!(keccak.address, sha3.address);
+            assert!(spec.iter_accounts().any(|(addr, _)| *addr == sha3.address));
+
+            let tx = sha3.sign(Address::ZERO, 1, 0);
+            assert_eq!(tx.from, sha3.address);
+            assert!(tx.verify(spec.chain_id(), HashAlgo::Sha3));
+        }
+    }
+
+    #[test]
+    fn test_invalid_dev_seeds_are_rejected() {
+        let mut spec = serde_json::to_value(ChainSpec::default()).unwrap();
+        spec["dev_seeds"]["0"] = serde_json::to_value(Address::ZERO).unwrap();
+        let err = ChainSpec::deserialize(&serde_json::to_vec(&spec).unwrap()).unwrap_err();
+        assert!(err.to_string().contains("dev seed 0"), "{err}");
+    }
+
+    #[test]
+    fn test_min_balance_is_optional() {
+        let spec = ChainSpec::deserialize(br#"{"chain_id":1,"accounts":{}}"#).unwrap();
+        assert_eq!(spec.min_balance(), None);
+        assert_eq!(spec.coinbase_maturity(), CoinbaseMaturity(1));
+        assert_eq!(spec.hash_algo(), HashAlgo::Keccak256);
+        assert!(spec.checkpoints().is_empty());
+        assert!(spec.producers().is_empty());
+    }
+
+    #[test]
+    fn test_immature_blocks() {
+        assert!(CoinbaseMaturity(0).immature_blocks(5).is_empty());
+        assert_eq!(CoinbaseMaturity(1).immature_blocks(5), 5..=5);
+        assert_eq!(CoinbaseMaturity(3).immature_blocks(5), 3..=5);
+        // Close to genesis there are fewer blocks to wait for
+        assert_eq!(CoinbaseMaturity(10).immature_blocks(5), 0..=5);
+    }
 }
@@ -1,86 +1,813 @@
-use crate::server::Message;
-use crate::utils::*;
-use crate::Error;
-use crate::Transaction;
-use alloy_primitives::U256;
+use crate::server::{
+    AdminReq, BlockReq, ChainInfo, Connection, MaybeTlsStream, Message, NodeLimits, PeerSnapshot,
+    Pruned, StatusReport, SubmissionResult, SubscriptionKind, TransactionEntry, TransactionReq,
+    UnixConnection, WireFormat,
+};
+use crate::{
+    Account, ChainSpec, SealedBlock, SealedHeader, TestAccount, Transaction, TransactionReceipt,
+    TransactionSummary,
+};
+use crate::{Error, MempoolStats};
+use alloy_primitives::{Address, B256};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Mutex,
+};
 use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
 use tokio::signal::ctrl_c;
+use tokio_rustls::{rustls::pki_types::ServerName, TlsConnector, TlsStream};
 
-#[allow(unreachable_code)]
-pub async fn run_loop() -> Result<(), Error> {
-    (1..=3).for_each(|x| {
-        tokio::spawn(async move {
-            let mut nonce = 0;
-            let pk = U256::from(x);
-            let pk = u256_to_signing_key(&pk).unwrap();
-            loop {
-                println!("{}", nonce);
-                let mut tx = Transaction::default();
+const SERVER_ADDRESS: &str = "localhost:8545";
 
-                let address = addr(&pk);
-                tx.from = address;
-                tx.nonce = nonce;
-                tx.value = 100;
+/// Name the node's certificate has to be valid for
+const SERVER_NAME: &str = "localhost";
 
-                tx.hash = tx.hash();
+/// How often an idle subscription makes sure the node is still there
+const SUBSCRIPTION_KEEPALIVE: Duration = Duration::from_secs(30);
 
-                let (v, r, s) = sign_hash(tx.hash(), &pk);
+/// Encoding of every connection the client opens, set once from the command line
+static WIRE_FORMAT: Mutex<WireFormat> = Mutex::new(WireFormat::Json);
 
-                tx.v = v;
-                tx.r = r;
-                tx.s = s;
+/// Whether connections start with [Connection::negotiate_encoding]
+static NEGOTIATE_ENCODING: AtomicBool = AtomicBool::new(false);
 
-                let msg: Message = Message::Transaction(tx);
-                let socket = TcpStream::connect("localhost:8545").await?;
-                let mut connection = crate::server::Connection::new(socket);
+/// Spec the node is expected to run, the default spec unless set
+static CHAIN_SPEC: Mutex<Option<ChainSpec>> = Mutex::new(None);
 
-                connection.write_message(&msg).await?;
+/// Connections are plain tcp unless set
+static TLS: Mutex<Option<TlsConnector>> = Mutex::new(None);
 
-                let msg = connection.read_message().await?;
-                println!("{:?}", msg);
+/// Has to match the format the node was started with
+pub fn set_wire_format(format: WireFormat) {
+    *WIRE_FORMAT.lock().unwrap() = format;
+}
 
-                nonce += 1;
-                tokio::time::sleep(Duration::from_secs(1)).await;
+/// Only offers the format of [set_wire_format], the node has to negotiate as well
+pub fn set_encoding_negotiation(negotiate: bool) {
+    NEGOTIATE_ENCODING.store(negotiate, Ordering::Relaxed);
+}
+
+/// Has to match the spec the node was started with, or the node refuses the handshake
+pub fn set_chain_spec(spec: ChainSpec) {
+    *CHAIN_SPEC.lock().unwrap() = Some(spec);
+}
+
+/// Connects over TLS, the node has to be started with a certificate `connector` accepts, see
+/// [crate::tls_connector]
+pub fn set_tls(connector: TlsConnector) {
+    *TLS.lock().unwrap() = Some(connector);
+}
+
+fn chain_spec() -> ChainSpec {
+    CHAIN_SPEC.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// Opens a connection to the node in the configured wire format and greets it with a
+/// [Message::Hello]
+async fn connect(keepalive: Option<Duration>) -> Result<Connection<MaybeTlsStream>, Error> {
+    let socket = TcpStream::connect(SERVER_ADDRESS).await?;
+    let tls = TLS.lock().unwrap().clone();
+    let socket = match tls {
+        Some(connector) => {
+            let name = ServerName::try_from(SERVER_NAME).map_err(|e| Error::Tls(e.to_string()))?;
+            let stream = connector.connect(name, socket).await?;
+            TlsStream::from(stream).into()
+        }
+        None => MaybeTlsStream::Plain(socket),
+    };
+    let connection = match keepalive {
+        Some(interval) => Connection::new_with_keepalive(socket, interval),
+        None => Connection::new(socket),
+    };
+    greet(connection).await
+}
+
+/// Opens a connection to the unix domain socket of a node started with
+/// [crate::Server::with_unix_socket], in the configured wire format and greeted like over tcp
+pub async fn connect_unix(path: impl AsRef<Path>) -> Result<UnixConnection, Error> {
+    greet(UnixConnection::connect_unix(path).await?).await
+}
+
+/// Settles the wire format of `connection` and exchanges a [Message::Hello] with the node
+async fn greet<S>(mut connection: Connection<S>) -> Result<Connection<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let format = *WIRE_FORMAT.lock().unwrap();
+    if NEGOTIATE_ENCODING.load(Ordering::Relaxed) {
+        connection.negotiate_encoding(format).await?;
+    } else {
+        connection = connection.with_wire_format(format);
+    }
+
+    connection
+        .write_message(&Message::hello(&chain_spec()))
+        .await?;
+    match connection.read_message().await? {
+        Some(Message::Hello { .. }) => Ok(connection),
+        Some(Message::IncompatiblePeer(reason)) => Err(Error::IncompatiblePeer(reason)),
+        Some(other) => Err(Error::UnexpectedResponse(Box::new(other))),
+        None => Err(Error::ConnectionEnded),
+    }
+}
+
+/// Sends a single message to the node and waits for the response
+async fn request(msg: &Message) -> Result<Message, Error> {
+    let mut connection = connect(None).await?;
+
+    connection.write_message(msg).await?;
+
+    connection
+        .read_message()
+        .await?
+        .ok_or(Error::ConnectionEnded)
+}
+
+/// Sends every one of `messages` in a single round trip, each response is at the position of
+/// its request
+pub async fn batch(messages: Vec<Message>) -> Result<Vec<Message>, Error> {
+    match request(&Message::Batch(messages)).await? {
+        Message::BatchResponse(responses) => Ok(responses),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Submits every one of `transactions` in a single round trip, each outcome is at the
+/// position of its transaction
+pub async fn submit_transactions(
+    transactions: Vec<Transaction>,
+) -> Result<Vec<SubmissionResult>, Error> {
+    match request(&Message::SubmitTransactions(transactions)).await? {
+        Message::SubmissionResults(results) => Ok(results),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches a block from the node, `None` if the node doesn't know it
+pub async fn get_block(req: BlockReq) -> Result<Option<SealedBlock>, Error> {
+    match request(&Message::BlockReq(req)).await? {
+        Message::Block(block) => Ok(Some(block)),
+        Message::NonExistentBlock => Ok(None),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches only the header of a block, `None` if the node doesn't know it
+pub async fn get_header(req: BlockReq) -> Result<Option<SealedHeader>, Error> {
+    match request(&Message::HeaderReq(req)).await? {
+        Message::Header(header) => Ok(Some(header)),
+        Message::NonExistentBlock => Ok(None),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches the headers of blocks `start..=end`, stopping at the first block the node doesn't
+/// have
+pub async fn get_headers(start: u64, end: u64) -> Result<Vec<SealedHeader>, Error> {
+    match request(&Message::HeaderReq(BlockReq::Range { start, end })).await? {
+        Message::Headers(headers) => Ok(headers),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches many transactions in one request, one entry per hash in the same order
+///
+/// At most [crate::MAX_TRANSACTIONS_PER_REQUEST] hashes can be asked for at once
+pub async fn get_transactions(hashes: Vec<B256>) -> Result<Vec<TransactionEntry>, Error> {
+    match request(&Message::TransactionReq(TransactionReq::Many(hashes))).await? {
+        Message::Transactions(transactions) => Ok(transactions),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches the receipt of a transaction, `None` if it isn't in a block yet
+pub async fn get_receipt(hash: B256) -> Result<Option<TransactionReceipt>, Error> {
+    match request(&Message::ReceiptReq(hash)).await? {
+        Message::Receipt(receipt) => Ok(Some(receipt)),
+        Message::NonExistentTx => Ok(None),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Asks the node which receipt the transaction would get if it was in the next block, without
+/// submitting it
+pub async fn simulate_transaction(tx: Transaction) -> Result<TransactionReceipt, Error> {
+    match request(&Message::SimulateTx(tx)).await? {
+        Message::SimulationResult(receipt) => Ok(receipt),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Hands a node started with the admin `token` a block produced elsewhere, it has to extend
+/// the node's head
+pub async fn import_block(block: SealedBlock, token: &str) -> Result<(), Error> {
+    match request(&Message::ImportBlock(block, token.to_string())).await? {
+        Message::Ok => Ok(()),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Sends an admin request carrying `token` and returns the black list after it
+pub async fn admin(req: AdminReq, token: &str) -> Result<Vec<String>, Error> {
+    match request(&Message::Admin(req, token.to_string())).await? {
+        Message::Bans(bans) => Ok(bans),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches the statistics of the peer at `ip`, or of every peer the node keeps track of, from a
+/// node started with the admin `token`
+pub async fn get_peer_stats(ip: Option<IpAddr>, token: &str) -> Result<Vec<PeerSnapshot>, Error> {
+    let req = AdminReq::PeerStats { ip };
+    match request(&Message::Admin(req, token.to_string())).await? {
+        Message::PeerStats(peers) => Ok(peers),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Stops block production on a node started with the admin `token`
+pub async fn pause(token: &str) -> Result<(), Error> {
+    match request(&Message::PauseReq(token.to_string())).await? {
+        Message::Ok => Ok(()),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Continues block production after [pause]
+pub async fn resume(token: &str) -> Result<(), Error> {
+    match request(&Message::ResumeReq(token.to_string())).await? {
+        Message::Ok => Ok(()),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Makes a follower started with the admin `token` produce blocks
+pub async fn promote(token: &str) -> Result<(), Error> {
+    match request(&Message::PromoteReq(token.to_string())).await? {
+        Message::Ok => Ok(()),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Makes a producer started with the admin `token` import blocks instead
+pub async fn demote(token: &str) -> Result<(), Error> {
+    match request(&Message::DemoteReq(token.to_string())).await? {
+        Message::Ok => Ok(()),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches the balance and nonce of an account, never touched addresses have neither
+pub async fn get_account(address: Address) -> Result<Account, Error> {
+    match request(&Message::AccountReq(address)).await? {
+        Message::Account(account) => Ok(account),
+        // The executor treats them as empty accounts as well
+        Message::NonExistentAccount => Ok(Account::default()),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches the account of whoever signed `tx`, the node recovers the signer from the signature
+pub async fn recover_sender(tx: Transaction) -> Result<Account, Error> {
+    match request(&Message::RecoverSender(tx)).await? {
+        Message::Account(account) => Ok(account),
+        Message::NonExistentAccount => Ok(Account::default()),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Sections of an [AddressReport] scanned from the blocks, left out if the node doesn't tell its
+/// [NodeLimits] to page them with. The last transactions are one as well
+const HISTORY_SECTIONS: [&str; 3] = [
+    "first seen and last active block",
+    "totals sent and received",
+    "failed and successful transactions",
+];
+
+/// Summary of an address as of the node's head, see [get_report]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct AddressReport {
+    pub address: Address,
+    pub balance: u128,
+    pub nonce: u64,
+    pub chain_id: u64,
+    pub head_number: u64,
+    pub head_hash: B256,
+    /// Transactions waiting in the node's mempool, of every sender since the mempool keeps no
+    /// index by sender
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub mempool_pending: Option<usize>,
+    /// What the blocks up to the head tell about the address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history: Option<AddressHistory>,
+    /// How many of the latest transactions of the address were asked for
+    pub last: usize,
+    /// Sections the node can't answer, left out of the report
+    pub unavailable: Vec<String>,
+}
+
+/// Transactions sent by or to an address, see [AddressReport::history]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct AddressHistory {
+    /// Blocks of the first and the latest transaction, `None` if the address has none
+    pub first_seen: Option<u64>,
+    pub last_active: Option<u64>,
+    /// Values of the successful transactions, neither block rewards nor the allocations of the
+    /// chainspec count
+    pub total_sent: u128,
+    pub total_received: u128,
+    pub successful: usize,
+    pub failed: usize,
+    /// Latest transactions, newest first
+    pub transactions: Vec<TransactionSummary>,
+}
+
+impl AddressHistory {
+    /// Sums up `summaries` of the transactions of `address`, in the order of the chain within
+    /// each block
+    fn new(address: Address, mut summaries: Vec<TransactionSummary>, last: usize) -> Self {
+        summaries.sort_by_key(|summary| summary.block_number);
+        let mut history = Self {
+            first_seen: summaries.first().map(|summary| summary.block_number),
+            last_active: summaries.last().map(|summary| summary.block_number),
+            ..Default::default()
+        };
+
+        for summary in &summaries {
+            if !summary.success {
+                history.failed += 1;
+                continue;
             }
-            Ok::<(), Error>(())
+            history.successful += 1;
+            if summary.from == address {
+                history.total_sent = history.total_sent.saturating_add(summary.value);
+            }
+            if summary.to == address {
+                history.total_received = history.total_received.saturating_add(summary.value);
+            }
+        }
+
+        history.transactions = summaries.into_iter().rev().take(last).collect();
+        history
+    }
+}
+
+/// Assembles an [AddressReport] batch by batch, the account, head, limits and mempool first,
+/// then the blocks up to the head and the receipts of the transactions of the address
+///
+/// Every batch of [ReportBuilder::next_requests] has to be answered with
+/// [ReportBuilder::add_responses] before the next one is asked for
+#[derive(Debug)]
+pub struct ReportBuilder {
+    address: Address,
+    last: usize,
+    report: Option<AddressReport>,
+    /// Sizes the batches, the blocks aren't scanned if the node doesn't tell them
+    limits: Option<NodeLimits>,
+    /// First block no range was asked for yet
+    next_block: u64,
+    /// Blocks the ranges skipped, the node only keeps a summary of their transactions
+    pruned: Vec<u64>,
+    /// Transactions of the address, the outcome of the ones from `requested` on is still to
+    /// be asked for with their receipt
+    unresolved: Vec<TransactionSummary>,
+    requested: usize,
+    /// Position of each of `unresolved`, by hash
+    positions: HashMap<B256, usize>,
+    /// Transactions of the address in pruned blocks, whose summary has the outcome already
+    summaries: Vec<TransactionSummary>,
+    /// Requests of the batch waiting for its responses
+    sent: Vec<Message>,
+    /// Requests the node rate limited, sent again first
+    retry: Vec<Message>,
+    retry_after: Option<Duration>,
+}
+
+impl ReportBuilder {
+    pub fn new(address: Address, last: usize) -> Self {
+        Self {
+            address,
+            last,
+            report: None,
+            limits: None,
+            next_block: 0,
+            pruned: Vec::new(),
+            unresolved: Vec::new(),
+            requested: 0,
+            positions: HashMap::new(),
+            summaries: Vec::new(),
+            sent: Vec::new(),
+            retry: Vec::new(),
+            retry_after: None,
+        }
+    }
+
+    /// Next batch to send, `None` once the report is complete
+    pub fn next_requests(&mut self) -> Option<Vec<Message>> {
+        let head = match &self.report {
+            Some(report) => report.head_number,
+            None => {
+                self.sent = vec![
+                    Message::AccountReq(self.address),
+                    Message::ChainInfoReq,
+                    Message::LimitsReq,
+                    Message::MempoolStatusReq,
+                ];
+                return Some(self.sent.clone());
+            }
+        };
+        let limits = self.limits?;
+        if !self.retry.is_empty() {
+            self.retry_after = None;
+            self.sent = std::mem::take(&mut self.retry);
+            return Some(self.sent.clone());
+        }
+        let batch_size = limits.max_batch_size.max(1);
+        let range_size = limits.max_blocks_per_message.max(1) as u64;
+
+        // A single range per batch, a response with more blocks might not fit into a frame
+        let mut requests = Vec::new();
+        if self.next_block <= head {
+            let end = head.min(self.next_block + range_size - 1);
+            requests.push(Message::BlockReq(BlockReq::Range {
+                start: self.next_block,
+                end,
+            }));
+            self.next_block = end + 1;
+        }
+
+        // The pruned blocks and the receipts are only known once every range is answered
+        if requests.is_empty() {
+            let count = self.pruned.len().min(batch_size);
+            requests.extend(
+                self.pruned
+                    .drain(..count)
+                    .map(|number| Message::BlockReq(BlockReq::Number(number))),
+            );
+        }
+        if requests.is_empty() {
+            let end = self.unresolved.len().min(self.requested + batch_size);
+            requests.extend(
+                self.unresolved[self.requested..end]
+                    .iter()
+                    .map(|summary| Message::ReceiptReq(summary.hash)),
+            );
+            self.requested = end;
+        }
+
+        if requests.is_empty() {
+            return None;
+        }
+        self.sent = requests.clone();
+        Some(requests)
+    }
+
+    /// Takes in the responses to the last [ReportBuilder::next_requests], in their order
+    pub fn add_responses(&mut self, responses: Vec<Message>) -> Result<(), Error> {
+        let sent = std::mem::take(&mut self.sent);
+        if sent.len() != responses.len() {
+            return Err(Error::UnexpectedResponse(Box::new(Message::BatchResponse(
+                responses,
+            ))));
+        }
+        if self.report.is_none() {
+            return self.add_overview(responses);
+        }
+
+        for (request, response) in sent.into_iter().zip(responses) {
+            self.add_response(request, response)?;
+        }
+        Ok(())
+    }
+
+    /// How long the node wants the next batch to wait, set if it rate limited requests
+    pub fn retry_after(&self) -> Option<Duration> {
+        self.retry_after
+    }
+
+    /// The report, `None` until the first batch is answered
+    pub fn finish(self) -> Option<AddressReport> {
+        let mut report = self.report?;
+        if self.limits.is_some() {
+            let mut summaries = self.unresolved;
+            summaries.extend(self.summaries);
+            report.history = Some(AddressHistory::new(self.address, summaries, self.last));
+        }
+        Some(report)
+    }
+
+    fn add_overview(&mut self, responses: Vec<Message>) -> Result<(), Error> {
+        let [account, info, limits, mempool] =
+            <[Message; 4]>::try_from(responses).map_err(|responses| {
+                Error::UnexpectedResponse(Box::new(Message::BatchResponse(responses)))
+            })?;
+        let account = match account {
+            Message::Account(account) => account,
+            Message::NonExistentAccount => Account::default(),
+            other => return Err(Error::UnexpectedResponse(Box::new(other))),
+        };
+        let info = match info {
+            Message::ChainInfo(info) => info,
+            other => return Err(Error::UnexpectedResponse(Box::new(other))),
+        };
+
+        // Nodes that can't answer a request leave out the sections built from it
+        self.limits = match limits {
+            Message::Limits(limits) => Some(limits),
+            _ => None,
+        };
+        let mempool_pending = match mempool {
+            Message::MempoolStatus { pending, .. } => Some(pending),
+            _ => None,
+        };
+
+        // The node keeps no labels
+        let mut unavailable = vec![String::from("label")];
+        if self.limits.is_none() {
+            unavailable.extend(HISTORY_SECTIONS.map(String::from));
+            unavailable.push(format!("last {} transactions", self.last));
+        }
+        if mempool_pending.is_none() {
+            unavailable.push(String::from("pending mempool entries"));
+        }
+
+        self.report = Some(AddressReport {
+            address: self.address,
+            balance: account.balance(),
+            nonce: account.nonce(),
+            chain_id: info.chain_id,
+            head_number: info.head_block,
+            head_hash: info.head_hash,
+            mempool_pending,
+            history: None,
+            last: self.last,
+            unavailable,
         });
-    });
+        Ok(())
+    }
 
-    let _ = ctrl_c().await;
-    Ok(())
+    fn add_response(&mut self, request: Message, response: Message) -> Result<(), Error> {
+        match (request, response) {
+            (Message::BlockReq(BlockReq::Range { start, end }), Message::Blocks(blocks)) => {
+                let mut expected = start;
+                for block in &blocks {
+                    self.pruned.extend(expected..block.number());
+                    expected = block.number() + 1;
+                    self.add_block(block);
+                }
+                self.pruned.extend(expected..=end);
+            }
+            (Message::BlockReq(_), Message::Block(block)) => self.add_block(&block),
+            (Message::BlockReq(_), Message::Pruned(Pruned::Block { transactions, .. })) => {
+                let address = self.address;
+                self.summaries.extend(
+                    transactions
+                        .into_iter()
+                        .filter(|summary| summary.from == address || summary.to == address),
+                );
+            }
+            (Message::BlockReq(_), Message::NonExistentBlock) => {}
+            (Message::ReceiptReq(hash), Message::Receipt(receipt)) => {
+                self.resolve(&hash, receipt.success);
+            }
+            (Message::ReceiptReq(hash), Message::Pruned(Pruned::Transaction(summary))) => {
+                self.resolve(&hash, summary.success);
+            }
+            (request, Message::RateLimited { retry_after_ms }) => {
+                let retry_after = Duration::from_millis(retry_after_ms);
+                self.retry_after = self.retry_after.max(Some(retry_after));
+                self.retry.push(request);
+            }
+            (_, other) => return Err(Error::UnexpectedResponse(Box::new(other))),
+        }
+        Ok(())
+    }
+
+    fn add_block(&mut self, block: &SealedBlock) {
+        for tx in block.transactions() {
+            if tx.from == self.address || tx.to == self.address {
+                self.positions.insert(tx.hash, self.unresolved.len());
+                self.unresolved
+                    .push(TransactionSummary::new(tx, block.number(), false));
+            }
+        }
+    }
+
+    fn resolve(&mut self, hash: &B256, success: bool) {
+        if let Some(position) = self.positions.get(hash) {
+            self.unresolved[*position].success = success;
+        }
+    }
 }
 
-pub async fn run() -> Result<(), Error> {
-    let pk = U256::from(1);
-    let pk = u256_to_signing_key(&pk).unwrap();
+/// Fetches everything the node can tell about `address` in as few round trips as its limits
+/// allow, every block up to the head is scanned, so long chains take a while
+pub async fn get_report(address: Address, last: usize) -> Result<AddressReport, Error> {
+    let mut builder = ReportBuilder::new(address, last);
+    while let Some(requests) = builder.next_requests() {
+        builder.add_responses(batch(requests).await?)?;
+        if let Some(retry_after) = builder.retry_after() {
+            tokio::time::sleep(retry_after).await;
+        }
+    }
+    Ok(builder
+        .finish()
+        .expect("the first batch asks for the account and the head"))
+}
+
+/// Fetches which chain the node is on and its head
+pub async fn get_chain_info() -> Result<ChainInfo, Error> {
+    match request(&Message::ChainInfoReq).await? {
+        Message::ChainInfo(info) => Ok(info),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches the uptime, head and load of the node, which it answers even while building a block
+pub async fn get_status() -> Result<StatusReport, Error> {
+    match request(&Message::StatusReq).await? {
+        Message::Status {
+            uptime_secs,
+            head_number,
+            head_hash,
+            pending_txs,
+            peer_count,
+            version,
+            role,
+            last_failure,
+            network_head,
+            lag_blocks,
+        } => Ok(StatusReport {
+            uptime_secs,
+            head_number,
+            head_hash,
+            pending_txs,
+            peer_count,
+            version,
+            role,
+            last_failure,
+            network_head,
+            lag_blocks,
+        }),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
+
+/// Fetches the limits the node enforces on incoming messages
+pub async fn get_limits() -> Result<NodeLimits, Error> {
+    match request(&Message::LimitsReq).await? {
+        Message::Limits(limits) => Ok(limits),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
 
-    let mut tx = Transaction::default();
+/// Blocks pushed by the node over a connection opened with [subscribe_blocks]
+pub struct BlockSubscription {
+    connection: Connection<MaybeTlsStream>,
+}
 
-    let addr = addr(&pk);
-    tx.from = addr;
-    tx.value = 100;
-    tx.nonce = 0;
+impl BlockSubscription {
+    /// Waits for the next sealed block, `None` once the node closes the connection
+    pub async fn next(&mut self) -> Result<Option<SealedBlock>, Error> {
+        match self.connection.read_message().await? {
+            Some(Message::Block(block)) => Ok(Some(block)),
+            None => Ok(None),
+            Some(other) => Err(Error::UnexpectedResponse(Box::new(other))),
+        }
+    }
+}
 
-    tx.hash = tx.hash();
+/// Transactions pushed by the node over a connection opened with [subscribe_transactions]
+pub struct TransactionSubscription {
+    connection: Connection<MaybeTlsStream>,
+}
 
-    let (v, r, s) = sign_hash(tx.hash(), &pk);
+impl TransactionSubscription {
+    /// Waits for the next transaction the mempool accepts, `None` once the node closes the
+    /// connection
+    pub async fn next(&mut self) -> Result<Option<Transaction>, Error> {
+        match self.connection.read_message().await? {
+            Some(Message::Transaction(tx)) => Ok(Some(tx)),
+            None => Ok(None),
+            Some(other) => Err(Error::UnexpectedResponse(Box::new(other))),
+        }
+    }
+}
 
-    tx.v = v;
-    tx.r = r;
-    tx.s = s;
+/// Asks the node to push every block it seals from now on
+pub async fn subscribe_blocks() -> Result<BlockSubscription, Error> {
+    let connection = subscribe(SubscriptionKind::Blocks).await?;
+    Ok(BlockSubscription { connection })
+}
 
-    let msg: Message = Message::Transaction(tx);
+/// Asks the node to push every transaction its mempool accepts from now on
+pub async fn subscribe_transactions() -> Result<TransactionSubscription, Error> {
+    let connection = subscribe(SubscriptionKind::PendingTransactions).await?;
+    Ok(TransactionSubscription { connection })
+}
+
+/// Opens a connection that is only used for the updates of `kind`
+async fn subscribe(kind: SubscriptionKind) -> Result<Connection<MaybeTlsStream>, Error> {
+    let mut connection = connect(Some(SUBSCRIPTION_KEEPALIVE)).await?;
 
-    let socket = TcpStream::connect("localhost:8545").await?;
-    let mut connection = crate::server::Connection::new(socket);
+    connection.write_message(&Message::Subscribe(kind)).await?;
 
-    connection.write_message(&msg).await?;
+    match connection.read_message().await? {
+        Some(Message::Ok) => Ok(connection),
+        Some(other) => Err(Error::UnexpectedResponse(Box::new(other))),
+        None => Err(Error::ConnectionEnded),
+    }
+}
 
-    let msg = connection.read_message().await?;
+/// Fetches the statistics of the node's mempool
+pub async fn get_mempool_status() -> Result<MempoolStats, Error> {
+    match request(&Message::MempoolStatusReq).await? {
+        Message::MempoolStatus {
+            pending,
+            evicted,
+            expired,
+            oldest_pending,
+        } => Ok(MempoolStats {
+            pending,
+            evicted,
+            expired,
+            oldest_pending,
+        }),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
 
-    println!("{:?}", msg);
+/// Fetches what `spender` may still send on behalf of `owner`
+pub async fn get_allowance(owner: Address, spender: Address) -> Result<u128, Error> {
+    match request(&Message::AllowanceReq { owner, spender }).await? {
+        Message::Allowance(remaining) => Ok(remaining),
+        other => Err(Error::UnexpectedResponse(Box::new(other))),
+    }
+}
 
+/// Prints the chain the node is on, so transactions aren't sent to the wrong one unnoticed
+async fn print_chain_info() -> Result<(), Error> {
+    let info = get_chain_info().await?;
+    println!(
+        "Connected to chain {} at block {} ({}), the node is a {}",
+        info.chain_id, info.head_block, info.head_hash, info.role
+    );
     Ok(())
 }
 
+#[allow(unreachable_code)]
+pub async fn run_loop() -> Result<(), Error> {
+    print_chain_info().await?;
+
+    for mut account in chain_spec().dev_accounts()? {
+        tokio::spawn(async move {
+            // The node keeps the connection open, so every transaction goes over the same one
+            let mut connection = connect(None).await?;
+            loop {
+                println!("{}", account.nonce());
+                let tx = account.transfer(Address::ZERO, 100);
+
+                let msg: Message = Message::Transaction(tx);
+                connection.write_message(&msg).await?;
+
+                match connection.read_message().await? {
+                    Some(Message::TransactionAccepted { hash }) => {
+                        println!("Transaction accepted: {hash}")
+                    }
+                    msg => println!("{:?}", msg),
+                }
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            Ok::<(), Error>(())
+        });
+    }
+
+    let _ = ctrl_c().await;
+    Ok(())
+}
+
+pub async fn run() -> Result<(), Error> {
+    print_chain_info().await?;
+
+    let spec = chain_spec();
+    let mut account = TestAccount::new(1)?
+        .with_chain_id(spec.chain_id())
+        .with_hash_algo(spec.hash_algo());
+    let tx = account.transfer(Address::ZERO, 100);
+
+    match request(&Message::Transaction(tx)).await? {
+        Message::TransactionAccepted { hash } => println!("Transaction accepted: {hash}"),
+        msg => println!("{:?}", msg),
+    }
+
+    Ok(())
+}
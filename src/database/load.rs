@@ -0,0 +1,380 @@
+//! Streaming loader for dumps written by [InMemoryDB::mem_dump]
+//!
+//! Entries are inserted as soon as they are parsed, so loading never holds more than the
+//! database itself plus the entry being read
+
+use super::InMemoryDB;
+use crate::{DatabaseReader, Error};
+use serde::de::{DeserializeOwned, DeserializeSeed, IgnoredAny, MapAccess, Visitor};
+use std::{
+    cell::Cell,
+    fmt,
+    fs::File,
+    io::{BufReader, Read},
+    marker::PhantomData,
+    path::Path,
+    rc::Rc,
+    time::{Duration, Instant},
+};
+use tracing::info;
+
+/// How often loading progress is logged
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(5);
+
+impl InMemoryDB {
+    /// Loads a dump written by [InMemoryDB::mem_dump] without reading the whole file first
+    ///
+    /// A malformed entry aborts the load with the byte offset it was found at. Once the file
+    /// is read the block indexes are cross checked instead of trusted
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        let total = file.metadata()?.len();
+
+        let read = Rc::new(Cell::new(0));
+        let reader = CountingReader {
+            inner: BufReader::new(file),
+            read: read.clone(),
+        };
+
+        let mut db = InMemoryDB::new();
+        let mut progress = Progress::new(total, read.clone());
+        let mut deserializer = serde_json::Deserializer::from_reader(reader);
+
+        let seed = DumpSeed {
+            db: &mut db,
+            progress: &mut progress,
+        };
+        if let Err(e) = seed
+            .deserialize(&mut deserializer)
+            .and_then(|_| deserializer.end())
+        {
+            return Err(Error::MalformedDump {
+                offset: read.get(),
+                reason: e.to_string(),
+            });
+        }
+
+        db.validate()?;
+        info!(
+            entries = progress.entries,
+            bytes = total,
+            blocks = db.block_count(),
+            "Database loaded"
+        );
+
+        Ok(db)
+    }
+
+    /// Checks that the blocks and the index of blocks by number agree with each other
+    fn validate(&self) -> Result<(), Error> {
+        for (hash, block) in &self.blocks {
+            if block.get_hash() != hash {
+                return Err(Error::InconsistentDump(format!(
+                    "block {} is stored under {}",
+                    block.get_hash(),
+                    hash
+                )));
+            }
+        }
+
+        for (number, hash) in &self.block_by_number {
+            match self.blocks.get(hash) {
+                Some(block) if block.number() == *number => {}
+                Some(block) => {
+                    return Err(Error::InconsistentDump(format!(
+                        "block number {} points to block {} with number {}",
+                        number,
+                        hash,
+                        block.number()
+                    )))
+                }
+                None => {
+                    return Err(Error::InconsistentDump(format!(
+                        "block number {} points to the missing block {}",
+                        number, hash
+                    )))
+                }
+            }
+        }
+
+        if self.blocks.len() != self.block_by_number.len() {
+            return Err(Error::InconsistentDump(format!(
+                "{} blocks but only {} of them are indexed by number",
+                self.blocks.len(),
+                self.block_by_number.len()
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+/// Keeps track of how many bytes the deserializer consumed, serde_json reads byte by byte so
+/// this is also the offset of an error
+struct CountingReader<R> {
+    inner: R,
+    read: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.set(self.read.get() + n as u64);
+        Ok(n)
+    }
+}
+
+struct Progress {
+    total: u64,
+    read: Rc<Cell<u64>>,
+    entries: u64,
+    started: Instant,
+    last_report: Instant,
+}
+
+impl Progress {
+    fn new(total: u64, read: Rc<Cell<u64>>) -> Self {
+        let now = Instant::now();
+        Self {
+            total,
+            read,
+            entries: 0,
+            started: now,
+            last_report: now,
+        }
+    }
+
+    fn tick(&mut self) {
+        self.entries += 1;
+
+        // Checking the clock for every entry would slow loading down noticeably
+        if !self.entries.is_multiple_of(1024) || self.last_report.elapsed() < PROGRESS_INTERVAL {
+            return;
+        }
+        self.last_report = Instant::now();
+
+        let read = self.read.get().max(1);
+        let remaining = self.total.saturating_sub(read) as f64 / read as f64;
+        let eta = self.started.elapsed().mul_f64(remaining);
+        info!(
+            entries = self.entries,
+            bytes = read,
+            total = self.total,
+            eta = %humantime::format_duration(Duration::from_secs(eta.as_secs())),
+            "Loading database"
+        );
+    }
+}
+
+/// Visits the top level map of the dump and streams every table into the database
+struct DumpSeed<'a> {
+    db: &'a mut InMemoryDB,
+    progress: &'a mut Progress,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for DumpSeed<'a> {
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a> Visitor<'de> for DumpSeed<'a> {
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a database dump")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        let DumpSeed { db, progress } = self;
+
+        while let Some(table) = map.next_key::<String>()? {
+            match table.as_str() {
+                "accounts" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.accounts.insert(k, v);
+                }))?,
+                "blocks" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.blocks.insert(k, v);
+                }))?,
+                "block_by_number" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.block_by_number.insert(k, v);
+                    db.head = db.head.max(Some(k));
+                }))?,
+                "transactions" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.transactions.insert(k, v);
+                }))?,
+                "tx_receipts" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.tx_receipts.insert(k, v);
+                }))?,
+                "bundle_receipts" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.bundle_receipts.insert(k, v);
+                }))?,
+                "allowances" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.allowances.insert(k, v);
+                }))?,
+                "tombstones" => map.next_value_seed(Entries::new(progress, |k, v| {
+                    db.tombstones.insert(k, v);
+                }))?,
+                "retention" => db.retention = map.next_value()?,
+                "chain_id" => db.chain_id = map.next_value()?,
+                // Same as the derived implementation, unknown tables are skipped
+                _ => {
+                    map.next_value::<IgnoredAny>()?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Streams the entries of one table of the dump into `insert`
+struct Entries<'a, K, V, F> {
+    progress: &'a mut Progress,
+    insert: F,
+    entry: PhantomData<(K, V)>,
+}
+
+impl<'a, K, V, F> Entries<'a, K, V, F> {
+    fn new(progress: &'a mut Progress, insert: F) -> Self {
+        Self {
+            progress,
+            insert,
+            entry: PhantomData,
+        }
+    }
+}
+
+impl<'de, 'a, K, V, F> DeserializeSeed<'de> for Entries<'a, K, V, F>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    F: FnMut(K, V),
+{
+    type Value = ();
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de, 'a, K, V, F> Visitor<'de> for Entries<'a, K, V, F>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    F: FnMut(K, V),
+{
+    type Value = ();
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a table of the database")
+    }
+
+    fn visit_map<A>(mut self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: MapAccess<'de>,
+    {
+        while let Some((key, value)) = map.next_entry()? {
+            (self.insert)(key, value);
+            self.progress.tick();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{utils::HashAlgo, Account, Block, BlockHeader, DatabaseWriter};
+    use alloy_primitives::{Address, B256};
+
+    fn dump_file(name: &str, contents: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "mini-blockchain-{}-{}.json",
+            std::process::id(),
+            name
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn block(number: u64) -> crate::SealedBlock {
+        let header = BlockHeader {
+            number,
+            ..Default::default()
+        };
+        Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256)
+    }
+
+    #[test]
+    fn test_load_dump() {
+        let mut db = InMemoryDB::new().with_retain_full_blocks(1);
+        db.write_chain_id(7).unwrap();
+        db.write_account(Address::repeat_byte(1), Account::new(10, 1))
+            .unwrap();
+        db.write_allowance(Address::repeat_byte(1), Address::repeat_byte(2), 5)
+            .unwrap();
+        db.delete_account(&Address::repeat_byte(3), 4).unwrap();
+        for number in 1..=3 {
+            let block = block(number);
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+
+        let path = dump_file("load", &serde_json::to_string_pretty(&db).unwrap());
+        let loaded = InMemoryDB::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&db).unwrap()
+        );
+        assert_eq!(loaded.retention_horizon(), 3);
+        assert_eq!(loaded.read_chain_id(), Some(7));
+        assert_eq!(
+            loaded.read_allowance(&Address::repeat_byte(1), &Address::repeat_byte(2)),
+            5
+        );
+        assert_eq!(loaded.read_tombstone(&Address::repeat_byte(3)), Some(4));
+    }
+
+    #[test]
+    fn test_malformed_entry_reports_offset() {
+        let contents = r#"{"accounts": {"0x0101010101010101010101010101010101010101": 12}}"#;
+        let path = dump_file("malformed", contents);
+        let err = InMemoryDB::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        // Somewhere after the start of the value that isn't an account
+        let value = contents.find("12").unwrap() as u64;
+        match err {
+            Error::MalformedDump { offset, .. } => assert!(offset > value, "offset {offset}"),
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_inconsistent_index_is_rejected() {
+        let mut db = InMemoryDB::new();
+        let block = block(1);
+        db.write_block(*block.get_hash(), block).unwrap();
+        db.block_by_number.insert(1, B256::ZERO);
+
+        let path = dump_file("inconsistent", &serde_json::to_string(&db).unwrap());
+        let err = InMemoryDB::load(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(err, Error::InconsistentDump(_)));
+    }
+}
@@ -0,0 +1,711 @@
+use crate::{
+    Account, BundleReceipt, ChainSpec, Error, SealedBlock, Transaction, TransactionReceipt,
+    TransactionSummary,
+};
+use alloy_primitives::{Address, B256};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+};
+use tokio::{fs::File, io::AsyncWriteExt};
+
+mod load;
+
+pub trait DatabaseWriter {
+    fn write_account(&mut self, addr: Address, account: Account) -> Result<(), Error>;
+
+    /// Removes the account, keeping `nonce` as a tombstone so an account created at `addr`
+    /// later continues from it and the transactions of the old one can't be replayed
+    fn delete_account(&mut self, addr: &Address, nonce: u64) -> Result<(), Error>;
+    fn write_block(&mut self, block_hash: B256, block: SealedBlock) -> Result<(), Error>;
+    fn write_transaction(&mut self, tx: Transaction) -> Result<(), Error>;
+    fn write_transaction_receipt(
+        &mut self,
+        tx_hash: B256,
+        tx_receipt: TransactionReceipt,
+    ) -> Result<(), Error>;
+
+    /// Databases without bundle support simply don't keep their receipts
+    fn write_bundle_receipt(
+        &mut self,
+        _bundle_hash: B256,
+        _receipt: BundleReceipt,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Sets what `spender` may still send on behalf of `owner`, zero removes the allowance
+    ///
+    /// Databases without approval support keep none, so every
+    /// [crate::TransactionKind::TransferFrom] fails on them
+    fn write_allowance(
+        &mut self,
+        _owner: Address,
+        _spender: Address,
+        _remaining: u128,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Databases that don't remember their chain leave it to the [ChainSpec] they are run with
+    fn write_chain_id(&mut self, _chain_id: u64) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Writes the chain id, the preallocations and the [ChainSpec::genesis_block]
+    fn write_spec(&mut self, spec: &ChainSpec) -> Result<(), Error> {
+        self.write_chain_id(spec.chain_id())?;
+        for (addr, account) in spec.iter_accounts() {
+            self.write_account(*addr, *account)?;
+        }
+
+        let genesis = spec.genesis_block();
+        self.write_block(*genesis.get_hash(), genesis)
+    }
+}
+
+pub trait DatabaseReader {
+    fn read_account(&self, addr: &Address) -> Option<&Account>;
+    fn read_account_mut(&mut self, addr: &Address) -> Option<&mut Account>;
+    fn read_transaction(&self, hash: &B256) -> Option<&Transaction>;
+    fn read_transaction_receipt(&self, hash: &B256) -> Option<&TransactionReceipt>;
+    fn read_block_by_hash(&self, block_hash: &B256) -> Option<&SealedBlock>;
+    fn read_block_by_number(&self, block_number: u64) -> Option<&SealedBlock>;
+    fn transaction_count(&self) -> usize;
+    fn block_count(&self) -> usize;
+
+    /// Chain the database was created for, see [DatabaseWriter::write_chain_id]
+    fn read_chain_id(&self) -> Option<u64> {
+        None
+    }
+
+    /// Block 0, written together with the [ChainSpec]
+    fn read_genesis_block(&self) -> Option<&SealedBlock> {
+        self.read_block_by_number(0)
+    }
+
+    /// Number of the oldest block that still has its transactions and receipts, older blocks
+    /// only keep their header and a [TransactionSummary] of each transaction
+    fn retention_horizon(&self) -> u64 {
+        0
+    }
+
+    fn read_bundle_receipt(&self, _hash: &B256) -> Option<&BundleReceipt> {
+        None
+    }
+
+    /// What `spender` may still send on behalf of `owner`, see [DatabaseWriter::write_allowance]
+    fn read_allowance(&self, _owner: &Address, _spender: &Address) -> u128 {
+        0
+    }
+
+    /// Nonce a deleted account had, see [DatabaseWriter::delete_account]
+    ///
+    /// There is no default, a database forgetting it would let the transactions of a closed
+    /// account be replayed once the account is funded again
+    fn read_tombstone(&self, addr: &Address) -> Option<u64>;
+
+    /// Summary of a transaction whose block is behind the [DatabaseReader::retention_horizon]
+    fn read_transaction_summary(&self, _hash: &B256) -> Option<&TransactionSummary> {
+        None
+    }
+
+    /// Summaries of the transactions of a block behind the
+    /// [DatabaseReader::retention_horizon], in block order
+    fn read_block_summaries(&self, _block_number: u64) -> Option<Vec<TransactionSummary>> {
+        None
+    }
+
+    /// Returns the block with the highest number, the head of the chain
+    fn latest_block(&self) -> Option<&SealedBlock> {
+        self.read_block_by_number(self.latest_block_number()?)
+    }
+
+    /// Number of the head of the chain, `None` before the first block
+    fn latest_block_number(&self) -> Option<u64> {
+        let count = self.block_count() as u64;
+        if count == 0 {
+            return None;
+        }
+
+        Some(self.earliest_block_number()? + count - 1)
+    }
+
+    /// Number of the first block, the chain starts either at the genesis block or at the first
+    /// produced block
+    fn earliest_block_number(&self) -> Option<u64> {
+        if self.block_count() == 0 {
+            return None;
+        }
+
+        match self.read_block_by_number(0) {
+            Some(_) => Some(0),
+            None => Some(1),
+        }
+    }
+
+    /// Checks that every checkpoint at or below the head matches the local chain
+    fn verify_checkpoints(&self, checkpoints: &BTreeMap<u64, B256>) -> Result<(), Error> {
+        let head = match self.latest_block() {
+            Some(head) => head.number(),
+            None => return Ok(()),
+        };
+
+        for (number, expected) in checkpoints.range(..=head) {
+            let found = self
+                .read_block_by_number(*number)
+                .map(|block| *block.get_hash());
+            if found != Some(*expected) {
+                return Err(Error::CheckpointMismatch {
+                    number: *number,
+                    expected: *expected,
+                    found,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the number of the latest block with a timestamp at or before `timestamp`
+    ///
+    /// Block numbers are contiguous and timestamps never decrease, so this is a binary search
+    /// that reads O(log n) blocks. Returns `None` if `timestamp` is older than the first block
+    fn find_block_by_timestamp(&self, timestamp: u64) -> Option<u64> {
+        let first = self.earliest_block_number()?;
+        if self.read_block_by_number(first)?.timestamp() > timestamp {
+            return None;
+        }
+
+        // Invariant: block `low` is at or before `timestamp`, everything after `high` is not
+        let mut low = first;
+        let mut high = self.latest_block_number()?;
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if self.read_block_by_number(mid)?.timestamp() <= timestamp {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+
+        Some(low)
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct InMemoryDB {
+    /// Set by [DatabaseWriter::write_spec], dumps from before it was kept have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chain_id: Option<u64>,
+    accounts: HashMap<Address, Account>,
+    blocks: HashMap<B256, SealedBlock>,
+    /// Hashmap that stores each block hash by its number
+    block_by_number: HashMap<u64, B256>,
+    transactions: HashMap<B256, Transaction>,
+    tx_receipts: HashMap<B256, TransactionReceipt>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    bundle_receipts: HashMap<B256, BundleReceipt>,
+    /// What each spender may still send on behalf of each owner, by owner
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    allowances: HashMap<Address, HashMap<Address, u128>>,
+    /// Nonces of deleted accounts
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    tombstones: HashMap<Address, u64>,
+    /// Highest block number written, derived from `block_by_number` so it isn't dumped
+    #[serde(skip)]
+    head: Option<u64>,
+    /// Only set when old transactions and receipts are pruned, part of the dump so a loaded
+    /// database knows what it no longer has
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    retention: Option<Retention>,
+}
+
+/// Blocks below `horizon` only keep their header and a summary of each transaction
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Retention {
+    /// How many of the latest blocks keep their transactions and receipts
+    full_blocks: u64,
+    /// Number of the oldest block that still has its transactions and receipts
+    horizon: u64,
+    summaries: HashMap<B256, TransactionSummary>,
+    /// Hashes of the summarized transactions of every pruned block, in block order
+    pruned_blocks: HashMap<u64, Vec<B256>>,
+}
+
+impl InMemoryDB {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Keeps transactions and receipts of the latest `full_blocks` blocks only, older blocks
+    /// keep their header and a [TransactionSummary] of each transaction. The head is always kept
+    /// in full, its receipts are only written after the block itself
+    pub fn with_retain_full_blocks(mut self, full_blocks: u64) -> Self {
+        // A loaded database keeps the horizon and summaries it already has
+        match self.retention.as_mut() {
+            Some(retention) => retention.full_blocks = full_blocks,
+            None => {
+                self.retention = Some(Retention {
+                    full_blocks,
+                    ..Default::default()
+                })
+            }
+        }
+        self
+    }
+
+    /// Moves every block that fell behind the retention horizon to summaries
+    fn prune(&mut self, head: u64) {
+        let retention = match self.retention.as_mut() {
+            Some(retention) => retention,
+            None => return,
+        };
+
+        let horizon = (head + 1).saturating_sub(retention.full_blocks.max(1));
+        for number in retention.horizon..horizon {
+            let block = self
+                .block_by_number
+                .get(&number)
+                .and_then(|hash| self.blocks.get_mut(hash));
+            let block = match block {
+                Some(block) => block,
+                None => continue,
+            };
+
+            let mut hashes = Vec::new();
+            for tx in block.take_transactions() {
+                self.transactions.remove(&tx.hash);
+                let receipt = self.tx_receipts.remove(&tx.hash);
+                let success = receipt.as_ref().is_some_and(|receipt| receipt.success);
+                // The whole bundle went into the same block
+                if let Some(bundle) = receipt.and_then(|receipt| receipt.bundle) {
+                    self.bundle_receipts.remove(&bundle);
+                }
+
+                let summary = TransactionSummary::new(&tx, number, success);
+                retention.summaries.insert(tx.hash, summary);
+                hashes.push(tx.hash);
+            }
+            retention.pruned_blocks.insert(number, hashes);
+        }
+
+        retention.horizon = retention.horizon.max(horizon);
+    }
+}
+
+impl InMemoryDB {
+    pub async fn mem_dump(&self, path: PathBuf) -> Result<(), Error> {
+        let mut file = File::create(path).await.unwrap();
+        file.write_all(serde_json::to_string_pretty(&self)?.as_bytes())
+            .await?;
+        Ok(())
+    }
+}
+
+impl DatabaseWriter for InMemoryDB {
+    fn write_chain_id(&mut self, chain_id: u64) -> Result<(), Error> {
+        self.chain_id = Some(chain_id);
+        Ok(())
+    }
+
+    fn write_account(&mut self, addr: Address, account: Account) -> Result<(), Error> {
+        self.tombstones.remove(&addr);
+        self.accounts.insert(addr, account);
+        Ok(())
+    }
+
+    fn delete_account(&mut self, addr: &Address, nonce: u64) -> Result<(), Error> {
+        self.accounts.remove(addr);
+        self.tombstones.insert(*addr, nonce);
+        Ok(())
+    }
+
+    fn write_block(&mut self, block_hash: B256, block: SealedBlock) -> Result<(), Error> {
+        for tx in block.transactions() {
+            self.transactions.insert(tx.hash, tx.clone());
+        }
+
+        let number = block.number();
+        self.block_by_number.insert(number, block_hash);
+        self.head = self.head.max(Some(number));
+        self.blocks.insert(block_hash, block);
+        self.prune(number);
+
+        Ok(())
+    }
+
+    fn write_transaction(&mut self, tx: Transaction) -> Result<(), Error> {
+        self.transactions.insert(tx.hash, tx);
+        Ok(())
+    }
+
+    fn write_transaction_receipt(
+        &mut self,
+        tx_hash: B256,
+        tx_receipt: TransactionReceipt,
+    ) -> Result<(), Error> {
+        self.tx_receipts.insert(tx_hash, tx_receipt);
+        Ok(())
+    }
+
+    fn write_bundle_receipt(
+        &mut self,
+        bundle_hash: B256,
+        receipt: BundleReceipt,
+    ) -> Result<(), Error> {
+        self.bundle_receipts.insert(bundle_hash, receipt);
+        Ok(())
+    }
+
+    fn write_allowance(
+        &mut self,
+        owner: Address,
+        spender: Address,
+        remaining: u128,
+    ) -> Result<(), Error> {
+        if remaining > 0 {
+            self.allowances
+                .entry(owner)
+                .or_default()
+                .insert(spender, remaining);
+        } else if let Some(allowances) = self.allowances.get_mut(&owner) {
+            allowances.remove(&spender);
+            if allowances.is_empty() {
+                self.allowances.remove(&owner);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DatabaseReader for InMemoryDB {
+    fn read_chain_id(&self) -> Option<u64> {
+        self.chain_id
+    }
+
+    fn read_account(&self, addr: &Address) -> Option<&Account> {
+        self.accounts.get(addr)
+    }
+
+    fn read_account_mut(&mut self, addr: &Address) -> Option<&mut Account> {
+        self.accounts.get_mut(addr)
+    }
+
+    fn read_transaction(&self, hash: &B256) -> Option<&Transaction> {
+        self.transactions.get(hash)
+    }
+
+    fn read_transaction_receipt(&self, hash: &B256) -> Option<&TransactionReceipt> {
+        self.tx_receipts.get(hash)
+    }
+
+    fn read_block_by_hash(&self, block_hash: &B256) -> Option<&SealedBlock> {
+        self.blocks.get(block_hash)
+    }
+
+    fn read_block_by_number(&self, block_number: u64) -> Option<&SealedBlock> {
+        let hash = self.block_by_number.get(&block_number)?;
+        self.read_block_by_hash(hash)
+    }
+
+    fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn latest_block_number(&self) -> Option<u64> {
+        // Only a database deserialized without [InMemoryDB::load] has to look for its head
+        self.head
+            .or_else(|| self.block_by_number.keys().max().copied())
+    }
+
+    fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    fn retention_horizon(&self) -> u64 {
+        self.retention
+            .as_ref()
+            .map_or(0, |retention| retention.horizon)
+    }
+
+    fn read_bundle_receipt(&self, hash: &B256) -> Option<&BundleReceipt> {
+        self.bundle_receipts.get(hash)
+    }
+
+    fn read_allowance(&self, owner: &Address, spender: &Address) -> u128 {
+        self.allowances
+            .get(owner)
+            .and_then(|allowances| allowances.get(spender))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn read_tombstone(&self, addr: &Address) -> Option<u64> {
+        self.tombstones.get(addr).copied()
+    }
+
+    fn read_transaction_summary(&self, hash: &B256) -> Option<&TransactionSummary> {
+        self.retention.as_ref()?.summaries.get(hash)
+    }
+
+    fn read_block_summaries(&self, block_number: u64) -> Option<Vec<TransactionSummary>> {
+        let retention = self.retention.as_ref()?;
+        let hashes = retention.pruned_blocks.get(&block_number)?;
+        Some(
+            hashes
+                .iter()
+                .filter_map(|hash| retention.summaries.get(hash).cloned())
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{utils::HashAlgo, Block, BlockHeader};
+
+    /// Writes blocks numbered from 1 with the given timestamps
+    fn chain(timestamps: &[u64]) -> InMemoryDB {
+        let mut db = InMemoryDB::new();
+        for (i, timestamp) in timestamps.iter().enumerate() {
+            let header = BlockHeader {
+                number: i as u64 + 1,
+                timestamp: *timestamp,
+                ..Default::default()
+            };
+            let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+        db
+    }
+
+    #[test]
+    fn test_find_block_by_timestamp() {
+        let db = chain(&[100, 103, 103, 110, 111, 140, 141, 200]);
+
+        // Before the first block
+        assert_eq!(db.find_block_by_timestamp(99), None);
+        // Exact matches
+        assert_eq!(db.find_block_by_timestamp(100), Some(1));
+        assert_eq!(db.find_block_by_timestamp(140), Some(6));
+        // In between blocks resolves to the earlier one
+        assert_eq!(db.find_block_by_timestamp(102), Some(1));
+        assert_eq!(db.find_block_by_timestamp(139), Some(5));
+        // Ties resolve to the latest block with that timestamp
+        assert_eq!(db.find_block_by_timestamp(103), Some(3));
+        // After the head
+        assert_eq!(db.find_block_by_timestamp(200), Some(8));
+        assert_eq!(db.find_block_by_timestamp(u64::MAX), Some(8));
+    }
+
+    #[test]
+    fn test_find_block_by_timestamp_small_chains() {
+        assert_eq!(chain(&[]).find_block_by_timestamp(100), None);
+
+        let db = chain(&[50]);
+        assert_eq!(db.find_block_by_timestamp(49), None);
+        assert_eq!(db.find_block_by_timestamp(50), Some(1));
+        assert_eq!(db.find_block_by_timestamp(51), Some(1));
+    }
+
+    #[test]
+    fn test_latest_and_earliest_block_number() {
+        let empty = chain(&[]);
+        assert_eq!(empty.latest_block_number(), None);
+        assert_eq!(empty.earliest_block_number(), None);
+        assert!(empty.latest_block().is_none());
+
+        let db = chain(&[10, 20, 30]);
+        assert_eq!(db.latest_block_number(), Some(3));
+        assert_eq!(db.earliest_block_number(), Some(1));
+        assert_eq!(db.latest_block().unwrap().timestamp(), 30);
+
+        // Derived from the index when the database didn't see the blocks being written
+        let loaded: InMemoryDB =
+            serde_json::from_str(&serde_json::to_string(&db).unwrap()).unwrap();
+        assert_eq!(loaded.latest_block_number(), Some(3));
+    }
+
+    #[test]
+    fn test_genesis_block_is_written_with_the_spec() {
+        let spec = ChainSpec::new(7);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+
+        let genesis = db.read_genesis_block().unwrap();
+        assert_eq!(genesis.number(), 0);
+        assert_eq!(*genesis.parent_hash(), B256::ZERO);
+        assert_eq!(*genesis.get_hash(), spec.genesis_hash());
+        assert_eq!(db.read_block_by_number(0), Some(genesis));
+        assert_eq!(db.earliest_block_number(), Some(0));
+        assert_eq!(db.latest_block_number(), Some(0));
+
+        assert_eq!(db.read_chain_id(), Some(7));
+        let loaded: InMemoryDB =
+            serde_json::from_str(&serde_json::to_string(&db).unwrap()).unwrap();
+        assert_eq!(loaded.read_chain_id(), Some(7));
+    }
+
+    #[test]
+    fn test_verify_checkpoints() {
+        let db = chain(&[10, 20, 30]);
+        let second = *db.read_block_by_number(2).unwrap().get_hash();
+
+        assert!(db.verify_checkpoints(&BTreeMap::new()).is_ok());
+        assert!(db
+            .verify_checkpoints(&BTreeMap::from([(2, second)]))
+            .is_ok());
+        // Checkpoints above the head can't be contradicted yet
+        assert!(db
+            .verify_checkpoints(&BTreeMap::from([(2, second), (4, B256::ZERO)]))
+            .is_ok());
+
+        let err = db
+            .verify_checkpoints(&BTreeMap::from([(3, second)]))
+            .unwrap_err();
+        assert!(matches!(err, Error::CheckpointMismatch { number: 3, .. }));
+    }
+
+    /// Writes blocks numbered from 1 with one transaction each, every other one failed
+    fn pruned_chain(full_blocks: u64, length: u64) -> (InMemoryDB, Vec<Transaction>) {
+        let mut db = InMemoryDB::new().with_retain_full_blocks(full_blocks);
+        let mut transactions = Vec::new();
+        for number in 1..=length {
+            let mut tx = Transaction {
+                nonce: number,
+                value: number as u128,
+                ..Default::default()
+            };
+            tx.hash = tx.hash(HashAlgo::Keccak256);
+
+            let header = BlockHeader {
+                number,
+                ..Default::default()
+            };
+            let block = Block::new(header, vec![tx.clone()].into()).seal_slow(HashAlgo::Keccak256);
+            let receipt = TransactionReceipt {
+                success: number % 2 == 0,
+                block_number: number,
+                ..Default::default()
+            };
+            db.write_transaction_receipt(tx.hash, receipt).unwrap();
+            db.write_block(*block.get_hash(), block).unwrap();
+            transactions.push(tx);
+        }
+        (db, transactions)
+    }
+
+    #[test]
+    fn test_retention_horizon() {
+        let (db, transactions) = pruned_chain(2, 5);
+        assert_eq!(db.retention_horizon(), 4);
+        assert_eq!(db.block_count(), 5);
+
+        // Behind the horizon only the header and the summaries are left
+        let old = &transactions[1];
+        assert!(db.read_transaction(&old.hash).is_none());
+        assert!(db.read_transaction_receipt(&old.hash).is_none());
+        assert!(db
+            .read_block_by_number(2)
+            .unwrap()
+            .transactions()
+            .is_empty());
+        let summary = TransactionSummary::new(old, 2, true);
+        assert_eq!(db.read_transaction_summary(&old.hash), Some(&summary));
+        assert_eq!(db.read_block_summaries(2), Some(vec![summary]));
+
+        // Recent blocks are untouched
+        let recent = &transactions[4];
+        assert_eq!(db.read_transaction(&recent.hash), Some(recent));
+        assert!(db.read_transaction_receipt(&recent.hash).is_some());
+        assert!(db.read_transaction_summary(&recent.hash).is_none());
+        assert_eq!(db.read_block_summaries(5), None);
+    }
+
+    #[test]
+    fn test_head_is_never_pruned() {
+        let mut db = InMemoryDB::new().with_retain_full_blocks(0);
+        let mut tx = Transaction::default();
+        tx.hash = tx.hash(HashAlgo::Keccak256);
+
+        // Blocks are written before their changeset, like the executor does
+        let header = BlockHeader {
+            number: 1,
+            ..Default::default()
+        };
+        let block = Block::new(header, vec![tx.clone()].into()).seal_slow(HashAlgo::Keccak256);
+        db.write_block(*block.get_hash(), block).unwrap();
+        let receipt = TransactionReceipt {
+            success: true,
+            block_number: 1,
+            ..Default::default()
+        };
+        db.write_transaction_receipt(tx.hash, receipt).unwrap();
+        assert_eq!(db.read_transaction(&tx.hash), Some(&tx));
+        assert!(db.read_transaction_summary(&tx.hash).is_none());
+
+        let header = BlockHeader {
+            number: 2,
+            ..Default::default()
+        };
+        let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+        db.write_block(*block.get_hash(), block).unwrap();
+        assert_eq!(db.retention_horizon(), 2);
+        assert!(db.read_transaction_receipt(&tx.hash).is_none());
+        assert_eq!(
+            db.read_transaction_summary(&tx.hash),
+            Some(&TransactionSummary::new(&tx, 1, true))
+        );
+    }
+
+    #[test]
+    fn test_retention_survives_dump() {
+        let (db, transactions) = pruned_chain(2, 5);
+        let dump = serde_json::to_string(&db).unwrap();
+        let mut loaded: InMemoryDB = serde_json::from_str(&dump).unwrap();
+
+        assert_eq!(loaded.retention_horizon(), 4);
+        for number in 1..4 {
+            assert_eq!(
+                loaded.read_block_summaries(number),
+                db.read_block_summaries(number)
+            );
+        }
+        assert!(
+            !loaded
+                .read_transaction_summary(&transactions[0].hash)
+                .unwrap()
+                .success
+        );
+
+        // Pruning carries on from where the dump left off
+        let header = BlockHeader {
+            number: 6,
+            ..Default::default()
+        };
+        let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+        loaded.write_block(*block.get_hash(), block).unwrap();
+        assert_eq!(loaded.retention_horizon(), 5);
+        assert!(loaded
+            .read_transaction_summary(&transactions[3].hash)
+            .is_some());
+    }
+
+    #[test]
+    fn test_used_up_allowances_are_removed() {
+        let (owner, spender) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let mut db = InMemoryDB::new();
+        assert_eq!(db.read_allowance(&owner, &spender), 0);
+
+        db.write_allowance(owner, spender, 5).unwrap();
+        assert_eq!(db.read_allowance(&owner, &spender), 5);
+        assert_eq!(db.read_allowance(&spender, &owner), 0);
+
+        db.write_allowance(owner, spender, 0).unwrap();
+        assert_eq!(db.read_allowance(&owner, &spender), 0);
+        assert!(db.allowances.is_empty());
+    }
+}
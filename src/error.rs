@@ -1,3 +1,5 @@
+use crate::server::{Message, Task};
+use alloy_primitives::{Address, B256};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -5,9 +7,39 @@ pub enum Error {
     #[error("Serde error: {0}")]
     SerdeError(#[from] serde_json::Error),
 
+    #[error("Bincode error: {0}")]
+    BincodeError(#[from] bincode::Error),
+
+    #[error("CBOR error: {0}")]
+    CborError(String),
+
     #[error("I/O Error: {0}")]
     IOError(#[from] std::io::Error),
 
+    #[error("Couldn't listen on {addr}: {source}")]
+    Bind {
+        addr: std::net::SocketAddr,
+        source: std::io::Error,
+    },
+
+    #[error("Couldn't listen on {}: {source}", path.display())]
+    BindSocket {
+        path: std::path::PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("No address to listen on")]
+    NoListenAddr,
+
+    #[error("TLS error: {0}")]
+    Tls(String),
+
+    #[error("Noise error: {0}")]
+    Noise(String),
+
+    #[error("The peer proved to be node {found}, expected {expected}")]
+    UnexpectedNode { expected: Address, found: Address },
+
     #[error("K256 Error: {0}")]
     K256Error(#[from] k256::ecdsa::Error),
 
@@ -25,4 +57,72 @@ pub enum Error {
 
     #[error("Channel failure")]
     ChannelFailure,
+
+    #[error("The {0} task failed: {1}")]
+    TaskFailed(Task, String),
+
+    #[error("Unexpected response from the node: {0:?}")]
+    UnexpectedResponse(Box<Message>),
+
+    #[error("Invalid black list entry: {0}")]
+    InvalidBanEntry(String),
+
+    #[error("Invalid block: {0}")]
+    InvalidBlock(String),
+
+    #[error("Mining was cancelled")]
+    MiningCancelled,
+
+    #[error("Block {number} contradicts the checkpoint {expected}, found {found:?}")]
+    CheckpointMismatch {
+        number: u64,
+        expected: B256,
+        found: Option<B256>,
+    },
+
+    #[error("The database was created for chain {database}, the spec is for chain {spec}")]
+    ChainIdMismatch { database: u64, spec: u64 },
+
+    #[error("A pending transaction with the same sender and nonce has the same or higher value")]
+    ReplacementValueTooLow,
+
+    #[error("The sender already has the maximum amount of pending transactions")]
+    SenderQuotaExceeded,
+
+    #[error("Malformed database dump at byte {offset}: {reason}")]
+    MalformedDump { offset: u64, reason: String },
+
+    #[error("Inconsistent database dump: {0}")]
+    InconsistentDump(String),
+
+    #[error("No pong within {0:?}, the connection is dead")]
+    KeepAliveTimeout(std::time::Duration),
+
+    #[error("Nothing was read for {0:?}, the connection is idle")]
+    IdleTimeout(std::time::Duration),
+
+    #[error("The spec has {0} accounts but none of them was derived from a seed")]
+    NoDevAccounts(usize),
+
+    #[error("The peer offered no wire format this node speaks: {0:#010b}")]
+    NoCommonWireFormat(u8),
+
+    #[error("The node refused the handshake: {0}")]
+    IncompatiblePeer(String),
+
+    #[error("Invalid bundle: {0}")]
+    InvalidBundle(&'static str),
+
+    #[error("Invalid signature: {0}")]
+    InvalidSignature(&'static str),
+
+    #[error("Invalid proof: {0}")]
+    InvalidProof(&'static str),
+
+    #[error("Message exceeds the {limit} limit of {max}")]
+    LimitExceeded { limit: &'static str, max: usize },
+
+    /// The frame was dropped without being buffered in full
+    #[error("Message is larger than the limit of {0} bytes")]
+    MessageTooLarge(usize),
 }
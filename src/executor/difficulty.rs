@@ -0,0 +1,117 @@
+use alloy_primitives::{U256, U512};
+
+/// Blocks between two recalculations of the difficulty by default
+pub const ADJUSTMENT_INTERVAL: u64 = 2016;
+
+/// Recalculates the difficulty every [DifficultyAdjuster::interval] blocks, so blocks keep
+/// coming at the block time no matter how fast the node mines
+///
+/// The difficulty is the target a block hash has to stay below, so blocks that came too slowly
+/// raise it and blocks that came too quickly lower it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct DifficultyAdjuster {
+    interval: u64,
+}
+
+impl Default for DifficultyAdjuster {
+    fn default() -> Self {
+        Self::new(ADJUSTMENT_INTERVAL)
+    }
+}
+
+impl DifficultyAdjuster {
+    pub fn new(interval: u64) -> Self {
+        Self {
+            interval: interval.max(1),
+        }
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    /// Whether the difficulty is recalculated once block `number` is sealed
+    pub fn is_due(&self, number: u64, last_adjustment: u64) -> bool {
+        number >= last_adjustment.saturating_add(self.interval)
+    }
+
+    /// Scales `current` by how long the blocks took over how long they should have taken, both
+    /// measured over the same span, and moves it by at most a factor of 4 either way
+    pub fn adjust(&self, current: U256, actual_block_time: u64, target_block_time: u64) -> U256 {
+        if target_block_time == 0 {
+            return current;
+        }
+
+        let adjusted =
+            U512::from(current) * U512::from(actual_block_time) / U512::from(target_block_time);
+        let adjusted = U256::saturating_from(adjusted);
+
+        // A difficulty of 0 can't be mined, see [crate::Executor::mine_block]
+        adjusted
+            .clamp(
+                current / U256::from(4),
+                current.saturating_mul(U256::from(4)),
+            )
+            .max(U256::from(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_on_time_blocks_keep_the_difficulty() {
+        let adjuster = DifficultyAdjuster::default();
+        let current = U256::MAX >> 16;
+
+        assert_eq!(adjuster.adjust(current, 600, 600), current);
+    }
+
+    #[test]
+    fn test_adjustment_follows_the_block_time() {
+        let adjuster = DifficultyAdjuster::default();
+        let current = U256::from(1_000_000);
+
+        // Twice as slow makes the target twice as easy to hit and the other way around
+        assert_eq!(adjuster.adjust(current, 1200, 600), U256::from(2_000_000));
+        assert_eq!(adjuster.adjust(current, 300, 600), U256::from(500_000));
+    }
+
+    #[test]
+    fn test_adjustment_is_clamped() {
+        let adjuster = DifficultyAdjuster::default();
+        let current = U256::from(1_000_000);
+
+        assert_eq!(adjuster.adjust(current, 0, 600), U256::from(250_000));
+        assert_eq!(adjuster.adjust(current, 1, 600), U256::from(250_000));
+        assert_eq!(
+            adjuster.adjust(current, u64::MAX, 600),
+            U256::from(4_000_000)
+        );
+
+        // Can't get any easier than accepting every hash
+        assert_eq!(adjuster.adjust(U256::MAX, u64::MAX, 1), U256::MAX);
+        assert_eq!(
+            adjuster.adjust(U256::MAX >> 1, 1200, 600),
+            U256::MAX - U256::from(1)
+        );
+        assert_eq!(
+            adjuster.adjust(U256::MAX, 0, 600),
+            U256::MAX / U256::from(4)
+        );
+
+        // Never gets harder than a hash of at most 1
+        assert_eq!(adjuster.adjust(U256::from(3), 1, 600), U256::from(1));
+    }
+
+    #[test]
+    fn test_is_due() {
+        let adjuster = DifficultyAdjuster::new(10);
+
+        assert!(!adjuster.is_due(10, 1));
+        assert!(adjuster.is_due(11, 1));
+        assert!(adjuster.is_due(21, 11));
+    }
+}
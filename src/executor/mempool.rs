@@ -1,90 +1,784 @@
-use std::collections::VecDeque;
+use std::{
+    cmp::Ordering,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering as AtomicOrdering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use super::ExecutorMempoolRx;
-use crate::{Error, Shutdown, Transaction, Transactions};
+use super::{Batch, ExecutorMempoolRx, MempoolRequest, MempoolStatusRx};
+use crate::{
+    validation::{
+        TxValidator, ValidationConfig, ValidationContext, ValidationOutcome, ValidationPhase,
+    },
+    Bundle, DatabaseReader, Error, Shutdown, Transaction, Transactions,
+};
+use alloy_primitives::{Address, B256};
+use clap::ValueEnum;
 use tokio::{
     select,
     sync::{broadcast, mpsc},
+    time::{self, Instant},
 };
-use tracing::info;
+use tracing::{error, info, warn};
+
+/// Amount of pending transactions the [Mempool] holds by default
+pub const DEFAULT_MEMPOOL_MAX_SIZE: usize = 10_000;
+
+/// Amount of pending transactions a single sender may have in the [Mempool] by default
+pub const DEFAULT_MEMPOOL_MAX_PER_SENDER: usize = 64;
+
+/// How long a transaction may wait in the [Mempool] by default
+pub const DEFAULT_MEMPOOL_TTL: Duration = Duration::from_secs(60 * 60);
 
-#[derive(Debug, Default)]
+/// Most transactions handed to the executor for a single block
+const MAX_BATCH_SIZE: usize = 100;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
 pub enum MempoolOrdering {
+    /// Transactions are included in the order they arrived
     #[default]
     Fifo,
+    /// Transactions with the highest value are included first
+    ByValue,
+}
+
+/// Pending transaction along with when it entered the [Mempool]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TimedTransaction {
+    tx: Transaction,
+    submitted_at: Instant,
+}
+
+impl TimedTransaction {
+    fn new(tx: Transaction) -> Self {
+        Self {
+            tx,
+            submitted_at: Instant::now(),
+        }
+    }
+}
+
+/// Pending bundle along with when it entered the [Mempool]
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TimedBundle {
+    bundle: Bundle,
+    submitted_at: Instant,
+}
+
+/// Orders transactions by value in the [BinaryHeap] used by [MempoolOrdering::ByValue]
+///
+/// Ties are broken by hash, so the order never depends on arrival
+#[derive(Debug, PartialEq, Eq)]
+struct ValueOrdered(TimedTransaction);
+
+impl Ord for ValueOrdered {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0
+            .tx
+            .value
+            .cmp(&other.0.tx.value)
+            .then_with(|| self.0.tx.hash.cmp(&other.0.tx.hash))
+    }
+}
+
+impl PartialOrd for ValueOrdered {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Point in time counters of the [Mempool]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct MempoolStats {
+    /// Transactions waiting to be included
+    pub pending: usize,
+    /// Transactions dropped because the mempool was full
+    pub evicted: u64,
+    /// Transactions dropped because they waited longer than the ttl
+    pub expired: u64,
+    /// Unix timestamp at which the oldest pending transaction was submitted
+    pub oldest_pending: Option<u64>,
 }
 
 #[derive(Debug)]
 pub struct Mempool {
     /// We use [VecDeque] so we can pop from the front and use the [MempoolOrdering::Fifo] ordering
-    transactions: VecDeque<Transaction>,
+    transactions: VecDeque<TimedTransaction>,
+    /// Max heap by value, only used with [MempoolOrdering::ByValue]
+    by_value: BinaryHeap<ValueOrdered>,
+    /// Bundles in the order they arrived, they are always included before single transactions
+    bundles: VecDeque<TimedBundle>,
+    /// Hashes of every pending transaction, so the same one isn't included twice
+    seen_hashes: HashSet<B256>,
 
     server_mempool_rx: mpsc::Receiver<Transaction>,
+    bundle_rx: mpsc::Receiver<Bundle>,
     executor_mempool_rx: ExecutorMempoolRx,
+    status_rx: MempoolStatusRx,
 
     shutdown: Shutdown,
     _shutdown_complete: mpsc::Sender<()>,
 
     ordering: MempoolOrdering,
+
+    /// Once reached, the oldest transaction under [MempoolOrdering::Fifo] or the lowest value
+    /// one under [MempoolOrdering::ByValue] is evicted for every new one
+    max_size: usize,
+    evicted: u64,
+
+    /// Pending transactions a single signer may have, so one address can't fill the mempool
+    max_per_sender: usize,
+    sender_counts: HashMap<Address, usize>,
+
+    /// Transactions older than this are dropped by a sweep every `ttl / 2`
+    ttl: Duration,
+    expired: u64,
+
+    /// Where pending transactions are written on shutdown and read back on startup
+    persist_path: Option<PathBuf>,
+
+    /// Every transaction accepted by [Mempool::push] is published here
+    new_transactions: broadcast::Sender<Transaction>,
+
+    /// Amount of pending transactions, stored after every change for readers outside the task
+    pending_gauge: Arc<AtomicUsize>,
 }
 
 impl Mempool {
+    #[cfg(test)]
     pub fn new(
         server_mempool_rx: mpsc::Receiver<Transaction>,
         executor_mempool_rx: ExecutorMempoolRx,
+        status_rx: MempoolStatusRx,
         ordering: MempoolOrdering,
         shutdown: broadcast::Receiver<()>,
         _shutdown_complete: mpsc::Sender<()>,
+    ) -> Self {
+        Self::new_with_capacity(
+            server_mempool_rx,
+            executor_mempool_rx,
+            status_rx,
+            ordering,
+            shutdown,
+            _shutdown_complete,
+            DEFAULT_MEMPOOL_MAX_SIZE,
+        )
+    }
+
+    pub fn new_with_capacity(
+        server_mempool_rx: mpsc::Receiver<Transaction>,
+        executor_mempool_rx: ExecutorMempoolRx,
+        status_rx: MempoolStatusRx,
+        ordering: MempoolOrdering,
+        shutdown: broadcast::Receiver<()>,
+        _shutdown_complete: mpsc::Sender<()>,
+        max_size: usize,
     ) -> Self {
         Self {
             transactions: VecDeque::new(),
+            by_value: BinaryHeap::new(),
+            bundles: VecDeque::new(),
+            seen_hashes: HashSet::new(),
             server_mempool_rx,
+            // Closed until a sender is handed in with [Mempool::with_bundles]
+            bundle_rx: mpsc::channel(1).1,
             executor_mempool_rx,
+            status_rx,
             ordering,
             shutdown: Shutdown::new(shutdown),
             _shutdown_complete,
+            max_size,
+            evicted: 0,
+            max_per_sender: DEFAULT_MEMPOOL_MAX_PER_SENDER,
+            sender_counts: HashMap::new(),
+            ttl: DEFAULT_MEMPOOL_TTL,
+            expired: 0,
+            persist_path: None,
+            new_transactions: broadcast::channel(1).0,
+            pending_gauge: Arc::new(AtomicUsize::new(0)),
         }
     }
 
-    pub async fn run(mut self) -> Result<(), Error> {
+    /// Subscribers that fall behind lose transactions, the mempool never waits for them
+    pub fn with_new_transactions(
+        mut self,
+        new_transactions: broadcast::Sender<Transaction>,
+    ) -> Self {
+        self.new_transactions = new_transactions;
+        self
+    }
+
+    /// Keeps `pending_gauge` at [Mempool::len], so it can be read without asking the mempool
+    pub fn with_pending_gauge(mut self, pending_gauge: Arc<AtomicUsize>) -> Self {
+        self.pending_gauge = pending_gauge;
+        self
+    }
+
+    /// Accepts bundles from `bundle_rx` next to single transactions
+    pub fn with_bundles(mut self, bundle_rx: mpsc::Receiver<Bundle>) -> Self {
+        self.bundle_rx = bundle_rx;
+        self
+    }
+
+    pub fn with_max_per_sender(mut self, max_per_sender: usize) -> Self {
+        self.max_per_sender = max_per_sender;
+        self
+    }
+
+    pub fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// Writes the pending transactions to `path` on graceful shutdown, and restores the ones a
+    /// previous run left there
+    ///
+    /// Anyone with access to the file could have changed it, so restored transactions are
+    /// admitted by `validator` against `db` like submitted ones, execution won't check their
+    /// signatures again. Those that pass go through [Mempool::push] and their ttl starts over.
+    /// The file is removed once read, so a crash doesn't bring back transactions twice
+    pub fn with_persistence<DB: DatabaseReader>(
+        mut self,
+        path: PathBuf,
+        db: &DB,
+        validator: &TxValidator,
+        config: ValidationConfig,
+    ) -> Self {
+        match Self::read_persisted(&path) {
+            Ok(Some(transactions)) => {
+                let ctx = ValidationContext {
+                    state: db,
+                    config,
+                    head: db.latest_block().map(|block| block.number()).unwrap_or(0),
+                    phase: ValidationPhase::Admission,
+                };
+                let mut count = 0;
+                for tx in transactions {
+                    if let ValidationOutcome::Invalid(reason) = validator.validate(&tx, &ctx) {
+                        warn!(hash = %tx.hash, ?reason, "Dropped persisted transaction");
+                        continue;
+                    }
+                    match self.push(tx) {
+                        Ok(()) => count += 1,
+                        Err(e) => warn!(err = %e, "Couldn't restore persisted transaction"),
+                    }
+                }
+                info!(count, path = %path.display(), "Restored pending transactions");
+            }
+            Ok(None) => {}
+            Err(e) => {
+                error!(err = %e, path = %path.display(), "Couldn't restore pending transactions")
+            }
+        }
+
+        self.persist_path = Some(path);
+        self
+    }
+
+    fn read_persisted(path: &Path) -> Result<Option<Vec<Transaction>>, Error> {
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        let transactions = serde_json::from_slice(&data)?;
+        std::fs::remove_file(path)?;
+        Ok(Some(transactions))
+    }
+
+    /// Writes the pending transactions to the persistence path, in the order they would be
+    /// included
+    ///
+    /// Bundles aren't written, their senders have to submit them again
+    async fn persist(&self) -> Result<(), Error> {
+        let path = match &self.persist_path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+
+        let mut by_value: Vec<_> = self.by_value.iter().collect();
+        by_value.sort_by(|a, b| b.cmp(a));
+        let transactions: Vec<_> = self
+            .transactions
+            .iter()
+            .chain(by_value.into_iter().map(|tx| &tx.0))
+            .map(|pending| &pending.tx)
+            .collect();
+
+        tokio::fs::write(path, serde_json::to_vec(&transactions)?).await?;
+        info!(count = transactions.len(), path = %path.display(), "Persisted pending transactions");
+        Ok(())
+    }
+
+    /// Takes transactions until the shutdown signal, or until the server or the executor on the
+    /// other end of its channels is gone, and persists what is still pending either way
+    pub async fn run(&mut self) -> Result<(), Error> {
         info!("Mempool Initialized Successfuly");
 
+        // The interval panics on a zero period
+        let mut sweep = time::interval((self.ttl / 2).max(Duration::from_millis(1)));
+        let mut bundles_open = true;
+
         while !self.shutdown.is_shutdown() {
+            self.pending_gauge
+                .store(self.len(), AtomicOrdering::Relaxed);
             select! {
                 // Sender part of this channel is cloned to every single connection
                 tx = self.server_mempool_rx.recv() => {
-                    let tx = tx.ok_or(Error::ChannelFailure)?;
-                    self.push(tx);
+                    let Some(tx) = tx else { break };
+                    let hash = tx.get_hash();
+                    if let Err(e) = self.push(tx) {
+                        warn!(%hash, err = %e, "Transaction rejected by the mempool");
+                    }
                 },
 
-                oneshot = self.executor_mempool_rx.recv() => {
-                    let oneshot = oneshot.ok_or(Error::ChannelFailure)?;
-                    let transactions = self.get_transactions();
-                    oneshot.send(transactions).map_err(|_| Error::ChannelFailure)?;
+                bundle = self.bundle_rx.recv(), if bundles_open => {
+                    match bundle {
+                        Some(bundle) => {
+                            let hash = *bundle.get_hash();
+                            if let Err(e) = self.push_bundle(bundle) {
+                                warn!(%hash, err = %e, "Bundle rejected by the mempool");
+                            }
+                        }
+                        // Nothing submits bundles on this node
+                        None => bundles_open = false,
+                    }
+                },
+
+                request = self.executor_mempool_rx.recv() => {
+                    let Some(request) = request else { break };
+                    match request {
+                        MempoolRequest::GetBatch(oneshot) => {
+                            let batch = self.get_batch();
+                            // The executor stopped waiting, the batch stays pending
+                            if let Err(batch) = oneshot.send(batch) {
+                                self.push_front(batch.transactions);
+                                self.push_front_bundles(batch.bundles);
+                            }
+                        }
+                        MempoolRequest::Return(transactions) => self.push_front(transactions),
+                        MempoolRequest::ReturnBundles(bundles) => self.push_front_bundles(bundles),
+                    }
+                }
+
+                reply = self.status_rx.recv() => {
+                    let Some(reply) = reply else { break };
+                    // The handler may have stopped waiting, that's not our problem
+                    let _ = reply.send(self.stats());
                 }
+
+                _ = sweep.tick() => self.evict_expired(),
+
+                _ = self.shutdown.recv() => {}
+            }
+        }
+
+        // The handlers already told their peers these were accepted
+        while let Ok(tx) = self.server_mempool_rx.try_recv() {
+            let hash = tx.get_hash();
+            if let Err(e) = self.push(tx) {
+                warn!(%hash, err = %e, "Transaction rejected by the mempool");
+            }
+        }
+        while let Ok(bundle) = self.bundle_rx.try_recv() {
+            let hash = *bundle.get_hash();
+            if let Err(e) = self.push_bundle(bundle) {
+                warn!(%hash, err = %e, "Bundle rejected by the mempool");
             }
         }
 
+        if let Err(e) = self.persist().await {
+            error!(err = %e, "Couldn't persist pending transactions");
+        }
+
         Ok(())
     }
 
-    pub fn push(&mut self, tx: Transaction) {
-        self.transactions.push_back(tx);
+    /// Adds a transaction, unless one with the same hash is already pending
+    ///
+    /// A transaction with the same sender, nonce and signer as a pending one replaces it if it
+    /// sends strictly more value, otherwise it is rejected. Signers with `max_per_sender` pending
+    /// transactions are rejected too, so a spender only uses up its own quota. The
+    /// [crate::TxValidator] already admitted the transaction, so only the rules of the pool
+    /// itself are checked here
+    pub fn push(&mut self, tx: Transaction) -> Result<(), Error> {
+        if self.seen_hashes.contains(&tx.get_hash()) {
+            return Ok(());
+        }
+
+        let tx = match self.replace(tx)? {
+            Some(tx) => tx,
+            None => return Ok(()),
+        };
+
+        // Replacements don't count, they take the slot of the transaction they replace
+        if self.sender_count(&tx.signer()) >= self.max_per_sender {
+            return Err(Error::SenderQuotaExceeded);
+        }
+
+        self.seen_hashes.insert(tx.get_hash());
+        *self.sender_counts.entry(tx.signer()).or_default() += 1;
+        if self.len() >= self.max_size {
+            self.evict();
+        }
+
+        self.publish(&tx);
+
+        let tx = TimedTransaction::new(tx);
+        match self.ordering {
+            MempoolOrdering::Fifo => self.transactions.push_back(tx),
+            MempoolOrdering::ByValue => self.by_value.push(ValueOrdered(tx)),
+        }
+
+        Ok(())
     }
 
-    pub fn pop(&mut self) -> Option<Transaction> {
+    /// Adds a bundle, which is included in a single block and executed as a unit
+    ///
+    /// Rejected if any member is already pending or doesn't fit the sender quota. Bundles don't
+    /// replace pending transactions, a conflicting one simply makes the bundle revert
+    pub fn push_bundle(&mut self, bundle: Bundle) -> Result<(), Error> {
+        if bundle.len() > self.max_size {
+            return Err(Error::InvalidBundle("larger than the mempool"));
+        }
+        let transactions = bundle.transactions();
+        if transactions.iter().any(|tx| self.contains(&tx.hash)) {
+            return Err(Error::InvalidBundle("member already pending"));
+        }
+
+        let mut counts: HashMap<Address, usize> = HashMap::new();
+        for tx in transactions {
+            *counts.entry(tx.signer()).or_default() += 1;
+        }
+        if counts
+            .iter()
+            .any(|(sender, count)| self.sender_count(sender) + count > self.max_per_sender)
+        {
+            return Err(Error::SenderQuotaExceeded);
+        }
+
+        while self.len() + bundle.len() > self.max_size {
+            self.evict();
+        }
+
+        for tx in transactions {
+            self.seen_hashes.insert(tx.hash);
+            *self.sender_counts.entry(tx.signer()).or_default() += 1;
+
+            self.publish(tx);
+        }
+
+        self.bundles.push_back(TimedBundle {
+            bundle,
+            submitted_at: Instant::now(),
+        });
+        Ok(())
+    }
+
+    /// Swaps the pending transaction with the same sender, nonce and signer for `tx`, handing
+    /// `tx` back if there is none
+    fn replace(&mut self, tx: Transaction) -> Result<Option<Transaction>, Error> {
+        let hash = tx.get_hash();
+        // A spender shares the nonces of the owner, but may only replace its own transactions
+        let same_slot = |pending: &TimedTransaction| {
+            pending.tx.from == tx.from
+                && pending.tx.nonce == tx.nonce
+                && pending.tx.signer() == tx.signer()
+        };
+
+        let replaced = match self.ordering {
+            MempoolOrdering::Fifo => match self.transactions.iter_mut().find(|p| same_slot(p)) {
+                Some(pending) if tx.value <= pending.tx.value => {
+                    return Err(Error::ReplacementValueTooLow)
+                }
+                // The replacement keeps the place of the original in the queue
+                Some(pending) => std::mem::replace(pending, TimedTransaction::new(tx.clone())).tx,
+                None => return Ok(Some(tx)),
+            },
+            MempoolOrdering::ByValue => match self.by_value.iter().position(|p| same_slot(&p.0)) {
+                Some(i) => {
+                    // iter and into_vec expose the same underlying order
+                    let mut heap = std::mem::take(&mut self.by_value).into_vec();
+                    if tx.value <= heap[i].0.tx.value {
+                        self.by_value = heap.into();
+                        return Err(Error::ReplacementValueTooLow);
+                    }
+
+                    let replacement = ValueOrdered(TimedTransaction::new(tx.clone()));
+                    let pending = std::mem::replace(&mut heap[i], replacement).0.tx;
+                    self.by_value = heap.into();
+                    pending
+                }
+                None => return Ok(Some(tx)),
+            },
+        };
+
+        self.seen_hashes.remove(&replaced.get_hash());
+        self.seen_hashes.insert(hash);
+        // Subscribers hear of the replacement like of any other accepted transaction
+        self.publish(&tx);
+        Ok(None)
+    }
+
+    /// Sends an accepted transaction to the subscribers of [Mempool::with_new_transactions]
+    fn publish(&self, tx: &Transaction) {
+        if self.new_transactions.receiver_count() > 0 {
+            // Every subscriber may have left in the meantime
+            let _ = self.new_transactions.send(tx.clone());
+        }
+    }
+
+    /// Puts transactions back in front of the queue, keeping their order
+    ///
+    /// With [MempoolOrdering::ByValue] they simply go back into the heap. Transactions that no
+    /// longer fit are dropped, they already had their chance. The executor doesn't know when
+    /// they were submitted, so their ttl starts over
+    pub fn push_front(&mut self, transactions: Transactions) {
+        for tx in transactions.into_iter().rev() {
+            if self.len() >= self.max_size {
+                self.evicted += 1;
+                continue;
+            }
+
+            // Resubmitted while it was out of the mempool
+            if !self.seen_hashes.insert(tx.get_hash()) {
+                continue;
+            }
+            // Already accepted once, so they don't have to fit the sender quota again
+            *self.sender_counts.entry(tx.signer()).or_default() += 1;
+
+            let tx = TimedTransaction::new(tx);
+            match self.ordering {
+                MempoolOrdering::Fifo => self.transactions.push_front(tx),
+                MempoolOrdering::ByValue => self.by_value.push(ValueOrdered(tx)),
+            }
+        }
+    }
+
+    /// Puts bundles back in front of the other bundles, keeping their order. Like
+    /// [Mempool::push_front] bundles that no longer fit are dropped and their ttl starts over
+    pub fn push_front_bundles(&mut self, bundles: Vec<Bundle>) {
+        for bundle in bundles.into_iter().rev() {
+            let transactions = bundle.transactions();
+            if self.len() + transactions.len() > self.max_size {
+                self.evicted += transactions.len() as u64;
+                continue;
+            }
+
+            // A member was resubmitted while it was out of the mempool
+            if transactions.iter().any(|tx| self.contains(&tx.hash)) {
+                continue;
+            }
+            for tx in transactions {
+                self.seen_hashes.insert(tx.hash);
+                *self.sender_counts.entry(tx.signer()).or_default() += 1;
+            }
+
+            self.bundles.push_front(TimedBundle {
+                bundle,
+                submitted_at: Instant::now(),
+            });
+        }
+    }
+
+    /// Drops every transaction that has been waiting longer than the ttl
+    pub fn evict_expired(&mut self) {
+        let (now, ttl) = (Instant::now(), self.ttl);
+        let mut expired = Vec::new();
+        let mut keep = |pending: &TimedTransaction| {
+            let alive = now.duration_since(pending.submitted_at) <= ttl;
+            if !alive {
+                expired.push((pending.tx.hash, pending.tx.signer()));
+            }
+            alive
+        };
+
         match self.ordering {
+            MempoolOrdering::Fifo => self.transactions.retain(|pending| keep(pending)),
+            MempoolOrdering::ByValue => self.by_value.retain(|pending| keep(&pending.0)),
+        }
+
+        // A bundle expires as a whole
+        let mut bundles = std::mem::take(&mut self.bundles);
+        bundles.retain(|pending| {
+            let alive = now.duration_since(pending.submitted_at) <= ttl;
+            if !alive {
+                let members = pending.bundle.transactions().iter();
+                expired.extend(members.map(|tx| (tx.hash, tx.signer())));
+            }
+            alive
+        });
+        self.bundles = bundles;
+
+        for (hash, from) in &expired {
+            self.seen_hashes.remove(hash);
+            self.release_sender(from);
+        }
+
+        if !expired.is_empty() {
+            self.expired += expired.len() as u64;
+            info!(count = expired.len(), "Dropped expired transactions");
+        }
+    }
+
+    /// Drops the transaction that would be included last, or the oldest bundle once there are
+    /// no single transactions left
+    fn evict(&mut self) {
+        if self.transactions.is_empty() && self.by_value.is_empty() {
+            if let Some(TimedBundle { bundle, .. }) = self.bundles.pop_front() {
+                for tx in bundle.transactions() {
+                    self.seen_hashes.remove(&tx.hash);
+                    self.release_sender(&tx.signer());
+                }
+                self.evicted += bundle.len() as u64;
+                warn!(hash = %bundle.get_hash(), "Mempool is full, evicted bundle");
+            }
+            return;
+        }
+
+        let evicted = match self.ordering {
             MempoolOrdering::Fifo => self.transactions.pop_front(),
+            MempoolOrdering::ByValue => {
+                // A max heap can't pop its minimum, so we rebuild it without it. This is O(n)
+                // but only happens while the mempool is full
+                let mut heap = std::mem::take(&mut self.by_value).into_vec();
+                let lowest = heap
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| a.cmp(b))
+                    .map(|(i, _)| i);
+                let evicted = lowest.map(|i| heap.swap_remove(i).0);
+                self.by_value = heap.into();
+                evicted
+            }
+        };
+
+        if let Some(TimedTransaction { tx, .. }) = evicted {
+            self.seen_hashes.remove(&tx.get_hash());
+            self.release_sender(&tx.signer());
+            self.evicted += 1;
+            warn!(hash = %tx.hash, "Mempool is full, evicted transaction");
         }
     }
 
+    /// Pending transactions, members of bundles included
+    pub fn len(&self) -> usize {
+        let members: usize = self
+            .bundles
+            .iter()
+            .map(|pending| pending.bundle.len())
+            .sum();
+        self.transactions.len() + self.by_value.len() + members
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> MempoolStats {
+        MempoolStats {
+            pending: self.len(),
+            evicted: self.evicted,
+            expired: self.expired,
+            oldest_pending: self.oldest_pending(),
+        }
+    }
+
+    /// Replacements and returned transactions break the arrival order, so every entry is checked
+    fn oldest_pending(&self) -> Option<u64> {
+        let oldest = self
+            .transactions
+            .iter()
+            .chain(self.by_value.iter().map(|tx| &tx.0))
+            .map(|tx| tx.submitted_at)
+            .chain(self.bundles.iter().map(|pending| pending.submitted_at))
+            .min()?;
+
+        let submitted_at = SystemTime::now().checked_sub(oldest.elapsed())?;
+        submitted_at
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since_epoch| since_epoch.as_secs())
+    }
+
+    /// Whether a transaction with this hash is pending
+    pub fn contains(&self, hash: &B256) -> bool {
+        self.seen_hashes.contains(hash)
+    }
+
+    /// Takes out the next transaction, after which its hash can be submitted again
+    pub fn pop(&mut self) -> Option<Transaction> {
+        let tx = match self.ordering {
+            MempoolOrdering::Fifo => self.transactions.pop_front(),
+            MempoolOrdering::ByValue => self.by_value.pop().map(|tx| tx.0),
+        }?
+        .tx;
+
+        self.seen_hashes.remove(&tx.get_hash());
+        self.release_sender(&tx.signer());
+        Some(tx)
+    }
+
+    /// Pending transactions signed by `sender`, see [Transaction::signer]
+    pub fn sender_count(&self, sender: &Address) -> usize {
+        self.sender_counts.get(sender).copied().unwrap_or(0)
+    }
+
+    /// Forgets one pending transaction of `sender`, dropping the entry once it reaches zero
+    fn release_sender(&mut self, sender: &Address) {
+        if let Some(count) = self.sender_counts.get_mut(sender) {
+            *count -= 1;
+            if *count == 0 {
+                self.sender_counts.remove(sender);
+            }
+        }
+    }
+
+    #[cfg(test)]
     pub fn get_transactions(&mut self) -> Transactions {
+        self.take_transactions(MAX_BATCH_SIZE)
+    }
+
+    /// Takes out whole bundles first, then fills the rest of the batch with single transactions
+    pub fn get_batch(&mut self) -> Batch {
+        let mut bundles = Vec::new();
+        let mut members = 0;
+        while let Some(pending) = self.bundles.front() {
+            // A bundle bigger than a batch still goes out on its own
+            if members > 0 && members + pending.bundle.len() > MAX_BATCH_SIZE {
+                break;
+            }
+
+            let bundle = self.bundles.pop_front().unwrap().bundle;
+            for tx in bundle.transactions() {
+                self.seen_hashes.remove(&tx.hash);
+                self.release_sender(&tx.signer());
+            }
+            members += bundle.len();
+            bundles.push(bundle);
+        }
+
+        Batch {
+            transactions: self.take_transactions(MAX_BATCH_SIZE.saturating_sub(members)),
+            bundles,
+        }
+    }
+
+    fn take_transactions(&mut self, max: usize) -> Transactions {
         let mut transactions = Vec::new();
         // TODO: Make this more efficient with mem::swap or mem::copy or somthing
-        while let Some(tx) = self.pop() {
-            transactions.push(tx);
-            if transactions.len() >= 100 {
-                break;
+        while transactions.len() < max {
+            match self.pop() {
+                Some(tx) => transactions.push(tx),
+                None => break,
             }
         }
 
@@ -93,3 +787,495 @@ impl Mempool {
         transactions
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{utils::HashAlgo, ChainSpec, DatabaseWriter, InMemoryDB, TransactionKind};
+    use alloy_primitives::Address;
+    use tokio::sync::mpsc::unbounded_channel;
+
+    fn mempool(ordering: MempoolOrdering) -> Mempool {
+        bounded_mempool(ordering, DEFAULT_MEMPOOL_MAX_SIZE)
+    }
+
+    fn bounded_mempool(ordering: MempoolOrdering, max_size: usize) -> Mempool {
+        let (_, server_mempool_rx) = mpsc::channel(1);
+        let (_, executor_mempool_rx) = unbounded_channel();
+        let (_, status_rx) = mpsc::channel(1);
+        let (_, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        Mempool::new_with_capacity(
+            server_mempool_rx,
+            executor_mempool_rx,
+            status_rx,
+            ordering,
+            shutdown,
+            shutdown_complete,
+            max_size,
+        )
+        // Every test transaction comes from the same sender
+        .with_max_per_sender(usize::MAX)
+    }
+
+    fn transaction(nonce: u64, value: u128) -> Transaction {
+        let mut tx = Transaction {
+            nonce,
+            value,
+            ..Default::default()
+        };
+        tx.hash = tx.hash(HashAlgo::Keccak256);
+        tx
+    }
+
+    #[test]
+    fn test_by_value_drains_highest_value_first() {
+        let mut mempool = mempool(MempoolOrdering::ByValue);
+
+        // Small xorshift so the values are scattered without pulling in a rng
+        let mut seed = 0x2545_f491_u64;
+        let mut values = Vec::new();
+        while values.len() < 10 {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            let value = (seed % 1_000_000) as u128;
+            if !values.contains(&value) {
+                values.push(value);
+            }
+        }
+
+        for (nonce, value) in values.iter().enumerate() {
+            mempool.push(transaction(nonce as u64, *value)).unwrap();
+        }
+
+        let drained: Vec<u128> = std::iter::from_fn(|| mempool.pop())
+            .map(|tx| tx.value)
+            .collect();
+
+        assert_eq!(drained.len(), 10);
+        assert!(drained.windows(2).all(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn test_fifo_drains_in_arrival_order() {
+        let mut mempool = mempool(MempoolOrdering::Fifo);
+
+        for (nonce, value) in [5, 50, 1].into_iter().enumerate() {
+            mempool.push(transaction(nonce as u64, value)).unwrap();
+        }
+
+        let drained: Vec<u128> = std::iter::from_fn(|| mempool.pop())
+            .map(|tx| tx.value)
+            .collect();
+        assert_eq!(drained, vec![5, 50, 1]);
+    }
+
+    #[test]
+    fn test_full_mempool_stays_bounded() {
+        let mut mempool = bounded_mempool(MempoolOrdering::Fifo, 10);
+
+        for nonce in 0..25 {
+            mempool.push(transaction(nonce, 1)).unwrap();
+            assert!(mempool.len() <= 10);
+        }
+
+        assert_eq!(
+            mempool.stats(),
+            MempoolStats {
+                pending: 10,
+                evicted: 15,
+                ..mempool.stats()
+            }
+        );
+        // The oldest ones were evicted
+        assert_eq!(mempool.pop().unwrap().nonce, 15);
+    }
+
+    #[test]
+    fn test_full_by_value_mempool_evicts_lowest_value() {
+        let mut mempool = bounded_mempool(MempoolOrdering::ByValue, 3);
+
+        for (nonce, value) in [10, 1, 30, 20].into_iter().enumerate() {
+            mempool.push(transaction(nonce as u64, value)).unwrap();
+        }
+
+        assert_eq!(mempool.stats().evicted, 1);
+        let drained: Vec<u128> = std::iter::from_fn(|| mempool.pop())
+            .map(|tx| tx.value)
+            .collect();
+        assert_eq!(drained, vec![30, 20, 10]);
+    }
+
+    #[test]
+    fn test_duplicate_transaction_is_ignored() {
+        let mut mempool = mempool(MempoolOrdering::Fifo);
+        let tx = transaction(0, 10);
+
+        mempool.push(tx.clone()).unwrap();
+        mempool.push(tx.clone()).unwrap();
+        assert_eq!(mempool.len(), 1);
+        assert!(mempool.contains(&tx.hash));
+
+        // Once it is handed to the executor it can be submitted again
+        assert_eq!(mempool.get_transactions().iter().count(), 1);
+        assert!(!mempool.contains(&tx.hash));
+        mempool.push(tx).unwrap();
+        assert_eq!(mempool.len(), 1);
+    }
+
+    #[test]
+    fn test_sender_quota() {
+        let mut mempool = mempool(MempoolOrdering::Fifo).with_max_per_sender(2);
+        let from = |byte, nonce| {
+            let mut tx = Transaction {
+                from: Address::repeat_byte(byte),
+                ..transaction(nonce, 10)
+            };
+            tx.hash = tx.hash(HashAlgo::Keccak256);
+            tx
+        };
+        let (a, b) = (Address::repeat_byte(1), Address::repeat_byte(2));
+
+        mempool.push(from(1, 0)).unwrap();
+        mempool.push(from(1, 1)).unwrap();
+        assert!(matches!(
+            mempool.push(from(1, 2)),
+            Err(Error::SenderQuotaExceeded)
+        ));
+        assert_eq!(mempool.sender_count(&a), 2);
+
+        // Other senders are unaffected
+        mempool.push(from(2, 0)).unwrap();
+        mempool.push(from(2, 1)).unwrap();
+        assert_eq!(mempool.sender_count(&b), 2);
+
+        // Draining frees the quota
+        assert_eq!(mempool.get_transactions().iter().count(), 4);
+        assert_eq!(mempool.sender_count(&a), 0);
+        mempool.push(from(1, 2)).unwrap();
+        assert_eq!(mempool.sender_count(&a), 1);
+    }
+
+    #[test]
+    fn test_accepted_transactions_are_published() {
+        let (new_transactions, mut subscriber) = broadcast::channel(16);
+        let mut mempool = mempool(MempoolOrdering::Fifo)
+            .with_max_per_sender(2)
+            .with_new_transactions(new_transactions);
+
+        let (first, second) = (transaction(0, 10), transaction(1, 10));
+        mempool.push(first.clone()).unwrap();
+        mempool.push(second.clone()).unwrap();
+        // Neither a duplicate nor a transaction over the quota is news
+        mempool.push(first.clone()).unwrap();
+        mempool.push(transaction(2, 10)).unwrap_err();
+
+        assert_eq!(subscriber.try_recv().unwrap(), first);
+        assert_eq!(subscriber.try_recv().unwrap(), second);
+        assert!(subscriber.try_recv().is_err());
+
+        // A replacement is accepted as well, a replacement sending too little isn't
+        let replacement = transaction(0, 20);
+        mempool.push(replacement.clone()).unwrap();
+        mempool.push(transaction(1, 5)).unwrap_err();
+        assert_eq!(subscriber.try_recv().unwrap(), replacement);
+        assert!(subscriber.try_recv().is_err());
+
+        // Returned transactions were published when they were first accepted
+        let transactions = mempool.get_transactions();
+        mempool.push_front(transactions);
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_replace_by_value() {
+        for ordering in [MempoolOrdering::Fifo, MempoolOrdering::ByValue] {
+            let mut mempool = mempool(ordering);
+            mempool.push(transaction(0, 10)).unwrap();
+            mempool.push(transaction(1, 5)).unwrap();
+
+            let replacement = transaction(0, 20);
+            mempool.push(replacement.clone()).unwrap();
+            assert_eq!(mempool.len(), 2);
+            assert!(mempool.contains(&replacement.hash));
+            assert!(!mempool.contains(&transaction(0, 10).hash));
+
+            let transactions = mempool.get_transactions();
+            let nonce_zero: Vec<_> = transactions.iter().filter(|tx| tx.nonce == 0).collect();
+            assert_eq!(nonce_zero, vec![&replacement]);
+        }
+    }
+
+    #[test]
+    fn test_replacement_needs_higher_value() {
+        for ordering in [MempoolOrdering::Fifo, MempoolOrdering::ByValue] {
+            let mut mempool = mempool(ordering);
+            let original = transaction(0, 10);
+            mempool.push(original.clone()).unwrap();
+
+            // Same value to somebody else, and a lower value
+            let mut same_value = transaction(0, 10);
+            same_value.to = Address::repeat_byte(1);
+            same_value.hash = same_value.hash(HashAlgo::Keccak256);
+            for tx in [same_value, transaction(0, 3)] {
+                let err = mempool.push(tx).unwrap_err();
+                assert!(matches!(err, Error::ReplacementValueTooLow));
+            }
+
+            assert_eq!(
+                mempool.get_transactions().into_iter().collect::<Vec<_>>(),
+                vec![original]
+            );
+        }
+    }
+
+    #[test]
+    fn test_spenders_neither_replace_nor_use_up_the_owner() {
+        let spender = Address::repeat_byte(9);
+        let spend = |nonce, value| {
+            let mut tx = Transaction {
+                kind: TransactionKind::TransferFrom { spender },
+                ..transaction(nonce, value)
+            };
+            tx.hash = tx.hash(HashAlgo::Keccak256);
+            tx
+        };
+
+        for ordering in [MempoolOrdering::Fifo, MempoolOrdering::ByValue] {
+            let mut mempool = mempool(ordering).with_max_per_sender(1);
+            let owned = transaction(0, 10);
+            mempool.push(owned.clone()).unwrap();
+
+            // Sends more from the same account and nonce, but isn't signed by the owner
+            let spent = spend(0, 20);
+            mempool.push(spent.clone()).unwrap();
+            assert!(mempool.contains(&owned.hash));
+            assert_eq!(mempool.sender_count(&owned.from), 1);
+            assert_eq!(mempool.sender_count(&spender), 1);
+
+            // Each of them only ran out of its own quota
+            for tx in [transaction(1, 10), spend(1, 10)] {
+                let err = mempool.push(tx).unwrap_err();
+                assert!(matches!(err, Error::SenderQuotaExceeded));
+            }
+
+            // The spender still replaces its own transaction
+            let respent = spend(0, 30);
+            mempool.push(respent.clone()).unwrap();
+            assert!(!mempool.contains(&spent.hash));
+
+            let mut drained: Vec<_> = mempool.get_transactions().into_iter().collect();
+            drained.sort_by_key(|tx| tx.value);
+            assert_eq!(drained, vec![owned, respent]);
+            assert_eq!(mempool.sender_count(&spender), 0);
+        }
+    }
+
+    #[test]
+    fn test_bundles_are_taken_whole_and_first() {
+        let mut mempool = mempool(MempoolOrdering::Fifo);
+        mempool.push(transaction(5, 1)).unwrap();
+        let bundle = Bundle::new(
+            vec![transaction(0, 1), transaction(1, 1)],
+            HashAlgo::Keccak256,
+        )
+        .unwrap();
+        mempool.push_bundle(bundle.clone()).unwrap();
+        assert_eq!(mempool.len(), 3);
+
+        // Members can't be submitted twice
+        let again = Bundle::new(vec![transaction(1, 1)], HashAlgo::Keccak256).unwrap();
+        assert!(matches!(
+            mempool.push_bundle(again),
+            Err(Error::InvalidBundle(_))
+        ));
+
+        let batch = mempool.get_batch();
+        assert_eq!(batch.bundles, vec![bundle]);
+        assert_eq!(
+            batch.transactions,
+            Transactions::from(vec![transaction(5, 1)])
+        );
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.sender_count(&Address::ZERO), 0);
+    }
+
+    #[test]
+    fn test_bundles_expire_whole() {
+        let mut mempool = mempool(MempoolOrdering::Fifo).with_ttl(Duration::ZERO);
+        let bundle = Bundle::new(
+            vec![transaction(0, 1), transaction(1, 1)],
+            HashAlgo::Keccak256,
+        )
+        .unwrap();
+        mempool.push_bundle(bundle).unwrap();
+
+        std::thread::sleep(Duration::from_millis(5));
+        mempool.evict_expired();
+
+        assert!(mempool.is_empty());
+        assert!(mempool.get_batch().bundles.is_empty());
+        assert_eq!(mempool.stats().expired, 2);
+    }
+
+    #[test]
+    fn test_expired_transactions_are_dropped() {
+        for ordering in [MempoolOrdering::Fifo, MempoolOrdering::ByValue] {
+            let mut mempool = mempool(ordering).with_ttl(Duration::from_millis(20));
+            let old = transaction(0, 10);
+            mempool.push(old.clone()).unwrap();
+            std::thread::sleep(Duration::from_millis(40));
+
+            let fresh = transaction(1, 10);
+            mempool.push(fresh.clone()).unwrap();
+            mempool.evict_expired();
+
+            assert_eq!(mempool.stats().expired, 1);
+            assert!(!mempool.contains(&old.hash));
+            assert_eq!(mempool.pop(), Some(fresh));
+            assert!(mempool.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_oldest_pending() {
+        let mut mempool = mempool(MempoolOrdering::ByValue);
+        assert_eq!(mempool.stats().oldest_pending, None);
+
+        let before = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        mempool.push(transaction(0, 10)).unwrap();
+        mempool.push(transaction(1, 20)).unwrap();
+
+        let oldest = mempool.stats().oldest_pending.unwrap();
+        assert!(oldest + 1 >= before.as_secs());
+        assert!(oldest <= before.as_secs() + 1);
+    }
+
+    #[tokio::test]
+    async fn test_pending_transactions_survive_restart() {
+        let path = std::env::temp_dir().join(format!(
+            "mini-blockchain-{}-mempool.json",
+            std::process::id()
+        ));
+        let spec = ChainSpec::new(1).with_dev_account(1, crate::Account::new(100, 0));
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let config = ValidationConfig::from(&spec);
+        let validator = TxValidator::new();
+
+        let (_server_mempool_tx, server_mempool_rx) = mpsc::channel(1);
+        let (_executor_mempool_tx, executor_mempool_rx) = unbounded_channel();
+        let (_status_tx, status_rx) = mpsc::channel(1);
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let mut mempool = Mempool::new(
+            server_mempool_rx,
+            executor_mempool_rx,
+            status_rx,
+            MempoolOrdering::Fifo,
+            shutdown,
+            shutdown_complete,
+        )
+        .with_persistence(path.clone(), &db, &validator, config);
+
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let transactions: Vec<_> = (0..5)
+            .map(|_| account.transfer(Address::repeat_byte(9), 1))
+            .collect();
+        for tx in &transactions {
+            mempool.push(tx.clone()).unwrap();
+        }
+
+        notify_shutdown.send(()).unwrap();
+        mempool.run().await.unwrap();
+        drop(mempool);
+
+        let mut restored = self::mempool(MempoolOrdering::Fifo).with_persistence(
+            path.clone(),
+            &db,
+            &validator,
+            config,
+        );
+        assert!(!path.exists());
+        assert_eq!(restored.len(), transactions.len());
+        assert_eq!(restored.get_transactions(), transactions.into());
+    }
+
+    #[tokio::test]
+    async fn test_pending_transactions_survive_the_server_going_away() {
+        let path = std::env::temp_dir().join(format!(
+            "mini-blockchain-{}-dropped-mempool.json",
+            std::process::id()
+        ));
+        let spec = ChainSpec::new(1).with_dev_account(1, crate::Account::new(100, 0));
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let config = ValidationConfig::from(&spec);
+        let validator = TxValidator::new();
+
+        let (server_mempool_tx, server_mempool_rx) = mpsc::channel(1);
+        let (executor_mempool_tx, executor_mempool_rx) = unbounded_channel();
+        let (status_tx, status_rx) = mpsc::channel(1);
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let mut mempool = Mempool::new(
+            server_mempool_rx,
+            executor_mempool_rx,
+            status_rx,
+            MempoolOrdering::Fifo,
+            shutdown,
+            shutdown_complete,
+        )
+        .with_persistence(path.clone(), &db, &validator, config);
+
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let tx = account.transfer(Address::repeat_byte(9), 1);
+        server_mempool_tx.send(tx.clone()).await.unwrap();
+
+        // The order of a ctrl-c before the signal was sent first: the server and its senders
+        // are dropped while the shutdown signal is still to come
+        drop((server_mempool_tx, executor_mempool_tx, status_tx));
+        mempool.run().await.unwrap();
+        drop((mempool, notify_shutdown));
+
+        let mut restored = self::mempool(MempoolOrdering::Fifo).with_persistence(
+            path.clone(),
+            &db,
+            &validator,
+            config,
+        );
+        assert_eq!(restored.get_transactions(), vec![tx].into());
+    }
+
+    #[test]
+    fn test_tampered_persisted_transactions_are_dropped() {
+        let path = std::env::temp_dir().join(format!(
+            "mini-blockchain-{}-tampered-mempool.json",
+            std::process::id()
+        ));
+        let spec = ChainSpec::new(1).with_dev_account(1, crate::Account::new(100, 0));
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let valid = account.transfer(Address::repeat_byte(9), 1);
+        let mut tampered = account.transfer(Address::repeat_byte(9), 1);
+        tampered.value = 50;
+        let other_chain = account
+            .clone()
+            .with_chain_id(2)
+            .transfer(Address::repeat_byte(9), 1);
+        let persisted = vec![valid.clone(), tampered, other_chain];
+        std::fs::write(&path, serde_json::to_vec(&persisted).unwrap()).unwrap();
+
+        let mut restored = mempool(MempoolOrdering::Fifo).with_persistence(
+            path.clone(),
+            &db,
+            &TxValidator::new(),
+            ValidationConfig::from(&spec),
+        );
+        assert!(!path.exists());
+        assert_eq!(restored.get_transactions(), vec![valid].into());
+    }
+}
@@ -1,27 +1,120 @@
+mod difficulty;
 mod mempool;
 
 use crate::{
     database::{DatabaseReader, DatabaseWriter},
-    Account, Block, BlockHeader, ChangeSet, Error, SealedBlock, Shutdown, State,
-    TransactionReceipt, Transactions,
+    utils::HashAlgo,
+    validation::{
+        TxValidator, ValidationConfig, ValidationContext, ValidationOutcome, ValidationPhase,
+    },
+    Account, Block, BlockHeader, Bundle, BundleReceipt, ChangeSet, CoinbaseMaturity, Error,
+    FailureReason, SealedBlock, Shutdown, State, Transaction, TransactionKind, TransactionReceipt,
+    Transactions,
 };
 use alloy_primitives::{Address, B256, U256};
+use clap::ValueEnum;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 use std::time::{SystemTime, UNIX_EPOCH};
-use std::{sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use tokio::{
     select,
     sync::{
         broadcast,
         mpsc::{self, UnboundedReceiver, UnboundedSender},
-        oneshot, RwLock, RwLockReadGuard, RwLockWriteGuard,
+        oneshot, watch, RwLock, RwLockReadGuard, RwLockWriteGuard,
     },
+    time::Interval,
 };
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 const INITIAL_HASH: B256 = B256::ZERO;
 
-pub use mempool::{Mempool, MempoolOrdering};
-pub type ExecutorMempoolTx = UnboundedSender<oneshot::Sender<Transactions>>;
-pub type ExecutorMempoolRx = UnboundedReceiver<oneshot::Sender<Transactions>>;
+/// Most gas the transactions of a block may take up together by default
+pub const DEFAULT_BLOCK_GAS_LIMIT: u64 = 30_000_000;
+
+/// Nonces [Executor::mine_block] tries between two looks at its cancel flag
+const CANCEL_CHECK_INTERVAL: u64 = 1 << 16;
+
+pub use difficulty::{DifficultyAdjuster, ADJUSTMENT_INTERVAL};
+pub use mempool::{
+    Mempool, MempoolOrdering, MempoolStats, DEFAULT_MEMPOOL_MAX_PER_SENDER,
+    DEFAULT_MEMPOOL_MAX_SIZE, DEFAULT_MEMPOOL_TTL,
+};
+pub type ExecutorMempoolTx = UnboundedSender<MempoolRequest>;
+pub type ExecutorMempoolRx = UnboundedReceiver<MempoolRequest>;
+/// Handlers ask the [Mempool] for its [MempoolStats], which are sent back over the oneshot
+pub type MempoolStatusTx = mpsc::Sender<oneshot::Sender<MempoolStats>>;
+pub type MempoolStatusRx = mpsc::Receiver<oneshot::Sender<MempoolStats>>;
+/// Handlers ask the [Executor] to run a transaction without writing it, see
+/// [Executor::simulate_transaction]
+pub type SimulationTx = mpsc::Sender<(Transaction, oneshot::Sender<TransactionReceipt>)>;
+pub type SimulationRx = mpsc::Receiver<(Transaction, oneshot::Sender<TransactionReceipt>)>;
+/// Handlers hand the [Executor] blocks of other nodes, see [Executor::import_block]
+pub type ImportTx = mpsc::Sender<(SealedBlock, oneshot::Sender<Result<(), Error>>)>;
+pub type ImportRx = mpsc::Receiver<(SealedBlock, oneshot::Sender<Result<(), Error>>)>;
+
+/// Requests the [Executor] sends to the [Mempool]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MempoolRequest {
+    /// Transactions and bundles for the next block
+    GetBatch(oneshot::Sender<Batch>),
+    /// Transactions that weren't included, they are the first to go into the next block
+    Return(Transactions),
+    /// Bundles of a block that wasn't written, they go out with the next batch again
+    ReturnBundles(Vec<Bundle>),
+}
+
+/// Everything the [Mempool] hands out for one block
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    pub transactions: Transactions,
+    /// Executed after the single transactions, each one as a unit
+    pub bundles: Vec<Bundle>,
+}
+
+/// What the [Executor] does with a batch in which every transaction failed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum FailedBlockPolicy {
+    /// Seal the block with all of its failed transactions
+    #[default]
+    Always,
+    /// Drop the transactions and seal an empty block
+    Skip,
+    /// Return the transactions to the mempool once and seal an empty block
+    ///
+    /// If they fail again in a later batch they are dropped
+    Defer,
+}
+
+/// Whether the node produces blocks or only follows another node that does
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum NodeRole {
+    /// Builds a new block every block time
+    #[default]
+    Producer,
+    /// Never builds blocks, imports the blocks of the producer until promoted
+    Follower,
+}
+
+impl fmt::Display for NodeRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeRole::Producer => write!(f, "producer"),
+            NodeRole::Follower => write!(f, "follower"),
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Executor<DB> {
@@ -31,6 +124,48 @@ pub struct Executor<DB> {
     pub coinbase: Address,
     pub last_hash: B256,
     pub next_number: u64,
+    /// Reserve accounts have to keep, see [crate::ChainSpec::min_balance]
+    pub min_balance: Option<u128>,
+    /// See [crate::ChainSpec::coinbase_maturity]
+    pub coinbase_maturity: CoinbaseMaturity,
+    /// See [crate::ChainSpec::hash_algo]
+    pub hash_algo: HashAlgo,
+    pub chain_id: u64,
+    /// Decides which transactions of a block fail, shared with the connection handlers
+    pub validator: Arc<TxValidator>,
+    /// Every sealed block is published here once it is written
+    pub new_blocks: broadcast::Sender<SealedBlock>,
+    /// Current role of the node, the [crate::Server] flips it on promotion and demotion
+    pub role: watch::Receiver<NodeRole>,
+    /// Skips building blocks while `true`, transactions keep waiting in the mempool
+    pub paused: watch::Receiver<bool>,
+    pub failed_block_policy: FailedBlockPolicy,
+    /// Hashes of transactions that were returned to the mempool by [FailedBlockPolicy::Defer]
+    pub deferred: HashSet<B256>,
+    /// See [crate::ChainSpec::checkpoints]
+    pub checkpoints: BTreeMap<u64, B256>,
+    /// Coinbases other than [Executor::coinbase] blocks may be sealed for, see
+    /// [crate::ChainSpec::producers]
+    pub producers: BTreeSet<Address>,
+    /// Recorded in the header of every block and credited to the coinbase
+    pub block_reward: u128,
+    /// Recorded in the header of every block, see [FailureReason::ExceedsBlockGasLimit]
+    pub block_gas_limit: u64,
+    /// Target the hash of the next block has to stay below, see [Executor::mine_block]
+    pub current_difficulty: U256,
+    /// Whether [Executor::write_block] checks blocks before writing them
+    pub validate: bool,
+    /// Executes transactions of unrelated accounts on several threads, see
+    /// [Executor::with_parallel_execution]
+    pub parallel_execution: bool,
+    /// Recalculates [Executor::current_difficulty] every few blocks
+    pub difficulty_adjuster: DifficultyAdjuster,
+    /// Block [Executor::current_difficulty] was last recalculated at
+    pub last_adjustment: u64,
+    /// Transactions to answer with [Executor::simulate_transaction]
+    pub simulations: SimulationRx,
+    /// Blocks to add with [Executor::import_block]
+    pub imports: ImportRx,
     pub shutdown: Shutdown,
     pub _shutdown_complete: mpsc::Sender<()>,
 }
@@ -52,55 +187,289 @@ where
             coinbase,
             block_time,
             db,
+            // Until [Executor::sync_head] finds the head of the database
             last_hash: INITIAL_HASH,
             next_number: 1,
+            min_balance: None,
+            coinbase_maturity: CoinbaseMaturity::default(),
+            hash_algo: HashAlgo::default(),
+            chain_id: 0,
+            validator: Arc::new(TxValidator::new()),
+            new_blocks: broadcast::channel(1).0,
+            role: watch::channel(NodeRole::Producer).1,
+            paused: watch::channel(false).1,
+            failed_block_policy: FailedBlockPolicy::default(),
+            deferred: HashSet::new(),
+            checkpoints: BTreeMap::new(),
+            producers: BTreeSet::new(),
+            block_reward: 0,
+            block_gas_limit: DEFAULT_BLOCK_GAS_LIMIT,
+            current_difficulty: U256::MAX,
+            validate: false,
+            parallel_execution: false,
+            difficulty_adjuster: DifficultyAdjuster::default(),
+            last_adjustment: 1,
+            simulations: mpsc::channel(1).1,
+            imports: mpsc::channel(1).1,
             shutdown: Shutdown::new(shutdown),
             _shutdown_complete: shutdown_complete,
         }
     }
-    pub async fn run(mut self) -> Result<(), Error> {
-        info!("Executor Initialized Successfuly");
+
+    pub fn with_min_balance(mut self, min_balance: Option<u128>) -> Self {
+        self.min_balance = min_balance;
+        self
+    }
+
+    pub fn with_coinbase_maturity(mut self, coinbase_maturity: CoinbaseMaturity) -> Self {
+        self.coinbase_maturity = coinbase_maturity;
+        self
+    }
+
+    pub fn with_hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.hash_algo = hash_algo;
+        self
+    }
+
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = chain_id;
+        self
+    }
+
+    pub fn with_validator(mut self, validator: Arc<TxValidator>) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    pub fn with_new_blocks(mut self, new_blocks: broadcast::Sender<SealedBlock>) -> Self {
+        self.new_blocks = new_blocks;
+        self
+    }
+
+    pub fn with_role(mut self, role: watch::Receiver<NodeRole>) -> Self {
+        self.role = role;
+        self
+    }
+
+    pub fn with_paused(mut self, paused: watch::Receiver<bool>) -> Self {
+        self.paused = paused;
+        self
+    }
+
+    pub fn with_checkpoints(mut self, checkpoints: BTreeMap<u64, B256>) -> Self {
+        self.checkpoints = checkpoints;
+        self
+    }
+
+    pub fn with_producers(mut self, producers: BTreeSet<Address>) -> Self {
+        self.producers = producers;
+        self
+    }
+
+    pub fn with_failed_block_policy(mut self, failed_block_policy: FailedBlockPolicy) -> Self {
+        self.failed_block_policy = failed_block_policy;
+        self
+    }
+
+    pub fn with_block_reward(mut self, block_reward: u128) -> Self {
+        self.block_reward = block_reward;
+        self
+    }
+
+    pub fn with_block_gas_limit(mut self, block_gas_limit: u64) -> Self {
+        self.block_gas_limit = block_gas_limit;
+        self
+    }
+
+    /// Rejects blocks that don't pass [Executor::validate_block] with [Error::InvalidBlock]
+    #[cfg(test)]
+    pub fn with_block_validation(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// Executes the transactions of a block that share no account in parallel, bundles are
+    /// still executed one after the other
+    ///
+    /// Grouping the transactions and merging their changes is overhead that only pays off with
+    /// several cores, on a single core the block takes longer than executed sequentially
+    pub fn with_parallel_execution(mut self, parallel_execution: bool) -> Self {
+        self.parallel_execution = parallel_execution;
+        self
+    }
+
+    /// Answers the simulations handlers send over the other half of `simulations` in between
+    /// blocks
+    pub fn with_simulations(mut self, simulations: SimulationRx) -> Self {
+        self.simulations = simulations;
+        self
+    }
+
+    /// Imports the blocks handlers send over the other half of `imports` in between blocks
+    pub fn with_imports(mut self, imports: ImportRx) -> Self {
+        self.imports = imports;
+        self
+    }
+
+    /// Sets the difficulty the chain starts with, see [DifficultyAdjuster]
+    pub fn with_difficulty(mut self, difficulty: U256) -> Self {
+        self.current_difficulty = difficulty;
+        self
+    }
+
+    pub async fn run(&mut self) -> Result<(), Error> {
+        info!(role = %*self.role.borrow(), "Executor Initialized Successfuly");
+        // The database may already hold a chain, from the spec or a loaded dump
+        self.sync_head().await;
         let mut interval = tokio::time::interval(Duration::from_secs(self.block_time));
         interval.tick().await;
 
+        // Once the sender is gone the role can't change anymore, so we stop listening for it
+        let mut role_updates = true;
+        let mut simulations = true;
+        let mut imports = true;
+
         while !self.shutdown.is_shutdown() {
+            let producing = *self.role.borrow() == NodeRole::Producer;
+
             select! {
-                _ = interval.tick() => {}
+                _ = interval.tick(), if producing => {}
+                res = self.role.changed(), if role_updates => {
+                    match res {
+                        Ok(()) => self.on_role_change(&mut interval).await,
+                        Err(_) => role_updates = false,
+                    }
+                    continue;
+                }
+                req = self.simulations.recv(), if simulations => {
+                    match req {
+                        Some((tx, reply)) => {
+                            let db = self.db.read().await;
+                            // The handler may have given up on the answer already
+                            let _ = reply.send(self.simulate_transaction(&db, tx));
+                        }
+                        None => simulations = false,
+                    }
+                    continue;
+                }
+                req = self.imports.recv(), if imports => {
+                    match req {
+                        Some((block, reply)) => {
+                            let _ = reply.send(self.import_block(block).await);
+                        }
+                        None => imports = false,
+                    }
+                    continue;
+                }
                 _ = self.shutdown.recv() => {
                     return Ok(());
                 }
             }
 
-            let block = match self.build_block().await {
-                Ok(block) => block,
+            // The tick is used up, so the first block after resuming comes a block time later
+            if *self.paused.borrow() {
+                debug!("Paused, skipping block");
+                continue;
+            }
+
+            let (block, bundles) = match self.build_block().await {
+                Ok(built) => built,
                 Err(e) => {
                     error!(err = %e, "Failed to get transactions from mempool, retrying...");
                     continue;
                 }
             };
 
-            debug!("\n{:#?}", block);
-
-            let block_hash = *block.get_hash();
-
             // Get read lock since for executing the transactions we only need to read the db
             let db = self.db.read().await;
 
-            let change_set = self.execute_transactions(&db, &block).into();
+            let change_set = self.execute_block(&db, &block, &bundles).into();
 
             // Here we have to drop the db_reader otherwise we just shadow it in the next line
             // And create a deadlock, because this lock will be dropped at the end of the scope
             // even if shadowed, thus the next line will wait forever
             drop(db);
 
+            let (block, mut change_set) = self.apply_failed_block_policy(block, change_set);
+
+            // The block is only sealed once we know what ends up in it, so the receipts learn
+            // its hash here. The nonce search would stall the runtime, so it gets its own thread
+            let cancel = Arc::new(AtomicBool::new(false));
+            let transactions = block.transactions().clone();
+            let mut mining = tokio::task::spawn_blocking({
+                let cancel = cancel.clone();
+                let algo = self.hash_algo;
+                move || Self::mine_block(block, &cancel, algo)
+            });
+            let mined = loop {
+                select! {
+                    res = &mut mining => {
+                        break Some(res.map_err(Error::from).and_then(|mined| mined));
+                    }
+                    _ = self.shutdown.recv() => {
+                        // Nothing of the block was written yet, so it is simply dropped
+                        cancel.store(true, Ordering::Relaxed);
+                        return Ok(());
+                    }
+                    res = self.role.wait_for(|role| *role == NodeRole::Follower),
+                        if role_updates =>
+                    {
+                        match res {
+                            // A demoted node must not write the block, the new producer builds
+                            // this height
+                            Ok(_) => {
+                                cancel.store(true, Ordering::Relaxed);
+                                break None;
+                            }
+                            // The role can't change anymore, so the block is mined to the end
+                            Err(_) => role_updates = false,
+                        }
+                    }
+                }
+            };
+            let block = match mined {
+                Some(Ok(block)) => block,
+                Some(Err(e)) => {
+                    error!(err = %e, "Couldn't mine block, skipping");
+                    continue;
+                }
+                None => {
+                    warn!("Demoted to follower while mining, dropping the block");
+                    self.return_batch(transactions, bundles);
+                    continue;
+                }
+            };
+            let block_hash = *block.get_hash();
+            change_set.set_block_hash(block_hash);
+
+            // We are rebuilding a height the operator pinned to another block, so this chain
+            // diverged and must not grow any further
+            if let Some(expected) = self.checkpoints.get(&block.number()) {
+                if *expected != block_hash {
+                    return Err(Error::CheckpointMismatch {
+                        number: block.number(),
+                        expected: *expected,
+                        found: Some(block_hash),
+                    });
+                }
+            }
+
+            debug!("\n{:#?}", block);
+
             let mut db = self.db.write().await;
 
-            if let Err(e) = self.write_changeset(&mut db, change_set) {
-                error!(err = %e, "Couldn't write change_set to database, skipping");
+            // Subscribers may look the block up right away, so it is published once written
+            let published = (self.new_blocks.receiver_count() > 0).then(|| block.clone());
+
+            // The block goes first, so a block that fails validation leaves no state behind
+            if let Err(e) = self.write_block(&mut db, block) {
+                error!(err = %e, "Couldn't write block to database, skipping");
+                drop(db);
+                self.return_batch(transactions, bundles);
                 continue;
             }
 
-            if let Err(e) = self.write_block(&mut db, block) {
+            if let Err(e) = self.write_changeset(&mut db, change_set) {
                 error!(err = %e, "Couldn't write change_set to database, skipping");
                 continue;
             }
@@ -108,35 +477,341 @@ where
             // We always want to drop the lock as soon as possible
             drop(db);
 
+            if let Some(block) = published {
+                // Every subscriber may have left in the meantime
+                let _ = self.new_blocks.send(block);
+            }
+
             self.last_hash = block_hash;
             self.next_number += 1;
+
+            let number = self.next_number - 1;
+            if self
+                .difficulty_adjuster
+                .is_due(number, self.last_adjustment)
+            {
+                self.adjust_difficulty(number).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Followers stop at the next tick, a promoted node continues on top of the head it
+    /// has in the database so no block number is produced twice
+    async fn on_role_change(&mut self, interval: &mut Interval) {
+        let role = *self.role.borrow_and_update();
+
+        match role {
+            NodeRole::Producer => {
+                self.sync_head().await;
+                interval.reset();
+                info!(next_number = self.next_number, "Promoted to producer");
+            }
+            NodeRole::Follower => warn!("Demoted to follower, no longer producing blocks"),
+        }
+    }
+
+    /// Continues the chain from the latest block in the database, at the difficulty the next
+    /// block of that chain has
+    pub async fn sync_head(&mut self) {
+        let db = self.db.read().await;
+        let head = match db.latest_block() {
+            Some(head) => head,
+            None => return,
+        };
+        let number = head.number();
+        self.last_hash = *head.get_hash();
+        self.next_number = number + 1;
+
+        // Recalculations happen every interval counted from the first block, the genesis
+        // block doesn't count
+        let interval = self.difficulty_adjuster.interval();
+        let since = number.saturating_sub(1) % interval;
+        self.last_adjustment = (number - since).max(1);
+
+        // The genesis block isn't mined, the first block has the configured difficulty
+        if number == 0 {
+            return;
+        }
+        self.current_difficulty = *head.difficulty();
+        drop(db);
+
+        // The head was sealed before the difficulty was recalculated on top of it
+        if since == 0 && number > 1 {
+            self.last_adjustment = number - interval;
+            self.adjust_difficulty(number).await;
+        }
+    }
+
+    /// Adds a block produced by another node on top of the head, after checking it with
+    /// [Executor::validate_block] and against the checkpoints, and executes its transactions
+    pub async fn import_block(&mut self, block: SealedBlock) -> Result<(), Error> {
+        self.validate_block(&block)?;
+        let block_hash = *block.get_hash();
+        if let Some(expected) = self.checkpoints.get(&block.number()) {
+            if *expected != block_hash {
+                return Err(Error::CheckpointMismatch {
+                    number: block.number(),
+                    expected: *expected,
+                    found: Some(block_hash),
+                });
+            }
+        }
+
+        // Nothing about the transactions of another node can be trusted, so they go through
+        // every check again
+        let db = self.db.read().await;
+        let unsealed = block.clone().unseal();
+        let mut change_set: ChangeSet = self
+            .execute_in_phase(&db, &unsealed, &[], ValidationPhase::Import)
+            .into();
+        drop(db);
+        change_set.set_block_hash(block_hash);
+
+        let number = block.number();
+        let published = (self.new_blocks.receiver_count() > 0).then(|| block.clone());
+        let mut db = self.db.write().await;
+        self.write_block(&mut db, block)?;
+        self.write_changeset(&mut db, change_set)?;
+        drop(db);
+
+        if let Some(block) = published {
+            let _ = self.new_blocks.send(block);
+        }
+
+        self.last_hash = block_hash;
+        self.next_number = number + 1;
+        info!(number, hash = %block_hash, "Imported block");
+
+        if self
+            .difficulty_adjuster
+            .is_due(number, self.last_adjustment)
+        {
+            self.adjust_difficulty(number).await;
         }
         Ok(())
     }
 
-    pub async fn build_block(&self) -> Result<SealedBlock, Error> {
+    /// Scales [Executor::current_difficulty] by how long the blocks since the last
+    /// recalculation took compared to the block time, `number` is the block just written
+    pub async fn adjust_difficulty(&mut self, number: u64) {
+        let db = self.db.read().await;
+        let actual = match (
+            db.read_block_by_number(self.last_adjustment),
+            db.read_block_by_number(number),
+        ) {
+            (Some(first), Some(last)) => last.timestamp().saturating_sub(first.timestamp()),
+            _ => {
+                warn!(
+                    number,
+                    "Blocks since the last difficulty adjustment are missing"
+                );
+                return;
+            }
+        };
+        drop(db);
+
+        let target = (number - self.last_adjustment) * self.block_time;
+        let previous = self.current_difficulty;
+        self.current_difficulty = self.difficulty_adjuster.adjust(previous, actual, target);
+        self.last_adjustment = number;
+        info!(number, actual, target, difficulty = %self.current_difficulty, "Adjusted difficulty");
+    }
+
+    /// Builds the next block out of the transactions in the mempool, it is sealed after the
+    /// transactions are executed
+    ///
+    /// The members of the bundles follow the single transactions, in the order of the bundles
+    pub async fn build_block(&self) -> Result<(Block, Vec<Bundle>), Error> {
         let (oneshot_tx, oneshot_rx) = oneshot::channel();
         self.executor_mempool_tx
-            .send(oneshot_tx)
+            .send(MempoolRequest::GetBatch(oneshot_tx))
             .map_err(|_| Error::ChannelFailure)?;
 
-        let transactions = oneshot_rx.await.map_err(|_| Error::ChannelFailure)?;
+        let Batch {
+            mut transactions,
+            bundles,
+        } = oneshot_rx.await.map_err(|_| Error::ChannelFailure)?;
+
+        for bundle in &bundles {
+            for tx in bundle.transactions() {
+                transactions.push(tx.clone());
+            }
+        }
 
         let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
-        let tx_root = transactions.get_root();
+        Ok((self.next_block(transactions, timestamp), bundles))
+    }
+
+    /// Block on top of the current head, the one [Executor::build_block] would produce
+    pub fn next_block(&self, transactions: Transactions, timestamp: u64) -> Block {
+        let tx_root = transactions.get_root(self.hash_algo);
 
         let header = BlockHeader {
             parent_hash: self.last_hash,
             nonce: 0,
-            difficulty: U256::MAX,
+            difficulty: self.current_difficulty,
             number: self.next_number,
             timestamp,
             coinbase: self.coinbase,
             tx_root,
+            block_reward: self.block_reward,
+            gas_limit: self.block_gas_limit,
         };
 
-        let block = Block::new(header, transactions);
-        Ok(block.seal_slow())
+        Block::new(header, transactions)
+    }
+
+    /// Searches the nonce upward from the one in the header until the hash of the block, read
+    /// as a little endian number, is at most its difficulty, so [SealedBlock::verify] accepts it
+    /// when hashing with `algo`
+    ///
+    /// Once every nonce was tried the timestamp moves a second ahead and the search starts over
+    /// from 0. Gives up with [Error::MiningCancelled] soon after `cancel` is set
+    pub fn mine_block(
+        mut block: Block,
+        cancel: &AtomicBool,
+        algo: HashAlgo,
+    ) -> Result<SealedBlock, Error> {
+        // Only a hash of 0 would do, the search would never end
+        if block.header.difficulty.is_zero() {
+            return Err(Error::InvalidBlock("difficulty is 0".to_string()));
+        }
+
+        loop {
+            if block.header.nonce.is_multiple_of(CANCEL_CHECK_INTERVAL)
+                && cancel.load(Ordering::Relaxed)
+            {
+                return Err(Error::MiningCancelled);
+            }
+
+            let hash = block.hash(algo);
+            if U256::from_le_slice(&hash[..]) <= block.header.difficulty {
+                return Ok(block.seal(hash));
+            }
+
+            match block.header.nonce.checked_add(1) {
+                Some(nonce) => block.header.nonce = nonce,
+                None => {
+                    block.header.timestamp += 1;
+                    block.header.nonce = 0;
+                }
+            }
+        }
+    }
+
+    /// Hands the transactions and bundles of a block that couldn't be written back to the
+    /// mempool, so they go into the next block instead of being lost
+    fn return_batch(&mut self, transactions: Transactions, bundles: Vec<Bundle>) {
+        // An empty block had every transaction dropped or deferred by the FailedBlockPolicy
+        // already, bundles included
+        if transactions.is_empty() {
+            return;
+        }
+
+        let in_bundle: HashSet<B256> = bundles
+            .iter()
+            .flat_map(|bundle| bundle.transactions().iter().map(|tx| tx.hash))
+            .collect();
+        let single: Transactions = transactions
+            .into_iter()
+            .filter(|tx| !in_bundle.contains(&tx.hash))
+            .collect::<Vec<_>>()
+            .into();
+
+        // They weren't part of any block, so a later one may defer them again
+        for tx in single.iter() {
+            self.deferred.remove(&tx.hash);
+        }
+
+        if !single.is_empty()
+            && self
+                .executor_mempool_tx
+                .send(MempoolRequest::Return(single))
+                .is_err()
+        {
+            error!("Couldn't return the transactions of the block to the mempool, dropping them");
+        }
+        if !bundles.is_empty()
+            && self
+                .executor_mempool_tx
+                .send(MempoolRequest::ReturnBundles(bundles))
+                .is_err()
+        {
+            error!("Couldn't return the bundles of the block to the mempool, dropping them");
+        }
+    }
+
+    /// Decides what ends up in a block whose transactions all failed, see [FailedBlockPolicy]
+    ///
+    /// Blocks with at least one successful transaction are always kept as they are
+    pub fn apply_failed_block_policy(
+        &mut self,
+        block: Block,
+        change_set: ChangeSet,
+    ) -> (Block, ChangeSet) {
+        // Transactions only get a single second chance, whatever happens to them now
+        let deferred_before: Vec<bool> = block
+            .transactions
+            .iter()
+            .map(|tx| self.deferred.remove(&tx.hash))
+            .collect();
+
+        let all_failed = !block.transactions.is_empty()
+            && change_set.receipts.values().all(|receipt| !receipt.success);
+
+        if !all_failed || self.failed_block_policy == FailedBlockPolicy::Always {
+            return (block, change_set);
+        }
+
+        let Block {
+            mut header,
+            transactions,
+        } = block;
+
+        if self.failed_block_policy == FailedBlockPolicy::Defer {
+            // Members of bundles would come back as single transactions, so they are dropped
+            let in_bundle = |tx: &Transaction| {
+                change_set
+                    .receipts
+                    .get(&tx.hash)
+                    .is_some_and(|receipt| receipt.bundle.is_some())
+            };
+            let retry: Transactions = transactions
+                .into_iter()
+                .zip(deferred_before)
+                .filter(|(tx, deferred)| !deferred && !in_bundle(tx))
+                .map(|(tx, _)| tx)
+                .collect::<Vec<_>>()
+                .into();
+
+            self.deferred.extend(retry.iter().map(|tx| tx.hash));
+
+            if !retry.is_empty()
+                && self
+                    .executor_mempool_tx
+                    .send(MempoolRequest::Return(retry))
+                    .is_err()
+            {
+                error!("Couldn't return deferred transactions to the mempool, dropping them");
+            }
+        }
+
+        info!(
+            policy = ?self.failed_block_policy,
+            "Every transaction in the batch failed, sealing an empty block"
+        );
+
+        // Nothing but the block reward was applied, so there are only the receipts of the failed
+        // transactions to drop
+        let transactions = Transactions::default();
+        header.tx_root = transactions.get_root(self.hash_algo);
+        let change_set = ChangeSet {
+            receipts: HashMap::new(),
+            bundle_receipts: HashMap::new(),
+            ..change_set
+        };
+        (Block::new(header, transactions), change_set)
     }
 
     /// Executes all transactions in a given block and produces [ChangeSet]
@@ -151,87 +826,1825 @@ where
     pub fn execute_transactions<'a>(
         &self,
         db: &'a RwLockReadGuard<'a, DB>,
-        block: &SealedBlock,
+        block: &Block,
+    ) -> State<'a, DB> {
+        self.execute_block(db, block, &[])
+    }
+
+    /// Same as [Executor::execute_transactions], but the members of `bundles` are executed
+    /// after every other transaction of the block, each bundle as a unit
+    pub fn execute_block<'a>(
+        &self,
+        db: &'a RwLockReadGuard<'a, DB>,
+        block: &Block,
+        bundles: &[Bundle],
+    ) -> State<'a, DB> {
+        self.execute_in_phase(db, block, bundles, ValidationPhase::Execution)
+    }
+
+    /// Same as [Executor::execute_block], with the transactions validated in `phase`
+    fn execute_in_phase<'a>(
+        &self,
+        db: &'a RwLockReadGuard<'a, DB>,
+        block: &Block,
+        bundles: &[Bundle],
+        phase: ValidationPhase,
     ) -> State<'a, DB> {
         let mut state = State::new(db);
+        let mut cumulative_gas = 0;
 
-        for tx in block.transactions() {
-            let tx_hash = tx.get_hash();
-            let mut receipt = TransactionReceipt::build(tx, block);
+        // Paid first, the transactions of the block can spend it once it is mature, see
+        // [crate::ChainSpec::coinbase_maturity]
+        let BlockHeader {
+            coinbase,
+            block_reward,
+            ..
+        } = block.header;
+        state.pay_block_reward(&coinbase, block_reward);
 
-            let mut from_account = match state.get_account(&tx.from) {
-                Some(account) => *account,
-                None => {
-                    state.insert_receipt(&tx_hash, receipt);
-                    continue;
-                }
-            };
+        let members: HashSet<B256> = bundles
+            .iter()
+            .flat_map(|bundle| bundle.transactions().iter().map(|tx| tx.hash))
+            .collect();
+        let singles = block
+            .transactions
+            .iter()
+            .filter(|tx| !members.contains(&tx.hash));
 
-            if from_account.nonce() != tx.nonce {
-                state.insert_receipt(&tx_hash, receipt);
-                continue;
+        if self.parallel_execution {
+            // Which transactions fit only depends on the gas before them, so it is decided
+            // in block order before the groups are split up
+            let singles: Vec<_> = singles
+                .map(|tx| (tx, take_gas(&mut cumulative_gas, tx, block)))
+                .collect();
+            state.merge(self.execute_parallel(db, &singles, block, phase));
+        } else {
+            for tx in singles {
+                let receipt =
+                    self.execute_transaction(&mut state, tx, block, &mut cumulative_gas, phase);
+                state.insert_receipt(&tx.get_hash(), receipt);
             }
+        }
 
-            // We first check the changeset to make sure we have the latest state
-            let mut to_account = match state.get_account(&tx.to) {
-                Some(account) => *account,
-                None => Account::default(),
-            };
+        for bundle in bundles {
+            self.execute_bundle(&mut state, bundle, block, &mut cumulative_gas, phase);
+        }
 
-            if from_account.balance() < tx.value {
-                state.insert_receipt(&tx_hash, receipt);
-                continue;
-            }
+        state
+    }
 
-            let new_from_balance = from_account.balance() - tx.value;
-            from_account.update_balance(new_from_balance);
+    /// Runs `tx` as the only transaction of the next block and returns its receipt, nothing is
+    /// written so clients can check whether a transaction succeeds before submitting it
+    pub fn simulate_transaction<'a>(
+        &self,
+        db: &'a RwLockReadGuard<'a, DB>,
+        tx: Transaction,
+    ) -> TransactionReceipt {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let block = self.next_block(Transactions::default(), timestamp);
 
-            let new_to_balance = to_account.balance() + tx.value;
-            to_account.update_balance(new_to_balance);
+        let mut state = State::new(db);
+        self.execute_transaction(&mut state, &tx, &block, &mut 0, ValidationPhase::Execution)
+    }
 
-            receipt.success = true;
+    /// Applies every member of the bundle or none of them
+    ///
+    /// Members after the first failure aren't executed, the ones before it are reverted
+    fn execute_bundle(
+        &self,
+        state: &mut State<'_, DB>,
+        bundle: &Bundle,
+        block: &Block,
+        cumulative_gas: &mut u64,
+        phase: ValidationPhase,
+    ) {
+        let checkpoint = state.checkpoint();
+        let mut receipts = Vec::with_capacity(bundle.len());
+        let mut reverted_by = None;
 
-            from_account.increment_nonce();
+        for tx in bundle.transactions() {
+            let mut receipt = match reverted_by {
+                Some(_) => TransactionReceipt::build(tx, block),
+                None => self.execute_transaction(state, tx, block, cumulative_gas, phase),
+            };
+            receipt.bundle = Some(*bundle.get_hash());
 
-            state.insert_receipt(&tx_hash, receipt);
-            state.insert_account(&tx.from, from_account);
-            state.insert_account(&tx.to, to_account);
+            if !receipt.success && reverted_by.is_none() {
+                reverted_by = Some(tx.hash);
+            }
+            receipts.push((tx.hash, receipt));
         }
 
-        state
+        if reverted_by.is_some() {
+            state.revert_to(checkpoint);
+
+            for (hash, receipt) in &mut receipts {
+                if Some(*hash) != reverted_by {
+                    receipt.success = false;
+                    receipt.reason = Some(FailureReason::BundleReverted);
+                }
+            }
+        }
+
+        let bundle_receipt = BundleReceipt {
+            success: reverted_by.is_none(),
+            block_hash: B256::ZERO,
+            block_number: block.header.number,
+            members: receipts.iter().map(|(hash, _)| *hash).collect(),
+            reverted_by,
+        };
+        for (hash, receipt) in receipts {
+            state.insert_receipt(&hash, receipt);
+        }
+        state.insert_bundle_receipt(bundle.get_hash(), bundle_receipt);
     }
 
-    /// Writes the block to the database
-    pub fn write_block(
+    /// Runs groups of transactions that share no account on the rayon pool, the ones within a
+    /// group in block order
+    ///
+    /// Every transaction comes with whether it fits into the gas limit of the block
+    fn execute_parallel(
         &self,
-        db: &mut RwLockWriteGuard<'_, DB>,
-        block: SealedBlock,
-    ) -> Result<(), Error> {
-        let block_hash = *block.get_hash();
-        db.write_block(block_hash, block)?;
+        db: &RwLockReadGuard<'_, DB>,
+        transactions: &[(&Transaction, bool)],
+        block: &Block,
+        phase: ValidationPhase,
+    ) -> ChangeSet {
+        independent_groups(transactions)
+            .par_iter()
+            .map(|group| {
+                let mut state = State::new(db);
+                // The group with the coinbase brings its own copy of the reward, its account
+                // replaces the one of the block state on merge
+                let coinbase = block.header.coinbase;
+                if group
+                    .iter()
+                    .any(|(tx, _)| tx.from == coinbase || tx.to == coinbase)
+                {
+                    state.pay_block_reward(&coinbase, block.header.block_reward);
+                }
+                for &(tx, fits) in group {
+                    let receipt = match fits {
+                        true => self.apply_transaction(&mut state, tx, block, phase),
+                        false => gas_limit_receipt(tx, block),
+                    };
+                    state.insert_receipt(&tx.get_hash(), receipt);
+                }
+                ChangeSet::from(state)
+            })
+            .reduce(ChangeSet::default, |mut changeset, group| {
+                changeset.merge(group);
+                changeset
+            })
+    }
 
-        Ok(())
+    /// Applies the transaction to `state` if it is valid, the receipt tells whether it was
+    ///
+    /// A transaction that doesn't fit into the gas left in the block is skipped, every other one
+    /// takes up its gas whether it succeeds or not
+    fn execute_transaction(
+        &self,
+        state: &mut State<'_, DB>,
+        tx: &Transaction,
+        block: &Block,
+        cumulative_gas: &mut u64,
+        phase: ValidationPhase,
+    ) -> TransactionReceipt {
+        match take_gas(cumulative_gas, tx, block) {
+            true => self.apply_transaction(state, tx, block, phase),
+            false => gas_limit_receipt(tx, block),
+        }
     }
 
-    pub fn write_changeset(
+    /// Applies a transaction that fits into the block to `state` if it is valid
+    fn apply_transaction(
         &self,
-        db: &mut RwLockWriteGuard<'_, DB>,
-        changeset: ChangeSet,
-    ) -> Result<(), Error> {
-        // TODO: Remove the two loops and introduce some better mechanism
-        // to write the receipts and touched accounts
-        for (addr, account) in changeset.touched_accounts {
-            if let Err(e) = db.write_account(addr, account) {
-                error!(err = %e, "Couldn't write account to database, skipping...");
-            }
+        state: &mut State<'_, DB>,
+        tx: &Transaction,
+        block: &Block,
+        phase: ValidationPhase,
+    ) -> TransactionReceipt {
+        let mut receipt = TransactionReceipt::build(tx, block);
+        receipt.gas_used = tx.gas;
+
+        let ctx = ValidationContext {
+            state: &*state,
+            config: ValidationConfig {
+                chain_id: self.chain_id,
+                min_balance: self.min_balance,
+                coinbase_maturity: self.coinbase_maturity,
+                hash_algo: self.hash_algo,
+            },
+            head: block.header.number.saturating_sub(1),
+            phase,
+        };
+        if let ValidationOutcome::Invalid(reason) = self.validator.validate(tx, &ctx) {
+            receipt.reason = Some(reason);
+            return receipt;
         }
 
-        for (tx_hash, tx_receipt) in changeset.receipts {
-            if let Err(e) = db.write_transaction_receipt(tx_hash, tx_receipt) {
-                error!(err = %e, "Couldn't write transaction receipt to database, skipping...");
+        // The validator already made sure the sender exists
+        let mut from_account = match state.get_account(&tx.from) {
+            Some(account) => *account,
+            None => {
+                receipt.reason = Some(FailureReason::UnknownSender);
+                return receipt;
+            }
+        };
+
+        // We first check the changeset to make sure we have the latest state. An account
+        // created where one was deleted continues from its nonce
+        let mut to_account = match state.get_account(&tx.to) {
+            Some(account) => *account,
+            None => Account::new(0, state.get_tombstone(&tx.to).unwrap_or_default()),
+        };
+
+        let new_from_balance = from_account.balance() - tx.value;
+        from_account.update_balance(new_from_balance);
+
+        let new_to_balance = to_account.balance() + tx.value;
+        to_account.update_balance(new_to_balance);
+
+        match tx.kind {
+            TransactionKind::Transfer => {}
+            TransactionKind::Approve { spender, limit } => {
+                state.insert_allowance(&tx.from, &spender, limit)
+            }
+            // The validator already made sure the allowance covers the value
+            TransactionKind::TransferFrom { spender } => {
+                let remaining = state.get_allowance(&tx.from, &spender) - tx.value;
+                state.insert_allowance(&tx.from, &spender, remaining);
             }
         }
 
-        Ok(())
+        receipt.success = true;
+
+        from_account.increment_nonce();
+
+        state.insert_account(&tx.from, from_account);
+        state.insert_account(&tx.to, to_account);
+
+        // With a reserve in place an account can only reach zero by being closed, so we
+        // remove it instead of keeping an empty account around
+        if self.min_balance.is_some() && new_from_balance == 0 && tx.from != tx.to {
+            state.delete_account(&tx.from);
+        }
+
+        receipt
+    }
+
+    /// Checks the hash, proof of work and transactions of the block, that it extends the
+    /// current head and that its header holds what this node would have put into it, apart
+    /// from the coinbase of another producer of the chain
+    pub fn validate_block(&self, block: &SealedBlock) -> Result<(), Error> {
+        if !block.verify(self.chain_id, self.hash_algo) {
+            return Err(Error::InvalidBlock(format!(
+                "block {} doesn't verify",
+                block.get_hash()
+            )));
+        }
+        if *block.parent_hash() != self.last_hash {
+            return Err(Error::InvalidBlock(format!(
+                "parent {} isn't the head {}",
+                block.parent_hash(),
+                self.last_hash
+            )));
+        }
+        if block.number() != self.next_number {
+            return Err(Error::InvalidBlock(format!(
+                "number {} isn't the next number {}",
+                block.number(),
+                self.next_number
+            )));
+        }
+
+        let header = block.header();
+        // The reward goes to the coinbase of the header, so it has to be one of ours
+        if *header.coinbase() != self.coinbase && !self.producers.contains(header.coinbase()) {
+            return Err(Error::InvalidBlock(format!(
+                "coinbase {} isn't a producer of the chain",
+                header.coinbase()
+            )));
+        }
+        if *header.difficulty() != self.current_difficulty {
+            return Err(Error::InvalidBlock(format!(
+                "difficulty {} isn't the current difficulty {}",
+                header.difficulty(),
+                self.current_difficulty
+            )));
+        }
+        if header.block_reward() != self.block_reward {
+            return Err(Error::InvalidBlock(format!(
+                "block reward {} isn't the block reward {}",
+                header.block_reward(),
+                self.block_reward
+            )));
+        }
+        if header.gas_limit() != self.block_gas_limit {
+            return Err(Error::InvalidBlock(format!(
+                "gas limit {} isn't the block gas limit {}",
+                header.gas_limit(),
+                self.block_gas_limit
+            )));
+        }
+        let tx_root = block.transactions().get_root(self.hash_algo);
+        if *header.tx_root() != tx_root {
+            return Err(Error::InvalidBlock(format!(
+                "transaction root {} doesn't match the transactions {}",
+                header.tx_root(),
+                tx_root
+            )));
+        }
+        Ok(())
+    }
+
+    /// Writes the block to the database, when validation is on only if it passes
+    /// [Executor::validate_block]
+    pub fn write_block(
+        &self,
+        db: &mut RwLockWriteGuard<'_, DB>,
+        block: SealedBlock,
+    ) -> Result<(), Error> {
+        if self.validate {
+            self.validate_block(&block)?;
+        }
+
+        let block_hash = *block.get_hash();
+        db.write_block(block_hash, block)?;
+
+        Ok(())
+    }
+
+    pub fn write_changeset(
+        &self,
+        db: &mut RwLockWriteGuard<'_, DB>,
+        changeset: ChangeSet,
+    ) -> Result<(), Error> {
+        // TODO: Remove the two loops and introduce some better mechanism
+        // to write the receipts and touched accounts
+        for (addr, account) in changeset.touched_accounts {
+            if let Err(e) = db.write_account(addr, account) {
+                error!(err = %e, "Couldn't write account to database, skipping...");
+            }
+        }
+
+        for (addr, nonce) in changeset.deleted_accounts {
+            if let Err(e) = db.delete_account(&addr, nonce) {
+                error!(err = %e, "Couldn't delete account from database, skipping...");
+            }
+        }
+
+        for (tx_hash, tx_receipt) in changeset.receipts {
+            if let Err(e) = db.write_transaction_receipt(tx_hash, tx_receipt) {
+                error!(err = %e, "Couldn't write transaction receipt to database, skipping...");
+            }
+        }
+
+        for (bundle_hash, receipt) in changeset.bundle_receipts {
+            if let Err(e) = db.write_bundle_receipt(bundle_hash, receipt) {
+                error!(err = %e, "Couldn't write bundle receipt to database, skipping...");
+            }
+        }
+
+        for (owner, allowances) in changeset.allowances {
+            for (spender, remaining) in allowances {
+                if let Err(e) = db.write_allowance(owner, spender, remaining) {
+                    error!(err = %e, "Couldn't write allowance to database, skipping...");
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Adds the gas of `tx` to the gas used in the block, unless it would exceed the gas limit
+fn take_gas(cumulative_gas: &mut u64, tx: &Transaction, block: &Block) -> bool {
+    match cumulative_gas.checked_add(tx.gas) {
+        Some(gas) if gas <= block.header.gas_limit => {
+            *cumulative_gas = gas;
+            true
+        }
+        _ => false,
+    }
+}
+
+fn gas_limit_receipt(tx: &Transaction, block: &Block) -> TransactionReceipt {
+    let mut receipt = TransactionReceipt::build(tx, block);
+    receipt.reason = Some(FailureReason::ExceedsBlockGasLimit);
+    receipt
+}
+
+/// Splits the transactions into groups such that no account is touched by two groups, the
+/// transactions within a group keep their order
+fn independent_groups<'t>(
+    transactions: &[(&'t Transaction, bool)],
+) -> Vec<Vec<(&'t Transaction, bool)>> {
+    // Union find over the accounts, senders and recipients of a transaction end up together
+    fn root(parents: &mut HashMap<Address, Address>, addr: Address) -> Address {
+        let parent = *parents.entry(addr).or_insert(addr);
+        if parent == addr {
+            return addr;
+        }
+        let root = root(parents, parent);
+        parents.insert(addr, root);
+        root
+    }
+
+    let mut parents = HashMap::new();
+    for (tx, _) in transactions {
+        let from = root(&mut parents, tx.from);
+        let to = root(&mut parents, tx.to);
+        if from != to {
+            parents.insert(from, to);
+        }
+    }
+
+    let mut groups: HashMap<Address, Vec<_>> = HashMap::new();
+    for &(tx, fits) in transactions {
+        let root = root(&mut parents, tx.from);
+        groups.entry(root).or_default().push((tx, fits));
+    }
+    groups.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ChainSpec, InMemoryDB, Transaction};
+
+    const SENDER: Address = Address::repeat_byte(1);
+    const RECEIVER: Address = Address::repeat_byte(2);
+    const SPENDER: Address = Address::repeat_byte(3);
+
+    fn executor(db: Arc<RwLock<InMemoryDB>>) -> Executor<InMemoryDB> {
+        let (executor_mempool_tx, _) = mpsc::unbounded_channel();
+        let (_, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        Executor::new(
+            db,
+            1,
+            executor_mempool_tx,
+            Address::ZERO,
+            shutdown,
+            shutdown_complete,
+        )
+    }
+
+    fn transfer(nonce: u64, value: u128) -> Transaction {
+        let mut tx = Transaction {
+            from: SENDER,
+            to: RECEIVER,
+            nonce,
+            value,
+            ..Default::default()
+        };
+        tx.hash = tx.hash(HashAlgo::Keccak256);
+        tx
+    }
+
+    /// Executes a single transfer from an account holding 1000 coins with a reserve of 100
+    async fn execute_with_reserve(value: u128) -> (TransactionReceipt, InMemoryDB) {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let executor = executor(db.clone()).with_min_balance(Some(100));
+
+        let tx = transfer(0, value);
+        let block = Block::new(BlockHeader::default(), vec![tx.clone()].into());
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+        drop(reader);
+
+        let receipt = changeset.receipts.get(&tx.hash).unwrap().clone();
+
+        let mut writer = db.write().await;
+        executor.write_changeset(&mut writer, changeset).unwrap();
+        drop(writer);
+        drop(executor);
+
+        (receipt, Arc::try_unwrap(db).unwrap().into_inner())
+    }
+
+    #[tokio::test]
+    async fn test_transfer_above_reserve() {
+        let (receipt, db) = execute_with_reserve(500).await;
+        assert!(receipt.success);
+        assert_eq!(db.read_account(&SENDER).unwrap().balance(), 500);
+        assert_eq!(db.read_account(&RECEIVER).unwrap().balance(), 500);
+    }
+
+    #[tokio::test]
+    async fn test_transfer_below_reserve() {
+        let (receipt, db) = execute_with_reserve(950).await;
+        assert!(!receipt.success);
+        assert_eq!(receipt.reason, Some(FailureReason::BelowReserve));
+        assert_eq!(db.read_account(&SENDER).unwrap().balance(), 1000);
+        assert!(db.read_account(&RECEIVER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_transfer_closing_account() {
+        let (receipt, db) = execute_with_reserve(1000).await;
+        assert!(receipt.success);
+        assert!(db.read_account(&SENDER).is_none());
+        assert_eq!(db.read_account(&RECEIVER).unwrap().balance(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_closed_account_is_gone_for_later_transactions() {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let executor = executor(db.clone()).with_min_balance(Some(100));
+
+        let transactions = vec![transfer(0, 1000), transfer(1, 1)];
+        let block = Block::new(BlockHeader::default(), transactions.into());
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+
+        let receipts: Vec<_> = block
+            .transactions
+            .iter()
+            .map(|tx| changeset.receipts.get(&tx.hash).unwrap())
+            .collect();
+        assert!(receipts[0].success);
+        assert_eq!(receipts[1].reason, Some(FailureReason::UnknownSender));
+    }
+
+    #[tokio::test]
+    async fn test_closed_account_keeps_its_nonce() {
+        let (receipt, db) = execute_with_reserve(1000).await;
+        assert!(receipt.success);
+        assert_eq!(db.read_tombstone(&SENDER), Some(1));
+
+        let db = Arc::new(RwLock::new(db));
+        let executor = executor(db.clone()).with_min_balance(Some(100));
+
+        // Funded again, the closing transfer must not go through a second time
+        let mut refund = Transaction {
+            from: RECEIVER,
+            to: SENDER,
+            value: 1000,
+            ..Default::default()
+        };
+        refund.hash = refund.hash(HashAlgo::Keccak256);
+        let transactions = vec![refund, transfer(0, 1000), transfer(1, 10)];
+        let block = Block::new(BlockHeader::default(), transactions.into());
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+        drop(reader);
+
+        let receipts: Vec<_> = block
+            .transactions
+            .iter()
+            .map(|tx| changeset.receipts[&tx.hash].clone())
+            .collect();
+        assert!(receipts[0].success);
+        assert_eq!(receipts[1].reason, Some(FailureReason::InvalidNonce));
+        assert!(receipts[2].success);
+
+        let mut writer = db.write().await;
+        executor.write_changeset(&mut writer, changeset).unwrap();
+        assert_eq!(writer.read_account(&SENDER), Some(&Account::new(990, 2)));
+        assert_eq!(writer.read_tombstone(&SENDER), None);
+    }
+
+    #[tokio::test]
+    async fn test_account_closed_and_funded_in_one_block_keeps_its_nonce() {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let executor = executor(db.clone()).with_min_balance(Some(100));
+
+        let mut refund = Transaction {
+            from: RECEIVER,
+            to: SENDER,
+            value: 1000,
+            ..Default::default()
+        };
+        refund.hash = refund.hash(HashAlgo::Keccak256);
+        let transactions = vec![transfer(0, 1000), refund, transfer(0, 10)];
+        let block = Block::new(BlockHeader::default(), transactions.into());
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+        drop(reader);
+
+        let receipts: Vec<_> = block
+            .transactions
+            .iter()
+            .map(|tx| changeset.receipts[&tx.hash].clone())
+            .collect();
+        assert!(receipts[0].success);
+        assert!(receipts[1].success);
+        assert_eq!(receipts[2].reason, Some(FailureReason::InvalidNonce));
+
+        let mut writer = db.write().await;
+        executor.write_changeset(&mut writer, changeset).unwrap();
+        assert_eq!(writer.read_account(&SENDER), Some(&Account::new(1000, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_block_gas_limit() {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let executor = executor(db.clone()).with_block_gas_limit(100);
+
+        let transactions: Vec<_> = (0..2)
+            .map(|nonce| {
+                let mut tx = transfer(nonce, 1);
+                tx.gas = 60;
+                tx.hash = tx.hash(HashAlgo::Keccak256);
+                tx
+            })
+            .collect();
+        let block = executor.next_block(transactions.into(), 1);
+        assert_eq!(block.header.gas_limit, 100);
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+
+        let receipts: Vec<_> = block
+            .transactions
+            .iter()
+            .map(|tx| changeset.receipts.get(&tx.hash).unwrap())
+            .collect();
+        assert!(receipts[0].success);
+        assert_eq!(receipts[0].gas_used, 60);
+        assert!(!receipts[1].success);
+        assert_eq!(
+            receipts[1].reason,
+            Some(FailureReason::ExceedsBlockGasLimit)
+        );
+        assert_eq!(receipts[1].gas_used, 0);
+        assert_eq!(changeset.touched_accounts[&RECEIVER].balance(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_parallel_execution_matches_sequential() {
+        let accounts: Vec<_> = (1..=6).map(Address::repeat_byte).collect();
+        let mut db = InMemoryDB::default();
+        for addr in &accounts[..5] {
+            db.write_account(*addr, Account::new(100, 0)).unwrap();
+        }
+        let db = Arc::new(RwLock::new(db));
+
+        let tx = |from: usize, to: usize, nonce: u64, value: u128| {
+            let mut tx = Transaction {
+                from: accounts[from],
+                to: accounts[to],
+                nonce,
+                value,
+                gas: 10,
+                ..Default::default()
+            };
+            tx.hash = tx.hash(HashAlgo::Keccak256);
+            tx
+        };
+        let transactions = vec![
+            // 0 -> 1 -> 2 only works in this order
+            tx(0, 1, 0, 100),
+            tx(1, 2, 0, 200),
+            tx(3, 4, 0, 50),
+            // Wrong nonce
+            tx(3, 4, 5, 1),
+            tx(4, 5, 0, 120),
+            tx(2, 0, 0, 300),
+            // Over the gas limit
+            tx(5, 4, 0, 1),
+        ];
+
+        let mut changesets = Vec::new();
+        for parallel in [false, true] {
+            let executor = executor(db.clone())
+                .with_block_gas_limit(60)
+                .with_parallel_execution(parallel);
+            let block = executor.next_block(transactions.clone().into(), 1);
+
+            let reader = db.read().await;
+            changesets.push(ChangeSet::from(
+                executor.execute_transactions(&reader, &block),
+            ));
+        }
+
+        assert_eq!(changesets[0], changesets[1]);
+        let receipts = &changesets[1].receipts;
+        assert!(receipts[&transactions[1].hash].success);
+        assert!(!receipts[&transactions[3].hash].success);
+        assert!(receipts[&transactions[5].hash].success);
+        assert_eq!(
+            receipts[&transactions[6].hash].reason,
+            Some(FailureReason::ExceedsBlockGasLimit)
+        );
+        assert_eq!(changesets[1].touched_accounts[&accounts[0]].balance(), 300);
+    }
+
+    /// Executes `singles` followed by `bundles` against a sender holding 1000 coins
+    async fn execute_bundles(
+        singles: Vec<Transaction>,
+        bundles: Vec<Bundle>,
+    ) -> (ChangeSet, InMemoryDB) {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let executor = executor(db.clone());
+
+        let mut transactions = singles;
+        for bundle in &bundles {
+            transactions.extend(bundle.transactions().iter().cloned());
+        }
+        let block = Block::new(BlockHeader::default(), transactions.into());
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_block(&reader, &block, &bundles).into();
+        drop(reader);
+
+        let mut writer = db.write().await;
+        executor
+            .write_changeset(&mut writer, changeset.clone())
+            .unwrap();
+        drop(writer);
+        drop(executor);
+
+        (changeset, Arc::try_unwrap(db).unwrap().into_inner())
+    }
+
+    #[tokio::test]
+    async fn test_bundle_is_applied() {
+        let bundle = Bundle::new(
+            vec![transfer(0, 100), transfer(1, 200)],
+            HashAlgo::Keccak256,
+        )
+        .unwrap();
+        let (changeset, db) = execute_bundles(Vec::new(), vec![bundle.clone()]).await;
+
+        let receipt = db.read_bundle_receipt(bundle.get_hash()).unwrap();
+        assert!(receipt.success);
+        assert_eq!(receipt.reverted_by, None);
+        for tx in bundle.transactions() {
+            let tx_receipt = changeset.receipts.get(&tx.hash).unwrap();
+            assert!(tx_receipt.success);
+            assert_eq!(tx_receipt.bundle, Some(*bundle.get_hash()));
+        }
+        assert_eq!(db.read_account(&SENDER).unwrap().balance(), 700);
+        assert_eq!(db.read_account(&RECEIVER).unwrap().balance(), 300);
+    }
+
+    #[tokio::test]
+    async fn test_failing_member_reverts_bundle() {
+        let (first, second) = (transfer(0, 100), transfer(1, 5000));
+        let bundle = Bundle::new(vec![first.clone(), second.clone()], HashAlgo::Keccak256).unwrap();
+        let (changeset, db) = execute_bundles(Vec::new(), vec![bundle.clone()]).await;
+
+        let receipt = db.read_bundle_receipt(bundle.get_hash()).unwrap();
+        assert!(!receipt.success);
+        assert_eq!(receipt.reverted_by, Some(second.hash));
+        assert_eq!(receipt.members, vec![first.hash, second.hash]);
+
+        let first_receipt = changeset.receipts.get(&first.hash).unwrap();
+        assert!(!first_receipt.success);
+        assert_eq!(first_receipt.reason, Some(FailureReason::BundleReverted));
+        assert!(!changeset.receipts.get(&second.hash).unwrap().success);
+
+        assert_eq!(db.read_account(&SENDER).unwrap(), &Account::new(1000, 0));
+        assert!(db.read_account(&RECEIVER).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reverted_bundle_gives_the_allowance_back() {
+        let bundle =
+            Bundle::new(vec![spend(1, 50), transfer(2, 5000)], HashAlgo::Keccak256).unwrap();
+        let (changeset, db) = execute_bundles(vec![approve(0, 100)], vec![bundle.clone()]).await;
+
+        let spent = &changeset.receipts[&bundle.transactions()[0].hash];
+        assert_eq!(spent.reason, Some(FailureReason::BundleReverted));
+        assert_eq!(db.read_allowance(&SENDER, &SPENDER), 100);
+        assert_eq!(db.read_account(&SENDER).unwrap().balance(), 1000);
+    }
+
+    #[tokio::test]
+    async fn test_single_transaction_wins_over_bundle() {
+        // Same sender and nonce, the single transaction runs first
+        let single = transfer(0, 50);
+        let bundle = Bundle::new(
+            vec![transfer(0, 100), transfer(1, 100)],
+            HashAlgo::Keccak256,
+        )
+        .unwrap();
+        let (changeset, db) = execute_bundles(vec![single.clone()], vec![bundle.clone()]).await;
+
+        assert!(changeset.receipts.get(&single.hash).unwrap().success);
+        let receipt = db.read_bundle_receipt(bundle.get_hash()).unwrap();
+        assert_eq!(receipt.reverted_by, Some(bundle.transactions()[0].hash));
+
+        let unexecuted = changeset
+            .receipts
+            .get(&bundle.transactions()[1].hash)
+            .unwrap();
+        assert_eq!(unexecuted.reason, Some(FailureReason::BundleReverted));
+        assert_eq!(db.read_account(&SENDER).unwrap().balance(), 950);
+    }
+
+    fn with_kind(nonce: u64, value: u128, kind: TransactionKind) -> Transaction {
+        let mut tx = transfer(nonce, value);
+        tx.kind = kind;
+        tx.hash = tx.hash(HashAlgo::Keccak256);
+        tx
+    }
+
+    fn approve(nonce: u64, limit: u128) -> Transaction {
+        let spender = SPENDER;
+        with_kind(nonce, 0, TransactionKind::Approve { spender, limit })
+    }
+
+    fn spend(nonce: u64, value: u128) -> Transaction {
+        let spender = SPENDER;
+        with_kind(nonce, value, TransactionKind::TransferFrom { spender })
+    }
+
+    #[tokio::test]
+    async fn test_allowance_runs_out_mid_block() {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let executor = executor(db.clone());
+
+        let transactions = vec![
+            approve(0, 100),
+            spend(1, 60),
+            spend(2, 60),
+            spend(2, 40),
+            // Revoked, even the smallest spend fails
+            approve(3, 0),
+            spend(4, 1),
+        ];
+        let block = Block::new(BlockHeader::default(), transactions.clone().into());
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+        drop(reader);
+
+        let receipts: Vec<_> = transactions
+            .iter()
+            .map(|tx| changeset.receipts[&tx.hash].clone())
+            .collect();
+        let outcomes: Vec<_> = receipts.iter().map(|r| (r.success, r.reason)).collect();
+        let exhausted = (false, Some(FailureReason::InsufficientAllowance));
+        assert_eq!(
+            outcomes,
+            vec![
+                (true, None),
+                (true, None),
+                exhausted,
+                (true, None),
+                (true, None),
+                exhausted
+            ]
+        );
+        assert_eq!(receipts[0].spender, None);
+        assert!(receipts[1..4].iter().all(|r| r.spender == Some(SPENDER)));
+
+        let mut writer = db.write().await;
+        executor.write_changeset(&mut writer, changeset).unwrap();
+        assert_eq!(writer.read_allowance(&SENDER, &SPENDER), 0);
+        assert_eq!(writer.read_account(&SENDER).unwrap().balance(), 900);
+        assert_eq!(writer.read_account(&RECEIVER).unwrap().balance(), 100);
+        // The spender only signs, it neither pays nor uses up a nonce of its own
+        assert!(writer.read_account(&SPENDER).is_none());
+        assert_eq!(writer.read_account(&SENDER).unwrap().nonce(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_dev_account_can_transfer_right_away() {
+        let spec = ChainSpec::new(1).with_dev_account(7, Account::new(1000, 0));
+        let mut db = InMemoryDB::default();
+        db.write_spec(&spec).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let mut executor = executor(db.clone()).with_chain_id(spec.chain_id());
+
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let tx = account.transfer(RECEIVER, 10);
+        let block = Block::new(BlockHeader::default(), vec![tx.clone()].into());
+
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+        assert!(changeset.receipts.get(&tx.hash).unwrap().success);
+        assert_eq!(account.nonce(), 1);
+        drop(reader);
+
+        // The first block builds on the genesis block
+        executor.sync_head().await;
+        assert_eq!(executor.last_hash, spec.genesis_hash());
+        assert_eq!(executor.next_number, 1);
+    }
+
+    #[tokio::test]
+    async fn test_imported_block_can_be_queried_by_number() {
+        let spec = ChainSpec::new(1).with_dev_account(7, Account::new(1000, 0));
+        let spec_db = || {
+            let mut db = InMemoryDB::default();
+            db.write_spec(&spec).unwrap();
+            Arc::new(RwLock::new(db))
+        };
+
+        // Built by another node on the same chain
+        let producer = executor(spec_db()).with_chain_id(spec.chain_id());
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let tx = account.transfer(RECEIVER, 10);
+        let block = producer.next_block(vec![tx.clone()].into(), 1);
+        let block =
+            Executor::<InMemoryDB>::mine_block(block, &AtomicBool::new(false), HashAlgo::Keccak256)
+                .unwrap();
+
+        let db = spec_db();
+        let mut importer = executor(db.clone()).with_chain_id(spec.chain_id());
+        let mut tampered = block.clone().unseal();
+        tampered.header.timestamp = 2;
+        assert!(matches!(
+            importer
+                .import_block(tampered.seal(*block.get_hash()))
+                .await,
+            Err(Error::InvalidBlock(_))
+        ));
+
+        importer.import_block(block.clone()).await.unwrap();
+        assert_eq!(importer.last_hash, *block.get_hash());
+        assert_eq!(importer.next_number, 2);
+
+        let reader = db.read().await;
+        assert_eq!(reader.read_block_by_number(1), Some(&block));
+        assert!(reader.read_transaction_receipt(&tx.hash).unwrap().success);
+        assert_eq!(reader.read_account(&RECEIVER).unwrap().balance(), 10);
+        drop(reader);
+
+        // It doesn't extend the head anymore
+        assert!(importer.import_block(block).await.is_err());
+    }
+
+    /// Imports a block of another node into a fresh node with the same settings, after
+    /// `tamper` changed its header
+    async fn import_tampered(
+        importer: impl FnOnce(Executor<InMemoryDB>) -> Executor<InMemoryDB>,
+        tamper: impl FnOnce(&mut BlockHeader),
+    ) -> Result<(), Error> {
+        let spec = ChainSpec::new(1).with_dev_account(7, Account::new(1000, 0));
+        let spec_db = || {
+            let mut db = InMemoryDB::default();
+            db.write_spec(&spec).unwrap();
+            Arc::new(RwLock::new(db))
+        };
+
+        let producer = executor(spec_db()).with_chain_id(spec.chain_id());
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let mut block = producer.next_block(vec![account.transfer(RECEIVER, 10)].into(), 1);
+        tamper(&mut block.header);
+        let block =
+            Executor::<InMemoryDB>::mine_block(block, &AtomicBool::new(false), HashAlgo::Keccak256)
+                .unwrap();
+
+        let mut importer = importer(executor(spec_db()).with_chain_id(spec.chain_id()));
+        importer.import_block(block).await
+    }
+
+    #[tokio::test]
+    async fn test_import_refuses_another_difficulty() {
+        import_tampered(|importer| importer, |_| {}).await.unwrap();
+        let result = import_tampered(
+            |importer| importer,
+            |header| header.difficulty = U256::MAX >> 1,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::InvalidBlock(_))), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_import_refuses_another_block_reward() {
+        let result = import_tampered(|importer| importer, |header| header.block_reward = 1).await;
+        assert!(matches!(result, Err(Error::InvalidBlock(_))), "{result:?}");
+
+        // Unless the node pays the same
+        let result = import_tampered(
+            |importer| importer.with_block_reward(1),
+            |header| header.block_reward = 1,
+        )
+        .await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_import_refuses_another_gas_limit() {
+        let result = import_tampered(
+            |importer| importer,
+            |header| header.gas_limit = DEFAULT_BLOCK_GAS_LIMIT * 2,
+        )
+        .await;
+        assert!(matches!(result, Err(Error::InvalidBlock(_))), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_import_refuses_another_coinbase() {
+        let stranger = Address::repeat_byte(9);
+        let result =
+            import_tampered(|importer| importer, |header| header.coinbase = stranger).await;
+        assert!(matches!(result, Err(Error::InvalidBlock(_))), "{result:?}");
+
+        // Unless the chain names it as a producer
+        let result = import_tampered(
+            |importer| importer.with_producers(BTreeSet::from([stranger])),
+            |header| header.coinbase = stranger,
+        )
+        .await;
+        assert!(result.is_ok(), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_import_refuses_a_tx_root_of_other_transactions() {
+        let result =
+            import_tampered(|importer| importer, |header| header.tx_root = B256::ZERO).await;
+        assert!(matches!(result, Err(Error::InvalidBlock(_))), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn test_imported_transactions_are_validated_for_import() {
+        /// Records the phase of every transaction it sees
+        #[derive(Default)]
+        struct Phases(std::sync::Mutex<Vec<ValidationPhase>>);
+
+        impl crate::ValidationStage for Phases {
+            fn name(&self) -> &'static str {
+                "phases"
+            }
+
+            fn check(&self, _tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome {
+                self.0.lock().unwrap().push(ctx.phase);
+                ValidationOutcome::Valid
+            }
+        }
+
+        let phases = Arc::new(Phases::default());
+        let validator = Arc::new(TxValidator::new().with_stage(phases.clone()));
+        import_tampered(|importer| importer.with_validator(validator), |_| {})
+            .await
+            .unwrap();
+        assert_eq!(*phases.0.lock().unwrap(), [ValidationPhase::Import]);
+    }
+
+    #[tokio::test]
+    async fn test_simulation_writes_nothing() {
+        let spec = ChainSpec::new(1).with_dev_account(7, Account::new(1000, 0));
+        let mut db = InMemoryDB::default();
+        db.write_spec(&spec).unwrap();
+        let db = Arc::new(RwLock::new(db));
+
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let (simulation_tx, simulation_rx) = mpsc::channel(1);
+        let mut executor = Executor::new(
+            db.clone(),
+            1,
+            mpsc::unbounded_channel().0,
+            Address::ZERO,
+            shutdown,
+            shutdown_complete,
+        )
+        .with_chain_id(spec.chain_id())
+        .with_role(watch::channel(NodeRole::Follower).1)
+        .with_simulations(simulation_rx);
+        let handle = tokio::spawn(async move { executor.run().await });
+
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let tx = account.transfer(RECEIVER, 10);
+        let simulate = |tx: Transaction| {
+            let simulation_tx = simulation_tx.clone();
+            async move {
+                let (reply_tx, reply_rx) = oneshot::channel();
+                simulation_tx.send((tx, reply_tx)).await.unwrap();
+                reply_rx.await.unwrap()
+            }
+        };
+
+        // The same transaction succeeds every time, since its nonce was never used up
+        for _ in 0..2 {
+            let receipt = simulate(tx.clone()).await;
+            assert!(receipt.success);
+            assert_eq!(receipt.block_number, 1);
+        }
+        let overdraft = account.sign(RECEIVER, 10_000, 0);
+        assert_eq!(
+            simulate(overdraft).await.reason,
+            Some(FailureReason::InsufficientBalance)
+        );
+
+        let reader = db.read().await;
+        assert_eq!(reader.read_account(&account.address).unwrap().nonce(), 0);
+        assert!(reader.read_account(&RECEIVER).is_none());
+        assert!(reader.read_transaction_receipt(&tx.hash).is_none());
+        drop(reader);
+
+        drop(notify_shutdown);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_promoted_follower_continues_from_head() {
+        // Blocks 1 and 2 were imported while following the old producer
+        let mut db = InMemoryDB::default();
+        let mut parent_hash = INITIAL_HASH;
+        for number in 1..=2 {
+            let header = BlockHeader {
+                parent_hash,
+                number,
+                difficulty: U256::MAX,
+                ..Default::default()
+            };
+            let block = Block::new(header, Transactions::default()).seal_slow(HashAlgo::Keccak256);
+            parent_hash = *block.get_hash();
+            db.write_block(parent_hash, block).unwrap();
+        }
+        let db = Arc::new(RwLock::new(db));
+
+        let (executor_mempool_tx, mut executor_mempool_rx) = mpsc::unbounded_channel();
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let (role_tx, role_rx) = watch::channel(NodeRole::Follower);
+
+        let mut executor = Executor::new(
+            db.clone(),
+            1,
+            executor_mempool_tx,
+            Address::ZERO,
+            shutdown,
+            shutdown_complete,
+        )
+        .with_role(role_rx);
+
+        tokio::spawn(async move {
+            while let Some(request) = executor_mempool_rx.recv().await {
+                if let MempoolRequest::GetBatch(tx) = request {
+                    let _ = tx.send(Batch::default());
+                }
+            }
+        });
+        let handle = tokio::spawn(async move { executor.run().await });
+
+        let block_count = || async { db.read().await.block_count() };
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(block_count().await, 2);
+
+        role_tx.send(NodeRole::Producer).unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        role_tx.send(NodeRole::Follower).unwrap();
+
+        let produced = block_count().await;
+        assert!(produced > 2);
+
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert_eq!(block_count().await, produced);
+
+        let reader = db.read().await;
+        let third = reader.read_block_by_number(3).unwrap();
+        assert_eq!(third.parent_hash(), &parent_hash);
+        drop(reader);
+
+        drop(notify_shutdown);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_demotion_cancels_mining() {
+        let spec = ChainSpec::new(1).with_dev_account(7, Account::new(1000, 0));
+        let mut db = InMemoryDB::default();
+        db.write_spec(&spec).unwrap();
+        let db = Arc::new(RwLock::new(db));
+
+        let (executor_mempool_tx, mut executor_mempool_rx) = mpsc::unbounded_channel();
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let (simulation_tx, simulation_rx) = mpsc::channel(1);
+        let (role_tx, role_rx) = watch::channel(NodeRole::Producer);
+
+        // No nonce meets a difficulty of 1 in time, the first block is mined until cancelled
+        let mut executor = Executor::new(
+            db.clone(),
+            1,
+            executor_mempool_tx,
+            Address::ZERO,
+            shutdown,
+            shutdown_complete,
+        )
+        .with_chain_id(spec.chain_id())
+        .with_difficulty(U256::from(1))
+        .with_role(role_rx)
+        .with_simulations(simulation_rx);
+
+        tokio::spawn(async move {
+            while let Some(request) = executor_mempool_rx.recv().await {
+                if let MempoolRequest::GetBatch(tx) = request {
+                    let _ = tx.send(Batch::default());
+                }
+            }
+        });
+        let handle = tokio::spawn(async move { executor.run().await });
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        role_tx.send(NodeRole::Follower).unwrap();
+
+        // Simulations are only answered once the executor is back from mining
+        let mut account = spec.dev_accounts().unwrap().remove(0);
+        let tx = account.transfer(RECEIVER, 10);
+        let (reply_tx, reply_rx) = oneshot::channel();
+        simulation_tx.send((tx, reply_tx)).await.unwrap();
+        let receipt = tokio::time::timeout(Duration::from_secs(2), reply_rx)
+            .await
+            .expect("the executor kept mining after its demotion")
+            .unwrap();
+        assert!(receipt.success);
+        assert_eq!(db.read().await.block_count(), 1);
+
+        drop(notify_shutdown);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transactions_wait_out_a_pause() {
+        let db = Arc::new(RwLock::new(InMemoryDB::default()));
+        let (server_mempool_tx, server_mempool_rx) = mpsc::channel(16);
+        let (executor_mempool_tx, executor_mempool_rx) = mpsc::unbounded_channel();
+        let (_status_tx, status_rx) = mpsc::channel(1);
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let (paused_tx, paused_rx) = watch::channel(true);
+
+        let mut mempool = Mempool::new_with_capacity(
+            server_mempool_rx,
+            executor_mempool_rx,
+            status_rx,
+            MempoolOrdering::Fifo,
+            notify_shutdown.subscribe(),
+            shutdown_complete.clone(),
+            DEFAULT_MEMPOOL_MAX_SIZE,
+        );
+        let mut executor = Executor::new(
+            db.clone(),
+            1,
+            executor_mempool_tx,
+            Address::ZERO,
+            notify_shutdown.subscribe(),
+            shutdown_complete,
+        )
+        .with_paused(paused_rx);
+        tokio::spawn(async move { mempool.run().await });
+        let handle = tokio::spawn(async move { executor.run().await });
+
+        for nonce in 0..3 {
+            server_mempool_tx.send(transfer(nonce, 1)).await.unwrap();
+        }
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(db.read().await.block_count(), 0);
+
+        paused_tx.send(false).unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+
+        let reader = db.read().await;
+        let first = reader.read_block_by_number(1).unwrap();
+        let hashes: Vec<_> = first.transactions().iter().map(|tx| tx.hash).collect();
+        assert_eq!(
+            hashes,
+            (0..3)
+                .map(|nonce| transfer(nonce, 1).hash)
+                .collect::<Vec<_>>()
+        );
+        drop(reader);
+
+        drop(notify_shutdown);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_transactions_of_an_invalid_block_stay_pending() {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+        let db = Arc::new(RwLock::new(db));
+        let (server_mempool_tx, server_mempool_rx) = mpsc::channel(16);
+        let (executor_mempool_tx, executor_mempool_rx) = mpsc::unbounded_channel();
+        let (status_tx, status_rx) = mpsc::channel(1);
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+
+        let mut mempool = Mempool::new_with_capacity(
+            server_mempool_rx,
+            executor_mempool_rx,
+            status_rx,
+            MempoolOrdering::Fifo,
+            notify_shutdown.subscribe(),
+            shutdown_complete.clone(),
+            DEFAULT_MEMPOOL_MAX_SIZE,
+        );
+        let mut executor = Executor::new(
+            db.clone(),
+            1,
+            executor_mempool_tx,
+            Address::ZERO,
+            notify_shutdown.subscribe(),
+            shutdown_complete,
+        )
+        .with_block_validation(true);
+        tokio::spawn(async move { mempool.run().await });
+        let handle = tokio::spawn(async move { executor.run().await });
+
+        // Never signed, so every block holding it fails validation
+        server_mempool_tx.send(transfer(0, 1)).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(db.read().await.block_count(), 0);
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        status_tx.send(reply_tx).await.unwrap();
+        assert_eq!(reply_rx.await.unwrap().pending, 1);
+
+        drop(notify_shutdown);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sealed_blocks_are_published() {
+        let db = Arc::new(RwLock::new(InMemoryDB::default()));
+        let (executor_mempool_tx, mut executor_mempool_rx) = mpsc::unbounded_channel();
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let (new_blocks, mut subscriber) = broadcast::channel(16);
+
+        let mut executor = Executor::new(
+            db.clone(),
+            1,
+            executor_mempool_tx,
+            Address::ZERO,
+            shutdown,
+            shutdown_complete,
+        )
+        .with_new_blocks(new_blocks);
+
+        tokio::spawn(async move {
+            while let Some(request) = executor_mempool_rx.recv().await {
+                if let MempoolRequest::GetBatch(tx) = request {
+                    let _ = tx.send(Batch::default());
+                }
+            }
+        });
+        let handle = tokio::spawn(async move { executor.run().await });
+
+        let block = tokio::time::timeout(Duration::from_secs(3), subscriber.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(block.number(), 1);
+
+        // Written before it was published
+        let written = db.read().await.read_block_by_number(1).cloned();
+        assert_eq!(written, Some(block));
+
+        drop(notify_shutdown);
+        handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_loaded_chain_is_continued() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::default();
+        db.write_spec(&spec).unwrap();
+        let mut parent_hash = spec.genesis_hash();
+        for number in 1..=3 {
+            let header = BlockHeader {
+                parent_hash,
+                number,
+                difficulty: U256::MAX,
+                ..Default::default()
+            };
+            let block = Block::new(header, Transactions::default()).seal_slow(HashAlgo::Keccak256);
+            parent_hash = *block.get_hash();
+            db.write_block(parent_hash, block).unwrap();
+        }
+
+        // What --load-database starts the node from
+        let path = std::env::temp_dir().join(format!(
+            "mini-blockchain-{}-continued.json",
+            std::process::id()
+        ));
+        std::fs::write(&path, serde_json::to_string(&db).unwrap()).unwrap();
+        let loaded = InMemoryDB::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let db = Arc::new(RwLock::new(loaded));
+
+        let (executor_mempool_tx, mut executor_mempool_rx) = mpsc::unbounded_channel();
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete, _) = mpsc::channel(1);
+        let (new_blocks, mut subscriber) = broadcast::channel(16);
+
+        let mut executor = Executor::new(
+            db.clone(),
+            1,
+            executor_mempool_tx,
+            Address::ZERO,
+            shutdown,
+            shutdown_complete,
+        )
+        .with_new_blocks(new_blocks);
+
+        tokio::spawn(async move {
+            while let Some(request) = executor_mempool_rx.recv().await {
+                if let MempoolRequest::GetBatch(tx) = request {
+                    let _ = tx.send(Batch::default());
+                }
+            }
+        });
+        let handle = tokio::spawn(async move { executor.run().await });
+
+        let block = tokio::time::timeout(Duration::from_secs(3), subscriber.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(block.number(), 4);
+        assert_eq!(block.parent_hash(), &parent_hash);
+
+        // The loaded head is still where it was
+        let reader = db.read().await;
+        assert_eq!(
+            reader.read_block_by_number(3).unwrap().get_hash(),
+            &parent_hash
+        );
+        drop(reader);
+
+        drop(notify_shutdown);
+        handle.await.unwrap().unwrap();
+    }
+
+    /// Executes a batch and runs it through the [FailedBlockPolicy] of the executor
+    async fn execute_batch(
+        executor: &mut Executor<InMemoryDB>,
+        transactions: Vec<Transaction>,
+    ) -> (Block, ChangeSet) {
+        let block = Block::new(BlockHeader::default(), transactions.into());
+
+        let db = executor.db.clone();
+        let reader = db.read().await;
+        let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+        drop(reader);
+
+        executor.apply_failed_block_policy(block, changeset)
+    }
+
+    /// Executor whose sender holds 1000 coins, along with the mempool end of its channel
+    fn policy_executor(policy: FailedBlockPolicy) -> (Executor<InMemoryDB>, ExecutorMempoolRx) {
+        let mut db = InMemoryDB::default();
+        db.write_account(SENDER, Account::new(1000, 0)).unwrap();
+
+        let (executor_mempool_tx, executor_mempool_rx) = mpsc::unbounded_channel();
+        let mut executor = executor(Arc::new(RwLock::new(db))).with_failed_block_policy(policy);
+        executor.executor_mempool_tx = executor_mempool_tx;
+        (executor, executor_mempool_rx)
+    }
+
+    #[tokio::test]
+    async fn test_all_failed_batch_is_sealed_by_default() {
+        let (mut executor, mut mempool_rx) = policy_executor(FailedBlockPolicy::Always);
+
+        let (block, changeset) = execute_batch(&mut executor, vec![transfer(5, 1)]).await;
+        assert_eq!(block.transactions.iter().count(), 1);
+        assert_eq!(changeset.receipts.len(), 1);
+        assert!(mempool_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_all_failed_batch_is_skipped() {
+        let (mut executor, mut mempool_rx) = policy_executor(FailedBlockPolicy::Skip);
+
+        let (block, changeset) = execute_batch(&mut executor, vec![transfer(5, 1)]).await;
+        assert!(block.transactions.is_empty());
+        assert_eq!(
+            block.header.tx_root,
+            Transactions::default().get_root(HashAlgo::Keccak256)
+        );
+        assert!(changeset.receipts.is_empty());
+        assert!(mempool_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_all_failed_batch_is_deferred_once() {
+        let (mut executor, mut mempool_rx) = policy_executor(FailedBlockPolicy::Defer);
+        let tx = transfer(5, 1);
+
+        let (block, changeset) = execute_batch(&mut executor, vec![tx.clone()]).await;
+        assert!(block.transactions.is_empty());
+        assert!(changeset.receipts.is_empty());
+        match mempool_rx.try_recv() {
+            Ok(MempoolRequest::Return(returned)) => {
+                assert_eq!(returned, Transactions::from(vec![tx.clone()]))
+            }
+            other => panic!("expected returned transactions, got {other:?}"),
+        }
+
+        // The second time around the transaction is dropped
+        let (block, _) = execute_batch(&mut executor, vec![tx]).await;
+        assert!(block.transactions.is_empty());
+        assert!(mempool_rx.try_recv().is_err());
+        assert!(executor.deferred.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_coinbase_is_rewarded() {
+        let (executor, _mempool_rx) = policy_executor(FailedBlockPolicy::Skip);
+        let mut executor = executor.with_block_reward(50);
+
+        for number in 0..10 {
+            // Every other block only has a failing transaction, which is dropped but the
+            // reward is still paid
+            let transactions = match number % 2 {
+                0 => Transactions::default(),
+                _ => vec![transfer(5, 1)].into(),
+            };
+            let block = executor.next_block(transactions, number);
+            assert_eq!(block.header.block_reward, 50);
+
+            let db = executor.db.clone();
+            let reader = db.read().await;
+            let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+            drop(reader);
+            let (block, changeset) = executor.apply_failed_block_policy(block, changeset);
+            assert!(block.transactions.is_empty());
+
+            let mut writer = db.write().await;
+            executor.write_changeset(&mut writer, changeset).unwrap();
+        }
+
+        let db = executor.db.read().await;
+        assert_eq!(db.read_account(&executor.coinbase).unwrap().balance(), 500);
+    }
+
+    /// Rewards [SENDER] with 50 for block 1 and nothing after, block `spend_at` tries to send
+    /// the reward on
+    async fn spend_reward(maturity: u64, spend_at: u64, parallel: bool) -> TransactionReceipt {
+        let db = Arc::new(RwLock::new(InMemoryDB::default()));
+        let mut executor = executor(db.clone())
+            .with_block_reward(50)
+            .with_coinbase_maturity(CoinbaseMaturity(maturity))
+            .with_parallel_execution(parallel);
+        executor.coinbase = SENDER;
+
+        let spend = transfer(0, 50);
+        for number in 1..=spend_at {
+            let transactions = match number == spend_at {
+                true => vec![spend.clone()].into(),
+                false => Transactions::default(),
+            };
+            executor.next_number = number;
+            let block = executor.next_block(transactions, number);
+            executor.block_reward = 0;
+
+            let reader = db.read().await;
+            let changeset: ChangeSet = executor.execute_transactions(&reader, &block).into();
+            drop(reader);
+
+            let mut writer = db.write().await;
+            let block = block.seal_slow(HashAlgo::Keccak256);
+            writer.write_block(*block.get_hash(), block).unwrap();
+            executor.write_changeset(&mut writer, changeset).unwrap();
+        }
+
+        let db = db.read().await;
+        db.read_transaction_receipt(&spend.hash).unwrap().clone()
+    }
+
+    #[tokio::test]
+    async fn test_reward_spendable_in_its_block_without_maturity() {
+        for parallel in [false, true] {
+            let receipt = spend_reward(0, 1, parallel).await;
+            assert!(receipt.success, "{receipt:?}");
+            assert_eq!(receipt.reason, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reward_not_spendable_in_its_block() {
+        for parallel in [false, true] {
+            let receipt = spend_reward(1, 1, parallel).await;
+            assert!(!receipt.success);
+            assert_eq!(receipt.reason, Some(FailureReason::ImmatureReward));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reward_matures_at_the_boundary() {
+        for parallel in [false, true] {
+            let receipt = spend_reward(3, 3, parallel).await;
+            assert_eq!(receipt.reason, Some(FailureReason::ImmatureReward));
+
+            let receipt = spend_reward(3, 4, parallel).await;
+            assert!(receipt.success, "{receipt:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_mined_block_verifies() {
+        let (executor, _mempool_rx) = policy_executor(FailedBlockPolicy::Always);
+        let executor = executor.with_difficulty(U256::MAX >> 8);
+
+        let block = executor.next_block(Transactions::default(), 1);
+        let sealed =
+            Executor::<InMemoryDB>::mine_block(block, &AtomicBool::new(false), HashAlgo::Keccak256)
+                .unwrap();
+
+        assert!(sealed.verify(executor.chain_id, HashAlgo::Keccak256));
+        assert_eq!(*sealed.difficulty(), U256::MAX >> 8);
+        assert!(U256::from_le_slice(&sealed.get_hash()[..]) <= U256::MAX >> 8);
+    }
+
+    #[tokio::test]
+    async fn test_mining_refuses_zero_difficulty() {
+        let (executor, _mempool_rx) = policy_executor(FailedBlockPolicy::Always);
+        let executor = executor.with_difficulty(U256::ZERO);
+
+        let block = executor.next_block(Transactions::default(), 1);
+        assert!(matches!(
+            Executor::<InMemoryDB>::mine_block(block, &AtomicBool::new(false), HashAlgo::Keccak256),
+            Err(Error::InvalidBlock(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mining_stops_when_cancelled() {
+        let (executor, _mempool_rx) = policy_executor(FailedBlockPolicy::Always);
+        // Practically no hash meets it
+        let executor = executor.with_difficulty(U256::from(1));
+
+        let block = executor.next_block(Transactions::default(), 1);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let mining = std::thread::spawn({
+            let cancel = cancel.clone();
+            move || Executor::<InMemoryDB>::mine_block(block, &cancel, HashAlgo::Keccak256)
+        });
+        cancel.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            mining.join().unwrap(),
+            Err(Error::MiningCancelled)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_mining_moves_the_timestamp_once_nonces_run_out() {
+        let (executor, _mempool_rx) = policy_executor(FailedBlockPolicy::Always);
+        let executor = executor.with_difficulty(U256::MAX >> 1);
+
+        // Half of the hashes meet the difficulty, so one of the first timestamps misses it
+        // with the last nonce
+        let mut block = executor.next_block(Transactions::default(), 0);
+        block.header.nonce = u64::MAX;
+        while U256::from_le_slice(&block.hash(HashAlgo::Keccak256)[..]) <= block.header.difficulty {
+            block.header.timestamp += 1;
+        }
+        let timestamp = block.header.timestamp;
+
+        let sealed =
+            Executor::<InMemoryDB>::mine_block(block, &AtomicBool::new(false), HashAlgo::Keccak256)
+                .unwrap();
+        assert_eq!(sealed.timestamp(), timestamp + 1);
+        assert!(sealed.verify(executor.chain_id, HashAlgo::Keccak256));
+    }
+
+    #[tokio::test]
+    async fn test_difficulty_follows_block_time() {
+        let mut executor = executor(Arc::new(RwLock::new(InMemoryDB::default())));
+        executor.difficulty_adjuster = DifficultyAdjuster::new(2);
+        let initial = U256::MAX >> 8;
+        executor = executor.with_difficulty(initial);
+
+        // Two blocks that took twice the block time of 1 second each
+        for (number, timestamp) in [(1, 100), (2, 102), (3, 104)] {
+            executor.next_number = number;
+            let block = Executor::<InMemoryDB>::mine_block(
+                executor.next_block(Transactions::default(), timestamp),
+                &AtomicBool::new(false),
+                HashAlgo::Keccak256,
+            )
+            .unwrap();
+            let mut db = executor.db.write().await;
+            executor.write_block(&mut db, block).unwrap();
+            drop(db);
+
+            if executor
+                .difficulty_adjuster
+                .is_due(number, executor.last_adjustment)
+            {
+                executor.adjust_difficulty(number).await;
+            }
+        }
+
+        assert_eq!(executor.last_adjustment, 3);
+        assert_eq!(executor.current_difficulty, initial * U256::from(2));
+        assert_eq!(
+            executor
+                .next_block(Transactions::default(), 105)
+                .header
+                .difficulty,
+            initial * U256::from(2)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_restart_keeps_the_adjusted_difficulty() {
+        let db = Arc::new(RwLock::new(InMemoryDB::default()));
+        let initial = U256::MAX >> 8;
+        let restarted = || {
+            let mut executor = executor(db.clone()).with_difficulty(initial);
+            executor.difficulty_adjuster = DifficultyAdjuster::new(2);
+            executor
+        };
+
+        let mut executor = restarted();
+        for (number, timestamp) in [(1, 100), (2, 102), (3, 104), (4, 105)] {
+            let block = Executor::<InMemoryDB>::mine_block(
+                executor.next_block(Transactions::default(), timestamp),
+                &AtomicBool::new(false),
+                HashAlgo::Keccak256,
+            )
+            .unwrap();
+            executor.last_hash = *block.get_hash();
+            executor.next_number += 1;
+            let mut db = db.write().await;
+            executor.write_block(&mut db, block).unwrap();
+            drop(db);
+
+            if executor
+                .difficulty_adjuster
+                .is_due(number, executor.last_adjustment)
+            {
+                executor.adjust_difficulty(number).await;
+            }
+
+            // A node restarted on this head seals the next block like the running one
+            let mut restarted = restarted();
+            restarted.sync_head().await;
+            assert_eq!(restarted.current_difficulty, executor.current_difficulty);
+            assert_eq!(restarted.last_adjustment, executor.last_adjustment);
+        }
+        assert_eq!(executor.current_difficulty, initial * U256::from(2));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_blocks_are_not_written() {
+        let db = Arc::new(RwLock::new(InMemoryDB::default()));
+        let mut executor = executor(db.clone()).with_block_validation(true);
+        let mut writer = db.write().await;
+
+        let block = executor.next_block(Transactions::default(), 1);
+        let tampered = block.clone().seal(B256::repeat_byte(1));
+        assert!(matches!(
+            executor.write_block(&mut writer, tampered),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        let mined =
+            Executor::<InMemoryDB>::mine_block(block, &AtomicBool::new(false), HashAlgo::Keccak256)
+                .unwrap();
+        executor.last_hash = B256::repeat_byte(2);
+        assert!(matches!(
+            executor.write_block(&mut writer, mined.clone()),
+            Err(Error::InvalidBlock(_))
+        ));
+
+        executor.last_hash = INITIAL_HASH;
+        executor.next_number = 2;
+        assert!(matches!(
+            executor.write_block(&mut writer, mined.clone()),
+            Err(Error::InvalidBlock(_))
+        ));
+        assert!(writer.latest_block().is_none());
+
+        executor.next_number = 1;
+        executor.write_block(&mut writer, mined).unwrap();
+        assert_eq!(writer.latest_block().unwrap().number(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_mixed_batch_is_always_sealed() {
+        for policy in [
+            FailedBlockPolicy::Always,
+            FailedBlockPolicy::Skip,
+            FailedBlockPolicy::Defer,
+        ] {
+            let (mut executor, mut mempool_rx) = policy_executor(policy);
+
+            let (block, changeset) =
+                execute_batch(&mut executor, vec![transfer(0, 1), transfer(5, 1)]).await;
+            assert_eq!(block.transactions.iter().count(), 2);
+            assert_eq!(changeset.receipts.len(), 2);
+            assert!(mempool_rx.try_recv().is_err());
+        }
     }
 }
@@ -0,0 +1,365 @@
+//! Deterministic chains for projects that test against this node, such as explorers and
+//! indexers, without having to run one
+//!
+//! Every scenario is scripted, uses fixed keys and fixed timestamps, and is written with sorted
+//! keys, so generating it twice with the same crate version produces identical files
+
+use crate::{
+    database::{DatabaseReader, DatabaseWriter},
+    utils::{addr, sign_transaction, u256_to_signing_key, HashAlgo},
+    Account, ChainSpec, ChangeSet, Error, Executor, InMemoryDB, Transaction, TransactionReceipt,
+    Transactions,
+};
+use alloy_primitives::{Address, B256, U256};
+use clap::ValueEnum;
+use k256::ecdsa::SigningKey;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::Path,
+    sync::Arc,
+};
+use tokio::sync::{broadcast, mpsc, RwLock};
+
+pub const CHAINSPEC_FILE: &str = "chainspec.json";
+pub const DUMP_FILE: &str = "dump.json";
+pub const BLOCKS_FILE: &str = "blocks.jsonl";
+pub const MANIFEST_FILE: &str = "manifest.json";
+
+const CHAIN_ID: u64 = 1337;
+const HASH_ALGO: HashAlgo = HashAlgo::Keccak256;
+const GENESIS_TIMESTAMP: u64 = 1_700_000_000;
+const BLOCK_TIME: u64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum Scenario {
+    /// 200 blocks of transfers mixed with every kind of failed transaction and a few empty
+    /// blocks
+    Standard,
+}
+
+/// Objects of the fixture chain worth asserting on, with what the node recorded for them
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// Version of the crate that generated the fixture
+    pub version: String,
+    pub scenario: Scenario,
+    pub chain_id: u64,
+    /// Number of the last block
+    pub head_number: u64,
+    pub head_hash: B256,
+    /// Addresses by their label
+    pub accounts: BTreeMap<String, Address>,
+    /// First transaction of each kind the scenario sends, by label
+    pub transactions: BTreeMap<String, NotableTransaction>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NotableTransaction {
+    pub hash: B256,
+    pub receipt: TransactionReceipt,
+}
+
+#[derive(Debug)]
+pub struct Fixture {
+    pub spec: ChainSpec,
+    pub db: InMemoryDB,
+    pub manifest: Manifest,
+}
+
+/// Builds the chain of `scenario` in memory
+pub async fn build(scenario: Scenario) -> Result<Fixture, Error> {
+    match scenario {
+        Scenario::Standard => build_standard().await,
+    }
+}
+
+/// Builds the chain of `scenario` and writes the chainspec, the database dump, the blocks as
+/// JSON lines and the manifest into `out`
+pub async fn generate(scenario: Scenario, out: &Path) -> Result<Manifest, Error> {
+    let fixture = build(scenario).await?;
+    tokio::fs::create_dir_all(out).await?;
+
+    let mut blocks = String::new();
+    for number in 0..=fixture.manifest.head_number {
+        if let Some(block) = fixture.db.read_block_by_number(number) {
+            blocks.push_str(&serde_json::to_string(block)?);
+            blocks.push('\n');
+        }
+    }
+
+    tokio::fs::write(out.join(CHAINSPEC_FILE), to_sorted_json(&fixture.spec)?).await?;
+    tokio::fs::write(out.join(DUMP_FILE), to_sorted_json(&fixture.db)?).await?;
+    tokio::fs::write(out.join(BLOCKS_FILE), blocks).await?;
+    tokio::fs::write(out.join(MANIFEST_FILE), to_sorted_json(&fixture.manifest)?).await?;
+
+    Ok(fixture.manifest)
+}
+
+/// How long [connect_when_up] waits for the node to listen
+#[cfg(any(test, feature = "test-utils"))]
+const CONNECT_DEADLINE: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Connects to a node starting up on `port` of localhost once it listens, for tests that run
+/// one. Fails if nothing listens within a few seconds, so a node that never comes up fails the
+/// test instead of hanging it
+///
+/// Dialing a local port nobody listens on yet can connect the socket to itself, which would
+/// then hold the port the node is about to bind
+#[cfg(any(test, feature = "test-utils"))]
+pub async fn connect_when_up(port: u16) -> Result<tokio::net::TcpStream, Error> {
+    let connect = async {
+        loop {
+            match tokio::net::TcpStream::connect(("127.0.0.1", port)).await {
+                Ok(stream) if stream.local_addr().ok() != stream.peer_addr().ok() => break stream,
+                _ => tokio::time::sleep(std::time::Duration::from_millis(1)).await,
+            }
+        }
+    };
+
+    tokio::time::timeout(CONNECT_DEADLINE, connect)
+        .await
+        .map_err(|_| {
+            let reason = format!("nothing listened on port {port} within {CONNECT_DEADLINE:?}");
+            std::io::Error::new(std::io::ErrorKind::TimedOut, reason).into()
+        })
+}
+
+/// Going through [serde_json::Value] sorts the keys of every map, hash maps included
+fn to_sorted_json<T: Serialize>(value: &T) -> Result<String, Error> {
+    let value = serde_json::to_value(value)?;
+    Ok(serde_json::to_string_pretty(&value)? + "\n")
+}
+
+/// Account of the scenario that signs its own transactions
+struct Actor {
+    key: SigningKey,
+    address: Address,
+    nonce: u64,
+}
+
+impl Actor {
+    fn new(seed: u64) -> Result<Self, Error> {
+        let key = u256_to_signing_key(&U256::from(seed))?;
+        Ok(Self {
+            address: addr(&key, HASH_ALGO),
+            key,
+            nonce: 0,
+        })
+    }
+
+    fn transfer(&mut self, to: Address, value: u128) -> Transaction {
+        let tx = self.sign(to, value, self.nonce);
+        self.nonce += 1;
+        tx
+    }
+
+    fn sign(&self, to: Address, value: u128, nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            from: self.address,
+            to,
+            nonce,
+            value,
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, CHAIN_ID, &self.key, HASH_ALGO);
+        tx
+    }
+}
+
+async fn build_standard() -> Result<Fixture, Error> {
+    let mut alice = Actor::new(1)?;
+    let mut bob = Actor::new(2)?;
+    let carol = Actor::new(3)?;
+    let dave = Actor::new(4)?;
+    // Never funded, so every transaction it sends fails
+    let stranger = Actor::new(5)?;
+
+    let spec = ChainSpec::new(CHAIN_ID)
+        .with_hash_algo(HASH_ALGO)
+        .with_account(alice.address, Account::new(1_000_000_000, 0))
+        .with_account(bob.address, Account::new(1_000, 0))
+        .with_account(carol.address, Account::new(1_000, 0))
+        .with_account(dave.address, Account::new(100, 0));
+
+    let accounts = BTreeMap::from([
+        ("alice".to_string(), alice.address),
+        ("bob".to_string(), bob.address),
+        ("carol".to_string(), carol.address),
+        ("dave".to_string(), dave.address),
+        ("stranger".to_string(), stranger.address),
+    ]);
+
+    let mut db = InMemoryDB::new();
+    db.write_spec(&spec)?;
+    let db = Arc::new(RwLock::new(db));
+
+    // The executor is only used for its block building and execution, it never runs
+    let (executor_mempool_tx, _) = mpsc::unbounded_channel();
+    let (_, shutdown) = broadcast::channel(1);
+    let (shutdown_complete, _) = mpsc::channel(1);
+    let mut executor = Executor::new(
+        db.clone(),
+        BLOCK_TIME,
+        executor_mempool_tx,
+        Address::ZERO,
+        shutdown,
+        shutdown_complete,
+    )
+    .with_chain_id(CHAIN_ID)
+    .with_hash_algo(HASH_ALGO);
+    executor.sync_head().await;
+
+    let mut labels: HashMap<B256, &str> = HashMap::new();
+    let mut notable = BTreeMap::new();
+
+    for number in 1..=200u64 {
+        let mut transactions = Vec::new();
+
+        // Every 20th block is empty
+        if number % 20 != 0 {
+            let tx = alice.transfer(bob.address, number as u128 * 10);
+            labels.entry(tx.hash).or_insert("transfer");
+            transactions.push(tx);
+
+            if number % 3 == 0 {
+                transactions.push(bob.transfer(carol.address, 5));
+            }
+
+            if number % 7 == 0 {
+                let tx = carol.sign(alice.address, 1, carol.nonce + 1);
+                labels.entry(tx.hash).or_insert("invalid_nonce");
+                transactions.push(tx);
+            }
+
+            if number % 11 == 0 {
+                let tx = dave.sign(alice.address, 1_000, dave.nonce);
+                labels.entry(tx.hash).or_insert("insufficient_balance");
+                transactions.push(tx);
+            }
+
+            if number % 13 == 0 {
+                let tx = stranger.sign(alice.address, 1, stranger.nonce);
+                labels.entry(tx.hash).or_insert("unknown_sender");
+                transactions.push(tx);
+            }
+        }
+
+        let mut transactions: Transactions = transactions.into();
+        transactions.sort();
+
+        let timestamp = GENESIS_TIMESTAMP + number * BLOCK_TIME;
+        let block = executor.next_block(transactions, timestamp);
+
+        let reader = db.read().await;
+        let mut change_set: ChangeSet = executor.execute_transactions(&reader, &block).into();
+        drop(reader);
+
+        let block = block.seal_slow(HASH_ALGO);
+        let block_hash = *block.get_hash();
+        change_set.set_block_hash(block_hash);
+
+        for (hash, receipt) in &change_set.receipts {
+            if let Some(label) = labels.remove(hash) {
+                notable
+                    .entry(label.to_string())
+                    .or_insert_with(|| NotableTransaction {
+                        hash: *hash,
+                        receipt: receipt.clone(),
+                    });
+            }
+        }
+
+        let mut writer = db.write().await;
+        executor.write_changeset(&mut writer, change_set)?;
+        executor.write_block(&mut writer, block)?;
+        drop(writer);
+
+        executor.last_hash = block_hash;
+        executor.next_number += 1;
+    }
+
+    let manifest = Manifest {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        scenario: Scenario::Standard,
+        chain_id: CHAIN_ID,
+        head_number: executor.next_number - 1,
+        head_hash: executor.last_hash,
+        accounts,
+        transactions: notable,
+    };
+
+    drop(executor);
+    let db = Arc::into_inner(db)
+        .expect("the executor was the only other owner of the database")
+        .into_inner();
+
+    Ok(Fixture { spec, db, manifest })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{utils::sha3, FailureReason};
+
+    /// Hashes of the files of [Scenario::Standard], regenerate them whenever the scenario or
+    /// the crate version changes on purpose
+    const STANDARD_HASHES: [(&str, &str); 4] = [
+        (
+            CHAINSPEC_FILE,
+            "0x051337a9143c212a3c7f9c9df1bdac069748ab333669ae2078e96ec0991e5b8c",
+        ),
+        (
+            DUMP_FILE,
+            "0xec1a83fc92f10aa107627b4f7849a5360d986888f653017852e0abadc39722e4",
+        ),
+        (
+            BLOCKS_FILE,
+            "0x33617da7966d8cebf0d0d67d9e49bc280b9918fa6a0f1f4951545bb729d8970a",
+        ),
+        (
+            MANIFEST_FILE,
+            "0x8fc010e9aa642d51b5bcf56702aa5331166a219a15a931ad334d557022a1e8b4",
+        ),
+    ];
+
+    #[tokio::test]
+    async fn test_standard_fixture_is_reproducible() {
+        let out = std::env::temp_dir().join(format!("mini-blockchain-{}", std::process::id()));
+        let manifest = generate(Scenario::Standard, &out).await.unwrap();
+
+        for (file, expected) in STANDARD_HASHES {
+            let data = std::fs::read(out.join(file)).unwrap();
+            assert_eq!(sha3(data).to_string(), expected, "{file} changed");
+        }
+        std::fs::remove_dir_all(&out).unwrap();
+
+        assert_eq!(manifest.head_number, 200);
+        assert_eq!(manifest, build(Scenario::Standard).await.unwrap().manifest);
+    }
+
+    #[tokio::test]
+    async fn test_standard_fixture_has_every_outcome() {
+        let Fixture { db, manifest, .. } = build(Scenario::Standard).await.unwrap();
+
+        let reason = |label: &str| manifest.transactions[label].receipt.reason;
+        assert!(manifest.transactions["transfer"].receipt.success);
+        assert_eq!(reason("invalid_nonce"), Some(FailureReason::InvalidNonce));
+        assert_eq!(
+            reason("insufficient_balance"),
+            Some(FailureReason::InsufficientBalance)
+        );
+        assert_eq!(reason("unknown_sender"), Some(FailureReason::UnknownSender));
+
+        // The genesis block and 200 produced ones
+        assert_eq!(db.block_count(), 201);
+        assert!(db
+            .read_block_by_number(20)
+            .unwrap()
+            .transactions()
+            .is_empty());
+        assert_eq!(db.latest_block().unwrap().get_hash(), &manifest.head_hash);
+    }
+}
@@ -3,22 +3,57 @@ pub mod client;
 mod database;
 mod error;
 mod executor;
+pub mod fixtures;
 mod primitives;
 mod report;
 mod server;
 pub mod utils;
+mod validation;
 
-pub use chainspec::ChainSpec;
+pub use chainspec::{ChainSpec, CoinbaseMaturity, TestAccount};
 pub use database::{DatabaseReader, DatabaseWriter, InMemoryDB};
 pub use error::Error;
-pub use executor::Executor;
-pub use primitives::*;
+pub(crate) use executor::Executor;
+pub use executor::{
+    DifficultyAdjuster, FailedBlockPolicy, MempoolOrdering, MempoolStats, NodeRole,
+    ADJUSTMENT_INTERVAL, DEFAULT_BLOCK_GAS_LIMIT, DEFAULT_MEMPOOL_MAX_PER_SENDER,
+    DEFAULT_MEMPOOL_MAX_SIZE, DEFAULT_MEMPOOL_TTL,
+};
+pub use primitives::{
+    merkle, Account, Block, BlockHeader, Bundle, BundleReceipt, FailureReason, SealedBlock,
+    SealedHeader, Transaction, TransactionKind, TransactionReceipt, TransactionSummary,
+    Transactions, MAX_BUNDLE_SIZE,
+};
+pub(crate) use primitives::{ChangeSet, State};
 pub use report::Reporter;
-pub use server::Server;
+pub use server::{
+    noise_accept, noise_connect, tls_acceptor, tls_connector, AdminReq, BanPolicy, BlackList,
+    BlockReq, ChainInfo, Connection, ControlMsg, FrameMode, MaybeTlsStream, Message, NodeLimits,
+    NodeStatus, NoiseStream, OnTaskFailure, PeerCounters, PeerEncryption, PeerSnapshot, PeerStats,
+    Pruned, RateLimit, Server, StartupPhase, StatusReport, SubmissionResult, SubscriptionKind,
+    SyncEvent, Task, TlsConnection, TransactionEntry, TransactionReq, UnixConnection, WhiteList,
+    WireFormat, WsStream, DEFAULT_BAN_POLICY, DEFAULT_CONNECTION_RATE_LIMIT, DEFAULT_HOST,
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_IP,
+    DEFAULT_PEER_STATS_CAPACITY, DEFAULT_RATE_LIMIT, DEFAULT_REQUEST_TIMEOUT,
+    DEFAULT_TRANSACTION_RATE_LIMIT, FELL_BEHIND_LAG, MAX_TRANSACTIONS_PER_REQUEST,
+    PROTOCOL_VERSION,
+};
 use tokio::sync::broadcast;
+pub use validation::{
+    ChainIdCheck, PolicyCheck, SignatureCheck, StateView, StatefulCheck, TxValidator,
+    ValidationConfig, ValidationContext, ValidationOutcome, ValidationPhase, ValidationStage,
+};
+
+/// Everything needed to run a node or talk to one, in a single import
+pub mod prelude {
+    pub use crate::{
+        Account, BlockReq, ChainSpec, Connection, DatabaseReader, DatabaseWriter, Error,
+        InMemoryDB, Message, SealedBlock, Server, Transaction, TransactionReceipt, TransactionReq,
+    };
+}
 
 #[derive(Debug)]
-pub struct Shutdown {
+pub(crate) struct Shutdown {
     pub(crate) is_shutdown: bool,
     pub(crate) notify: broadcast::Receiver<()>,
 }
@@ -1,14 +1,36 @@
-use alloy_primitives::Address;
+use alloy_primitives::{Address, B256, U256};
 use anyhow::Result;
-use mini_blockchain::{ChainSpec, DatabaseWriter, Error, InMemoryDB, Reporter, Server};
-use clap::{Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use ipnet::IpNet;
+use k256::ecdsa::SigningKey;
+use mini_blockchain::{
+    client::AddressReport,
+    fixtures::{self, Scenario},
+    merkle::MerkleProof,
+    tls_acceptor, tls_connector,
+    utils::HashAlgo,
+    AdminReq, BanPolicy, BlackList, BlockReq, ChainSpec, DatabaseWriter, Error, FailedBlockPolicy,
+    FrameMode, InMemoryDB, MempoolOrdering, NodeLimits, NodeRole, OnTaskFailure, PeerEncryption,
+    PeerSnapshot, RateLimit, Reporter, SealedHeader, Server, WhiteList, WireFormat,
+    DEFAULT_BAN_POLICY, DEFAULT_BLOCK_GAS_LIMIT, DEFAULT_CONNECTION_RATE_LIMIT, DEFAULT_HOST,
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_IP,
+    DEFAULT_MEMPOOL_MAX_PER_SENDER, DEFAULT_MEMPOOL_MAX_SIZE, DEFAULT_MEMPOOL_TTL,
+    DEFAULT_PEER_STATS_CAPACITY, DEFAULT_RATE_LIMIT, DEFAULT_REQUEST_TIMEOUT,
+    DEFAULT_TRANSACTION_RATE_LIMIT,
+};
 use serde::de::DeserializeOwned;
 use std::fs::File;
-use std::{io::BufReader, path::PathBuf, sync::Arc};
+use std::time::{Duration, UNIX_EPOCH};
+use std::{
+    io::BufReader,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+};
 use tokio::select;
 use tokio::signal::ctrl_c;
 use tokio::sync::{broadcast, mpsc, RwLock};
-use tracing::info;
+use tracing::{error, info};
 
 #[derive(Parser)]
 struct Cli {
@@ -19,9 +41,27 @@ struct Cli {
 #[derive(Subcommand)]
 enum Commands {
     /// Runs the server and listens to new transactions
-    Server(ServerArgs),
+    Server(Box<ServerArgs>),
+    /// Runs the server on a unix domain socket only, for tooling on the same machine
+    UnixServer(Box<UnixServerArgs>),
     /// Runs the client and tries to connect to the server and send it transactions
     Client(ClientArgs),
+    /// Deterministic test chains for projects that build on top of the node
+    #[clap(subcommand)]
+    Fixtures(FixturesCommands),
+}
+
+#[derive(Subcommand)]
+enum FixturesCommands {
+    /// Builds the chain of a scenario and writes it to a directory
+    Generate {
+        #[clap(long, value_enum, default_value_t = Scenario::Standard)]
+        scenario: Scenario,
+
+        /// Directory the fixture files are written to
+        #[clap(long, default_value = "fixtures")]
+        out: PathBuf,
+    },
 }
 
 #[derive(Args)]
@@ -29,8 +69,194 @@ struct ClientArgs {
     /// Whether to just send one transaction to the client or many from many different clients
     #[clap(short, long)]
     many: bool,
+
+    /// Has to match the server's --wire-format, unless both negotiate
+    #[clap(long, value_enum, default_value_t = WireFormat::Json)]
+    wire_format: WireFormat,
+
+    /// Only offer --wire-format and fall back to json, the server has to negotiate as well
+    #[clap(long)]
+    negotiate_encoding: bool,
+
+    /// Chainspec the node was started with, the default spec if not given
+    #[clap(long)]
+    spec: Option<PathBuf>,
+
+    /// Overrides the hash algorithm of the chainspec, has to match the node's
+    #[clap(long, value_enum)]
+    hash_algo: Option<HashAlgo>,
+
+    /// Connects over TLS, the node is verified against the web's root CAs unless --ca is given
+    #[clap(long)]
+    tls: bool,
+
+    /// PEM file of the CA that signed the node's certificate, implies --tls
+    #[clap(long)]
+    ca: Option<PathBuf>,
+
+    #[clap(subcommand)]
+    command: Option<ClientCommands>,
 }
 
+#[derive(Subcommand)]
+enum ClientCommands {
+    /// Prints a block by its number, hash or timestamp
+    Block {
+        /// Block number, block hash, `latest`, `earliest`, or `@` followed by either a unix
+        /// timestamp or an RFC 3339 date in UTC, for example `@2024-06-01T15:00:00Z`
+        #[clap(value_parser = parse_block_req)]
+        id: BlockReq,
+    },
+    /// Prints only the header of a block, by its number, hash or timestamp
+    Header {
+        /// Same as for the block command
+        #[clap(value_parser = parse_block_req)]
+        id: BlockReq,
+    },
+    /// Prints the receipt of a transaction, telling whether it succeeded
+    Receipt {
+        /// Hash of the transaction
+        hash: B256,
+    },
+    /// Prints how many transactions are waiting for the next block
+    Mempool,
+    /// Prints the uptime, head, pending transactions and peers of the node
+    Status,
+    /// Prints the balance and nonce of an account
+    Account {
+        /// Address of the account
+        address: Address,
+    },
+    /// Prints everything the node can tell about an address and which sections it can't
+    Report {
+        /// Address of the account
+        address: Address,
+        /// Transactions the recent transactions section is made of
+        #[clap(long, default_value_t = 20)]
+        last: usize,
+        #[clap(long, value_enum, default_value_t = ReportOutput::Text)]
+        output: ReportOutput,
+    },
+    /// Prints what a spender may still send on behalf of an account
+    Allowance {
+        /// Address of the account that approved the spender
+        owner: Address,
+        spender: Address,
+    },
+    /// Bans an ip or a network like 10.0.0.0/8 and prints the black list
+    Ban {
+        entry: String,
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Lifts the ban of an ip or network and prints the black list
+    Unban {
+        entry: String,
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Prints the black list
+    Bans {
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Prints the traffic, messages and rejections of the peers the node keeps track of
+    Peers {
+        /// Only prints this peer
+        #[clap(long)]
+        ip: Option<IpAddr>,
+        /// Column the peers are sorted by, highest first
+        #[clap(long, value_enum, default_value_t = PeerColumn::LastSeen)]
+        sort: PeerColumn,
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Stops block production until resumed, transactions are still accepted
+    Pause {
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Continues block production
+    Resume {
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Makes a follower produce blocks on top of the latest block it has
+    Promote {
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Stops block production for good, the node imports blocks from then on
+    Demote {
+        /// Has to match the server's admin token
+        #[clap(long)]
+        admin_token: String,
+    },
+    /// Checks offline that a transaction is part of a block, exits with an error if it isn't
+    VerifyProof {
+        /// JSON of a merkle::MerkleProof
+        #[clap(long)]
+        proof: PathBuf,
+        /// JSON of the SealedHeader of the block the proof is for
+        #[clap(long)]
+        header: PathBuf,
+    },
+    /// Prints every block the node seals until it stops
+    Subscribe {
+        /// Prints the transactions the mempool accepts instead
+        #[clap(long)]
+        pending: bool,
+    },
+}
+
+/// No hash realistically meets a difficulty of 0, so the node would never seal a block
+fn parse_difficulty(difficulty: &str) -> Result<U256, String> {
+    match difficulty.parse::<U256>().map_err(|e| e.to_string())? {
+        difficulty if difficulty.is_zero() => Err("has to be at least 1".to_string()),
+        difficulty => Ok(difficulty),
+    }
+}
+
+fn parse_block_req(id: &str) -> Result<BlockReq, String> {
+    match id {
+        "latest" => return Ok(BlockReq::Latest),
+        "earliest" => return Ok(BlockReq::Earliest),
+        _ => {}
+    }
+
+    if let Some(time) = id.strip_prefix('@') {
+        if let Ok(timestamp) = time.parse() {
+            return Ok(BlockReq::Timestamp(timestamp));
+        }
+
+        let time = humantime::parse_rfc3339_weak(time).map_err(|e| e.to_string())?;
+        let timestamp = time.duration_since(UNIX_EPOCH).map_err(|e| e.to_string())?;
+        return Ok(BlockReq::Timestamp(timestamp.as_secs()));
+    }
+
+    if id.starts_with("0x") {
+        return id.parse().map(BlockReq::Hash).map_err(|e| e.to_string());
+    }
+
+    id.parse().map(BlockReq::Number).map_err(|e| e.to_string())
+}
+
+#[derive(Args)]
+struct UnixServerArgs {
+    /// Path of the socket, a socket a previous run left behind is replaced
+    #[clap(long)]
+    socket_path: PathBuf,
+
+    #[clap(flatten)]
+    server: ServerArgs,
+}
 
 #[derive(Args)]
 struct ServerArgs {
@@ -39,10 +265,31 @@ struct ServerArgs {
     #[clap(long, short)]
     spec: Option<PathBuf>,
 
+    /// Overrides the hash algorithm of the chainspec, sha3 for chains created before
+    /// keccak256 was the default
+    #[clap(long, value_enum)]
+    hash_algo: Option<HashAlgo>,
+
     /// Rpc Port
     #[clap(long, short, default_value = "8545")]
     port: u16,
 
+    /// Interface to listen on, 0.0.0.0 or :: to be reachable from other machines
+    #[clap(long, default_value_t = DEFAULT_HOST)]
+    host: IpAddr,
+
+    /// Address to listen on, can be repeated, replaces --host and --port
+    #[clap(long = "bind", value_name = "ADDR", conflicts_with = "host")]
+    bind: Vec<SocketAddr>,
+
+    /// Port to serve Ethereum style JSON-RPC over plain HTTP on, next to every listen address
+    #[clap(long)]
+    http_port: Option<u16>,
+
+    /// Port to serve the messages of the node over WebSocket on, next to every listen address
+    #[clap(long)]
+    ws_port: Option<u16>,
+
     /// Coinbase address
     #[clap(
         long,
@@ -55,6 +302,11 @@ struct ServerArgs {
     #[clap(long)]
     database_dump: Option<PathBuf>,
 
+    /// Dump to start from instead of an empty database, the accounts of the chainspec are
+    /// then only used for a fresh database
+    #[clap(long)]
+    load_database: Option<PathBuf>,
+
     /// Wheter chain-bit should output debug info to the terminal
     /// For example when debug mode is activated every block will be
     /// printed to the terminal
@@ -70,6 +322,305 @@ struct ServerArgs {
     /// Block time of the blockchain
     #[clap(short, long, default_value_t = 10)]
     block_time: u64,
+
+    /// Coins credited to the coinbase for every sealed block
+    #[clap(long, default_value_t = 0)]
+    block_reward: u128,
+
+    /// Proof of work target, the hash of every block has to be at most this number
+    #[clap(long, default_value_t = U256::MAX >> 16, value_parser = parse_difficulty)]
+    difficulty: U256,
+
+    /// Most gas the transactions of a block may take up together
+    #[clap(long, default_value_t = DEFAULT_BLOCK_GAS_LIMIT)]
+    block_gas_limit: u64,
+
+    /// Execute the transactions of unrelated accounts on several threads
+    #[clap(long)]
+    parallel_execution: bool,
+
+    /// Accept blocks produced by other nodes on top of the head while producing, followers
+    /// always accept them. They have to carry the admin token
+    #[clap(long)]
+    allow_import: bool,
+
+    /// Node to pull blocks from while following it, over plain tcp unless --peer-encryption is
+    /// set
+    #[clap(long)]
+    producer_addr: Option<SocketAddr>,
+
+    /// Address the node of --producer-addr has to prove with its node key over Noise
+    #[clap(long, requires = "producer_addr")]
+    producer_node: Option<Address>,
+
+    /// Encrypts the links between nodes, noise takes --node-key-file and, to follow a producer,
+    /// --producer-node
+    #[clap(long, value_enum, default_value_t = PeerEncryption::None)]
+    peer_encryption: PeerEncryption,
+
+    /// File holding the hex private key the node proves its address with to other nodes
+    #[clap(long)]
+    node_key_file: Option<PathBuf>,
+
+    /// Port other nodes connect to, next to every listen address
+    #[clap(long)]
+    peer_port: Option<u16>,
+
+    /// Threads verifying the signatures of submitted transactions, one per cpu by default
+    #[clap(long)]
+    verifier_workers: Option<usize>,
+
+    /// What to do when the mempool or the executor stops because of an error
+    #[clap(long, value_enum, default_value_t = OnTaskFailure::Shutdown)]
+    on_task_failure: OnTaskFailure,
+
+    /// How many peers to keep connection statistics for
+    #[clap(long, default_value_t = DEFAULT_PEER_STATS_CAPACITY)]
+    peer_stats_capacity: usize,
+
+    /// Whether this node produces blocks or imports those of another node until promoted
+    #[clap(long, value_enum, default_value_t = NodeRole::Producer)]
+    role: NodeRole,
+
+    /// What to do with blocks in which every transaction failed
+    #[clap(long, value_enum, default_value_t = FailedBlockPolicy::Always)]
+    seal_all_failed_blocks: FailedBlockPolicy,
+
+    /// Which transactions go into the next block first
+    #[clap(long, value_enum, default_value_t = MempoolOrdering::Fifo)]
+    mempool_ordering: MempoolOrdering,
+
+    /// How many pending transactions the mempool holds before evicting the oldest ones
+    #[clap(long, default_value_t = DEFAULT_MEMPOOL_MAX_SIZE)]
+    mempool_max_size: usize,
+
+    /// How many pending transactions a single sender may have in the mempool
+    #[clap(long, default_value_t = DEFAULT_MEMPOOL_MAX_PER_SENDER)]
+    mempool_max_per_sender: usize,
+
+    /// Seconds a transaction may wait in the mempool before it is dropped
+    #[clap(long, default_value_t = DEFAULT_MEMPOOL_TTL.as_secs())]
+    mempool_ttl: u64,
+
+    /// File pending transactions are written to on shutdown and restored from on startup
+    #[clap(long)]
+    mempool_persist_path: Option<PathBuf>,
+
+    /// How many of the latest blocks keep their transactions and receipts, older blocks only
+    /// keep their header and a summary of each transaction
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    retain_full_blocks: Option<u64>,
+
+    /// How messages are delimited on the wire, the client only speaks crlf
+    #[clap(long, value_enum, default_value_t = FrameMode::CrLf)]
+    frame_mode: FrameMode,
+
+    /// How messages are encoded on the wire, binary formats always use length prefixed frames
+    #[clap(long, value_enum, default_value_t = WireFormat::Json)]
+    wire_format: WireFormat,
+
+    /// Only offer --wire-format and fall back to json, every client has to negotiate as well
+    #[clap(long)]
+    negotiate_encoding: bool,
+
+    /// Larger messages are dropped without being read into memory in full
+    #[clap(long, default_value_t = NodeLimits::default().max_frame_size)]
+    max_message_bytes: usize,
+
+    /// Seconds a connection may go without sending a complete message before it is closed
+    #[clap(long, default_value_t = DEFAULT_IDLE_TIMEOUT.as_secs())]
+    idle_timeout: u64,
+
+    /// Seconds a single request may take before it is answered with a timeout error
+    #[clap(long, default_value_t = DEFAULT_REQUEST_TIMEOUT.as_secs())]
+    request_timeout: u64,
+
+    /// Connections served at the same time, further ones are told the server is busy
+    #[clap(long, default_value_t = DEFAULT_MAX_CONNECTIONS)]
+    max_connections: usize,
+
+    /// Connections a single ip may have open at the same time
+    #[clap(long, default_value_t = DEFAULT_MAX_CONNECTIONS_PER_IP)]
+    max_connections_per_ip: usize,
+
+    /// Requests other than transactions a single ip may make per second, 0 disables the limit
+    #[clap(long, default_value_t = DEFAULT_RATE_LIMIT.per_second)]
+    requests_per_second: u32,
+
+    /// Requests other than transactions a single ip may make in a burst
+    #[clap(long, default_value_t = DEFAULT_RATE_LIMIT.burst)]
+    request_burst: u32,
+
+    /// Transactions and bundles a single ip may submit per second, 0 disables the limit
+    #[clap(long, default_value_t = DEFAULT_TRANSACTION_RATE_LIMIT.per_second)]
+    transactions_per_second: u32,
+
+    /// Transactions and bundles a single ip may submit in a burst
+    #[clap(long, default_value_t = DEFAULT_TRANSACTION_RATE_LIMIT.burst)]
+    transaction_burst: u32,
+
+    /// Connections a single ip may open per second, further ones are closed right away, 0
+    /// disables the limit
+    #[clap(long, default_value_t = DEFAULT_CONNECTION_RATE_LIMIT.per_second)]
+    connections_per_second: u32,
+
+    /// Connections a single ip may open in a burst
+    #[clap(long, default_value_t = DEFAULT_CONNECTION_RATE_LIMIT.burst)]
+    connection_burst: u32,
+
+    /// Invalid transactions or malformed messages after which an ip is banned, 0 disables
+    /// banning
+    #[clap(long, default_value_t = DEFAULT_BAN_POLICY.max_offences)]
+    ban_threshold: u32,
+
+    /// Seconds within which the offences have to happen for an ip to be banned
+    #[clap(long, default_value_t = DEFAULT_BAN_POLICY.window.as_secs())]
+    ban_window: u64,
+
+    /// Seconds an automatic ban lasts, bans are permanent without it
+    #[clap(long)]
+    ban_duration: Option<u64>,
+
+    /// File banned ips and networks like 10.0.0.0/8 are restored from on startup and saved to
+    /// whenever a ban changes
+    #[clap(long)]
+    blacklist_file: Option<PathBuf>,
+
+    /// Ip or network like 192.168.1.0/24 to ban from the start, can be repeated
+    #[clap(long, value_name = "NETWORK", value_parser = BlackList::parse_entry)]
+    blacklist_cidr: Vec<IpNet>,
+
+    /// Ip that is never banned nor rate limited, like that of a local monitoring node, can be
+    /// repeated
+    #[clap(long, value_name = "IP")]
+    whitelist_addr: Vec<IpAddr>,
+
+    /// Network like 10.0.0.0/8 whose ips are never banned nor rate limited, can be repeated
+    #[clap(long, value_name = "NETWORK", value_parser = BlackList::parse_entry)]
+    whitelist_cidr: Vec<IpNet>,
+
+    /// Token admin requests have to carry, the admin requests are refused without a token
+    #[clap(long, conflicts_with = "admin_token_file")]
+    admin_token: Option<String>,
+
+    /// File holding the admin token, so it doesn't show up in the process list
+    #[clap(long)]
+    admin_token_file: Option<PathBuf>,
+
+    /// PEM file of the certificate chain, every connection is served over TLS with it
+    #[clap(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file of the private key of --tls-cert
+    #[clap(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+}
+
+async fn print_bans(req: AdminReq, admin_token: &str) -> Result<()> {
+    let bans = mini_blockchain::client::admin(req, admin_token).await?;
+    if bans.is_empty() {
+        println!("No peer is banned");
+    }
+    for entry in bans {
+        println!("{entry}");
+    }
+    Ok(())
+}
+
+/// Format of the `report` command
+#[derive(Clone, Copy, ValueEnum)]
+enum ReportOutput {
+    Json,
+    Text,
+}
+
+fn print_report(report: &AddressReport, output: ReportOutput) -> Result<()> {
+    match output {
+        ReportOutput::Json => println!("{}", serde_json::to_string_pretty(report)?),
+        ReportOutput::Text => {
+            println!("address: {}", report.address);
+            println!("balance: {}, nonce: {}", report.balance, report.nonce);
+            println!(
+                "chain {} at block {} ({})",
+                report.chain_id, report.head_number, report.head_hash
+            );
+            if let Some(pending) = report.mempool_pending {
+                println!("pending in the mempool, of every sender: {pending}");
+            }
+            if let Some(history) = &report.history {
+                let block = |number: Option<u64>| number.map_or("-".to_string(), |n| n.to_string());
+                println!(
+                    "first seen: {}, last active: {}",
+                    block(history.first_seen),
+                    block(history.last_active)
+                );
+                println!(
+                    "sent: {}, received: {}",
+                    history.total_sent, history.total_received
+                );
+                println!(
+                    "successful: {}, failed: {}",
+                    history.successful, history.failed
+                );
+                println!("last {} transactions:", report.last);
+                for tx in &history.transactions {
+                    let outcome = if tx.success { "success" } else { "failed" };
+                    println!(
+                        "  {} block {} {} -> {} value {} {outcome}",
+                        tx.hash, tx.block_number, tx.from, tx.to, tx.value
+                    );
+                }
+            }
+            for section in &report.unavailable {
+                println!("{section}: unavailable");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Column of the `peers` command
+#[derive(Clone, Copy, ValueEnum)]
+enum PeerColumn {
+    Connections,
+    BytesIn,
+    BytesOut,
+    LastSeen,
+    Rejections,
+}
+
+fn print_peers(mut peers: Vec<PeerSnapshot>, sort: PeerColumn) {
+    let rejections = |peer: &PeerSnapshot| peer.rejections.values().sum::<u64>();
+    peers.sort_by_key(|peer| {
+        std::cmp::Reverse(match sort {
+            PeerColumn::Connections => peer.connections,
+            PeerColumn::BytesIn => peer.bytes_in,
+            PeerColumn::BytesOut => peer.bytes_out,
+            PeerColumn::LastSeen => peer.last_seen,
+            PeerColumn::Rejections => rejections(peer),
+        })
+    });
+
+    if peers.is_empty() {
+        println!("No peer is known");
+        return;
+    }
+    println!(
+        "{:<39} {:>11} {:>12} {:>12} {:>10}  last seen",
+        "ip", "connections", "bytes in", "bytes out", "rejections"
+    );
+    for peer in &peers {
+        let last_seen = UNIX_EPOCH + Duration::from_secs(peer.last_seen);
+        println!(
+            "{:<39} {:>11} {:>12} {:>12} {:>10}  {}",
+            peer.ip,
+            peer.connections,
+            peer.bytes_in,
+            peer.bytes_out,
+            rejections(peer),
+            humantime::format_rfc3339_seconds(last_seen)
+        );
+    }
 }
 
 fn read_file<T>(path: PathBuf) -> Result<T, Error>
@@ -82,6 +633,28 @@ where
 }
 
 impl ServerArgs {
+    fn admin_token(&self) -> Result<Option<String>> {
+        let token = match &self.admin_token_file {
+            Some(path) => std::fs::read_to_string(path)?.trim().to_string(),
+            None => match &self.admin_token {
+                Some(token) => token.clone(),
+                None => return Ok(None),
+            },
+        };
+        if token.is_empty() {
+            anyhow::bail!("The admin token is empty");
+        }
+        Ok(Some(token))
+    }
+
+    fn node_key(&self) -> Result<Option<SigningKey>> {
+        let Some(path) = &self.node_key_file else {
+            return Ok(None);
+        };
+        let key: B256 = std::fs::read_to_string(path)?.trim().parse()?;
+        Ok(Some(SigningKey::from_slice(key.as_slice())?))
+    }
+
     pub fn set_tracing(&self) {
         let level = if self.debug {
             tracing::Level::DEBUG
@@ -95,17 +668,36 @@ impl ServerArgs {
         tracing::subscriber::set_global_default(subscriber).unwrap();
     }
 
-    pub async fn run(self) -> Result<()> {
+    /// Runs the node, only on the unix domain socket at `socket_path` if given
+    pub async fn run(self, socket_path: Option<PathBuf>) -> Result<()> {
         self.set_tracing();
+        let admin_token = self.admin_token()?;
+        let node_key = self.node_key()?;
 
-        let spec: ChainSpec = if let Some(spec) = self.spec {
+        let mut spec: ChainSpec = if let Some(spec) = self.spec {
             read_file(spec)?
         } else {
             ChainSpec::default()
         };
+        if let Some(hash_algo) = self.hash_algo {
+            spec = spec.with_hash_algo(hash_algo);
+        }
 
-        let mut database = InMemoryDB::default();
-        database.write_spec(&spec)?;
+        // Fully loaded or initialized before the server exists, see StartupPhase
+        let mut database = match self.load_database.clone() {
+            Some(path) => {
+                info!(path = %path.display(), "Loading database");
+                tokio::task::spawn_blocking(move || InMemoryDB::load(&path)).await??
+            }
+            None => {
+                let mut database = InMemoryDB::default();
+                database.write_spec(&spec)?;
+                database
+            }
+        };
+        if let Some(full_blocks) = self.retain_full_blocks {
+            database = database.with_retain_full_blocks(full_blocks);
+        }
         let database = Arc::new(RwLock::new(database));
 
         let reporter = Reporter::new(self.report_frequency, database.clone());
@@ -114,20 +706,118 @@ impl ServerArgs {
         let (notify_shutdown_tx, _) = broadcast::channel(1);
         let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
-        let server = Server::new(
+        let mut ban_policy =
+            BanPolicy::new(self.ban_threshold, Duration::from_secs(self.ban_window));
+        if let Some(ban_duration) = self.ban_duration {
+            ban_policy = ban_policy.with_ban_duration(Duration::from_secs(ban_duration));
+        }
+
+        let mut server = Server::new(
             database.clone(),
+            spec,
             self.port,
             self.block_time,
             self.coinbase,
             notify_shutdown_tx,
             shutdown_complete_tx,
-        );
+        )
+        .with_host(self.host)
+        .with_on_task_failure(self.on_task_failure)
+        .with_peer_stats_capacity(self.peer_stats_capacity)
+        .with_role(self.role)
+        .with_failed_block_policy(self.seal_all_failed_blocks)
+        .with_block_reward(self.block_reward)
+        .with_difficulty(self.difficulty)
+        .with_block_gas_limit(self.block_gas_limit)
+        .with_parallel_execution(self.parallel_execution)
+        .with_block_import(self.allow_import)
+        .with_peer_encryption(self.peer_encryption)
+        .with_mempool_ordering(self.mempool_ordering)
+        .with_mempool_max_size(self.mempool_max_size)
+        .with_mempool_max_per_sender(self.mempool_max_per_sender)
+        .with_mempool_ttl(Duration::from_secs(self.mempool_ttl))
+        .with_frame_mode(self.frame_mode)
+        .with_wire_format(self.wire_format)
+        .with_encoding_negotiation(self.negotiate_encoding)
+        .with_idle_timeout(Duration::from_secs(self.idle_timeout))
+        .with_request_timeout(Duration::from_secs(self.request_timeout))
+        .with_max_connections(self.max_connections)
+        .with_max_connections_per_ip(self.max_connections_per_ip)
+        .with_rate_limit(RateLimit::new(self.requests_per_second, self.request_burst))
+        .with_transaction_rate_limit(RateLimit::new(
+            self.transactions_per_second,
+            self.transaction_burst,
+        ))
+        .with_connection_rate_limit(RateLimit::new(
+            self.connections_per_second,
+            self.connection_burst,
+        ))
+        .with_ban_policy(ban_policy)
+        .with_limits(NodeLimits::default().with_max_frame_size(self.max_message_bytes));
+        if !self.bind.is_empty() {
+            server = server.with_addrs(self.bind.clone());
+        }
+        if let Some(path) = socket_path {
+            server = server.with_addrs(Vec::new()).with_unix_socket(path);
+        }
+        if let Some(http_port) = self.http_port {
+            server = server.with_http_port(http_port);
+        }
+        if let Some(ws_port) = self.ws_port {
+            server = server.with_ws_port(ws_port);
+        }
+        if let Some(addr) = self.producer_addr {
+            server = server.with_producer_addr(addr);
+        }
+        if let Some(node) = self.producer_node {
+            server = server.with_producer_node(node);
+        }
+        if let Some(key) = node_key {
+            server = server.with_node_key(key);
+        }
+        if let Some(peer_port) = self.peer_port {
+            server = server.with_peer_port(peer_port);
+        }
+        if let Some(workers) = self.verifier_workers {
+            server = server.with_verifier_workers(workers);
+        }
+        if let Some(path) = self.mempool_persist_path.clone() {
+            server = server.with_mempool_persistence(path);
+        }
+        if let Some(path) = self.blacklist_file.clone() {
+            server = server.with_black_list_persistence(path);
+        }
+        let black_list = server.black_list();
+        for &network in &self.blacklist_cidr {
+            black_list.write().await.insert(network);
+        }
+        let white_list = WhiteList::new(self.whitelist_addr.clone(), self.whitelist_cidr.clone());
+        if !white_list.is_empty() {
+            server = server.with_white_list(white_list);
+        }
+        if let Some(token) = admin_token {
+            server = server.with_admin_token(token);
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            server = server.with_tls(tls_acceptor(cert, key)?);
+        }
 
-        select! {
-            _ = server.run() => {}
-            _ = ctrl_c() => {
-                info!("Ctrl-c received shutting down gracefully");
+        let res = {
+            let run = server.run();
+            tokio::pin!(run);
+            select! {
+                res = &mut run => res,
+                _ = ctrl_c() => {
+                    info!("Ctrl-c received shutting down gracefully");
+                    // The tasks get the signal while the server still holds their channels, and
+                    // the server waits for them, so failures during shutdown are reported too
+                    let _ = server.notify_shutdown.send(());
+                    run.await
+                }
             }
+        };
+        if let Err(e) = res {
+            error!(err = %e, "Server stopped, shutting down");
         }
 
         let Server {
@@ -148,6 +838,7 @@ impl ServerArgs {
 
         info!("Waiting for other tasks to complete");
         let _ = shutdown_complete_rx.recv().await;
+        black_list.read().await.persist()?;
         info!("Shutdown complete");
 
         Ok(())
@@ -160,16 +851,175 @@ async fn main() -> Result<()> {
 
     match cli.command {
         Commands::Server(server) => {
-            server.run().await?;
+            server.run(None).await?;
+        }
+
+        Commands::UnixServer(args) => {
+            let UnixServerArgs {
+                socket_path,
+                server,
+            } = *args;
+            server.run(Some(socket_path)).await?;
         }
 
         Commands::Client(client) => {
-            if client.many {
-                mini_blockchain::client::run_loop().await?;
+            mini_blockchain::client::set_wire_format(client.wire_format);
+            mini_blockchain::client::set_encoding_negotiation(client.negotiate_encoding);
+            let mut spec: ChainSpec = if let Some(spec) = client.spec {
+                read_file(spec)?
             } else {
-                mini_blockchain::client::run().await?;
+                ChainSpec::default()
+            };
+            if let Some(hash_algo) = client.hash_algo {
+                spec = spec.with_hash_algo(hash_algo);
+            }
+            let hash_algo = spec.hash_algo();
+            mini_blockchain::client::set_chain_spec(spec);
+            if client.tls || client.ca.is_some() {
+                let connector = tls_connector(client.ca.as_deref())?;
+                mini_blockchain::client::set_tls(connector);
+            }
+            match client.command {
+                Some(ClientCommands::Block { id }) => {
+                    match mini_blockchain::client::get_block(id).await? {
+                        Some(block) => println!("{:#?}", block),
+                        None => println!("Block not found"),
+                    }
+                }
+                Some(ClientCommands::Header { id }) => {
+                    match mini_blockchain::client::get_header(id).await? {
+                        Some(header) => println!("{:#?}", header),
+                        None => println!("Block not found"),
+                    }
+                }
+                Some(ClientCommands::Receipt { hash }) => {
+                    match mini_blockchain::client::get_receipt(hash).await? {
+                        Some(receipt) => println!("{:#?}", receipt),
+                        None => println!("Transaction not found in any block"),
+                    }
+                }
+                Some(ClientCommands::Mempool) => {
+                    let stats = mini_blockchain::client::get_mempool_status().await?;
+                    println!("{} txs pending", stats.pending);
+                    if let Some(oldest) = stats.oldest_pending {
+                        let oldest = UNIX_EPOCH + Duration::from_secs(oldest);
+                        println!("oldest submitted at {}", humantime::format_rfc3339(oldest));
+                    }
+                }
+                Some(ClientCommands::Status) => {
+                    let status = mini_blockchain::client::get_status().await?;
+                    let uptime = Duration::from_secs(status.uptime_secs);
+                    println!(
+                        "{}, version {}, up {}",
+                        status.role,
+                        status.version,
+                        humantime::format_duration(uptime)
+                    );
+                    println!(
+                        "head at block {} ({})",
+                        status.head_number, status.head_hash
+                    );
+                    println!(
+                        "{} txs pending, {} peers connected",
+                        status.pending_txs, status.peer_count
+                    );
+                    if let (Some(network_head), Some(lag)) =
+                        (status.network_head, status.lag_blocks)
+                    {
+                        println!("network head at block {network_head}, {lag} blocks behind");
+                    }
+                    if let Some(failure) = status.last_failure {
+                        println!("last task failure: {failure}");
+                    }
+                }
+                Some(ClientCommands::Account { address }) => {
+                    let account = mini_blockchain::client::get_account(address).await?;
+                    println!("balance: {}, nonce: {}", account.balance(), account.nonce());
+                }
+                Some(ClientCommands::Ban { entry, admin_token }) => {
+                    print_bans(AdminReq::Ban(entry), &admin_token).await?
+                }
+                Some(ClientCommands::Unban { entry, admin_token }) => {
+                    print_bans(AdminReq::Unban(entry), &admin_token).await?
+                }
+                Some(ClientCommands::Bans { admin_token }) => {
+                    print_bans(AdminReq::ListBans, &admin_token).await?
+                }
+                Some(ClientCommands::Peers {
+                    ip,
+                    sort,
+                    admin_token,
+                }) => {
+                    let peers = mini_blockchain::client::get_peer_stats(ip, &admin_token).await?;
+                    print_peers(peers, sort);
+                }
+                Some(ClientCommands::Pause { admin_token }) => {
+                    mini_blockchain::client::pause(&admin_token).await?;
+                    println!("Block production paused");
+                }
+                Some(ClientCommands::Resume { admin_token }) => {
+                    mini_blockchain::client::resume(&admin_token).await?;
+                    println!("Block production resumed");
+                }
+                Some(ClientCommands::Promote { admin_token }) => {
+                    mini_blockchain::client::promote(&admin_token).await?;
+                    println!("Promoted to producer");
+                }
+                Some(ClientCommands::Demote { admin_token }) => {
+                    mini_blockchain::client::demote(&admin_token).await?;
+                    println!("Demoted to follower");
+                }
+                Some(ClientCommands::Subscribe { pending: false }) => {
+                    let mut blocks = mini_blockchain::client::subscribe_blocks().await?;
+                    while let Some(block) = blocks.next().await? {
+                        println!("{:#?}", block);
+                    }
+                }
+                Some(ClientCommands::Subscribe { pending: true }) => {
+                    let mut transactions =
+                        mini_blockchain::client::subscribe_transactions().await?;
+                    while let Some(tx) = transactions.next().await? {
+                        println!("{:#?}", tx);
+                    }
+                }
+                Some(ClientCommands::VerifyProof { proof, header }) => {
+                    let proof: MerkleProof = read_file(proof)?;
+                    let header: SealedHeader = read_file(header)?;
+                    proof.verify_against_header(&header, hash_algo)?;
+                    println!(
+                        "Valid proof, transaction {} is at index {} of block {} ({})",
+                        proof.tx_hash,
+                        proof.index,
+                        header.number(),
+                        header.get_hash()
+                    );
+                }
+                Some(ClientCommands::Report {
+                    address,
+                    last,
+                    output,
+                }) => {
+                    let report = mini_blockchain::client::get_report(address, last).await?;
+                    print_report(&report, output)?;
+                }
+                Some(ClientCommands::Allowance { owner, spender }) => {
+                    let remaining = mini_blockchain::client::get_allowance(owner, spender).await?;
+                    println!("allowance: {remaining}");
+                }
+                None if client.many => mini_blockchain::client::run_loop().await?,
+                None => mini_blockchain::client::run().await?,
             }
         }
+
+        Commands::Fixtures(FixturesCommands::Generate { scenario, out }) => {
+            let manifest = fixtures::generate(scenario, &out).await?;
+            println!(
+                "Wrote {} blocks to {}, head {}",
+                manifest.head_number,
+                out.display(),
+                manifest.head_hash
+            );
+        }
     }
 
     Ok(())
@@ -1,18 +1,19 @@
-use crate::{utils, DatabaseReader, DatabaseWriter};
-use alloy_primitives::{Address, B256, U256};
-use elliptic_curve::{consts::U32, sec1::ToEncodedPoint};
-use k256::{
-    ecdsa::{RecoveryId, Signature, VerifyingKey},
-    elliptic_curve::generic_array::GenericArray,
-    PublicKey,
+use crate::{
+    utils,
+    utils::{ChainHasher, HashAlgo},
+    DatabaseReader, DatabaseWriter, Error,
 };
+use alloy_primitives::{Address, B256, U256};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::slice::{Iter, IterMut};
 use std::vec::IntoIter;
-use tiny_keccak::{Hasher, Sha3};
 use tokio::sync::RwLockReadGuard;
 
+pub mod merkle;
+
+use merkle::merkle_root;
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct Transaction {
     /// Hash of the transaction
@@ -25,6 +26,16 @@ pub struct Transaction {
     pub nonce: u64,
     /// Amount of coins to send
     pub value: u128,
+    /// Share of the block's gas limit the transaction takes up
+    #[serde(default)]
+    pub gas: u64,
+    /// Chain the transaction is meant for, part of the hash so it can't be replayed on another
+    /// chain
+    #[serde(default)]
+    pub chain_id: u64,
+    /// What the transaction does besides sending `value`, and who has to sign it
+    #[serde(default)]
+    pub kind: TransactionKind,
     /// ECDSA recovery id
     pub v: u8,
     /// ECDSA signature r
@@ -34,68 +45,82 @@ pub struct Transaction {
 }
 
 impl Transaction {
-    pub fn hash(&self) -> B256 {
-        let mut hasher = Sha3::v256();
+    pub fn hash(&self, algo: HashAlgo) -> B256 {
+        let mut hasher = ChainHasher::new(algo);
         hasher.update(&self.from[..]);
         hasher.update(&self.to[..]);
         hasher.update(&self.nonce.to_le_bytes());
         hasher.update(&self.value.to_le_bytes());
-        let mut buf = [0u8; 32];
-        hasher.finalize(&mut buf);
-        B256::from_slice(&buf)
+        hasher.update(&self.gas.to_le_bytes());
+        hasher.update(&self.chain_id.to_le_bytes());
+        // Plain transfers hash like they did before there were other kinds
+        match self.kind {
+            TransactionKind::Transfer => {}
+            TransactionKind::Approve { spender, limit } => {
+                hasher.update(&[1]);
+                hasher.update(&spender[..]);
+                hasher.update(&limit.to_le_bytes());
+            }
+            TransactionKind::TransferFrom { spender } => {
+                hasher.update(&[2]);
+                hasher.update(&spender[..]);
+            }
+        }
+        hasher.finalize()
     }
 
     pub fn get_hash(&self) -> B256 {
         self.hash
     }
 
-    pub fn verify(&self) -> bool {
-        let hash = self.hash();
-        if hash != self.hash {
-            return false;
+    /// Address the transaction has to be signed by, the spender of a
+    /// [TransactionKind::TransferFrom] and the sender of every other transaction
+    pub fn signer(&self) -> Address {
+        match self.kind {
+            TransactionKind::TransferFrom { spender } => spender,
+            _ => self.from,
         }
+    }
 
-        let recovery_id = match RecoveryId::from_byte(self.v) {
-            Some(id) => id,
-            None => return false,
-        };
-
-        let signature = {
-            let r_bytes = self.r.to_be_bytes::<32>();
-            let s_bytes = self.s.to_be_bytes::<32>();
-            let gar: &GenericArray<u8, U32> = GenericArray::from_slice(&r_bytes);
-            let gas: &GenericArray<u8, U32> = GenericArray::from_slice(&s_bytes);
-            match Signature::from_scalars(*gar, *gas) {
-                Ok(sig) => sig,
-                Err(_) => return false,
-            }
-        };
-
-        let verify_key =
-            match VerifyingKey::recover_from_prehash(&hash[..], &signature, recovery_id) {
-                Ok(key) => key,
-                Err(_) => return false,
-            };
-
-        let public_key = PublicKey::from(&verify_key);
-        let public_key = public_key.to_encoded_point(false);
-        let public_key = public_key.as_bytes();
+    /// Spender whose allowance the transaction is paid from, see [TransactionKind::TransferFrom]
+    pub fn spender(&self) -> Option<Address> {
+        match self.kind {
+            TransactionKind::TransferFrom { spender } => Some(spender),
+            _ => None,
+        }
+    }
 
-        if public_key[0] != 0x04 {
+    /// Whether the transaction is meant for `chain_id` and signed by its [Transaction::signer],
+    /// with hash and addresses derived with `algo`
+    pub fn verify(&self, chain_id: u64, algo: HashAlgo) -> bool {
+        if self.chain_id != chain_id {
             return false;
         }
 
-        let hash = utils::sha3(&public_key[1..]);
-        let addr = Address::from_word(hash);
-
-        if addr != self.from {
+        let hash = self.hash(algo);
+        if hash != self.hash {
             return false;
         }
 
-        true
+        utils::recover_address(hash, self.v, self.r, self.s, algo)
+            .is_ok_and(|addr| addr == self.signer())
     }
 }
 
+/// What a [Transaction] does besides sending its `value` from `from` to `to`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub enum TransactionKind {
+    /// Nothing else, signed by `from`
+    #[default]
+    Transfer,
+    /// Lets `spender` send up to `limit` coins of `from` with [TransactionKind::TransferFrom],
+    /// replacing what was left of an earlier approval. A limit of zero revokes it
+    Approve { spender: Address, limit: u128 },
+    /// Signed by `spender` instead of `from`, the value is taken out of what `from` approved
+    /// for it. Uses the nonce of `from` like any other transaction of `from`
+    TransferFrom { spender: Address },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct BlockHeader {
     /// Hash of the parent block
@@ -112,6 +137,12 @@ pub struct BlockHeader {
     pub coinbase: Address,
     /// Merkle root of all the transactions included in this block
     pub tx_root: B256,
+    /// Credited to the coinbase once the transactions of the block are executed
+    #[serde(default)]
+    pub block_reward: u128,
+    /// Most gas the transactions of the block may take up together
+    #[serde(default)]
+    pub gas_limit: u64,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -130,8 +161,8 @@ impl Block {
         }
     }
 
-    pub fn hash(&self) -> B256 {
-        let mut hasher = Sha3::v256();
+    pub fn hash(&self, algo: HashAlgo) -> B256 {
+        let mut hasher = ChainHasher::new(algo);
         hasher.update(self.header.parent_hash.as_slice());
         hasher.update(&self.header.nonce.to_le_bytes());
         hasher.update(&self.header.number.to_le_bytes());
@@ -139,21 +170,23 @@ impl Block {
         hasher.update(self.header.difficulty.as_le_slice());
         hasher.update(&self.header.coinbase[..]);
         hasher.update(self.header.tx_root.as_slice());
+        hasher.update(&self.header.block_reward.to_le_bytes());
+        hasher.update(&self.header.gas_limit.to_le_bytes());
 
-        let mut hash = [0u8; 32];
-        hasher.finalize(&mut hash);
-        B256::from_slice(&hash)
+        hasher.finalize()
     }
-    pub fn seal_slow(self) -> SealedBlock {
+    pub fn seal_slow(self, algo: HashAlgo) -> SealedBlock {
         let header = SealedHeader {
             parent_hash: self.header.parent_hash,
-            block_hash: self.hash(),
+            block_hash: self.hash(algo),
             number: self.header.number,
             nonce: self.header.nonce,
             timestamp: self.header.timestamp,
             difficulty: self.header.difficulty,
             coinbase: self.header.coinbase,
             tx_root: self.header.tx_root,
+            block_reward: self.header.block_reward,
+            gas_limit: self.header.gas_limit,
         };
 
         SealedBlock {
@@ -172,6 +205,8 @@ impl Block {
             timestamp: self.header.timestamp,
             coinbase: self.header.coinbase,
             tx_root: self.header.tx_root,
+            block_reward: self.header.block_reward,
+            gas_limit: self.header.gas_limit,
         };
 
         SealedBlock {
@@ -206,6 +241,72 @@ pub struct SealedHeader {
 
     /// Merkle root of all the transactions in the block
     tx_root: B256,
+
+    /// Credited to the coinbase for producing the block
+    #[serde(default)]
+    block_reward: u128,
+
+    /// Most gas the transactions of the block may take up together
+    #[serde(default)]
+    gas_limit: u64,
+}
+
+impl SealedHeader {
+    /// Hash of the header fields, which [SealedHeader::get_hash] has to match
+    pub fn hash(&self, algo: HashAlgo) -> B256 {
+        let mut hasher = ChainHasher::new(algo);
+        hasher.update(self.parent_hash.as_slice());
+        hasher.update(&self.nonce.to_le_bytes());
+        hasher.update(&self.number.to_le_bytes());
+        hasher.update(&self.timestamp.to_le_bytes());
+        hasher.update(self.difficulty.as_le_slice());
+        hasher.update(&self.coinbase[..]);
+        hasher.update(self.tx_root.as_slice());
+        hasher.update(&self.block_reward.to_le_bytes());
+        hasher.update(&self.gas_limit.to_le_bytes());
+
+        hasher.finalize()
+    }
+
+    pub fn get_hash(&self) -> &B256 {
+        &self.block_hash
+    }
+
+    pub fn parent_hash(&self) -> &B256 {
+        &self.parent_hash
+    }
+
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    pub fn difficulty(&self) -> &U256 {
+        &self.difficulty
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    pub fn coinbase(&self) -> &Address {
+        &self.coinbase
+    }
+
+    pub fn tx_root(&self) -> &B256 {
+        &self.tx_root
+    }
+
+    pub fn block_reward(&self) -> u128 {
+        self.block_reward
+    }
+
+    pub fn gas_limit(&self) -> u64 {
+        self.gas_limit
+    }
 }
 
 /// # Sealed Block
@@ -220,32 +321,55 @@ pub struct SealedBlock {
 }
 
 impl SealedBlock {
-    pub fn hash(&self) -> B256 {
-        let mut hasher = Sha3::v256();
-        hasher.update(self.header.parent_hash.as_slice());
-        hasher.update(&self.header.nonce.to_le_bytes());
-        hasher.update(&self.header.number.to_le_bytes());
-        hasher.update(&self.header.timestamp.to_le_bytes());
-        hasher.update(self.header.difficulty.as_le_slice());
-        hasher.update(&self.header.coinbase[..]);
-        hasher.update(self.header.tx_root.as_slice());
-
-        let mut hash = [0u8; 32];
-        hasher.finalize(&mut hash);
-        B256::from_slice(&hash)
+    pub fn hash(&self, algo: HashAlgo) -> B256 {
+        self.header.hash(algo)
     }
 
     pub fn get_hash(&self) -> &B256 {
         &self.header.block_hash
     }
 
-    /// Verify if the block is valid
-    pub fn verify(&self) -> bool {
-        let hash = self.hash();
+    pub fn parent_hash(&self) -> &B256 {
+        &self.header.parent_hash
+    }
+
+    pub fn header(&self) -> &SealedHeader {
+        &self.header
+    }
+
+    /// Drops the hash again, so the block can be executed like one built locally
+    pub fn unseal(self) -> Block {
+        let header = BlockHeader {
+            parent_hash: self.header.parent_hash,
+            nonce: self.header.nonce,
+            number: self.header.number,
+            timestamp: self.header.timestamp,
+            difficulty: self.header.difficulty,
+            coinbase: self.header.coinbase,
+            tx_root: self.header.tx_root,
+            block_reward: self.header.block_reward,
+            gas_limit: self.header.gas_limit,
+        };
+
+        Block {
+            header,
+            transactions: self.transactions,
+        }
+    }
+
+    /// Leaves only the header, used when the block falls behind the retention horizon
+    pub(crate) fn take_transactions(&mut self) -> Transactions {
+        std::mem::take(&mut self.transactions)
+    }
+
+    /// Verify if the block is valid, with every transaction meant for `chain_id` and every hash
+    /// derived with `algo`
+    pub fn verify(&self, chain_id: u64, algo: HashAlgo) -> bool {
+        let hash = self.hash(algo);
         let u256_hash = U256::from_le_slice(&hash[..]);
 
         for tx in &self.transactions {
-            if !tx.verify() || tx.hash() != tx.get_hash() {
+            if !tx.verify(chain_id, algo) || tx.hash(algo) != tx.get_hash() {
                 return false;
             }
         }
@@ -261,6 +385,10 @@ impl SealedBlock {
         self.header.number
     }
 
+    pub fn timestamp(&self) -> u64 {
+        self.header.timestamp
+    }
+
     pub fn transactions(&self) -> &Transactions {
         &self.transactions
     }
@@ -292,6 +420,16 @@ impl Account {
     pub fn update_balance(&mut self, new_balance: u128) {
         self.balance = new_balance;
     }
+
+    /// Whether sending `value` would leave the account above zero but below `min_balance`
+    ///
+    /// Emptying the account completely is always allowed
+    pub fn breaks_reserve(&self, value: u128, min_balance: u128) -> bool {
+        match self.balance.checked_sub(value) {
+            Some(remaining) => remaining != 0 && remaining < min_balance,
+            None => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
@@ -300,17 +438,11 @@ pub struct Transactions {
 }
 
 impl Transactions {
-    pub fn get_root(&self) -> B256 {
-        // TODO: In the future use a merkle tree
-        let mut hasher = Sha3::v256();
-        for tx in self {
-            let tx_hash = tx.hash;
-            hasher.update(&tx_hash[..]);
-        }
-
-        let mut buf = [0u8; 32];
-        hasher.finalize(&mut buf);
-        B256::from_slice(&buf)
+    /// [merkle_root] of the transaction hashes, see [merkle::MerkleProof] to prove a transaction
+    /// is part of a block
+    pub fn get_root(&self, algo: HashAlgo) -> B256 {
+        let hashes: Vec<B256> = self.iter().map(|tx| tx.hash).collect();
+        merkle_root(&hashes, algo)
     }
 
     pub fn push(&mut self, tx: Transaction) {
@@ -321,6 +453,14 @@ impl Transactions {
         self.inner.remove(index)
     }
 
+    pub fn iter(&self) -> Iter<'_, Transaction> {
+        self.inner.iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
     /// We implement sort to make sure we process transactions from the same address
     /// sequentiall from lowest nonce to highest
     pub fn sort(&mut self) {
@@ -364,6 +504,105 @@ impl<'a> IntoIterator for &'a mut Transactions {
     }
 }
 
+/// Most transactions a [Bundle] may have
+pub const MAX_BUNDLE_SIZE: usize = 16;
+
+/// Transactions from any senders that are included together in one block or not at all
+///
+/// Members are executed in order, if any of them fails the changes of the others are reverted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Bundle {
+    transactions: Vec<Transaction>,
+    hash: B256,
+}
+
+impl Bundle {
+    /// Rejects empty and oversized bundles, duplicate members and members of the same sender
+    /// whose nonces don't follow each other
+    pub fn new(transactions: Vec<Transaction>, algo: HashAlgo) -> Result<Self, Error> {
+        if transactions.is_empty() {
+            return Err(Error::InvalidBundle(
+                "a bundle needs at least one transaction",
+            ));
+        }
+        if transactions.len() > MAX_BUNDLE_SIZE {
+            return Err(Error::InvalidBundle("too many transactions"));
+        }
+
+        let mut hashes = HashSet::new();
+        let mut next_nonces = HashMap::new();
+        for tx in &transactions {
+            if !hashes.insert(tx.hash) {
+                return Err(Error::InvalidBundle("duplicate transaction"));
+            }
+
+            let next_nonce = next_nonces.entry(tx.from).or_insert(tx.nonce);
+            if tx.nonce != *next_nonce {
+                return Err(Error::InvalidBundle(
+                    "nonces of a sender must follow each other",
+                ));
+            }
+            *next_nonce += 1;
+        }
+
+        let mut hasher = ChainHasher::new(algo);
+        for tx in &transactions {
+            hasher.update(&tx.hash[..]);
+        }
+
+        Ok(Self {
+            transactions,
+            hash: hasher.finalize(),
+        })
+    }
+
+    /// Hash of the member hashes, in order
+    pub fn get_hash(&self) -> &B256 {
+        &self.hash
+    }
+
+    pub fn transactions(&self) -> &[Transaction] {
+        &self.transactions
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+}
+
+/// Why a transaction was rejected, see [crate::TxValidator]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum FailureReason {
+    /// The sender doesn't exist in the state
+    UnknownSender,
+    /// The nonce of the transaction isn't the next nonce of the sender
+    InvalidNonce,
+    /// The sender can't cover the value of the transaction
+    InsufficientBalance,
+    /// The transaction would leave the sender above zero but below the chain's `min_balance`
+    BelowReserve,
+    /// The hash or the signature doesn't match the transaction
+    InvalidSignature,
+    /// Rejected by a [crate::ValidationStage] added by the embedder
+    RejectedByPolicy,
+    /// Another member of its [Bundle] failed, so its changes were reverted
+    BundleReverted,
+    /// The transactions before it already took up the gas limit of the block
+    ExceedsBlockGasLimit,
+    /// The transaction was signed for another chain
+    WrongChainId,
+    /// The value is more than the sender approved for the spender, or left of it
+    InsufficientAllowance,
+    /// The sender could only cover the value with block rewards that aren't spendable yet, see
+    /// [crate::ChainSpec::coinbase_maturity]
+    ImmatureReward,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct TransactionReceipt {
     pub success: bool,
@@ -371,16 +610,69 @@ pub struct TransactionReceipt {
     pub block_number: u64,
     pub from: Address,
     pub to: Address,
+    /// Set for every failed transaction
+    #[serde(default)]
+    pub reason: Option<FailureReason>,
+    /// Hash of the [Bundle] the transaction was included with
+    #[serde(default)]
+    pub bundle: Option<B256>,
+    /// Gas the transaction took up from the block, zero if it was skipped for lack of gas
+    #[serde(default)]
+    pub gas_used: u64,
+    /// Who sent the transaction on behalf of `from`, see [TransactionKind::TransferFrom]
+    #[serde(default)]
+    pub spender: Option<Address>,
 }
 
 impl TransactionReceipt {
-    pub fn build(tx: &Transaction, block: &SealedBlock) -> Self {
+    /// The block isn't sealed yet, so the hash is filled in later by [ChangeSet::set_block_hash]
+    pub fn build(tx: &Transaction, block: &Block) -> Self {
         Self {
             success: false,
-            block_hash: *block.get_hash(),
-            block_number: block.number(),
+            block_hash: B256::ZERO,
+            block_number: block.header.number,
             from: tx.from,
             to: tx.to,
+            reason: None,
+            bundle: None,
+            gas_used: 0,
+            spender: tx.spender(),
+        }
+    }
+}
+
+/// Outcome of a [Bundle], the receipts of its members tell how each of them went
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct BundleReceipt {
+    pub success: bool,
+    pub block_hash: B256,
+    pub block_number: u64,
+    /// Hashes of the members, in execution order
+    pub members: Vec<B256>,
+    /// Member whose failure reverted the bundle
+    pub reverted_by: Option<B256>,
+}
+
+/// What is kept of a transaction once its block falls behind the retention horizon
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
+pub struct TransactionSummary {
+    pub hash: B256,
+    pub block_number: u64,
+    pub success: bool,
+    pub from: Address,
+    pub to: Address,
+    pub value: u128,
+}
+
+impl TransactionSummary {
+    pub fn new(tx: &Transaction, block_number: u64, success: bool) -> Self {
+        Self {
+            hash: tx.hash,
+            block_number,
+            success,
+            from: tx.from,
+            to: tx.to,
+            value: tx.value,
         }
     }
 }
@@ -388,7 +680,16 @@ impl TransactionReceipt {
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct ChangeSet {
     pub touched_accounts: HashMap<Address, Account>,
+    /// Accounts that were emptied and have to be removed from the database, with the nonce
+    /// they are deleted at
+    pub deleted_accounts: HashMap<Address, u64>,
     pub receipts: HashMap<B256, TransactionReceipt>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub bundle_receipts: HashMap<B256, BundleReceipt>,
+    /// What each spender may still send on behalf of each owner, zero for allowances that
+    /// were used up or revoked
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub allowances: HashMap<Address, HashMap<Address, u128>>,
 }
 
 impl ChangeSet {
@@ -397,21 +698,84 @@ impl ChangeSet {
     }
 
     pub fn insert_account(&mut self, addr: Address, account: Account) {
+        self.deleted_accounts.remove(&addr);
         self.touched_accounts.insert(addr, account);
     }
 
+    /// Removes the account, `nonce` is kept as its tombstone, see
+    /// [crate::DatabaseWriter::delete_account]
+    pub fn delete_account(&mut self, addr: Address, nonce: u64) {
+        self.touched_accounts.remove(&addr);
+        self.deleted_accounts.insert(addr, nonce);
+    }
+
+    #[cfg(test)]
     pub fn get_account(&self, addr: &Address) -> Option<&Account> {
         self.touched_accounts.get(addr)
     }
 
+    pub fn insert_allowance(&mut self, owner: Address, spender: Address, remaining: u128) {
+        self.allowances
+            .entry(owner)
+            .or_default()
+            .insert(spender, remaining);
+    }
+
+    pub fn get_allowance(&self, owner: &Address, spender: &Address) -> Option<u128> {
+        self.allowances.get(owner)?.get(spender).copied()
+    }
+
+    /// Points every receipt to the block they were sealed in
+    pub fn set_block_hash(&mut self, block_hash: B256) {
+        for receipt in self.receipts.values_mut() {
+            receipt.block_hash = block_hash;
+        }
+        for receipt in self.bundle_receipts.values_mut() {
+            receipt.block_hash = block_hash;
+        }
+    }
+
     pub fn touched_accounts_ref(&self) -> &HashMap<Address, Account> {
         &self.touched_accounts
     }
+
+    /// Applies `other` on top of this changeset, its accounts win over the ones in here
+    pub fn merge(&mut self, other: ChangeSet) {
+        for (addr, account) in other.touched_accounts {
+            self.insert_account(addr, account);
+        }
+        for (addr, nonce) in other.deleted_accounts {
+            self.delete_account(addr, nonce);
+        }
+        self.receipts.extend(other.receipts);
+        self.bundle_receipts.extend(other.bundle_receipts);
+        for (owner, allowances) in other.allowances {
+            self.allowances.entry(owner).or_default().extend(allowances);
+        }
+    }
 }
 
 pub struct State<'a, DB> {
     changeset: ChangeSet,
     db: &'a RwLockReadGuard<'a, DB>,
+    /// Pre-images of every account and allowance change, so [State::revert_to] can undo them
+    journal: Vec<PreImage>,
+    /// Coinbase and reward of the block being executed, see [State::pay_block_reward]
+    block_reward: Option<(Address, u128)>,
+}
+
+/// What the changeset knew about an account or allowance before it was changed
+enum PreImage {
+    Account {
+        addr: Address,
+        touched: Option<Account>,
+        deleted: Option<u64>,
+    },
+    Allowance {
+        owner: Address,
+        spender: Address,
+        remaining: Option<u128>,
+    },
 }
 
 impl<'a, DB> State<'a, DB>
@@ -422,10 +786,41 @@ where
         Self {
             changeset: ChangeSet::default(),
             db,
+            journal: Vec::new(),
+            block_reward: None,
+        }
+    }
+
+    /// Credits the reward of the block being executed to its coinbase, the reward is remembered
+    /// so the transactions of the block can tell it isn't mature yet
+    pub fn pay_block_reward(&mut self, coinbase: &Address, reward: u128) {
+        self.block_reward = Some((*coinbase, reward));
+        if reward == 0 {
+            return;
         }
+
+        let mut account = match self.get_account(coinbase) {
+            Some(account) => *account,
+            None => Account::new(0, self.get_tombstone(coinbase).unwrap_or_default()),
+        };
+        account.update_balance(account.balance().saturating_add(reward));
+        self.insert_account(coinbase, account);
+    }
+
+    /// Coinbase and reward passed to [State::pay_block_reward]
+    pub fn block_reward(&self) -> Option<(Address, u128)> {
+        self.block_reward
+    }
+
+    pub(crate) fn db(&self) -> &DB {
+        self.db
     }
 
     pub fn get_account(&self, addr: &Address) -> Option<&Account> {
+        if self.changeset.deleted_accounts.contains_key(addr) {
+            return None;
+        }
+
         // We first check if the account is in the change set to make
         // sure we are getting the latest data
         match self.changeset.touched_accounts_ref().get(addr) {
@@ -435,9 +830,101 @@ where
     }
 
     pub fn insert_account(&mut self, addr: &Address, account: Account) {
+        self.record_pre_image(addr);
         self.changeset.insert_account(*addr, account);
     }
 
+    /// Removes the account, its nonce is kept as a tombstone
+    pub fn delete_account(&mut self, addr: &Address) {
+        let nonce = self.get_account(addr).map_or(0, Account::nonce);
+        self.record_pre_image(addr);
+        self.changeset.delete_account(*addr, nonce);
+    }
+
+    /// Nonce of the deleted account at `addr`, which a new account there has to start from
+    pub fn get_tombstone(&self, addr: &Address) -> Option<u64> {
+        match self.changeset.deleted_accounts.get(addr) {
+            Some(nonce) => Some(*nonce),
+            None => self.db.read_tombstone(addr),
+        }
+    }
+
+    fn record_pre_image(&mut self, addr: &Address) {
+        self.journal.push(PreImage::Account {
+            addr: *addr,
+            touched: self.changeset.touched_accounts.get(addr).copied(),
+            deleted: self.changeset.deleted_accounts.get(addr).copied(),
+        });
+    }
+
+    /// What `spender` may still send on behalf of `owner`
+    pub fn get_allowance(&self, owner: &Address, spender: &Address) -> u128 {
+        match self.changeset.get_allowance(owner, spender) {
+            Some(remaining) => remaining,
+            None => self.db.read_allowance(owner, spender),
+        }
+    }
+
+    pub fn insert_allowance(&mut self, owner: &Address, spender: &Address, remaining: u128) {
+        self.journal.push(PreImage::Allowance {
+            owner: *owner,
+            spender: *spender,
+            remaining: self.changeset.get_allowance(owner, spender),
+        });
+        self.changeset.insert_allowance(*owner, *spender, remaining);
+    }
+
+    /// Point [State::revert_to] can go back to
+    pub fn checkpoint(&self) -> usize {
+        self.journal.len()
+    }
+
+    /// Undoes every account and allowance change made since `checkpoint`, receipts are kept
+    pub fn revert_to(&mut self, checkpoint: usize) {
+        let changeset = &mut self.changeset;
+        for pre_image in self.journal.drain(checkpoint..).rev() {
+            match pre_image {
+                PreImage::Account {
+                    addr,
+                    touched,
+                    deleted,
+                } => {
+                    match touched {
+                        Some(account) => changeset.touched_accounts.insert(addr, account),
+                        None => changeset.touched_accounts.remove(&addr),
+                    };
+                    match deleted {
+                        Some(nonce) => changeset.deleted_accounts.insert(addr, nonce),
+                        None => changeset.deleted_accounts.remove(&addr),
+                    };
+                }
+                PreImage::Allowance {
+                    owner,
+                    spender,
+                    remaining: Some(remaining),
+                } => changeset.insert_allowance(owner, spender, remaining),
+                PreImage::Allowance {
+                    owner,
+                    spender,
+                    remaining: None,
+                } => {
+                    if let Some(allowances) = changeset.allowances.get_mut(&owner) {
+                        allowances.remove(&spender);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies changes made on another [State] of the same database, they can't be reverted
+    pub fn merge(&mut self, changeset: ChangeSet) {
+        self.changeset.merge(changeset);
+    }
+
+    pub fn insert_bundle_receipt(&mut self, bundle_hash: &B256, receipt: BundleReceipt) {
+        self.changeset.bundle_receipts.insert(*bundle_hash, receipt);
+    }
+
     pub fn insert_receipt(&mut self, tx_hash: &B256, tx_receipt: TransactionReceipt) {
         self.changeset.insert_receipt(tx_hash, tx_receipt)
     }
@@ -451,48 +938,77 @@ impl<'a, DB> From<State<'a, DB>> for ChangeSet {
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::{addr, sign_hash, u256_to_signing_key};
+    use crate::utils::{addr, random_keypair, sign_transaction};
 
     use super::*;
 
     #[test]
     fn test_verify() {
-        let pk = U256::from(98234);
-        let pk = u256_to_signing_key(&pk).unwrap();
-
-        let mut tx = Transaction::default();
-
-        let addr = addr(&pk);
-        tx.from = addr;
+        let pk = random_keypair();
 
-        tx.hash = tx.hash();
+        let mut tx = Transaction {
+            from: addr(&pk, HashAlgo::Keccak256),
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &pk, HashAlgo::Keccak256);
 
-        let (v, r, s) = sign_hash(tx.hash(), &pk);
+        assert!(tx.verify(1, HashAlgo::Keccak256));
+    }
 
-        tx.v = v;
-        tx.r = r;
-        tx.s = s;
+    #[test]
+    fn test_transaction_of_another_chain_doesnt_verify() {
+        let pk = random_keypair();
+        let mut tx = Transaction {
+            from: addr(&pk, HashAlgo::Keccak256),
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &pk, HashAlgo::Keccak256);
+        assert!(!tx.verify(2, HashAlgo::Keccak256));
+
+        // Claiming the other chain breaks the hash, rehashing breaks the signature
+        let mut replayed = tx.clone();
+        replayed.chain_id = 2;
+        assert!(!replayed.verify(2, HashAlgo::Keccak256));
+        replayed.hash = replayed.hash(HashAlgo::Keccak256);
+        assert_ne!(replayed.hash, tx.hash);
+        assert!(!replayed.verify(2, HashAlgo::Keccak256));
+    }
 
-        assert!(tx.verify());
+    #[test]
+    fn test_changeset_merge() {
+        let (first, second, third) = (
+            Address::repeat_byte(1),
+            Address::repeat_byte(2),
+            Address::repeat_byte(3),
+        );
+        let mut changeset = ChangeSet::default();
+        changeset.insert_account(first, Account::new(1, 0));
+        changeset.delete_account(second, 0);
+        changeset.insert_account(third, Account::new(3, 0));
+
+        let mut other = ChangeSet::default();
+        other.insert_account(first, Account::new(10, 1));
+        other.insert_account(second, Account::new(20, 0));
+        other.delete_account(third, 0);
+        other.insert_receipt(&B256::repeat_byte(1), TransactionReceipt::default());
+
+        changeset.merge(other);
+        assert_eq!(changeset.get_account(&first), Some(&Account::new(10, 1)));
+        assert_eq!(changeset.get_account(&second), Some(&Account::new(20, 0)));
+        assert!(!changeset.deleted_accounts.contains_key(&second));
+        assert!(changeset.deleted_accounts.contains_key(&third));
+        assert_eq!(changeset.receipts.len(), 1);
     }
 
     #[test]
     fn test_verify_block() {
-        let pk = U256::from(98234);
-        let pk = u256_to_signing_key(&pk).unwrap();
-
-        let mut tx = Transaction::default();
-
-        let addr = addr(&pk);
-        tx.from = addr;
-
-        tx.hash = tx.hash();
-
-        let (v, r, s) = sign_hash(tx.hash(), &pk);
+        let pk = random_keypair();
 
-        tx.v = v;
-        tx.r = r;
-        tx.s = s;
+        let mut tx = Transaction {
+            from: addr(&pk, HashAlgo::Keccak256),
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &pk, HashAlgo::Keccak256);
 
         let mut block = Block {
             header: BlockHeader {
@@ -503,13 +1019,90 @@ mod tests {
                 difficulty: U256::MAX,
                 coinbase: Address::ZERO,
                 tx_root: B256::ZERO,
+                block_reward: 0,
+                gas_limit: 0,
             },
             transactions: Transactions::default(),
         };
 
         block.transactions.inner.push(tx);
 
-        let sealed_block = block.seal_slow();
-        assert!(sealed_block.verify());
+        let sealed_block = block.seal_slow(HashAlgo::Keccak256);
+        assert!(sealed_block.verify(1, HashAlgo::Keccak256));
+        assert!(!sealed_block.verify(2, HashAlgo::Keccak256));
+    }
+
+    #[test]
+    fn test_verify_block_hashed_with_sha3() {
+        let pk = random_keypair();
+
+        let mut tx = Transaction {
+            from: addr(&pk, HashAlgo::Sha3),
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &pk, HashAlgo::Sha3);
+        assert_eq!(tx.hash, tx.hash(HashAlgo::Sha3));
+        assert_ne!(tx.hash, tx.hash(HashAlgo::Keccak256));
+
+        let transactions: Transactions = vec![tx].into();
+        let header = BlockHeader {
+            difficulty: U256::MAX,
+            tx_root: transactions.get_root(HashAlgo::Sha3),
+            ..Default::default()
+        };
+        let block = Block::new(header, transactions);
+        let keccak = block.hash(HashAlgo::Keccak256);
+
+        let sealed_block = block.seal_slow(HashAlgo::Sha3);
+        assert_ne!(*sealed_block.get_hash(), keccak);
+        assert!(sealed_block.verify(1, HashAlgo::Sha3));
+        // Neither the block nor its transaction hash the same with keccak256
+        assert!(!sealed_block.verify(1, HashAlgo::Keccak256));
+    }
+
+    #[test]
+    fn test_transfer_from_is_signed_by_the_spender() {
+        let (owner, spender) = (random_keypair(), random_keypair());
+        let transfer = Transaction {
+            from: addr(&owner, HashAlgo::Keccak256),
+            value: 10,
+            ..Default::default()
+        };
+        let with_kind = |kind| Transaction {
+            kind,
+            ..transfer.clone()
+        };
+
+        // Every kind and its parameters are part of the hash
+        let hashes: HashSet<_> = [
+            transfer.clone(),
+            with_kind(TransactionKind::Approve {
+                spender: addr(&spender, HashAlgo::Keccak256),
+                limit: 10,
+            }),
+            with_kind(TransactionKind::Approve {
+                spender: addr(&spender, HashAlgo::Keccak256),
+                limit: 11,
+            }),
+            with_kind(TransactionKind::TransferFrom {
+                spender: addr(&spender, HashAlgo::Keccak256),
+            }),
+            with_kind(TransactionKind::TransferFrom {
+                spender: addr(&owner, HashAlgo::Keccak256),
+            }),
+        ]
+        .iter()
+        .map(|tx| tx.hash(HashAlgo::Keccak256))
+        .collect();
+        assert_eq!(hashes.len(), 5);
+
+        let mut tx = with_kind(TransactionKind::TransferFrom {
+            spender: addr(&spender, HashAlgo::Keccak256),
+        });
+        assert_eq!(tx.signer(), addr(&spender, HashAlgo::Keccak256));
+        sign_transaction(&mut tx, 1, &owner, HashAlgo::Keccak256);
+        assert!(!tx.verify(1, HashAlgo::Keccak256));
+        sign_transaction(&mut tx, 1, &spender, HashAlgo::Keccak256);
+        assert!(tx.verify(1, HashAlgo::Keccak256));
     }
 }
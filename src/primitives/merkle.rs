@@ -0,0 +1,392 @@
+use crate::{
+    utils::{self, ChainHasher, HashAlgo},
+    Error, SealedBlock, SealedHeader,
+};
+use alloy_primitives::B256;
+use serde::{Deserialize, Serialize};
+
+/// Hashed in front of every leaf
+const LEAF_PREFIX: u8 = 0x00;
+
+/// Hashed in front of every pair of nodes
+const NODE_PREFIX: u8 = 0x01;
+
+/// Hashed in front of the amount of leaves and the top node of their tree
+const ROOT_PREFIX: u8 = 0x02;
+
+/// Proof that the transaction `tx_hash` is at `index` in the block `block_hash`, which can be
+/// checked with nothing but the header of the block
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub block_hash: B256,
+    pub tx_hash: B256,
+    pub index: u64,
+    /// Transactions of the block, the root commits to it so the proof only holds for a tree
+    /// of that size
+    pub tx_count: u64,
+    /// See [merkle_proof]
+    pub siblings: Vec<B256>,
+}
+
+impl MerkleProof {
+    /// Proves the transaction at `index` of `block`, `None` if the block has fewer
+    pub fn new(block: &SealedBlock, index: usize, algo: HashAlgo) -> Option<Self> {
+        let hashes: Vec<B256> = block.transactions().iter().map(|tx| tx.hash).collect();
+        let tx_hash = *hashes.get(index)?;
+
+        Some(Self {
+            block_hash: *block.get_hash(),
+            tx_hash,
+            index: index as u64,
+            tx_count: hashes.len() as u64,
+            siblings: merkle_proof(&hashes, index, algo),
+        })
+    }
+
+    /// Checks that `header` is the block the proof is for, that it hashes to its own hash and
+    /// that the path leads from the transaction to its tx root, all hashed with `algo`
+    pub fn verify_against_header(
+        &self,
+        header: &SealedHeader,
+        algo: HashAlgo,
+    ) -> Result<(), Error> {
+        if header.hash(algo) != *header.get_hash() {
+            return Err(Error::InvalidProof("the header doesn't match its hash"));
+        }
+        if self.block_hash != *header.get_hash() {
+            return Err(Error::InvalidProof("the proof is for another block"));
+        }
+
+        let (index, tx_count) = match (usize::try_from(self.index), usize::try_from(self.tx_count))
+        {
+            (Ok(index), Ok(tx_count)) => (index, tx_count),
+            _ => return Err(Error::InvalidProof("the index is out of range")),
+        };
+        let root = *header.tx_root();
+        if !verify_proof(self.tx_hash, &self.siblings, index, tx_count, root, algo) {
+            return Err(Error::InvalidProof(
+                "the path doesn't lead to the tx root of the header",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Root of the binary Merkle tree over `leaves`, a layer of odd length pairs its last node with
+/// itself
+///
+/// Leaves, pairs and the root are hashed with different prefixes, so no inner node passes for a
+/// leaf, and the root commits to the amount of leaves, so the duplicated nodes don't make room
+/// for leaves that don't exist. No leaves hash to the hash of the empty input
+pub fn merkle_root(leaves: &[B256], algo: HashAlgo) -> B256 {
+    if leaves.is_empty() {
+        return utils::chain_hash([], algo);
+    }
+
+    let mut layer = leaf_layer(leaves, algo);
+    while layer.len() > 1 {
+        layer = next_layer(&layer, algo);
+    }
+    hash_root(leaves.len(), &layer[0], algo)
+}
+
+/// Sibling of the leaf at `index` on every layer, from the hashed leaves up to the root
+///
+/// Empty if `index` is out of bounds
+pub fn merkle_proof(leaves: &[B256], mut index: usize, algo: HashAlgo) -> Vec<B256> {
+    if index >= leaves.len() {
+        return Vec::new();
+    }
+
+    let mut proof = Vec::new();
+    let mut layer = leaf_layer(leaves, algo);
+    while layer.len() > 1 {
+        let sibling = index ^ 1;
+        proof.push(*layer.get(sibling).unwrap_or(&layer[index]));
+        layer = next_layer(&layer, algo);
+        index /= 2;
+    }
+    proof
+}
+
+/// Whether `leaf` is at `index` in the tree of `root` over `leaf_count` leaves, given the
+/// [merkle_proof] of the leaf
+pub fn verify_proof(
+    leaf: B256,
+    proof: &[B256],
+    mut index: usize,
+    leaf_count: usize,
+    root: B256,
+    algo: HashAlgo,
+) -> bool {
+    if index >= leaf_count || proof.len() != tree_height(leaf_count) {
+        return false;
+    }
+
+    let mut hash = hash_leaf(&leaf, algo);
+    for sibling in proof {
+        hash = if index % 2 == 0 {
+            hash_pair(&hash, sibling, algo)
+        } else {
+            hash_pair(sibling, &hash, algo)
+        };
+        index /= 2;
+    }
+    hash_root(leaf_count, &hash, algo) == root
+}
+
+/// Layers between the leaves and the top node of a tree over `leaf_count` leaves, none for a
+/// single leaf
+fn tree_height(leaf_count: usize) -> usize {
+    (usize::BITS - (leaf_count - 1).leading_zeros()) as usize
+}
+
+fn leaf_layer(leaves: &[B256], algo: HashAlgo) -> Vec<B256> {
+    leaves.iter().map(|leaf| hash_leaf(leaf, algo)).collect()
+}
+
+fn next_layer(layer: &[B256], algo: HashAlgo) -> Vec<B256> {
+    layer
+        .chunks(2)
+        .map(|pair| hash_pair(&pair[0], pair.last().unwrap(), algo))
+        .collect()
+}
+
+fn hash_leaf(leaf: &B256, algo: HashAlgo) -> B256 {
+    let mut hasher = ChainHasher::new(algo);
+    hasher.update(&[LEAF_PREFIX]);
+    hasher.update(leaf.as_slice());
+    hasher.finalize()
+}
+
+fn hash_pair(left: &B256, right: &B256, algo: HashAlgo) -> B256 {
+    let mut hasher = ChainHasher::new(algo);
+    hasher.update(&[NODE_PREFIX]);
+    hasher.update(left.as_slice());
+    hasher.update(right.as_slice());
+    hasher.finalize()
+}
+
+fn hash_root(leaf_count: usize, top: &B256, algo: HashAlgo) -> B256 {
+    let mut hasher = ChainHasher::new(algo);
+    hasher.update(&[ROOT_PREFIX]);
+    hasher.update(&(leaf_count as u64).to_be_bytes());
+    hasher.update(top.as_slice());
+    hasher.finalize()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Block, BlockHeader, Transaction, Transactions};
+
+    const ALGO: HashAlgo = HashAlgo::Keccak256;
+
+    fn leaves(amount: u8) -> Vec<B256> {
+        (0..amount).map(B256::repeat_byte).collect()
+    }
+
+    fn assert_every_proof_verifies(leaves: &[B256]) {
+        let root = merkle_root(leaves, ALGO);
+        for (index, leaf) in leaves.iter().enumerate() {
+            let proof = merkle_proof(leaves, index, ALGO);
+            assert!(
+                verify_proof(*leaf, &proof, index, leaves.len(), root, ALGO),
+                "leaf {index}"
+            );
+        }
+    }
+
+    #[test]
+    fn test_empty_tree() {
+        assert_eq!(merkle_root(&[], ALGO), utils::chain_hash([], ALGO));
+        assert!(merkle_proof(&[], 0, ALGO).is_empty());
+        assert!(!verify_proof(
+            B256::ZERO,
+            &[],
+            0,
+            0,
+            merkle_root(&[], ALGO),
+            ALGO
+        ));
+    }
+
+    #[test]
+    fn test_one_leaf() {
+        let leaves = leaves(1);
+        let root = hash_root(1, &hash_leaf(&leaves[0], ALGO), ALGO);
+        assert_eq!(merkle_root(&leaves, ALGO), root);
+        assert!(merkle_proof(&leaves, 0, ALGO).is_empty());
+        assert_every_proof_verifies(&leaves);
+    }
+
+    #[test]
+    fn test_two_leaves() {
+        let leaves = leaves(2);
+        let (a, b) = (hash_leaf(&leaves[0], ALGO), hash_leaf(&leaves[1], ALGO));
+        let root = hash_root(2, &hash_pair(&a, &b, ALGO), ALGO);
+        assert_eq!(merkle_root(&leaves, ALGO), root);
+        assert_eq!(merkle_proof(&leaves, 0, ALGO), vec![b]);
+        assert_eq!(merkle_proof(&leaves, 1, ALGO), vec![a]);
+        assert_every_proof_verifies(&leaves);
+
+        // The order of the leaves matters
+        assert_ne!(merkle_root(&[leaves[1], leaves[0]], ALGO), root);
+    }
+
+    #[test]
+    fn test_seven_leaves() {
+        let leaves = leaves(7);
+        let l: Vec<B256> = leaves.iter().map(|leaf| hash_leaf(leaf, ALGO)).collect();
+
+        let ab = hash_pair(&l[0], &l[1], ALGO);
+        let cd = hash_pair(&l[2], &l[3], ALGO);
+        let ef = hash_pair(&l[4], &l[5], ALGO);
+        // The odd leaf out is paired with itself
+        let gg = hash_pair(&l[6], &l[6], ALGO);
+        let abcd = hash_pair(&ab, &cd, ALGO);
+        let efgg = hash_pair(&ef, &gg, ALGO);
+        let root = hash_root(7, &hash_pair(&abcd, &efgg, ALGO), ALGO);
+
+        assert_eq!(merkle_root(&leaves, ALGO), root);
+        assert_eq!(merkle_proof(&leaves, 6, ALGO), vec![l[6], ef, abcd]);
+        assert_eq!(merkle_proof(&leaves, 2, ALGO), vec![l[3], ab, efgg]);
+        assert_every_proof_verifies(&leaves);
+    }
+
+    #[test]
+    fn test_wrong_proofs_are_rejected() {
+        let leaves = leaves(7);
+        let root = merkle_root(&leaves, ALGO);
+        let proof = merkle_proof(&leaves, 3, ALGO);
+
+        assert!(!verify_proof(leaves[2], &proof, 3, 7, root, ALGO));
+        assert!(!verify_proof(leaves[3], &proof, 2, 7, root, ALGO));
+        assert!(!verify_proof(leaves[3], &proof, 3, 7, B256::ZERO, ALGO));
+        assert!(!verify_proof(leaves[3], &proof[..2], 3, 7, root, ALGO));
+        // Same path, but beyond the leaves of the tree
+        assert!(!verify_proof(leaves[3], &proof, 3 + 8, 7, root, ALGO));
+        // Same path, but in a tree of another size
+        assert!(!verify_proof(leaves[3], &proof, 3, 8, root, ALGO));
+        assert!(!verify_proof(leaves[3], &proof, 3, 5, root, ALGO));
+        assert!(merkle_proof(&leaves, 7, ALGO).is_empty());
+    }
+
+    #[test]
+    fn test_overlong_proofs_are_rejected() {
+        let leaf = B256::repeat_byte(1);
+        for len in [usize::BITS as usize, usize::BITS as usize + 1, 100] {
+            let proof = vec![B256::repeat_byte(2); len];
+            for (index, leaf_count) in [(0, 1), (0, usize::MAX), (usize::MAX - 1, usize::MAX)] {
+                assert!(
+                    !verify_proof(leaf, &proof, index, leaf_count, B256::ZERO, ALGO),
+                    "{len}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_inner_node_is_not_a_leaf() {
+        let leaves = leaves(4);
+        let root = merkle_root(&leaves, ALGO);
+        let l: Vec<B256> = leaves.iter().map(|leaf| hash_leaf(leaf, ALGO)).collect();
+        let (ab, cd) = (hash_pair(&l[0], &l[1], ALGO), hash_pair(&l[2], &l[3], ALGO));
+
+        // The left half of the tree, posing as a transaction with the right half as its sibling
+        for leaf_count in [2, 4] {
+            assert!(!verify_proof(ab, &[cd], 0, leaf_count, root, ALGO));
+        }
+    }
+
+    #[test]
+    fn test_duplicated_leaf_is_not_at_a_missing_index() {
+        let leaves = leaves(3);
+        let root = merkle_root(&leaves, ALGO);
+        let proof = merkle_proof(&leaves, 2, ALGO);
+        assert!(verify_proof(leaves[2], &proof, 2, 3, root, ALGO));
+
+        // The last leaf is paired with itself, so its path is the one of a fourth leaf as well
+        for leaf_count in [3, 4] {
+            assert!(!verify_proof(leaves[2], &proof, 3, leaf_count, root, ALGO));
+        }
+    }
+
+    fn block_with(transactions: u64) -> SealedBlock {
+        let transactions: Transactions = (0..transactions)
+            .map(|nonce| {
+                let mut tx = Transaction {
+                    nonce,
+                    ..Default::default()
+                };
+                tx.hash = tx.hash(ALGO);
+                tx
+            })
+            .collect::<Vec<_>>()
+            .into();
+        let header = BlockHeader {
+            number: 3,
+            tx_root: transactions.get_root(ALGO),
+            ..Default::default()
+        };
+        Block::new(header, transactions).seal_slow(ALGO)
+    }
+
+    fn assert_invalid(proof: &MerkleProof, header: &SealedHeader) {
+        let err = proof.verify_against_header(header, ALGO).unwrap_err();
+        assert!(matches!(err, Error::InvalidProof(_)), "{err}");
+    }
+
+    #[test]
+    fn test_proofs_verify_against_the_header() {
+        let block = block_with(5);
+        for index in 0..5 {
+            let proof = MerkleProof::new(&block, index, ALGO).unwrap();
+            // What the client loads from disk
+            let json = serde_json::to_string(&proof).unwrap();
+            let proof: MerkleProof = serde_json::from_str(&json).unwrap();
+            proof.verify_against_header(block.header(), ALGO).unwrap();
+        }
+        assert_eq!(MerkleProof::new(&block, 5, ALGO), None);
+    }
+
+    #[test]
+    fn test_corrupted_proofs_are_rejected() {
+        let block = block_with(5);
+        let header = block.header();
+        let proof = MerkleProof::new(&block, 2, ALGO).unwrap();
+
+        let mut wrong_sibling = proof.clone();
+        wrong_sibling.siblings[1] = B256::repeat_byte(1);
+        assert_invalid(&wrong_sibling, header);
+
+        let mut wrong_index = proof.clone();
+        wrong_index.index = 3;
+        assert_invalid(&wrong_index, header);
+
+        let mut wrong_count = proof.clone();
+        wrong_count.tx_count = 8;
+        assert_invalid(&wrong_count, header);
+
+        // The duplicate of the last transaction, at an index the block doesn't have
+        let mut missing_index = MerkleProof::new(&block, 4, ALGO).unwrap();
+        missing_index.index = 5;
+        assert_invalid(&missing_index, header);
+        missing_index.tx_count = 6;
+        assert_invalid(&missing_index, header);
+
+        let mut truncated = proof.clone();
+        truncated.siblings.pop();
+        assert_invalid(&truncated, header);
+
+        let mut wrong_tx = proof.clone();
+        wrong_tx.tx_hash = block.transactions().iter().nth(3).unwrap().hash;
+        assert_invalid(&wrong_tx, header);
+
+        // A header of another block, and one whose tx root was swapped after sealing
+        assert_invalid(&proof, block_with(4).header());
+        let mut forged = serde_json::to_value(header).unwrap();
+        forged["tx_root"] = serde_json::to_value(B256::repeat_byte(2)).unwrap();
+        assert_invalid(&proof, &serde_json::from_value(forged).unwrap());
+    }
+}
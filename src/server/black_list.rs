@@ -1,12 +1,622 @@
-use std::net::SocketAddr;
+use crate::Error;
+use ipnet::{IpNet, Ipv4Net};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tracing::{error, info, warn};
 
+/// Peers whose offences the [BlackList] keeps before it forgets the ones outside the window
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// How many offences, invalid transactions or malformed messages, a peer may commit within
+/// `window` before it is banned
+///
+/// A `max_offences` of 0 disables automatic banning
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BanPolicy {
+    pub max_offences: u32,
+    pub window: Duration,
+    /// How long automatic bans last, `None` bans for good
+    pub ban_duration: Option<Duration>,
+}
+
+impl BanPolicy {
+    pub const fn new(max_offences: u32, window: Duration) -> Self {
+        Self {
+            max_offences,
+            window,
+            ban_duration: None,
+        }
+    }
+
+    /// Never bans anyone on its own, the [BlackList] only holds what was inserted by hand
+    pub const fn disabled() -> Self {
+        Self::new(0, Duration::ZERO)
+    }
+
+    /// Lifts automatic bans again after `ban_duration`
+    pub const fn with_ban_duration(mut self, ban_duration: Duration) -> Self {
+        self.ban_duration = Some(ban_duration);
+        self
+    }
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        Self::disabled()
+    }
+}
+
+/// Peers connecting over ipv6 to a listener on `::` show up as ipv4 mapped addresses
+pub(super) fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    }
+}
+
+/// Same as [normalize] for networks within the ipv4 mapped range
+pub(super) fn normalize_net(network: IpNet) -> IpNet {
+    match network {
+        IpNet::V6(v6) if v6.prefix_len() >= 96 => match v6.addr().to_ipv4_mapped() {
+            Some(v4) => Ipv4Net::new(v4, v6.prefix_len() - 96)
+                .map(IpNet::V4)
+                .unwrap_or(network),
+            None => network,
+        },
+        _ => network,
+    }
+}
+
+/// Single addresses are written without a prefix
+fn display_entry(network: &IpNet) -> String {
+    match network.prefix_len() == network.max_prefix_len() {
+        true => network.addr().to_string(),
+        false => network.to_string(),
+    }
+}
+
+/// A ban as it is saved to disk
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct BanEntry {
+    /// Address or network, see [BlackList::parse_entry]
+    ip: String,
+    /// Unix timestamp at which the ban is lifted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+/// Ips whose connections are closed right after they are accepted
+///
+/// Shared between the accept loop and the handlers as an `Arc<RwLock<BlackList>>`, handlers
+/// report offences of their peer and ban it once the [BanPolicy] says so
 #[derive(Debug, Clone, Default)]
 pub struct BlackList {
-    blacklisted: Vec<SocketAddr>,
+    /// Banned networks, single ips as networks of one address, and when their ban expires,
+    /// `None` for bans that don't
+    banned: HashMap<IpNet, Option<u64>>,
+    /// Prefix lengths of the banned networks by whether they are ipv4, so a lookup only masks
+    /// the address once per length in use
+    prefixes: BTreeSet<(bool, u8)>,
+    policy: BanPolicy,
+    /// When each peer committed its offences within the window, oldest first
+    offences: HashMap<IpAddr, VecDeque<Instant>>,
+    /// File every change is saved to, see [BlackList::restore]
+    path: Option<PathBuf>,
 }
 
 impl BlackList {
-    pub fn contains(&self, ip: &SocketAddr) -> bool {
-        self.blacklisted.contains(ip)    
+    /// Parses a single address like `10.0.0.1` or `2001:db8::1`, or a network in prefix notation
+    /// like `10.0.0.0/8` or `2001:db8::/32`
+    ///
+    /// Networks with bits set after the prefix are rejected, since they usually are a typo
+    pub fn parse_entry(entry: &str) -> Result<IpNet, Error> {
+        let entry = entry.trim();
+        if let Ok(ip) = entry.parse::<IpAddr>() {
+            return Ok(IpNet::from(normalize(ip)));
+        }
+
+        let network: IpNet = entry.parse().map_err(|_| {
+            Error::InvalidBanEntry(format!(
+                "{entry:?} is neither an ip address nor a network like 10.0.0.0/8"
+            ))
+        })?;
+        if network.trunc() != network {
+            return Err(Error::InvalidBanEntry(format!(
+                "{entry:?} has bits set after the prefix, did you mean {}",
+                network.trunc()
+            )));
+        }
+        Ok(normalize_net(network))
+    }
+
+    pub fn new(policy: BanPolicy) -> Self {
+        Self {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    /// Adds the bans saved at `path` that haven't expired yet and saves every later change
+    /// there, returns how many bans were restored
+    ///
+    /// A missing file is created with the next change
+    pub fn restore(&mut self, path: PathBuf) -> Result<usize, Error> {
+        let restored = match std::fs::read(&path) {
+            Ok(data) => {
+                let entries: Vec<BanEntry> = serde_json::from_slice(&data)?;
+                // Nothing is restored from a file with a malformed entry
+                let entries = entries
+                    .into_iter()
+                    .map(|entry| Ok((Self::parse_entry(&entry.ip)?, entry.expires_at)))
+                    .collect::<Result<Vec<_>, Error>>()?;
+
+                let now = now();
+                let mut restored = 0;
+                for (network, expires_at) in entries {
+                    if in_force(expires_at, now) {
+                        self.banned.insert(network, expires_at);
+                        restored += 1;
+                    }
+                }
+                self.index_prefixes();
+                restored
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+
+        info!(restored, path = %path.display(), "Restored banned peers");
+        self.path = Some(path);
+        Ok(restored)
+    }
+
+    /// Writes the bans to `path`, through a temporary file that replaces it once complete, so
+    /// a crash leaves either the old or the new list behind
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let mut entries: Vec<_> = self.banned.iter().collect();
+        entries.sort();
+        let entries: Vec<_> = entries
+            .into_iter()
+            .map(|(network, expires_at)| BanEntry {
+                ip: display_entry(network),
+                expires_at: *expires_at,
+            })
+            .collect();
+
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        std::fs::write(&tmp, serde_json::to_vec_pretty(&entries)?)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Saves the bans to the file they were restored from, nothing to do for a list that
+    /// isn't persisted
+    ///
+    /// Every change is saved right away, this catches up on a save that failed, e.g. when the
+    /// node shuts down
+    pub fn persist(&self) -> Result<(), Error> {
+        match &self.path {
+            Some(path) => self.save(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Bans an ip or a whole network for good, returns whether it wasn't banned already
+    pub fn insert(&mut self, entry: impl Into<IpNet>) -> bool {
+        self.ban(entry.into(), None)
+    }
+
+    /// Bans an ip or a whole network until the unix timestamp `expires_at`, returns whether it
+    /// wasn't banned already
+    pub fn insert_until(&mut self, entry: impl Into<IpNet>, expires_at: u64) -> bool {
+        self.ban(entry.into(), Some(expires_at))
+    }
+
+    /// Lifts the ban of exactly this ip or network, returns whether it was banned
+    ///
+    /// Addresses within a banned network stay banned
+    pub fn remove(&mut self, entry: impl Into<IpNet>) -> bool {
+        let removed = self.banned.remove(&normalize_net(entry.into())).is_some();
+        if removed {
+            self.index_prefixes();
+            self.changed();
+        }
+        removed
+    }
+
+    /// Whether `ip` or any network containing it is banned
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.is_banned_at(ip, now())
+    }
+
+    fn is_banned_at(&self, ip: &IpAddr, now: u64) -> bool {
+        let ip = normalize(*ip);
+        self.prefixes
+            .iter()
+            .filter(|(v4, _)| *v4 == ip.is_ipv4())
+            .filter_map(|(_, len)| IpNet::new(ip, *len).ok())
+            .filter_map(|network| self.banned.get(&network.trunc()))
+            .any(|expires_at| in_force(*expires_at, now))
+    }
+
+    /// Ips and networks whose ban hasn't expired yet
+    pub fn banned(&self) -> impl Iterator<Item = &IpNet> {
+        let now = now();
+        self.banned
+            .iter()
+            .filter(move |(_, expires_at)| in_force(**expires_at, now))
+            .map(|(network, _)| network)
+    }
+
+    /// Same as [BlackList::banned], sorted and written like in the file
+    pub fn entries(&self) -> Vec<String> {
+        let mut banned: Vec<_> = self.banned().collect();
+        banned.sort();
+        banned.into_iter().map(display_entry).collect()
+    }
+
+    /// Forgets the bans that expired, returns how many there were
+    pub fn prune_expired(&mut self) -> usize {
+        self.prune_expired_at(now())
+    }
+
+    fn prune_expired_at(&mut self, now: u64) -> usize {
+        let pruned = self.drop_expired(now);
+        if pruned > 0 {
+            self.changed();
+        }
+        pruned
+    }
+
+    fn drop_expired(&mut self, now: u64) -> usize {
+        let before = self.banned.len();
+        self.banned
+            .retain(|_, expires_at| in_force(*expires_at, now));
+        let dropped = before - self.banned.len();
+        if dropped > 0 {
+            self.index_prefixes();
+        }
+        dropped
+    }
+
+    fn ban(&mut self, network: IpNet, expires_at: Option<u64>) -> bool {
+        let network = normalize_net(network).trunc();
+        let now = now();
+        let was_banned = self
+            .banned
+            .get(&network)
+            .is_some_and(|expires_at| in_force(*expires_at, now));
+
+        self.offences
+            .retain(|ip, _| !network.contains(&normalize(*ip)));
+        self.drop_expired(now);
+        self.banned.insert(network, expires_at);
+        self.index_prefixes();
+        self.changed();
+        !was_banned
+    }
+
+    fn index_prefixes(&mut self) {
+        self.prefixes = self
+            .banned
+            .keys()
+            .map(|network| (matches!(network, IpNet::V4(_)), network.prefix_len()))
+            .collect();
+    }
+
+    /// Saves the bans if the list is persisted, a failed save is retried with the next change
+    fn changed(&self) {
+        if let Some(path) = &self.path {
+            if let Err(e) = self.save(path) {
+                error!(err = %e, path = %path.display(), "Couldn't save banned peers");
+            }
+        }
+    }
+
+    /// Counts an offence of `ip` and bans it once it reached the limit of the [BanPolicy],
+    /// returns whether `ip` is banned now
+    pub fn record_offence(&mut self, ip: IpAddr, reason: &str) -> bool {
+        self.record_offence_at(ip, reason, Instant::now())
+    }
+
+    fn record_offence_at(&mut self, ip: IpAddr, reason: &str, now: Instant) -> bool {
+        if self.is_banned(&ip) {
+            return true;
+        }
+        if self.policy.max_offences == 0 {
+            return false;
+        }
+
+        let window = self.policy.window;
+        let outside_window = move |at: &Instant| now.saturating_duration_since(*at) > window;
+        if self.offences.len() >= PRUNE_THRESHOLD && !self.offences.contains_key(&ip) {
+            self.offences
+                .retain(|_, offences| offences.back().is_some_and(|at| !outside_window(at)));
+        }
+
+        let offences = self.offences.entry(ip).or_default();
+        while offences.front().is_some_and(outside_window) {
+            offences.pop_front();
+        }
+        offences.push_back(now);
+
+        if offences.len() < self.policy.max_offences as usize {
+            return false;
+        }
+
+        warn!(
+            %ip,
+            reason,
+            offences = offences.len(),
+            window = ?self.policy.window,
+            duration = ?self.policy.ban_duration,
+            "Banning peer"
+        );
+        let expires_at = self
+            .policy
+            .ban_duration
+            .map(|duration| self::now().saturating_add(duration.as_secs()));
+        self.ban(IpNet::from(ip), expires_at)
+    }
+}
+
+/// Whether a ban expiring at `expires_at` still holds at `now`
+fn in_force(expires_at: Option<u64>, now: u64) -> bool {
+    expires_at.is_none_or(|expires_at| expires_at > now)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn list_file(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("mini-blockchain-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut black_list = BlackList::default();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        assert!(!black_list.is_banned(&ip));
+        assert!(black_list.insert(ip));
+        assert!(!black_list.insert(ip));
+        assert!(black_list.is_banned(&ip));
+
+        assert!(black_list.remove(ip));
+        assert!(!black_list.is_banned(&ip));
+        assert!(!black_list.remove(ip));
+    }
+
+    #[test]
+    fn test_offences_within_the_window_ban() {
+        let mut black_list = BlackList::new(BanPolicy::new(3, Duration::from_secs(10)));
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let start = Instant::now();
+
+        assert!(!black_list.record_offence_at(ip, "invalid_transaction", start));
+        assert!(!black_list.record_offence_at(ip, "invalid_transaction", start));
+
+        // The first two fell out of the window by now
+        let later = start + Duration::from_secs(11);
+        assert!(!black_list.record_offence_at(ip, "invalid_transaction", later));
+        assert!(!black_list.record_offence_at(ip, "malformed_message", later));
+        assert!(!black_list.is_banned(&ip));
+
+        assert!(black_list.record_offence_at(ip, "malformed_message", later));
+        assert!(black_list.is_banned(&ip));
+
+        // Lifting the ban starts over
+        black_list.remove(ip);
+        assert!(!black_list.record_offence_at(ip, "malformed_message", later));
+    }
+
+    #[test]
+    fn test_disabled_policy_never_bans() {
+        let mut black_list = BlackList::default();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        for _ in 0..100 {
+            assert!(!black_list.record_offence(ip, "invalid_transaction"));
+        }
+        assert!(!black_list.is_banned(&ip));
+    }
+
+    #[test]
+    fn test_bans_expire() {
+        let policy =
+            BanPolicy::new(1, Duration::from_secs(10)).with_ban_duration(Duration::from_secs(60));
+        let mut black_list = BlackList::new(policy);
+        let temporary = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let permanent = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(black_list.record_offence(temporary, "invalid_transaction"));
+        black_list.insert(permanent);
+        let expires_at = black_list.banned[&IpNet::from(temporary)].unwrap();
+        assert!(expires_at >= now() + 59);
+
+        assert!(black_list.is_banned_at(&temporary, expires_at - 1));
+        assert!(!black_list.is_banned_at(&temporary, expires_at));
+
+        assert_eq!(black_list.prune_expired_at(expires_at), 1);
+        assert!(!black_list.banned.contains_key(&IpNet::from(temporary)));
+        assert!(black_list.is_banned_at(&permanent, u64::MAX));
+    }
+
+    #[test]
+    fn test_save_and_restore() {
+        let path = list_file("save_and_restore.json");
+        let permanent = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let temporary = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let expired = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3));
+
+        let mut black_list = BlackList::default();
+        assert_eq!(black_list.restore(path.clone()).unwrap(), 0);
+        black_list.insert(permanent);
+        black_list.insert_until(temporary, now() + 3600);
+        assert!(path.exists());
+
+        // Only written by hand, a running list prunes it as soon as something changes
+        let mut entries: Vec<BanEntry> =
+            serde_json::from_slice(&std::fs::read(&path).unwrap()).unwrap();
+        entries.push(BanEntry {
+            ip: expired.to_string(),
+            expires_at: Some(1),
+        });
+        std::fs::write(&path, serde_json::to_vec(&entries).unwrap()).unwrap();
+
+        let mut restored = BlackList::default();
+        assert_eq!(restored.restore(path.clone()).unwrap(), 2);
+        assert!(restored.is_banned(&permanent));
+        assert!(restored.is_banned(&temporary));
+        assert!(!restored.is_banned(&expired));
+
+        // Changes are saved right away
+        restored.remove(temporary);
+        let mut reloaded = BlackList::default();
+        assert_eq!(reloaded.restore(path.clone()).unwrap(), 1);
+        assert!(reloaded.is_banned(&permanent));
+        assert!(!reloaded.is_banned(&temporary));
+
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        assert!(!PathBuf::from(tmp).exists());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_persist_catches_up_on_failed_saves() {
+        let path = list_file("persist.json");
+        let mut black_list = BlackList::default();
+        black_list.persist().unwrap();
+        assert!(!path.exists());
+
+        black_list.restore(path.clone()).unwrap();
+        black_list.insert(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)));
+        // As if the save had failed
+        std::fs::remove_file(&path).unwrap();
+        black_list.persist().unwrap();
+
+        let mut restored = BlackList::default();
+        assert_eq!(restored.restore(path.clone()).unwrap(), 1);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_file_is_an_error() {
+        let path = list_file("corrupt.json");
+        std::fs::write(&path, "[{\"ip\":").unwrap();
+
+        let mut black_list = BlackList::default();
+        assert!(black_list.restore(path.clone()).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_networks_ban_every_address_within() {
+        let mut black_list = BlackList::default();
+        black_list.insert(BlackList::parse_entry("10.0.0.0/8").unwrap());
+        black_list.insert(BlackList::parse_entry("2001:db8::/32").unwrap());
+        black_list.insert(BlackList::parse_entry("192.168.1.7").unwrap());
+
+        for ip in [
+            "10.0.0.1",
+            "10.255.255.255",
+            "2001:db8::1",
+            "2001:db8:ffff::1",
+            "192.168.1.7",
+        ] {
+            assert!(black_list.is_banned(&ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["11.0.0.1", "192.168.1.8", "2001:db9::1", "::1"] {
+            assert!(!black_list.is_banned(&ip.parse().unwrap()), "{ip}");
+        }
+
+        // Peers connecting over ipv6 to a listener on ::
+        assert!(black_list.is_banned(&"::ffff:10.1.2.3".parse().unwrap()));
+        assert!(black_list.is_banned(&"::ffff:192.168.1.7".parse().unwrap()));
+
+        // Lifting a single address within a banned network changes nothing
+        assert!(!black_list.remove("10.0.0.1".parse::<IpAddr>().unwrap()));
+        assert!(black_list.remove(BlackList::parse_entry("10.0.0.0/8").unwrap()));
+        assert!(!black_list.is_banned(&"10.0.0.1".parse().unwrap()));
+        assert!(black_list.is_banned(&"2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_entry() {
+        assert_eq!(
+            BlackList::parse_entry(" 10.0.0.1 ").unwrap(),
+            "10.0.0.1/32".parse::<IpNet>().unwrap()
+        );
+        assert_eq!(
+            BlackList::parse_entry("::ffff:10.0.0.0/104").unwrap(),
+            "10.0.0.0/8".parse::<IpNet>().unwrap()
+        );
+        assert_eq!(
+            display_entry(&BlackList::parse_entry("2001:db8::1").unwrap()),
+            "2001:db8::1"
+        );
+        assert_eq!(
+            display_entry(&BlackList::parse_entry("2001:db8::/32").unwrap()),
+            "2001:db8::/32"
+        );
+
+        for entry in [
+            "",
+            "10.0.0",
+            "10.0.0.0/33",
+            "2001:db8::/129",
+            "localhost",
+            "10.0.0.0/",
+        ] {
+            assert!(
+                matches!(
+                    BlackList::parse_entry(entry),
+                    Err(Error::InvalidBanEntry(_))
+                ),
+                "{entry}"
+            );
+        }
+        let err = BlackList::parse_entry("10.0.0.1/8").unwrap_err();
+        assert!(err.to_string().contains("10.0.0.0/8"), "{err}");
+    }
+
+    #[test]
+    fn test_malformed_entry_restores_nothing() {
+        let path = list_file("malformed_entry.json");
+        std::fs::write(
+            &path,
+            r#"[{"ip":"10.0.0.0/8"},{"ip":"2001:db8::/32"},{"ip":"10.0.0.300"}]"#,
+        )
+        .unwrap();
+
+        let mut black_list = BlackList::default();
+        let err = black_list.restore(path.clone()).unwrap_err();
+        assert!(matches!(err, Error::InvalidBanEntry(_)), "{err}");
+        assert_eq!(black_list.banned().count(), 0);
+
+        std::fs::write(&path, r#"[{"ip":"10.0.0.0/8"},{"ip":"2001:db8::/32"}]"#).unwrap();
+        assert_eq!(black_list.restore(path.clone()).unwrap(), 2);
+        assert!(black_list.is_banned(&"2001:db8::1".parse().unwrap()));
+        std::fs::remove_file(path).unwrap();
     }
 }
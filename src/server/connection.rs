@@ -1,61 +1,256 @@
-use super::Message;
+use super::{Message, NodeLimits, WireFormat};
 use crate::Error;
 use bytes::{Buf, BytesMut};
-use std::io::Cursor;
+use clap::ValueEnum;
+use std::{
+    io::Cursor,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt, BufWriter},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter},
     net::TcpStream,
+    select,
+    time::{self, Instant},
 };
 
 const BUFFER_SIZE: usize = 1024 * 4;
 
-pub struct Connection {
-    stream: BufWriter<TcpStream>,
+/// Bytes of the length in front of every [FrameMode::LengthPrefixed] frame
+pub(super) const LENGTH_PREFIX_SIZE: usize = 4;
+
+/// How messages are delimited on the wire, both sides of a connection have to agree on it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum FrameMode {
+    /// Every message is followed by `\r\n`
+    #[default]
+    #[value(name = "crlf")]
+    CrLf,
+    /// Every message is preceded by its length as a big endian u32, the payload may contain
+    /// any byte
+    LengthPrefixed,
+}
+
+/// Pings sent by a connection made with [Connection::new_with_keepalive]
+#[derive(Debug)]
+struct KeepAlive {
+    /// Time between pings, and how long a ping may wait for its pong
+    interval: Duration,
+    /// When the next ping is due, or when the outstanding one times out
+    deadline: Instant,
+    /// Nonce of the ping waiting for its pong, along with when it was sent
+    outstanding: Option<(u64, Instant)>,
+    /// Round trip of the latest answered ping
+    rtt: Option<Duration>,
+}
+
+/// Messages over a stream, plain tcp unless it is a [super::MaybeTlsStream]
+pub struct Connection<S = TcpStream> {
+    stream: BufWriter<S>,
     buffer: BytesMut,
+    /// Total bytes read from the socket
+    bytes_read: u64,
+    /// Total bytes written to the socket
+    bytes_written: u64,
+    /// Checked for every message read
+    limits: NodeLimits,
+    /// Set while the rest of a frame that was too large is dropped
+    discarding: bool,
+    /// Bytes of the dropped frame still to come, only known with [FrameMode::LengthPrefixed]
+    discard_remaining: usize,
+    frame_mode: FrameMode,
+    wire_format: WireFormat,
+    keepalive: Option<KeepAlive>,
 }
 
-impl Connection {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S: AsyncRead + AsyncWrite + Unpin> Connection<S> {
+    pub fn new(stream: S) -> Self {
         Self {
             stream: BufWriter::new(stream),
             buffer: BytesMut::with_capacity(BUFFER_SIZE),
+            bytes_read: 0,
+            bytes_written: 0,
+            limits: NodeLimits::default(),
+            discarding: false,
+            discard_remaining: 0,
+            frame_mode: FrameMode::default(),
+            wire_format: WireFormat::default(),
+            keepalive: None,
+        }
+    }
+
+    /// Sends a [Message::Ping] every `interval` while waiting for messages, the connection
+    /// fails with [Error::KeepAliveTimeout] if the pong doesn't come back within `interval`
+    ///
+    /// Pongs are consumed by [Connection::read_message], the caller never sees them
+    pub fn new_with_keepalive(stream: S, interval: Duration) -> Self {
+        let mut connection = Self::new(stream);
+        connection.keepalive = Some(KeepAlive {
+            interval,
+            deadline: Instant::now() + interval,
+            outstanding: None,
+            rtt: None,
+        });
+        connection
+    }
+
+    /// Round trip of the latest ping, only measured with [Connection::new_with_keepalive]
+    pub fn rtt(&self) -> Option<Duration> {
+        self.keepalive.as_ref()?.rtt
+    }
+
+    pub fn with_frame_mode(mut self, frame_mode: FrameMode) -> Self {
+        self.frame_mode = frame_mode;
+        self
+    }
+
+    /// Binary formats switch the connection to [FrameMode::LengthPrefixed] as well
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        if wire_format.is_binary() {
+            self.frame_mode = FrameMode::LengthPrefixed;
+        }
+        self
+    }
+
+    pub fn wire_format(&self) -> WireFormat {
+        self.wire_format
+    }
+
+    /// Agrees on the wire format with the peer before any message is sent, both sides have to
+    /// call it right after connecting
+    ///
+    /// Each side sends one byte with the [WireFormat::capability] of JSON and of `preferred`,
+    /// then both pick the binary format if both offered the same one and JSON otherwise
+    pub async fn negotiate_encoding(&mut self, preferred: WireFormat) -> Result<WireFormat, Error> {
+        let offered = WireFormat::Json.capability() | preferred.capability();
+        self.stream.write_u8(offered).await?;
+        self.stream.flush().await?;
+        self.bytes_written += 1;
+
+        let peer = self.stream.read_u8().await?;
+        self.bytes_read += 1;
+
+        // Bits of formats this node doesn't know are ignored
+        let format = [WireFormat::Bincode, WireFormat::Cbor, WireFormat::Json]
+            .into_iter()
+            .find(|format| offered & peer & format.capability() != 0)
+            .ok_or(Error::NoCommonWireFormat(peer))?;
+
+        self.wire_format = format;
+        if format.is_binary() {
+            self.frame_mode = FrameMode::LengthPrefixed;
         }
+        Ok(format)
+    }
+
+    pub fn with_limits(mut self, limits: NodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &NodeLimits {
+        &self.limits
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Whether the peer started a message it didn't finish yet
+    pub fn has_partial_message(&self) -> bool {
+        !self.buffer.is_empty() || self.discarding
     }
 
     pub async fn shutdown(self) {
         let _ = self.stream.into_inner().shutdown().await;
     }
 
+    /// Frames that can't be parsed are dropped before the error is returned, so the next call
+    /// reads the frame after them
     pub async fn read_message(&mut self) -> Result<Option<Message>, Error> {
         loop {
-            if let Some(msg) = self.parse_message().await? {
-                return Ok(Some(msg));
+            if self.discarding {
+                self.discard_frame();
+            }
+
+            if !self.discarding {
+                if let Some(msg) = self.parse_message().await? {
+                    if self.take_pong(&msg) {
+                        continue;
+                    }
+                    return Ok(Some(msg));
+                }
+
+                // Without a delimiter in sight the frame can only grow further
+                if self.frame_mode == FrameMode::CrLf
+                    && self.buffer.len() > self.limits.max_frame_size
+                {
+                    self.discarding = true;
+                    self.discard_frame();
+                    return Err(Error::MessageTooLarge(self.limits.max_frame_size));
+                }
             }
 
-            if 0 == self.stream.read_buf(&mut self.buffer).await? {
+            let read = match self.keepalive.as_ref().map(|keepalive| keepalive.deadline) {
+                Some(deadline) => select! {
+                    read = self.stream.read_buf(&mut self.buffer) => read?,
+                    _ = time::sleep_until(deadline) => {
+                        self.ping().await?;
+                        continue;
+                    }
+                },
+                None => self.stream.read_buf(&mut self.buffer).await?,
+            };
+            if 0 == read {
                 if self.buffer.is_empty() {
                     return Ok(None);
                 } else {
                     return Err(Error::ConnectionEnded);
                 }
             }
+
+            self.bytes_read += read as u64;
         }
     }
 
     pub async fn parse_message(&mut self) -> Result<Option<Message>, Error> {
+        if let Some(len) = self.frame_len() {
+            // The length is known upfront, so an oversized frame is never buffered
+            if len > self.limits.max_frame_size {
+                self.discarding = true;
+                self.discard_remaining = LENGTH_PREFIX_SIZE + len;
+                self.discard_frame();
+                return Err(Error::MessageTooLarge(self.limits.max_frame_size));
+            }
+        }
+
         let mut buf = Cursor::new(&self.buffer[..]);
 
-        match Message::check(&mut buf) {
+        let checked = match self.frame_mode {
+            FrameMode::CrLf => Message::check(&mut buf),
+            FrameMode::LengthPrefixed => Message::check_frame(&mut buf),
+        };
+        match checked {
             Ok(_) => {
                 let len = buf.position() as usize;
 
                 buf.set_position(0);
 
-                let message = Message::parse(&mut buf)?;
+                let message = match self.frame_mode {
+                    FrameMode::CrLf => Message::parse_with_limits(&mut buf, &self.limits),
+                    FrameMode::LengthPrefixed => {
+                        Message::parse_frame_with_limits(&mut buf, &self.limits, self.wire_format)
+                    }
+                };
 
                 self.buffer.advance(len);
 
-                Ok(Some(message))
+                message.map(Some)
             }
 
             Err(Error::IncompleteMessage) => Ok(None),
@@ -63,11 +258,316 @@ impl Connection {
         }
     }
 
+    /// Sends the next ping, unless the previous one was never answered
+    async fn ping(&mut self) -> Result<(), Error> {
+        let keepalive = match self.keepalive.as_mut() {
+            Some(keepalive) => keepalive,
+            None => return Ok(()),
+        };
+        if keepalive.outstanding.is_some() {
+            return Err(Error::KeepAliveTimeout(keepalive.interval));
+        }
+
+        let nonce = SystemTime::now().duration_since(UNIX_EPOCH)?.as_millis() as u64;
+        let now = Instant::now();
+        keepalive.outstanding = Some((nonce, now));
+        keepalive.deadline = now + keepalive.interval;
+
+        self.write_message(&Message::Ping(nonce)).await
+    }
+
+    /// Whether `msg` is a pong for this connection's keepalive, which then schedules the next
+    /// ping
+    fn take_pong(&mut self, msg: &Message) -> bool {
+        let (keepalive, nonce) = match (self.keepalive.as_mut(), msg) {
+            (Some(keepalive), Message::Pong(nonce)) => (keepalive, *nonce),
+            _ => return false,
+        };
+
+        if let Some((outstanding, sent_at)) = keepalive.outstanding {
+            if outstanding == nonce {
+                keepalive.rtt = Some(sent_at.elapsed());
+                keepalive.outstanding = None;
+                keepalive.deadline = sent_at + keepalive.interval;
+            }
+        }
+        // Late pongs are dropped as well
+        true
+    }
+
+    /// Length of the buffered [FrameMode::LengthPrefixed] frame, once its prefix is in
+    fn frame_len(&self) -> Option<usize> {
+        if self.frame_mode != FrameMode::LengthPrefixed {
+            return None;
+        }
+
+        let prefix = self.buffer.get(..LENGTH_PREFIX_SIZE)?;
+        Some(u32::from_be_bytes(prefix.try_into().ok()?) as usize)
+    }
+
+    /// Drops the buffered part of a frame that was too large, up to and including its delimiter
+    fn discard_frame(&mut self) {
+        if self.frame_mode == FrameMode::LengthPrefixed {
+            let len = self.discard_remaining.min(self.buffer.len());
+            self.buffer.advance(len);
+            self.discard_remaining -= len;
+            self.discarding = self.discard_remaining > 0;
+            return;
+        }
+
+        match self.buffer.windows(2).position(|window| window == b"\r\n") {
+            Some(end) => {
+                self.buffer.advance(end + 2);
+                self.discarding = false;
+            }
+            None => {
+                // A trailing '\r' may be the start of the delimiter
+                let keep = usize::from(self.buffer.last() == Some(&b'\r'));
+                self.buffer.advance(self.buffer.len() - keep);
+            }
+        }
+    }
+
     pub async fn write_message(&mut self, message: &Message) -> Result<(), Error> {
-        let serialized_message = message.serialize()?;
-        self.stream.write_all(&serialized_message).await?;
-        self.stream.write_all(b"\r\n").await?;
+        let serialized_message = message.serialize_with(self.wire_format)?;
+        let framing = match self.frame_mode {
+            FrameMode::CrLf => {
+                self.stream.write_all(&serialized_message).await?;
+                self.stream.write_all(b"\r\n").await?;
+                2
+            }
+            FrameMode::LengthPrefixed => {
+                let len = u32::try_from(serialized_message.len())
+                    .map_err(|_| Error::MessageTooLarge(u32::MAX as usize))?;
+                self.stream.write_all(&len.to_be_bytes()).await?;
+                self.stream.write_all(&serialized_message).await?;
+                LENGTH_PREFIX_SIZE as u64
+            }
+        };
         self.stream.flush().await?;
+        self.bytes_written += serialized_message.len() as u64 + framing;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TransactionEntry;
+    use tokio::net::TcpListener;
+
+    /// Both ends of a local connection using `frame_mode`
+    async fn pair(frame_mode: FrameMode) -> (Connection, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (Connection::new(server).with_frame_mode(frame_mode), client)
+    }
+
+    #[tokio::test]
+    async fn test_keepalive_measures_rtt() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let mut client = Connection::new_with_keepalive(client, Duration::from_millis(20));
+        let mut server = Connection::new(server);
+
+        // Answers two pings, then sends a message of its own
+        let peer = tokio::spawn(async move {
+            for _ in 0..2 {
+                match server.read_message().await.unwrap() {
+                    Some(Message::Ping(nonce)) => {
+                        server.write_message(&Message::Pong(nonce)).await.unwrap()
+                    }
+                    other => panic!("expected a ping, got {other:?}"),
+                }
+            }
+            server.write_message(&Message::Ok).await.unwrap();
+            server
+        });
+
+        assert_eq!(client.read_message().await.unwrap(), Some(Message::Ok));
+        assert!(client.rtt().is_some());
+        let _server = peer.await.unwrap();
+
+        // Nobody answers anymore
+        assert!(matches!(
+            client.read_message().await,
+            Err(Error::KeepAliveTimeout(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_round_trip_in_both_modes() {
+        for frame_mode in [FrameMode::CrLf, FrameMode::LengthPrefixed] {
+            let (mut server, client) = pair(frame_mode).await;
+            let mut client = Connection::new(client).with_frame_mode(frame_mode);
+
+            // Written back to back, so several of them arrive in a single read
+            let messages = [
+                Message::InvalidMessage(String::from("first\nsecond")),
+                Message::LimitsReq,
+                Message::Ping(7),
+                Message::Transactions(vec![
+                    TransactionEntry::NotFound,
+                    TransactionEntry::Found(Default::default()),
+                ]),
+            ];
+            for message in &messages {
+                client.write_message(message).await.unwrap();
+            }
+            for message in messages {
+                assert_eq!(server.read_message().await.unwrap(), Some(message));
+            }
+            assert_eq!(server.bytes_read(), client.bytes_written());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_binary_round_trip() {
+        for format in [WireFormat::Bincode, WireFormat::Cbor] {
+            let (server, client) = pair(FrameMode::CrLf).await;
+            let mut server = server.with_wire_format(format);
+            let mut client = Connection::new(client).with_wire_format(format);
+            assert_eq!(client.frame_mode, FrameMode::LengthPrefixed);
+
+            let messages = [
+                Message::InvalidMessage(String::from("first\r\nsecond")),
+                Message::Ping(u64::from_be_bytes(*b"\r\n\r\n\r\n\r\n")),
+                Message::Transactions(vec![
+                    TransactionEntry::NotFound,
+                    TransactionEntry::Found(Default::default()),
+                ]),
+            ];
+            for message in &messages {
+                client.write_message(message).await.unwrap();
+            }
+            for message in messages {
+                assert_eq!(server.read_message().await.unwrap(), Some(message));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_negotiate_encoding() {
+        let cases = [
+            (WireFormat::Json, WireFormat::Json, WireFormat::Json),
+            (WireFormat::Json, WireFormat::Bincode, WireFormat::Json),
+            (WireFormat::Cbor, WireFormat::Bincode, WireFormat::Json),
+            (WireFormat::Cbor, WireFormat::Cbor, WireFormat::Cbor),
+            (
+                WireFormat::Bincode,
+                WireFormat::Bincode,
+                WireFormat::Bincode,
+            ),
+        ];
+        for (server_format, client_format, expected) in cases {
+            let (mut server, client) = pair(FrameMode::CrLf).await;
+            let mut client = Connection::new(client);
+
+            let (server_agreed, client_agreed) = tokio::join!(
+                server.negotiate_encoding(server_format),
+                client.negotiate_encoding(client_format)
+            );
+            assert_eq!(server_agreed.unwrap(), expected);
+            assert_eq!(client_agreed.unwrap(), expected);
+
+            // Messages after the capability byte are framed as usual
+            client.write_message(&Message::LimitsReq).await.unwrap();
+            assert_eq!(
+                server.read_message().await.unwrap(),
+                Some(Message::LimitsReq)
+            );
+            assert_eq!(server.bytes_read(), client.bytes_written());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_length_prefixed_payload_may_hold_crlf() {
+        let (mut server, client) = pair(FrameMode::LengthPrefixed).await;
+        let mut client = Connection::new(client).with_frame_mode(FrameMode::LengthPrefixed);
+
+        // Not valid json, but only this frame is lost, not the one after it as well
+        let payload = b"{\"InvalidMessage\":\"first\r\nsecond\"}";
+        client
+            .stream
+            .write_all(&(payload.len() as u32).to_be_bytes())
+            .await
+            .unwrap();
+        client.stream.write_all(payload).await.unwrap();
+        client.stream.flush().await.unwrap();
+
+        client.write_message(&Message::LimitsReq).await.unwrap();
+
+        assert!(matches!(
+            server.read_message().await,
+            Err(Error::SerdeError(_))
+        ));
+        assert_eq!(
+            server.read_message().await.unwrap(),
+            Some(Message::LimitsReq)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_oversized_crlf_frame_is_skipped() {
+        let (mut server, mut client) = pair(FrameMode::CrLf).await;
+        let max = NodeLimits::default().max_frame_size;
+
+        let writer = tokio::spawn(async move {
+            // Far more than the limit, only a bounded part of it is ever buffered
+            client.write_all(&vec![b' '; 4 * max + 1]).await.unwrap();
+            client.write_all(b"\r\n").await.unwrap();
+
+            let payload = Message::LimitsReq.serialize().unwrap();
+            client.write_all(&payload).await.unwrap();
+            client.write_all(b"\r\n").await.unwrap();
+            client
+        });
+
+        assert!(matches!(
+            server.read_message().await,
+            Err(Error::MessageTooLarge(_))
+        ));
+        assert_eq!(
+            server.read_message().await.unwrap(),
+            Some(Message::LimitsReq)
+        );
+        assert!(server.buffer.capacity() <= 2 * max);
+        drop(writer.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_length_prefixed_frame_is_skipped() {
+        let (mut server, mut client) = pair(FrameMode::LengthPrefixed).await;
+        let max = NodeLimits::default().max_frame_size;
+
+        // Larger than the socket buffers, so it is written while the server reads
+        let writer = tokio::spawn(async move {
+            let len = max as u32 + 1;
+            client.write_all(&len.to_be_bytes()).await.unwrap();
+            client.write_all(&vec![b' '; max + 1]).await.unwrap();
+
+            let payload = Message::LimitsReq.serialize().unwrap();
+            let len = payload.len() as u32;
+            client.write_all(&len.to_be_bytes()).await.unwrap();
+            client.write_all(&payload).await.unwrap();
+            client
+        });
+
+        assert!(matches!(
+            server.read_message().await,
+            Err(Error::MessageTooLarge(_))
+        ));
+        assert_eq!(
+            server.read_message().await.unwrap(),
+            Some(Message::LimitsReq)
+        );
+        drop(writer.await.unwrap());
+    }
+}
@@ -1,144 +1,998 @@
 use crate::{
     database::{DatabaseReader, DatabaseWriter},
     error::Error,
+    executor::{ImportTx, MempoolStatusTx, SimulationTx},
     server::connection::Connection,
-    Transaction,
+    utils::{self, HashAlgo},
+    validation::{TxValidator, ValidationConfig, ValidationOutcome},
+    Account, Bundle, ChainSpec, NodeRole, SealedBlock, Shutdown, Transaction,
 };
-use std::sync::Arc;
-use tokio::sync::{mpsc, RwLock};
-use tracing::error;
+use alloy_primitives::{Address, B256};
+use std::{future::Future, io, net::IpAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    select,
+    sync::{broadcast, mpsc, mpsc::error::TrySendError, oneshot, RwLock},
+    time,
+};
+use tracing::{error, info, warn};
 
 use super::{
-    message::{BlockReq, TransactionReq},
-    Message,
+    black_list::BlackList,
+    limits::range_len,
+    message::{
+        ChainInfo, Pruned, SubmissionResult, SubscriptionKind, TransactionEntry,
+        MAX_TRANSACTIONS_PER_REQUEST, PROTOCOL_VERSION,
+    },
+    peer_stats::{PeerCounters, PeerStats},
+    rate_limit::{RateLimiter, RequestKind},
+    status::NodeStatus,
+    tls::MaybeTlsStream,
+    verifier::{validate_all, Verified, VerifierPool},
+    AdminReq, BlockReq, ControlMsg, Message, TransactionReq, WireFormat,
 };
 
-pub struct Handler<DB> {
+/// How long a peer may still take to read a response once the node shuts down
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(3);
+
+/// Malformed messages in a row after which the connection is closed, such a peer most likely
+/// speaks another protocol or format altogether
+const MAX_CONSECUTIVE_MALFORMED: u32 = 8;
+
+/// How long peers are asked to back off when the mempool can't keep up with submissions
+const BUSY_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+pub struct Handler<DB, S = MaybeTlsStream> {
     /// Shared InMemoryDB handle
     db: Arc<RwLock<DB>>,
 
-    /// TcpConnection wrapper
-    connection: Connection,
+    /// TcpConnection wrapper, TLS if the server terminates it
+    connection: Connection<S>,
 
     /// Sender half of [mpsc] channel, that allows to send [Transaction]
     /// to the mempool from each handler
     server_mempool_tx: mpsc::Sender<Transaction>,
+
+    /// Hands bundles to the mempool, only set if the node accepts them
+    server_bundle_tx: Option<mpsc::Sender<Bundle>>,
+
+    /// Asks the mempool for its statistics
+    mempool_status_tx: MempoolStatusTx,
+
+    /// Spec of the chain this node is running
+    spec: Arc<ChainSpec>,
+
+    /// Seconds between blocks, reported in [ChainInfo]
+    block_time: u64,
+
+    /// Statistics of the peer on the other side of the connection
+    peer: Arc<PeerCounters>,
+
+    /// Admission checks a transaction has to pass before it reaches the mempool
+    validator: Arc<TxValidator>,
+
+    /// Workers the admission checks run on, a blocking task per message without them
+    verifier: Option<VerifierPool>,
+
+    /// Blocks sealed by the executor, only set if the node accepts subscriptions
+    new_blocks: Option<broadcast::Sender<SealedBlock>>,
+
+    /// Transactions accepted by the mempool, only set if the node accepts subscriptions
+    new_transactions: Option<broadcast::Sender<Transaction>>,
+
+    /// Ends open connections and subscriptions when the node shuts down
+    shutdown: Option<Shutdown>,
+
+    /// Dropped once the connection is closed, the node waits for it before exiting
+    _shutdown_complete: Option<mpsc::Sender<()>>,
+
+    /// Format offered to the peer before the first message, only set if the node negotiates
+    negotiate_encoding: Option<WireFormat>,
+
+    /// Set until the peer sent a compatible [Message::Hello], only with
+    /// [Handler::with_handshake]
+    awaiting_hello: bool,
+
+    /// Closes the connection if a read doesn't complete in time, subscriptions are exempt
+    idle_timeout: Option<Duration>,
+
+    /// Answers [Message::InternalError] if a message takes longer than this to handle
+    request_timeout: Option<Duration>,
+
+    /// Buckets shared with every other handler and the ip of the peer they are taken from
+    rate_limiter: Option<(Arc<RateLimiter>, IpAddr)>,
+
+    /// Where offences of the peer are reported and the ip they are reported for
+    black_list: Option<(Arc<RwLock<BlackList>>, IpAddr)>,
+
+    /// Where [Message::SimulateTx] is sent to the [crate::Executor]
+    simulation_tx: Option<SimulationTx>,
+
+    /// Where [Message::ImportBlock] is sent to the [crate::Executor] and whether blocks are
+    /// only imported while the [NodeStatus] reports the node as a follower
+    import_tx: Option<(ImportTx, bool)>,
+
+    /// Black list managed with [Message::Admin], the token requests have to carry and the ip
+    /// of the peer, which unauthorized attempts are logged with
+    admin: Option<(Arc<RwLock<BlackList>>, Arc<str>, IpAddr)>,
+
+    /// Statistics of every peer, answered to [AdminReq::PeerStats]
+    peer_stats: Option<Arc<PeerStats>>,
+
+    /// Where [Message::PauseReq] and [Message::ResumeReq] are sent to the [super::Server]
+    server_control_tx: Option<mpsc::Sender<ControlMsg>>,
+
+    /// What [Message::StatusReq] is answered from, only set if the node reports its status
+    status: Option<Arc<NodeStatus>>,
 }
 
-impl<DB> Handler<DB>
+impl<DB, S> Handler<DB, S>
 where
     DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+    S: AsyncRead + AsyncWrite + Unpin + Send,
 {
     pub fn new(
         db: Arc<RwLock<DB>>,
-        connection: Connection,
+        connection: Connection<S>,
         server_mempool_tx: mpsc::Sender<Transaction>,
+        mempool_status_tx: MempoolStatusTx,
+        spec: Arc<ChainSpec>,
+        block_time: u64,
+        peer: Arc<PeerCounters>,
     ) -> Self {
         Self {
             db,
             connection,
             server_mempool_tx,
+            server_bundle_tx: None,
+            mempool_status_tx,
+            spec,
+            block_time,
+            peer,
+            validator: Arc::new(TxValidator::new()),
+            verifier: None,
+            new_blocks: None,
+            new_transactions: None,
+            shutdown: None,
+            _shutdown_complete: None,
+            negotiate_encoding: None,
+            awaiting_hello: false,
+            idle_timeout: None,
+            request_timeout: None,
+            rate_limiter: None,
+            black_list: None,
+            simulation_tx: None,
+            import_tx: None,
+            admin: None,
+            peer_stats: None,
+            server_control_tx: None,
+            status: None,
         }
     }
 
+    /// Accepts [Message::Subscribe], only together with [Handler::with_shutdown] so open
+    /// subscriptions end with the node
+    pub fn with_subscriptions(
+        mut self,
+        new_blocks: broadcast::Sender<SealedBlock>,
+        new_transactions: broadcast::Sender<Transaction>,
+    ) -> Self {
+        self.new_blocks = Some(new_blocks);
+        self.new_transactions = Some(new_transactions);
+        self
+    }
+
+    /// Closes the connection when `shutdown` fires, after the response being written, and
+    /// drops `shutdown_complete` once it is closed
+    pub fn with_shutdown(
+        mut self,
+        shutdown: broadcast::Receiver<()>,
+        shutdown_complete: mpsc::Sender<()>,
+    ) -> Self {
+        self.shutdown = Some(Shutdown::new(shutdown));
+        self._shutdown_complete = Some(shutdown_complete);
+        self
+    }
+
+    /// Accepts [Message::SimulateTx]
+    pub fn with_simulations(mut self, simulation_tx: SimulationTx) -> Self {
+        self.simulation_tx = Some(simulation_tx);
+        self
+    }
+
+    /// Accepts [Message::ImportBlock], only together with [Handler::with_admin] since it
+    /// carries the admin token
+    pub fn with_imports(mut self, import_tx: ImportTx) -> Self {
+        self.import_tx = Some((import_tx, false));
+        self
+    }
+
+    /// Accepts [Message::ImportBlock] only while the node follows another one, only together
+    /// with [Handler::with_admin] and [Handler::with_status] which the role is read from
+    pub fn with_follower_imports(mut self, import_tx: ImportTx) -> Self {
+        self.import_tx = Some((import_tx, true));
+        self
+    }
+
+    /// Accepts [Message::Admin] carrying `token` from the peer at `ip`
+    pub fn with_admin(
+        mut self,
+        black_list: Arc<RwLock<BlackList>>,
+        token: Arc<str>,
+        ip: IpAddr,
+    ) -> Self {
+        self.admin = Some((black_list, token, ip));
+        self
+    }
+
+    /// Answers [AdminReq::PeerStats], only together with [Handler::with_admin]
+    pub fn with_peer_stats(mut self, peer_stats: Arc<PeerStats>) -> Self {
+        self.peer_stats = Some(peer_stats);
+        self
+    }
+
+    /// Accepts [Message::PauseReq], [Message::ResumeReq], [Message::PromoteReq] and
+    /// [Message::DemoteReq], only together with [Handler::with_admin] since they carry the
+    /// admin token
+    pub fn with_control(mut self, server_control_tx: mpsc::Sender<ControlMsg>) -> Self {
+        self.server_control_tx = Some(server_control_tx);
+        self
+    }
+
+    /// Accepts [Message::StatusReq]
+    pub fn with_status(mut self, status: Arc<NodeStatus>) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    /// Accepts [Message::Bundle]
+    pub fn with_bundles(mut self, server_bundle_tx: mpsc::Sender<Bundle>) -> Self {
+        self.server_bundle_tx = Some(server_bundle_tx);
+        self
+    }
+
+    /// Rejects every message until the peer sent a compatible [Message::Hello]
+    pub fn with_handshake(mut self) -> Self {
+        self.awaiting_hello = true;
+        self
+    }
+
+    /// Runs [Connection::negotiate_encoding] before reading the first message
+    pub fn with_encoding_negotiation(mut self, preferred: WireFormat) -> Self {
+        self.negotiate_encoding = Some(preferred);
+        self
+    }
+
+    /// Closes the connection once the peer took longer than `idle_timeout` to send a message
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Gives up on a message once handling it took longer than `request_timeout`, for
+    /// example while the database lock is contended, the connection stays open
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    /// Answers [Message::RateLimited] once the peer at `ip` ran out of tokens
+    pub(crate) fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>, ip: IpAddr) -> Self {
+        self.rate_limiter = Some((rate_limiter, ip));
+        self
+    }
+
+    /// Reports invalid transactions and malformed messages of the peer at `ip` to
+    /// `black_list`, and closes the connection once the peer is banned
+    pub fn with_black_list(mut self, black_list: Arc<RwLock<BlackList>>, ip: IpAddr) -> Self {
+        self.black_list = Some((black_list, ip));
+        self
+    }
+
+    pub fn with_validator(mut self, validator: Arc<TxValidator>) -> Self {
+        self.validator = validator;
+        self
+    }
+
+    /// Runs the admission checks on `verifier`, which has to check against the same validator
+    pub(crate) fn with_verifier(mut self, verifier: VerifierPool) -> Self {
+        self.verifier = Some(verifier);
+        self
+    }
+
     pub async fn shutdown(self) {
+        self.peer.record_traffic(
+            self.connection.bytes_read(),
+            self.connection.bytes_written(),
+        );
         self.connection.shutdown().await;
     }
 
+    /// Answers messages until the peer closes the connection, a message that can't be parsed
+    /// is answered with [Message::InvalidMessage] and the next one is read, unless the peer
+    /// sent nothing but malformed messages [MAX_CONSECUTIVE_MALFORMED] times in a row
     pub async fn handle_connection(mut self) {
-        let msg = match self.connection.read_message().await {
-            Ok(msg) => msg,
-            Err(e) => {
-                error!(err = %e, "Couldn't read message from connection, closing connection");
+        if let Some(preferred) = self.negotiate_encoding {
+            let negotiate = idle(
+                self.idle_timeout,
+                self.connection.negotiate_encoding(preferred),
+            );
+            let negotiated = match self.shutdown.as_mut() {
+                Some(shutdown) => select! {
+                    negotiated = negotiate => Some(negotiated),
+                    _ = shutdown.recv() => None,
+                },
+                None => Some(negotiate.await),
+            };
+            let negotiated = match negotiated {
+                Some(negotiated) => negotiated,
+                // The node shut down before the peer sent its formats
+                None => {
+                    self.shutdown().await;
+                    return;
+                }
+            };
+            if let Err(e) = negotiated {
+                warn!(err = %e, "Couldn't agree on a wire format, closing connection");
+                self.peer.record_rejection("encoding_negotiation");
                 self.shutdown().await;
                 return;
             }
-        };
+        }
 
-        let msg = match msg {
-            Some(msg) => msg,
-            None => {
-                error!("No message received from the connection, closing connection");
-                self.shutdown().await;
+        let mut malformed = 0;
+        loop {
+            let read = idle(self.idle_timeout, self.connection.read_message());
+            let read = match self.shutdown.as_mut() {
+                Some(shutdown) => select! {
+                    read = read => read,
+                    _ = shutdown.recv() => break,
+                },
+                None => read.await,
+            };
+
+            let msg = match read {
+                Ok(Some(msg)) => {
+                    malformed = 0;
+                    msg
+                }
+                // The peer is done
+                Ok(None) => break,
+                // The connection dropped the frame, so the session can go on
+                Err(
+                    e @ (Error::LimitExceeded { .. }
+                    | Error::MessageTooLarge(_)
+                    | Error::SerdeError(_)
+                    | Error::BincodeError(_)
+                    | Error::CborError(_)),
+                ) => {
+                    let reason = match e {
+                        // The peer gets to know which limit it broke
+                        Error::LimitExceeded { .. } => "limit_exceeded",
+                        Error::MessageTooLarge(_) => "message_too_large",
+                        _ => "malformed_message",
+                    };
+                    self.peer.record_rejection(reason);
+                    // Limits can be hit by honest peers, garbage can't
+                    let banned = reason == "malformed_message" && self.offence(reason).await;
+
+                    let response = Message::InvalidMessage(e.to_string());
+                    if let Err(e) =
+                        write_message(&mut self.connection, self.shutdown.as_mut(), &response).await
+                    {
+                        error!(err = %e, "Couldn't handle message, closing connection");
+                        break;
+                    }
+                    if banned {
+                        break;
+                    }
+                    if reason == "malformed_message" {
+                        malformed += 1;
+                        if malformed >= MAX_CONSECUTIVE_MALFORMED {
+                            warn!(
+                                malformed,
+                                "Peer only sends malformed messages, closing connection"
+                            );
+                            break;
+                        }
+                    }
+                    continue;
+                }
+                Err(Error::IdleTimeout(idle_timeout)) => {
+                    // A peer stuck in the middle of a message gets to know why it is dropped
+                    if self.connection.has_partial_message() {
+                        info!(
+                            ?idle_timeout,
+                            "Peer stalled mid-message, closing connection"
+                        );
+                        self.peer.record_rejection("request_timeout");
+                        let response = Message::InvalidMessage(String::from("request timed out"));
+                        let _ =
+                            write_message(&mut self.connection, self.shutdown.as_mut(), &response)
+                                .await;
+                    } else {
+                        info!(?idle_timeout, "Peer went idle, closing connection");
+                    }
+                    break;
+                }
+                Err(e) => {
+                    error!(err = %e, "Couldn't read message from connection, closing connection");
+                    self.peer.record_rejection("malformed_message");
+                    break;
+                }
+            };
+
+            self.peer.record_message(msg.kind());
+
+            if self.awaiting_hello && !matches!(msg, Message::Hello { .. }) {
+                self.peer.record_rejection("missing_hello");
+                let response = Message::InvalidMessage(String::from(
+                    "Expected Hello before any other message",
+                ));
+                if let Err(e) =
+                    write_message(&mut self.connection, self.shutdown.as_mut(), &response).await
+                {
+                    error!(err = %e, "Couldn't handle message, closing connection");
+                    break;
+                }
+                continue;
+            }
+
+            if let Some(response) = self.throttle(&msg) {
+                if let Err(e) =
+                    write_message(&mut self.connection, self.shutdown.as_mut(), &response).await
+                {
+                    error!(err = %e, "Couldn't handle message, closing connection");
+                    break;
+                }
+                continue;
+            }
+
+            // Takes the connection over until the peer leaves
+            if let Message::Subscribe(kind) = msg {
+                self.handle_subscription(kind).await;
                 return;
             }
-        };
 
-        let response = match self.handle_message(msg).await {
-            Ok(resp) => resp,
-            Err(e) => {
+            let (response, banned) = match self.respond(msg).await {
+                Ok(responded) => responded,
+                Err(e) => {
+                    error!(err = %e, "Couldn't handle message, closing connection");
+                    break;
+                }
+            };
+
+            if let Err(e) =
+                write_message(&mut self.connection, self.shutdown.as_mut(), &response).await
+            {
                 error!(err = %e, "Couldn't handle message, closing connection");
+                break;
+            }
+            if banned || matches!(response, Message::IncompatiblePeer(_)) {
+                break;
+            }
+        }
+
+        self.shutdown().await;
+    }
+
+    /// Answers a single message of a [super::rpc] request, which skips the handshake and
+    /// can't subscribe
+    pub(crate) async fn handle_rpc(&mut self, msg: Message) -> Result<Message, Error> {
+        self.peer.record_message(msg.kind());
+        if let Some(response) = self.throttle(&msg) {
+            return Ok(response);
+        }
+        // A ban only closes the next connection of the peer
+        let (response, _) = self.respond(msg).await?;
+        Ok(response)
+    }
+
+    /// Handles `msg` within the request timeout and reports what the peer got wrong, returns
+    /// the response and whether the peer is banned now
+    async fn respond(&mut self, msg: Message) -> Result<(Message, bool), Error> {
+        let response = match self.request_timeout {
+            // Dropping the handler future releases any lock it was holding or waiting for
+            Some(request_timeout) => time::timeout(request_timeout, self.handle_message(msg))
+                .await
+                .unwrap_or_else(|_| {
+                    warn!(?request_timeout, "Message took too long to handle");
+                    Ok(Message::InternalError(String::from("timeout")))
+                })?,
+            None => self.handle_message(msg).await?,
+        };
+
+        let mut banned = false;
+        let responses = match &response {
+            Message::BatchResponse(responses) => responses.as_slice(),
+            response => std::slice::from_ref(response),
+        };
+        for response in responses {
+            match response {
+                Message::InvalidTransaction => {
+                    self.peer.record_rejection("invalid_transaction");
+                    banned |= self.offence("invalid_transaction").await;
+                }
+                // A load generator may have a few stale nonces, a whole rejected submission
+                // counts as one offence
+                Message::SubmissionResults(results) => {
+                    let rejected = results
+                        .iter()
+                        .filter(|result| matches!(result, SubmissionResult::Rejected(_)))
+                        .count();
+                    for _ in 0..rejected {
+                        self.peer.record_rejection("invalid_transaction");
+                    }
+                    if rejected > 0 {
+                        banned |= self.offence("invalid_transaction").await;
+                    }
+                }
+                Message::InvalidMessage(_) => self.peer.record_rejection("invalid_message"),
+                Message::IncompatiblePeer(_) => self.peer.record_rejection("incompatible_peer"),
+                Message::Unauthorized => {
+                    self.peer.record_rejection("unauthorized");
+                    banned |= self.offence("unauthorized").await;
+                }
+                Message::Hello { .. } => self.awaiting_hello = false,
+                _ => {}
+            }
+        }
+        Ok((response, banned))
+    }
+
+    /// Pushes every update of `kind` to the peer until it disconnects or the node shuts down
+    pub async fn handle_subscription(mut self, kind: SubscriptionKind) {
+        let shutdown = self.shutdown.take();
+        match kind {
+            SubscriptionKind::Blocks => {
+                let updates = self.new_blocks.as_ref().map(broadcast::Sender::subscribe);
+                self.stream(updates, shutdown, Message::Block).await
+            }
+            SubscriptionKind::PendingTransactions => {
+                let updates = self
+                    .new_transactions
+                    .as_ref()
+                    .map(broadcast::Sender::subscribe);
+                self.stream(updates, shutdown, Message::Transaction).await
+            }
+        }
+    }
+
+    /// A peer too slow to keep up is told how many updates it missed and disconnected, so it
+    /// can fill the gap with regular requests
+    async fn stream<T: Clone>(
+        mut self,
+        updates: Option<broadcast::Receiver<T>>,
+        shutdown: Option<Shutdown>,
+        into_message: fn(T) -> Message,
+    ) {
+        let (mut updates, mut shutdown) = match (updates, shutdown) {
+            (Some(updates), Some(shutdown)) => (updates, shutdown),
+            _ => {
+                let response =
+                    Message::InvalidMessage(String::from("The node doesn't accept subscriptions"));
+                if let Err(e) = self.connection.write_message(&response).await {
+                    error!(err = %e, "Couldn't handle message, closing connection");
+                }
                 self.shutdown().await;
                 return;
             }
         };
 
-        match self.connection.write_message(&response).await {
-            Ok(_) => (),
-            Err(e) => {
-                error!(err = %e, "Couldn't handle message, closing connection");
+        if let Err(e) = write_message(&mut self.connection, Some(&mut shutdown), &Message::Ok).await
+        {
+            error!(err = %e, "Couldn't confirm subscription, closing connection");
+            self.shutdown().await;
+            return;
+        }
+
+        loop {
+            let msg = select! {
+                update = updates.recv() => match update {
+                    Ok(update) => into_message(update),
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "Subscriber fell behind, closing connection");
+                        let msg = Message::InvalidMessage(format!("Missed {} updates", missed));
+                        let connection = &mut self.connection;
+                        let _ = write_message(connection, Some(&mut shutdown), &msg).await;
+                        break;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                msg = self.connection.read_message() => match msg {
+                    // Subscribers idle for long are the ones that need a keepalive
+                    Ok(Some(Message::Ping(nonce))) => Message::Pong(nonce),
+                    Ok(Some(_)) => Message::InvalidMessage(String::from(
+                        "The connection is subscribed, open another one for requests",
+                    )),
+                    // The peer is gone
+                    Ok(None) | Err(_) => break,
+                },
+                _ = shutdown.recv() => break,
+            };
+
+            if let Err(e) = write_message(&mut self.connection, Some(&mut shutdown), &msg).await {
+                error!(err = %e, "Couldn't push to subscriber, closing connection");
+                break;
             }
         }
 
         self.shutdown().await;
     }
 
+    /// Reports an offence of the peer to the [BlackList], returns whether it is banned now
+    async fn offence(&self, reason: &str) -> bool {
+        match &self.black_list {
+            Some((black_list, ip)) => black_list.write().await.record_offence(*ip, reason),
+            None => false,
+        }
+    }
+
+    /// Answers [Message::RateLimited] if the peer ran out of tokens for `msg`
+    fn throttle(&self, msg: &Message) -> Option<Message> {
+        let retry_after = self.rate_limit(msg).err()?;
+        self.peer.record_rejection("rate_limited");
+        Some(Message::RateLimited {
+            retry_after_ms: retry_after.as_millis().max(1) as u64,
+        })
+    }
+
+    /// Takes a token for `msg`, keepalives and the handshake are free
+    fn rate_limit(&self, msg: &Message) -> Result<(), Duration> {
+        let (rate_limiter, ip) = match &self.rate_limiter {
+            Some(rate_limiter) => rate_limiter,
+            None => return Ok(()),
+        };
+        let kind = match msg {
+            // The requests of a batch take their own tokens
+            Message::Hello { .. } | Message::Ping(_) | Message::Pong(_) | Message::Batch(_) => {
+                return Ok(())
+            }
+            Message::Transaction(_)
+            | Message::Bundle(_)
+            | Message::SubmitTransactions(_)
+            | Message::SimulateTx(_)
+            | Message::ImportBlock(..)
+            // Costs a signature recovery, just like a submitted transaction
+            | Message::RecoverSender(_) => RequestKind::Transaction,
+            _ => RequestKind::Read,
+        };
+
+        rate_limiter.check(*ip, kind)
+    }
+
     pub async fn handle_message(&mut self, msg: Message) -> Result<Message, Error> {
         match msg {
+            Message::Hello {
+                protocol_version,
+                chain_id,
+                genesis_hash,
+                hash_algo,
+            } => Ok(self.handle_hello(protocol_version, chain_id, genesis_hash, hash_algo)),
             Message::Transaction(tx) => self.handle_transaction(tx).await,
+            Message::SubmitTransactions(transactions) => {
+                self.handle_submit_transactions(transactions).await
+            }
+            Message::Bundle(transactions) => self.handle_bundle(transactions).await,
             Message::BlockReq(req) => self.handle_block_req(req).await,
+            Message::HeaderReq(req) => self.handle_header_req(req).await,
             Message::TransactionReq(req) => self.handle_transaction_req(req).await,
+            Message::ReceiptReq(hash) => self.handle_receipt_req(hash).await,
+            Message::AccountReq(address) => self.handle_account_req(address).await,
+            Message::RecoverSender(tx) => self.handle_recover_sender(tx).await,
+            Message::ChainInfoReq => self.handle_chain_info_req().await,
+            Message::MempoolStatusReq => self.handle_mempool_status().await,
+            Message::StatusReq => Ok(self.handle_status_req()),
+            Message::SimulateTx(tx) => self.handle_simulation(tx).await,
+            Message::ImportBlock(block, token) => self.handle_import(block, &token).await,
+            Message::Admin(req, token) => Ok(self.handle_admin(req, &token).await),
+            Message::PauseReq(token) => self.handle_control(ControlMsg::Pause, &token).await,
+            Message::ResumeReq(token) => self.handle_control(ControlMsg::Resume, &token).await,
+            Message::PromoteReq(token) => self.handle_control(ControlMsg::Promote, &token).await,
+            Message::DemoteReq(token) => self.handle_control(ControlMsg::Demote, &token).await,
+            Message::LimitsReq => Ok(Message::Limits(*self.connection.limits())),
+            Message::Ping(nonce) => Ok(Message::Pong(nonce)),
+            Message::Batch(messages) => self.handle_batch(messages).await,
+            Message::Subscribe(_) => Ok(Message::InvalidMessage(String::from(
+                "Subscriptions are only accepted over a connection",
+            ))),
+            Message::AllowanceReq { owner, spender } => {
+                self.handle_allowance_req(owner, spender).await
+            }
 
             Message::Block(_) | Message::Blocks(_) => Ok(Message::InvalidMessage(String::from(
                 "The rpc server doesn't expect blocks",
             ))),
 
-            Message::InvalidMessage(_)
+            Message::Transactions(_)
+            | Message::IncompatiblePeer(_)
+            | Message::Receipt(_)
+            | Message::BundleReceipt(_)
+            | Message::SimulationResult(_)
+            | Message::Bans(_)
+            | Message::Unauthorized
+            | Message::Account(_)
+            | Message::Allowance(_)
+            | Message::Status { .. }
+            | Message::Pruned(_)
+            | Message::ChainInfo(_)
+            | Message::MempoolStatus { .. }
+            | Message::Limits(_)
+            | Message::Pong(_)
+            | Message::Header(_)
+            | Message::Headers(_)
+            | Message::InvalidMessage(_)
             | Message::Ok
             | Message::InternalError(_)
+            | Message::RateLimited { .. }
+            | Message::Busy { .. }
             | Message::InvalidTransaction
             | Message::NonExistentBlock
-            | Message::NonExistentTx => Ok(Message::InvalidMessage(String::new())),
+            | Message::NonExistentTx
+            | Message::NonExistentAccount
+            | Message::BatchResponse(_)
+            | Message::TransactionAccepted { .. }
+            | Message::SubmissionResults(_)
+            | Message::PeerStats(_) => Ok(Message::InvalidMessage(String::new())),
+            // Sent by a peer on a newer protocol, which may still use the messages it shares
+            // with this build
+            Message::Unknown(kind) => Ok(Message::InvalidMessage(format!(
+                "unsupported message type {kind}"
+            ))),
+        }
+    }
+
+    /// Answers every request of a [Message::Batch] in order, as if they were sent one by one
+    async fn handle_batch(&mut self, messages: Vec<Message>) -> Result<Message, Error> {
+        let mut responses = Vec::with_capacity(messages.len());
+        for msg in messages {
+            self.peer.record_message(msg.kind());
+            let response = match msg {
+                Message::Batch(_) => {
+                    Message::InvalidMessage(String::from("Batches can't be nested"))
+                }
+                msg => match self.throttle(&msg) {
+                    Some(response) => response,
+                    None => Box::pin(self.handle_message(msg)).await?,
+                },
+            };
+            responses.push(response);
         }
+        Ok(Message::BatchResponse(responses))
+    }
+
+    /// Answers with the node's own [Message::Hello] if the peer speaks the same protocol
+    /// version on the same chain and hashes it the same
+    fn handle_hello(
+        &self,
+        protocol_version: u32,
+        chain_id: u64,
+        genesis_hash: B256,
+        hash_algo: HashAlgo,
+    ) -> Message {
+        let reason = if protocol_version != PROTOCOL_VERSION {
+            format!("protocol version {protocol_version}, the node speaks {PROTOCOL_VERSION}")
+        } else if chain_id != self.spec.chain_id() {
+            format!("chain {chain_id}, the node is on {}", self.spec.chain_id())
+        } else if genesis_hash != self.spec.genesis_hash() {
+            format!(
+                "genesis {genesis_hash}, the node started from {}",
+                self.spec.genesis_hash()
+            )
+        } else if hash_algo != self.spec.hash_algo() {
+            format!(
+                "hash algorithm {hash_algo:?}, the node hashes with {:?}",
+                self.spec.hash_algo()
+            )
+        } else {
+            return Message::hello(&self.spec);
+        };
+        Message::IncompatiblePeer(reason)
     }
 
     pub async fn handle_transaction(&self, tx: Transaction) -> Result<Message, Error> {
-        let (result, tx) = tokio::task::spawn_blocking(move || (tx.verify(), tx)).await?;
+        let tx = match self.admit(vec![tx]).await? {
+            Some(mut admitted) => match admitted.pop() {
+                Some(tx) => tx,
+                None => return Ok(Message::InvalidTransaction),
+            },
+            None => return Ok(busy()),
+        };
+        // Admission verified the hash against the contents
+        let hash = tx.hash;
 
-        if !result {
-            return Ok(Message::InvalidTransaction);
+        // Send the transaction to the mempool to include it into the mempool
+        if let Some(response) = to_mempool(&self.server_mempool_tx, tx) {
+            return Ok(response);
         }
 
-        // Send the transaction to the mempool to include it into the mempool
-        if let Err(e) = self.server_mempool_tx.send(tx).await {
-            error!(err = %e, "Couldn't send transaction over the channel to the mempool");
-            return Ok(Message::InternalError(format!("Internal error: {}", e)));
+        Ok(Message::TransactionAccepted { hash })
+    }
+
+    /// Verifies all of `transactions` as a single job and hands the valid ones to the
+    /// mempool, the others are rejected with their [crate::FailureReason]
+    pub async fn handle_submit_transactions(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> Result<Message, Error> {
+        let verified = match self.validate(transactions).await? {
+            Some(verified) => verified,
+            None => return Ok(busy()),
+        };
+
+        let mut results = Vec::with_capacity(verified.len());
+        for (tx, outcome) in verified {
+            if let ValidationOutcome::Invalid(reason) = outcome {
+                results.push(SubmissionResult::Rejected(reason));
+                continue;
+            }
+
+            let hash = tx.hash;
+            match to_mempool(&self.server_mempool_tx, tx) {
+                None => results.push(SubmissionResult::Accepted(hash)),
+                Some(Message::Busy { retry_after_ms }) => {
+                    results.push(SubmissionResult::Busy { retry_after_ms })
+                }
+                Some(response) => return Ok(response),
+            }
+        }
+
+        Ok(Message::SubmissionResults(results))
+    }
+
+    /// Every member has to pass admission, a single invalid one rejects the whole bundle
+    pub async fn handle_bundle(&self, transactions: Vec<Transaction>) -> Result<Message, Error> {
+        let server_bundle_tx = match &self.server_bundle_tx {
+            Some(server_bundle_tx) => server_bundle_tx,
+            None => {
+                return Ok(Message::InvalidMessage(String::from(
+                    "The node doesn't accept bundles",
+                )))
+            }
+        };
+
+        let bundle = match Bundle::new(transactions, self.spec.hash_algo()) {
+            Ok(bundle) => bundle,
+            Err(e) => return Ok(Message::InvalidMessage(e.to_string())),
+        };
+
+        let len = bundle.len();
+        match self.admit(bundle.transactions().to_vec()).await? {
+            Some(transactions) if transactions.len() == len => {}
+            Some(_) => return Ok(Message::InvalidTransaction),
+            None => return Ok(busy()),
+        }
+
+        if let Some(response) = to_mempool(server_bundle_tx, bundle) {
+            return Ok(response);
         }
 
         Ok(Message::Ok)
     }
 
+    /// Runs the admission checks of the [TxValidator], handing back the valid transactions
+    ///
+    /// Checked against the latest written state so obviously doomed transactions don't reach
+    /// the mempool, verifying signatures is expensive so it runs off the runtime. `None` if the
+    /// [VerifierPool] has no room for them
+    async fn admit(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> Result<Option<Vec<Transaction>>, Error> {
+        let verified = self.validate(transactions).await?;
+        Ok(verified.map(|verified| {
+            verified
+                .into_iter()
+                .filter_map(|(tx, outcome)| (outcome == ValidationOutcome::Valid).then_some(tx))
+                .collect()
+        }))
+    }
+
+    /// Runs the admission checks on every one of `transactions` in one go, `None` if the
+    /// [VerifierPool] has no room for them
+    async fn validate(&self, transactions: Vec<Transaction>) -> Result<Option<Verified>, Error> {
+        if let Some(verifier) = &self.verifier {
+            return verifier.verify(transactions).await;
+        }
+
+        let db = self.db.clone();
+        let validator = self.validator.clone();
+        let config = ValidationConfig::from(self.spec.as_ref());
+        let verified = tokio::task::spawn_blocking(move || {
+            validate_all(&*db.blocking_read(), &validator, config, transactions)
+        })
+        .await?;
+
+        Ok(Some(verified))
+    }
+
     pub async fn handle_block_req(&self, block_req: BlockReq) -> Result<Message, Error> {
-        let db = self.db.read().await;
-        let block = match block_req {
-            BlockReq::Hash(hash) => db.read_block_by_hash(&hash),
-            BlockReq::Number(number) => db.read_block_by_number(number),
-            BlockReq::Range { .. } => unimplemented!("Block range is not yet implemented"),
-        };
+        if let BlockReq::Range { start, end } = block_req {
+            return self.handle_block_range(start, end).await;
+        }
 
-        match block {
+        let db = self.db.read().await;
+        match find_block(&*db, &block_req) {
+            Some(block) if block.number() < db.retention_horizon() => {
+                Ok(Message::Pruned(Pruned::Block {
+                    header: block.header().clone(),
+                    transactions: db.read_block_summaries(block.number()).unwrap_or_default(),
+                }))
+            }
             Some(block) => Ok(Message::Block(block.clone())),
             None => Ok(Message::NonExistentBlock),
         }
     }
 
+    /// Blocks `start..=end` the node has in full, missing and pruned ones are skipped
+    async fn handle_block_range(&self, start: u64, end: u64) -> Result<Message, Error> {
+        let max = self.connection.limits().max_blocks_per_message;
+        if range_len(start, end) > max {
+            let e = Error::LimitExceeded {
+                limit: "blocks per message",
+                max,
+            };
+            return Ok(Message::InvalidMessage(e.to_string()));
+        }
+
+        let db = self.db.read().await;
+        let blocks = (start.max(db.retention_horizon())..=end)
+            .filter_map(|number| db.read_block_by_number(number))
+            .cloned()
+            .collect();
+
+        Ok(Message::Blocks(blocks))
+    }
+
+    /// Pruned blocks still have their header, so they are answered like any other
+    pub async fn handle_header_req(&self, block_req: BlockReq) -> Result<Message, Error> {
+        let db = self.db.read().await;
+
+        if let BlockReq::Range { start, end } = block_req {
+            // The connection already rejected ranges that are too long. Like the blocks of
+            // [Handler::handle_block_range] the range includes `end`
+            let headers = (start..=end)
+                .map_while(|number| db.read_block_by_number(number))
+                .map(|block| block.header().clone())
+                .collect();
+            return Ok(Message::Headers(headers));
+        }
+
+        match find_block(&*db, &block_req) {
+            Some(block) => Ok(Message::Header(block.header().clone())),
+            None => Ok(Message::NonExistentBlock),
+        }
+    }
+
     pub async fn handle_transaction_req(&self, tx_req: TransactionReq) -> Result<Message, Error> {
         let db = self.db.read().await;
 
         let transaction = match tx_req {
-            TransactionReq::Hash(hash) => db.read_transaction(&hash),
-            TransactionReq::Many(_) => {
-                unimplemented!("Can't yet ask for many transactions at once")
+            TransactionReq::Hash(hash) => {
+                if let Some(summary) = db.read_transaction_summary(&hash) {
+                    return Ok(Message::Pruned(Pruned::Transaction(summary.clone())));
+                }
+                db.read_transaction(&hash)
+            }
+            TransactionReq::Many(hashes) => {
+                if hashes.len() > MAX_TRANSACTIONS_PER_REQUEST {
+                    return Ok(Message::InvalidMessage(format!(
+                        "At most {} transactions can be requested at once",
+                        MAX_TRANSACTIONS_PER_REQUEST
+                    )));
+                }
+
+                let transactions = hashes
+                    .iter()
+                    .map(|hash| match db.read_transaction_summary(hash) {
+                        Some(summary) => TransactionEntry::Pruned(summary.clone()),
+                        None => match db.read_transaction(hash) {
+                            Some(tx) => TransactionEntry::Found(tx.clone()),
+                            None => TransactionEntry::NotFound,
+                        },
+                    })
+                    .collect();
+                return Ok(Message::Transactions(transactions));
             }
         };
 
@@ -147,4 +1001,1773 @@ where
             None => Ok(Message::NonExistentTx),
         }
     }
+
+    pub async fn handle_receipt_req(&self, hash: B256) -> Result<Message, Error> {
+        let db = self.db.read().await;
+
+        if let Some(receipt) = db.read_transaction_receipt(&hash) {
+            return Ok(Message::Receipt(receipt.clone()));
+        }
+        if let Some(receipt) = db.read_bundle_receipt(&hash) {
+            return Ok(Message::BundleReceipt(receipt.clone()));
+        }
+
+        match db.read_transaction_summary(&hash) {
+            Some(summary) => Ok(Message::Pruned(Pruned::Transaction(summary.clone()))),
+            None => Ok(Message::NonExistentTx),
+        }
+    }
+
+    pub async fn handle_account_req(&self, address: Address) -> Result<Message, Error> {
+        let db = self.db.read().await;
+
+        if let Some(account) = db.read_account(&address) {
+            return Ok(Message::Account(*account));
+        }
+
+        // A deleted account only has its nonce left, which its next transaction has to use
+        match db.read_tombstone(&address) {
+            Some(nonce) => Ok(Message::Account(Account::new(0, nonce))),
+            None => Ok(Message::NonExistentAccount),
+        }
+    }
+
+    pub async fn handle_recover_sender(&self, tx: Transaction) -> Result<Message, Error> {
+        // Recovered from the hash the transaction should have, not the one it claims
+        let algo = self.spec.hash_algo();
+        match utils::recover_address(tx.hash(algo), tx.v, tx.r, tx.s, algo) {
+            Ok(address) => self.handle_account_req(address).await,
+            Err(_) => Ok(Message::InvalidTransaction),
+        }
+    }
+
+    pub async fn handle_mempool_status(&self) -> Result<Message, Error> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if let Err(e) = self.mempool_status_tx.send(reply_tx).await {
+            error!(err = %e, "Couldn't ask the mempool for its status");
+            return Ok(Message::InternalError(format!("Internal error: {}", e)));
+        }
+
+        match reply_rx.await {
+            Ok(stats) => Ok(Message::MempoolStatus {
+                pending: stats.pending,
+                evicted: stats.evicted,
+                expired: stats.expired,
+                oldest_pending: stats.oldest_pending,
+            }),
+            Err(e) => {
+                error!(err = %e, "Mempool didn't answer the status request");
+                Ok(Message::InternalError(format!("Internal error: {}", e)))
+            }
+        }
+    }
+
+    /// Answered from the [NodeStatus] alone, the database isn't locked
+    pub fn handle_status_req(&self) -> Message {
+        let status = match &self.status {
+            Some(status) => status.report(),
+            None => {
+                return Message::InvalidMessage(String::from("The node doesn't report its status"))
+            }
+        };
+
+        Message::Status {
+            uptime_secs: status.uptime_secs,
+            head_number: status.head_number,
+            head_hash: status.head_hash,
+            pending_txs: status.pending_txs,
+            peer_count: status.peer_count,
+            version: status.version,
+            role: status.role,
+            last_failure: status.last_failure,
+            network_head: status.network_head,
+            lag_blocks: status.lag_blocks,
+        }
+    }
+
+    pub async fn handle_simulation(&self, tx: Transaction) -> Result<Message, Error> {
+        let simulation_tx = match &self.simulation_tx {
+            Some(simulation_tx) => simulation_tx,
+            None => {
+                return Ok(Message::InvalidMessage(String::from(
+                    "The node doesn't simulate transactions",
+                )))
+            }
+        };
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if let Err(e) = simulation_tx.send((tx, reply_tx)).await {
+            error!(err = %e, "Couldn't ask the executor for a simulation");
+            return Ok(Message::InternalError(format!("Internal error: {}", e)));
+        }
+
+        match reply_rx.await {
+            Ok(receipt) => Ok(Message::SimulationResult(receipt)),
+            Err(e) => {
+                error!(err = %e, "Executor didn't answer the simulation");
+                Ok(Message::InternalError(format!("Internal error: {}", e)))
+            }
+        }
+    }
+
+    /// Blocks credit their reward to the coinbase in their header, so only the operator may
+    /// hand the node one
+    pub async fn handle_import(&self, block: SealedBlock, token: &str) -> Result<Message, Error> {
+        if !self.authorized(token) {
+            return Ok(Message::Unauthorized);
+        }
+        let import_tx = match &self.import_tx {
+            Some((import_tx, false)) => import_tx,
+            Some((import_tx, true)) if self.role() == NodeRole::Follower => import_tx,
+            Some(_) => {
+                return Ok(Message::InvalidMessage(String::from(
+                    "The node produces its own blocks",
+                )))
+            }
+            None => {
+                return Ok(Message::InvalidMessage(String::from(
+                    "The node doesn't import blocks",
+                )))
+            }
+        };
+
+        let (reply_tx, reply_rx) = oneshot::channel();
+        if let Err(e) = import_tx.send((block, reply_tx)).await {
+            error!(err = %e, "Couldn't hand the block to the executor");
+            return Ok(Message::InternalError(format!("Internal error: {}", e)));
+        }
+
+        match reply_rx.await {
+            Ok(Ok(())) => Ok(Message::Ok),
+            Ok(Err(e)) => Ok(Message::InvalidMessage(e.to_string())),
+            Err(e) => {
+                error!(err = %e, "Executor didn't answer the import");
+                Ok(Message::InternalError(format!("Internal error: {}", e)))
+            }
+        }
+    }
+
+    /// Answers with the black list after applying `req`, nodes without an admin token answer
+    /// every request as unauthorized
+    pub async fn handle_admin(&self, req: AdminReq, token: &str) -> Message {
+        let (black_list, _, ip) = match &self.admin {
+            Some(admin) if self.authorized(token) => admin,
+            _ => return Message::Unauthorized,
+        };
+
+        // Only reads the statistics, the black list stays unlocked
+        if let AdminReq::PeerStats { ip } = req {
+            return match &self.peer_stats {
+                Some(peer_stats) => Message::PeerStats(peer_stats.snapshot(ip)),
+                None => {
+                    Message::InvalidMessage(String::from("The node doesn't keep peer statistics"))
+                }
+            };
+        }
+
+        let mut black_list = black_list.write().await;
+        let changed = match &req {
+            AdminReq::Ban(entry) => BlackList::parse_entry(entry).map(|e| black_list.insert(e)),
+            AdminReq::Unban(entry) => BlackList::parse_entry(entry).map(|e| black_list.remove(e)),
+            AdminReq::ListBans | AdminReq::PeerStats { .. } => Ok(false),
+        };
+        match changed {
+            Ok(changed) => {
+                if changed {
+                    info!(%ip, ?req, "Black list changed by admin");
+                }
+                Message::Bans(black_list.entries())
+            }
+            Err(e) => Message::InvalidMessage(e.to_string()),
+        }
+    }
+
+    pub async fn handle_control(&self, msg: ControlMsg, token: &str) -> Result<Message, Error> {
+        if !self.authorized(token) {
+            return Ok(Message::Unauthorized);
+        }
+        let server_control_tx = match &self.server_control_tx {
+            Some(server_control_tx) => server_control_tx,
+            None => {
+                return Ok(Message::InvalidMessage(String::from(
+                    "The node doesn't take control requests",
+                )))
+            }
+        };
+
+        if let Err(e) = server_control_tx.send(msg).await {
+            error!(err = %e, "Couldn't pass the control request on to the server");
+            return Ok(Message::InternalError(format!("Internal error: {}", e)));
+        }
+        Ok(Message::Ok)
+    }
+
+    /// Whether `token` is the admin token, nodes without one authorize nothing
+    fn authorized(&self, token: &str) -> bool {
+        let (expected, ip) = match &self.admin {
+            Some((_, expected, ip)) => (expected, ip),
+            None => return false,
+        };
+        let authorized = token_matches(expected, token);
+        if !authorized {
+            warn!(%ip, "Unauthorized admin request");
+        }
+        authorized
+    }
+
+    pub async fn handle_chain_info_req(&self) -> Result<Message, Error> {
+        let db = self.db.read().await;
+
+        let (head_block, head_hash) = match db.latest_block() {
+            Some(head) => (head.number(), *head.get_hash()),
+            None => (0, B256::ZERO),
+        };
+
+        let network_head = self
+            .status
+            .as_ref()
+            .and_then(|status| status.network_head());
+        Ok(Message::ChainInfo(ChainInfo {
+            // The server refuses to start on a database of another chain
+            chain_id: self.spec.chain_id(),
+            head_block,
+            head_hash,
+            block_time: self.block_time,
+            role: self.role(),
+            coinbase_maturity: self.spec.coinbase_maturity(),
+            network_head,
+            lag_blocks: network_head.map(|network_head| network_head.saturating_sub(head_block)),
+        }))
+    }
+
+    /// Role the [NodeStatus] reports, nodes that don't report their status only produce
+    fn role(&self) -> NodeRole {
+        self.status
+            .as_ref()
+            .map_or(NodeRole::Producer, |status| status.role())
+    }
+
+    pub async fn handle_allowance_req(
+        &self,
+        owner: Address,
+        spender: Address,
+    ) -> Result<Message, Error> {
+        let db = self.db.read().await;
+        Ok(Message::Allowance(db.read_allowance(&owner, &spender)))
+    }
+}
+
+/// Looks up a single block, [BlockReq::Range] never matches
+fn find_block<'a, DB: DatabaseReader>(db: &'a DB, block_req: &BlockReq) -> Option<&'a SealedBlock> {
+    match *block_req {
+        BlockReq::Hash(hash) => db.read_block_by_hash(&hash),
+        BlockReq::Number(number) => db.read_block_by_number(number),
+        BlockReq::Timestamp(timestamp) => db
+            .find_block_by_timestamp(timestamp)
+            .and_then(|number| db.read_block_by_number(number)),
+        BlockReq::Latest => db.latest_block(),
+        BlockReq::Earliest => db
+            .earliest_block_number()
+            .and_then(|number| db.read_block_by_number(number)),
+        BlockReq::Range { .. } => None,
+    }
+}
+
+/// Fails with [Error::IdleTimeout] if `read` doesn't complete within `idle_timeout`
+async fn idle<T>(
+    idle_timeout: Option<Duration>,
+    read: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match idle_timeout {
+        Some(idle_timeout) => time::timeout(idle_timeout, read)
+            .await
+            .map_err(|_| Error::IdleTimeout(idle_timeout))?,
+        None => read.await,
+    }
+}
+
+/// Writes `msg` to the peer, which has [SHUTDOWN_GRACE] left to read it once the node shuts
+/// down, so a peer that stopped reading can't hold the node open
+async fn write_message<S: AsyncRead + AsyncWrite + Unpin>(
+    connection: &mut Connection<S>,
+    shutdown: Option<&mut Shutdown>,
+    msg: &Message,
+) -> Result<(), Error> {
+    let shutdown = match shutdown {
+        Some(shutdown) => shutdown,
+        None => return connection.write_message(msg).await,
+    };
+
+    let write = connection.write_message(msg);
+    tokio::pin!(write);
+    select! {
+        written = &mut write => return written,
+        _ = shutdown.recv() => {}
+    }
+
+    time::timeout(SHUTDOWN_GRACE, write)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+/// Hands `item` to the mempool without waiting for room, returns what to answer instead if
+/// it didn't take it, so a full channel answers [Message::Busy] instead of stalling the peer
+fn to_mempool<T>(sender: &mpsc::Sender<T>, item: T) -> Option<Message> {
+    match sender.try_send(item) {
+        Ok(()) => None,
+        Err(TrySendError::Full(_)) => Some(busy()),
+        Err(e) => {
+            error!(err = %e, "Couldn't send over the channel to the mempool");
+            Some(Message::InternalError(format!("Internal error: {}", e)))
+        }
+    }
+}
+
+/// Asks the peer to back off while the node can't keep up with submissions
+fn busy() -> Message {
+    Message::Busy {
+        retry_after_ms: BUSY_RETRY_AFTER.as_millis() as u64,
+    }
+}
+
+/// Compares every byte, so how long it takes doesn't tell how much of the token was right
+fn token_matches(expected: &str, token: &str) -> bool {
+    expected.len() == token.len()
+        && expected
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        client::ReportBuilder,
+        utils::{addr, random_keypair, sign_transaction},
+        BanPolicy, Block, BlockHeader, CoinbaseMaturity, FailureReason, InMemoryDB, MempoolStats,
+        NodeLimits, TransactionReceipt, TransactionSummary, Transactions,
+    };
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+        net::{tcp::OwnedReadHalf, TcpListener, TcpStream},
+    };
+
+    /// Handler on the server side of a local connection, the client side is returned as well
+    /// so it stays open
+    async fn handler(db: InMemoryDB) -> (Handler<InMemoryDB>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let (server_mempool_tx, _) = mpsc::channel(1);
+        let (mempool_status_tx, _) = mpsc::channel(1);
+        let handler = Handler::new(
+            Arc::new(RwLock::new(db)),
+            Connection::new(server.into()),
+            server_mempool_tx,
+            mempool_status_tx,
+            Arc::new(ChainSpec::new(7)),
+            10,
+            Arc::default(),
+        );
+        (handler, client)
+    }
+
+    fn transaction(nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            nonce,
+            ..Default::default()
+        };
+        tx.hash = tx.hash(HashAlgo::Keccak256);
+        tx
+    }
+
+    #[tokio::test]
+    async fn test_many_transactions() {
+        let mut db = InMemoryDB::new();
+        let known = transaction(0);
+        db.write_transaction(known.clone()).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let missing = transaction(1).hash;
+        let req = TransactionReq::Many(vec![missing, known.hash]);
+        let response = handler
+            .handle_message(Message::TransactionReq(req))
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Message::Transactions(vec![
+                TransactionEntry::NotFound,
+                TransactionEntry::Found(known)
+            ])
+        );
+
+        let req = TransactionReq::Many(Vec::new());
+        let response = handler
+            .handle_message(Message::TransactionReq(req))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Transactions(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_too_many_transactions() {
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+
+        let req = TransactionReq::Many(vec![B256::ZERO; MAX_TRANSACTIONS_PER_REQUEST + 1]);
+        let response = handler
+            .handle_message(Message::TransactionReq(req))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::InvalidMessage(_)));
+    }
+
+    #[tokio::test]
+    async fn test_receipt_req() {
+        let mut db = InMemoryDB::new();
+        let tx = transaction(0);
+        let receipt = TransactionReceipt {
+            success: true,
+            block_number: 1,
+            ..Default::default()
+        };
+        db.write_transaction_receipt(tx.hash, receipt.clone())
+            .unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let response = handler
+            .handle_message(Message::ReceiptReq(tx.hash))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Receipt(receipt));
+
+        let response = handler
+            .handle_message(Message::ReceiptReq(B256::ZERO))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::NonExistentTx);
+    }
+
+    #[tokio::test]
+    async fn test_bundle_is_forwarded() {
+        let key = random_keypair();
+        let signed = |nonce| {
+            let mut tx = Transaction {
+                from: addr(&key, HashAlgo::Keccak256),
+                nonce,
+                ..Default::default()
+            };
+            sign_transaction(&mut tx, 7, &key, HashAlgo::Keccak256);
+            tx
+        };
+
+        let (bundle_tx, mut bundle_rx) = mpsc::channel(1);
+        let (handler, _client) = handler(InMemoryDB::new()).await;
+        let mut handler = handler.with_bundles(bundle_tx);
+
+        let members = vec![signed(0), signed(1)];
+        let response = handler
+            .handle_message(Message::Bundle(members.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Ok);
+        assert_eq!(bundle_rx.recv().await.unwrap().transactions(), &members[..]);
+
+        // One unsigned member rejects the whole bundle
+        let response = handler
+            .handle_message(Message::Bundle(vec![signed(0), transaction(1)]))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::InvalidTransaction);
+
+        // Signatures made for another chain don't carry over
+        let mut replayed = signed(0);
+        sign_transaction(&mut replayed, 1, &key, HashAlgo::Keccak256);
+        let response = handler
+            .handle_message(Message::Bundle(vec![replayed]))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::InvalidTransaction);
+
+        let response = handler
+            .handle_message(Message::Bundle(Vec::new()))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::InvalidMessage(_)));
+        assert!(bundle_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sender_is_recovered() {
+        let key = random_keypair();
+        let mut tx = Transaction {
+            from: addr(&key, HashAlgo::Keccak256),
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 7, &key, HashAlgo::Keccak256);
+
+        let mut db = InMemoryDB::new();
+        db.write_account(tx.from, Account::new(500, 3)).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let response = handler
+            .handle_message(Message::RecoverSender(tx.clone()))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Account(Account::new(500, 3)));
+
+        // Signed by someone that never touched the chain
+        let mut other = tx.clone();
+        other.nonce = 1;
+        other.hash = other.hash(HashAlgo::Keccak256);
+        let response = handler
+            .handle_message(Message::RecoverSender(other))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::NonExistentAccount);
+
+        tx.v = 4;
+        let response = handler
+            .handle_message(Message::RecoverSender(tx))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::InvalidTransaction);
+    }
+
+    #[tokio::test]
+    async fn test_account_req() {
+        let mut db = InMemoryDB::new();
+        let address = Address::repeat_byte(1);
+        db.write_account(address, Account::new(500, 3)).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let response = handler
+            .handle_message(Message::AccountReq(address))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Account(Account::new(500, 3)));
+
+        let response = handler
+            .handle_message(Message::AccountReq(Address::ZERO))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::NonExistentAccount);
+    }
+
+    #[tokio::test]
+    async fn test_account_req_of_a_deleted_account() {
+        let mut db = InMemoryDB::new();
+        let address = Address::repeat_byte(1);
+        db.write_account(address, Account::new(500, 3)).unwrap();
+        db.delete_account(&address, 4).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let response = handler
+            .handle_message(Message::AccountReq(address))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Account(Account::new(0, 4)));
+    }
+
+    #[tokio::test]
+    async fn test_batch() {
+        let mut db = InMemoryDB::new();
+        let address = Address::repeat_byte(1);
+        db.write_account(address, Account::new(500, 3)).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let batch = Message::Batch(vec![
+            Message::AccountReq(address),
+            Message::ReceiptReq(B256::ZERO),
+            Message::Batch(vec![Message::LimitsReq]),
+            Message::Ping(7),
+        ]);
+        let response = handler.handle_message(batch).await.unwrap();
+        assert_eq!(
+            response,
+            Message::BatchResponse(vec![
+                Message::Account(Account::new(500, 3)),
+                Message::NonExistentTx,
+                Message::InvalidMessage(String::from("Batches can't be nested")),
+                Message::Pong(7),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_report_matches_the_fixture_chain() {
+        let fixture = crate::fixtures::build(crate::fixtures::Scenario::Standard)
+            .await
+            .unwrap();
+        let manifest = fixture.manifest;
+        let address = manifest.accounts["alice"];
+        let account = *fixture.db.read_account(&address).unwrap();
+        let (_, allocated) = fixture
+            .spec
+            .iter_accounts()
+            .find(|(allocated, _)| **allocated == address)
+            .unwrap();
+        let allocated = allocated.balance();
+
+        // What the blocks and receipts of the chain say about the address
+        let mut expected = Vec::new();
+        for number in 0..=manifest.head_number {
+            let block = fixture.db.read_block_by_number(number).unwrap();
+            for tx in block.transactions() {
+                if tx.from == address || tx.to == address {
+                    let receipt = fixture.db.read_transaction_receipt(&tx.hash).unwrap();
+                    expected.push(TransactionSummary::new(tx, number, receipt.success));
+                }
+            }
+        }
+        let successful = expected.iter().filter(|summary| summary.success).count();
+
+        let (mut handler, _client) = handler(fixture.db).await;
+        let (mempool_status_tx, mut mempool_status_rx) = mpsc::channel(1);
+        handler.mempool_status_tx = mempool_status_tx;
+        tokio::spawn(async move {
+            let reply: oneshot::Sender<MempoolStats> = mempool_status_rx.recv().await.unwrap();
+            let _ = reply.send(MempoolStats {
+                pending: 3,
+                ..Default::default()
+            });
+        });
+
+        let mut builder = ReportBuilder::new(address, 5);
+        let mut batches = 0;
+        while let Some(requests) = builder.next_requests() {
+            let response = handler
+                .handle_message(Message::Batch(requests))
+                .await
+                .unwrap();
+            let Message::BatchResponse(responses) = response else {
+                panic!("Expected a batch response, got {response:?}");
+            };
+            builder.add_responses(responses).unwrap();
+            batches += 1;
+        }
+        let report = builder.finish().unwrap();
+
+        assert_eq!(report.balance, account.balance());
+        assert_eq!(report.nonce, account.nonce());
+        assert_eq!(report.head_number, manifest.head_number);
+        assert_eq!(report.head_hash, manifest.head_hash);
+        assert_eq!(report.mempool_pending, Some(3));
+        assert_eq!(report.unavailable, vec![String::from("label")]);
+
+        // The account, head, limits and mempool, then the blocks and the receipts
+        let limits = NodeLimits::default();
+        let blocks = (manifest.head_number as usize + 1).div_ceil(limits.max_blocks_per_message);
+        let receipts = expected.len().div_ceil(limits.max_batch_size);
+        assert_eq!(batches, 1 + blocks + receipts);
+
+        let history = report.history.unwrap();
+        assert_eq!(history.successful, successful);
+        assert_eq!(history.failed, expected.len() - successful);
+        assert_eq!(history.first_seen, Some(expected[0].block_number));
+        assert_eq!(
+            history.last_active,
+            Some(expected[expected.len() - 1].block_number)
+        );
+        let last: Vec<_> = expected.iter().rev().take(5).cloned().collect();
+        assert_eq!(history.transactions, last);
+
+        // The address is no coinbase, so only its transactions moved its balance
+        assert_eq!(
+            allocated + history.total_received - history.total_sent,
+            account.balance()
+        );
+        // Only the transactions it sent itself used up its nonce
+        let sent = expected
+            .iter()
+            .filter(|summary| summary.success && summary.from == address)
+            .count();
+        assert_eq!(sent as u64, account.nonce());
+    }
+
+    #[tokio::test]
+    async fn test_submitted_transactions_are_judged_one_by_one() {
+        let key = random_keypair();
+        let signed = |nonce| {
+            let mut tx = Transaction {
+                from: addr(&key, HashAlgo::Keccak256),
+                nonce,
+                ..Default::default()
+            };
+            sign_transaction(&mut tx, 7, &key, HashAlgo::Keccak256);
+            tx
+        };
+
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+        let (mempool_tx, mut mempool_rx) = mpsc::channel(3);
+        handler.server_mempool_tx = mempool_tx;
+
+        let transactions = vec![signed(0), transaction(1), signed(2)];
+        let response = handler
+            .handle_message(Message::SubmitTransactions(transactions.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Message::SubmissionResults(vec![
+                SubmissionResult::Accepted(transactions[0].hash),
+                SubmissionResult::Rejected(FailureReason::WrongChainId),
+                SubmissionResult::Accepted(transactions[2].hash),
+            ])
+        );
+        assert_eq!(mempool_rx.recv().await.unwrap(), transactions[0]);
+        assert_eq!(mempool_rx.recv().await.unwrap(), transactions[2]);
+        assert!(mempool_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_full_mempool_answers_busy() {
+        let key = random_keypair();
+        let signed = |nonce| {
+            let mut tx = Transaction {
+                from: addr(&key, HashAlgo::Keccak256),
+                nonce,
+                ..Default::default()
+            };
+            sign_transaction(&mut tx, 7, &key, HashAlgo::Keccak256);
+            tx
+        };
+
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+        let (mempool_tx, mempool_rx) = mpsc::channel(1);
+        handler.server_mempool_tx = mempool_tx;
+
+        let response = handler
+            .handle_message(Message::Transaction(signed(0)))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::TransactionAccepted { .. }));
+
+        // The mempool didn't take the first one yet, the handler doesn't wait for it
+        let busy = Message::Busy {
+            retry_after_ms: BUSY_RETRY_AFTER.as_millis() as u64,
+        };
+        let response = time::timeout(
+            Duration::from_secs(1),
+            handler.handle_message(Message::Transaction(signed(1))),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(response, busy);
+
+        let response = handler
+            .handle_message(Message::SubmitTransactions(vec![signed(1), transaction(2)]))
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Message::SubmissionResults(vec![
+                SubmissionResult::Busy {
+                    retry_after_ms: BUSY_RETRY_AFTER.as_millis() as u64,
+                },
+                SubmissionResult::Rejected(FailureReason::WrongChainId),
+            ])
+        );
+
+        // A mempool that is gone is an error of the node
+        drop(mempool_rx);
+        let response = handler
+            .handle_message(Message::Transaction(signed(1)))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::InternalError(_)));
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transactions_in_a_batch_are_offences() {
+        let (handler, _client) = handler(InMemoryDB::new()).await;
+        let policy = BanPolicy::new(3, Duration::from_secs(60));
+        let black_list = Arc::new(RwLock::new(BlackList::new(policy)));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        let mut handler = handler.with_black_list(black_list.clone(), ip);
+
+        // Unsigned, every one of them is rejected
+        let batch = Message::Batch(vec![
+            Message::Transaction(transaction(0)),
+            Message::Transaction(transaction(1)),
+        ]);
+        let (_, banned) = handler.respond(batch.clone()).await.unwrap();
+        assert!(!banned);
+        let (_, banned) = handler.respond(batch).await.unwrap();
+        assert!(banned);
+        assert!(black_list.read().await.is_banned(&ip));
+    }
+
+    #[tokio::test]
+    async fn test_pruned_requests() {
+        let mut db = InMemoryDB::new().with_retain_full_blocks(1);
+        let tx = transaction(0);
+        let header = BlockHeader {
+            number: 1,
+            ..Default::default()
+        };
+        let block = Block::new(header, vec![tx.clone()].into()).seal_slow(HashAlgo::Keccak256);
+        db.write_block(*block.get_hash(), block.clone()).unwrap();
+        let recent = transaction(1);
+        let next = Block::new(
+            BlockHeader {
+                number: 2,
+                ..Default::default()
+            },
+            vec![recent.clone()].into(),
+        )
+        .seal_slow(HashAlgo::Keccak256);
+        db.write_block(*next.get_hash(), next).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let summary = TransactionSummary::new(&tx, 1, false);
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Number(1)))
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Message::Pruned(Pruned::Block {
+                header: block.header().clone(),
+                transactions: vec![summary.clone()],
+            })
+        );
+
+        let response = handler
+            .handle_message(Message::ReceiptReq(tx.hash))
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Message::Pruned(Pruned::Transaction(summary.clone()))
+        );
+
+        // Entries on both sides of the horizon in one request
+        let req = TransactionReq::Many(vec![tx.hash, recent.hash]);
+        let response = handler
+            .handle_message(Message::TransactionReq(req))
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Message::Transactions(vec![
+                TransactionEntry::Pruned(summary),
+                TransactionEntry::Found(recent)
+            ])
+        );
+
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Number(2)))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::Block(_)));
+    }
+
+    #[tokio::test]
+    async fn test_block_tags() {
+        let (mut empty, _client) = handler(InMemoryDB::new()).await;
+        for tag in [BlockReq::Latest, BlockReq::Earliest] {
+            let response = empty.handle_message(Message::BlockReq(tag)).await.unwrap();
+            assert_eq!(response, Message::NonExistentBlock);
+        }
+
+        let mut db = InMemoryDB::new();
+        for number in 1..=3 {
+            let header = BlockHeader {
+                number,
+                ..Default::default()
+            };
+            let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+        let (mut handler, _client) = handler(db).await;
+
+        for (tag, number) in [(BlockReq::Latest, 3), (BlockReq::Earliest, 1)] {
+            let response = handler.handle_message(Message::BlockReq(tag)).await;
+            match response.unwrap() {
+                Message::Block(block) => assert_eq!(block.number(), number),
+                other => panic!("unexpected response {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_header_req() {
+        let mut db = InMemoryDB::new().with_retain_full_blocks(1);
+        let mut headers = Vec::new();
+        for number in 1..=3 {
+            let header = BlockHeader {
+                number,
+                ..Default::default()
+            };
+            let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+            headers.push(block.header().clone());
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+        let (mut handler, _client) = handler(db).await;
+
+        // Block 1 is pruned, its header is still served
+        let response = handler
+            .handle_message(Message::HeaderReq(BlockReq::Number(1)))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Header(headers[0].clone()));
+
+        let response = handler
+            .handle_message(Message::HeaderReq(BlockReq::Latest))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Header(headers[2].clone()));
+
+        let response = handler
+            .handle_message(Message::HeaderReq(BlockReq::Hash(B256::ZERO)))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::NonExistentBlock);
+
+        // Stops at the head
+        let response = handler
+            .handle_message(Message::HeaderReq(BlockReq::Range { start: 2, end: 10 }))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Headers(headers[1..].to_vec()));
+
+        // Up to and including the head, the pruned block 1 as well
+        let response = handler
+            .handle_message(Message::HeaderReq(BlockReq::Range { start: 1, end: 3 }))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Headers(headers.clone()));
+
+        let response = handler
+            .handle_message(Message::HeaderReq(BlockReq::Range { start: 3, end: 3 }))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Headers(headers[2..].to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_block_range_req() {
+        let mut db = InMemoryDB::new();
+        let mut blocks = Vec::new();
+        // Block 4 is missing
+        for number in [1, 2, 3, 5] {
+            let header = BlockHeader {
+                number,
+                ..Default::default()
+            };
+            let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+            blocks.push(block.clone());
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+        let (mut handler, _client) = handler(db).await;
+
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Range { start: 2, end: 10 }))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Blocks(blocks[1..].to_vec()));
+
+        // Both ends are included
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Range { start: 1, end: 1 }))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Blocks(blocks[..1].to_vec()));
+
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Range { start: 3, end: 2 }))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Blocks(Vec::new()));
+
+        let max = NodeLimits::default().max_blocks_per_message as u64;
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Range { start: 1, end: max }))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::Blocks(_)));
+
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Range {
+                start: 1,
+                end: max + 1,
+            }))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::InvalidMessage(_)));
+    }
+
+    #[tokio::test]
+    async fn test_block_range_skips_pruned() {
+        let mut db = InMemoryDB::new().with_retain_full_blocks(1);
+        let mut blocks = Vec::new();
+        for number in 1..=3 {
+            let header = BlockHeader {
+                number,
+                ..Default::default()
+            };
+            let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+            blocks.push(block.clone());
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+        let (mut handler, _client) = handler(db).await;
+
+        let response = handler
+            .handle_message(Message::BlockReq(BlockReq::Range { start: 1, end: 3 }))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Blocks(blocks[2..].to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_chain_info_req() {
+        let (mut empty, _client) = handler(InMemoryDB::new()).await;
+        let response = empty.handle_message(Message::ChainInfoReq).await.unwrap();
+        assert_eq!(
+            response,
+            Message::ChainInfo(ChainInfo {
+                chain_id: 7,
+                head_block: 0,
+                head_hash: B256::ZERO,
+                block_time: 10,
+                role: NodeRole::Producer,
+                coinbase_maturity: CoinbaseMaturity(1),
+                network_head: None,
+                lag_blocks: None,
+            })
+        );
+
+        let mut db = InMemoryDB::new();
+        let block = Block::new(
+            BlockHeader {
+                number: 1,
+                ..Default::default()
+            },
+            Default::default(),
+        )
+        .seal_slow(HashAlgo::Keccak256);
+        db.write_block(*block.get_hash(), block.clone()).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let status = Arc::new(NodeStatus::new());
+        status.set_role(NodeRole::Follower);
+        status.set_network_head(4);
+        handler = handler.with_status(status);
+
+        match handler.handle_message(Message::ChainInfoReq).await.unwrap() {
+            Message::ChainInfo(info) => {
+                assert_eq!(info.chain_id, 7);
+                assert_eq!(info.head_block, 1);
+                assert_eq!(info.head_hash, *block.get_hash());
+                assert_eq!(info.role, NodeRole::Follower);
+                assert_eq!(info.network_head, Some(4));
+                assert_eq!(info.lag_blocks, Some(3));
+            }
+            other => panic!("unexpected response {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limits_req() {
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+        let response = handler.handle_message(Message::LimitsReq).await.unwrap();
+        assert_eq!(response, Message::Limits(NodeLimits::default()));
+    }
+
+    #[tokio::test]
+    async fn test_ping() {
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+
+        // Answered without waiting for the database
+        let _writer = handler.db.clone().write_owned().await;
+        let response = handler.handle_message(Message::Ping(42)).await.unwrap();
+        assert_eq!(response, Message::Pong(42));
+    }
+
+    async fn read_raw(reader: &mut BufReader<OwnedReadHalf>) -> Message {
+        let mut line = String::new();
+        reader.read_line(&mut line).await.unwrap();
+        serde_json::from_str(line.trim_end()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_connection_answers_many_messages() {
+        let (handler, client) = handler(InMemoryDB::new()).await;
+        let handle = tokio::spawn(handler.handle_connection());
+
+        // Raw frames, the connection only ever writes valid ones
+        let (reader, mut writer) = client.into_split();
+        let mut reader = BufReader::new(reader);
+        writer.write_all(b"\"ChainInfoReq\"\r\n").await.unwrap();
+        assert!(matches!(read_raw(&mut reader).await, Message::ChainInfo(_)));
+
+        // Neither garbage nor a frame that is too large ends the session
+        writer.write_all(b"{not json\r\n").await.unwrap();
+        assert!(matches!(
+            read_raw(&mut reader).await,
+            Message::InvalidMessage(_)
+        ));
+
+        // Nor does a message of a newer protocol
+        writer
+            .write_all(b"{\"FutureReq\":{\"since\":1}}\r\n")
+            .await
+            .unwrap();
+        assert_eq!(
+            read_raw(&mut reader).await,
+            Message::InvalidMessage(String::from("unsupported message type FutureReq"))
+        );
+
+        let huge = vec![b' '; NodeLimits::default().max_frame_size + 1];
+        writer.write_all(&huge).await.unwrap();
+        writer.write_all(b"\r\n\"LimitsReq\"\r\n").await.unwrap();
+        assert!(matches!(
+            read_raw(&mut reader).await,
+            Message::InvalidMessage(_)
+        ));
+        assert_eq!(
+            read_raw(&mut reader).await,
+            Message::Limits(NodeLimits::default())
+        );
+
+        drop(writer);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_consecutive_malformed_messages_close_the_connection() {
+        let (handler, client) = handler(InMemoryDB::new()).await;
+        let handle = tokio::spawn(handler.handle_connection());
+
+        let (reader, mut writer) = client.into_split();
+        let mut reader = BufReader::new(reader);
+        // A valid message in between starts the count over
+        for _ in 1..MAX_CONSECUTIVE_MALFORMED {
+            writer.write_all(b"{not json\r\n").await.unwrap();
+            assert!(matches!(
+                read_raw(&mut reader).await,
+                Message::InvalidMessage(_)
+            ));
+        }
+        writer.write_all(b"\"ChainInfoReq\"\r\n").await.unwrap();
+        assert!(matches!(read_raw(&mut reader).await, Message::ChainInfo(_)));
+
+        for _ in 0..MAX_CONSECUTIVE_MALFORMED {
+            writer.write_all(b"{not json\r\n").await.unwrap();
+            assert!(matches!(
+                read_raw(&mut reader).await,
+                Message::InvalidMessage(_)
+            ));
+        }
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut rest = String::new();
+        assert_eq!(reader.read_line(&mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_block_subscription() {
+        let (handler, client) = handler(InMemoryDB::new()).await;
+        let (new_blocks, _) = broadcast::channel(16);
+        let (new_transactions, _) = broadcast::channel(16);
+        let (_notify_shutdown, shutdown) = broadcast::channel(1);
+        let handler = handler
+            .with_subscriptions(new_blocks.clone(), new_transactions)
+            .with_shutdown(shutdown, mpsc::channel(1).0);
+        let handle = tokio::spawn(handler.handle_connection());
+
+        let mut client = Connection::new(client);
+        client
+            .write_message(&Message::Subscribe(SubscriptionKind::Blocks))
+            .await
+            .unwrap();
+        assert_eq!(client.read_message().await.unwrap(), Some(Message::Ok));
+
+        let block = Block::new(BlockHeader::default(), Transactions::default())
+            .seal_slow(HashAlgo::Keccak256);
+        new_blocks.send(block.clone()).unwrap();
+        assert_eq!(
+            client.read_message().await.unwrap(),
+            Some(Message::Block(block))
+        );
+
+        // The handler notices the peer left and lets go of the subscription
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(new_blocks.receiver_count(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_pending_transaction_subscription() {
+        let (handler, client) = handler(InMemoryDB::new()).await;
+        let (new_blocks, _) = broadcast::channel(16);
+        let (new_transactions, _) = broadcast::channel(1);
+        let (_notify_shutdown, shutdown) = broadcast::channel(1);
+        let handler = handler
+            .with_subscriptions(new_blocks, new_transactions.clone())
+            .with_shutdown(shutdown, mpsc::channel(1).0);
+        let handle = tokio::spawn(handler.handle_connection());
+
+        let mut client = Connection::new(client);
+        client
+            .write_message(&Message::Subscribe(SubscriptionKind::PendingTransactions))
+            .await
+            .unwrap();
+        assert_eq!(client.read_message().await.unwrap(), Some(Message::Ok));
+
+        new_transactions.send(transaction(0)).unwrap();
+        assert_eq!(
+            client.read_message().await.unwrap(),
+            Some(Message::Transaction(transaction(0)))
+        );
+
+        // Publishing never waits for the subscriber, which is dropped once it falls behind
+        for nonce in 1..=3 {
+            new_transactions.send(transaction(nonce)).unwrap();
+        }
+        let mut pushed = Vec::new();
+        while let Some(msg) = client.read_message().await.unwrap() {
+            pushed.push(msg);
+        }
+        assert!(matches!(pushed.last(), Some(Message::InvalidMessage(_))));
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_subscription_ends_on_shutdown() {
+        let (handler, client) = handler(InMemoryDB::new()).await;
+        let (new_blocks, _) = broadcast::channel(16);
+        let (new_transactions, _) = broadcast::channel(16);
+        let (notify_shutdown, shutdown) = broadcast::channel(1);
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+        let handler = handler
+            .with_subscriptions(new_blocks, new_transactions)
+            .with_shutdown(shutdown, shutdown_complete_tx);
+        let handle = tokio::spawn(handler.handle_connection());
+
+        let mut client = Connection::new(client);
+        client
+            .write_message(&Message::Subscribe(SubscriptionKind::Blocks))
+            .await
+            .unwrap();
+        assert_eq!(client.read_message().await.unwrap(), Some(Message::Ok));
+
+        drop(notify_shutdown);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(client.read_message().await.unwrap(), None);
+        assert_eq!(shutdown_complete_rx.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn test_slow_request_times_out() {
+        let (handler, client) = handler(InMemoryDB::new()).await;
+        let db = handler.db.clone();
+        let handler = handler.with_request_timeout(Duration::from_millis(50));
+        let handle = tokio::spawn(handler.handle_connection());
+        let mut client = Connection::new(client);
+
+        let writer = db.write().await;
+        client.write_message(&Message::ChainInfoReq).await.unwrap();
+        assert_eq!(
+            client.read_message().await.unwrap(),
+            Some(Message::InternalError(String::from("timeout")))
+        );
+        drop(writer);
+
+        // The lock is usable again and the connection still open
+        client.write_message(&Message::ChainInfoReq).await.unwrap();
+        assert!(matches!(
+            client.read_message().await.unwrap(),
+            Some(Message::ChainInfo(_))
+        ));
+        assert!(db.try_write().is_ok());
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_idle_peer_is_disconnected() {
+        let (handler, mut client) = handler(InMemoryDB::new()).await;
+        let handler = handler.with_idle_timeout(Duration::from_millis(50));
+        let handle = tokio::spawn(handler.handle_connection());
+
+        // An answered message resets the clock, half a message doesn't
+        client.write_all(b"\"LimitsReq\"\r\n").await.unwrap();
+        client.write_all(b"\"ChainInfo").await.unwrap();
+
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+        let mut client = Connection::new(client);
+        assert!(matches!(
+            client.read_message().await.unwrap(),
+            Some(Message::Limits(_))
+        ));
+        assert_eq!(
+            client.read_message().await.unwrap(),
+            Some(Message::InvalidMessage(String::from("request timed out")))
+        );
+        assert_eq!(client.read_message().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_message_times_out() {
+        let (handler, mut client) = handler(InMemoryDB::new()).await;
+        let idle_timeout = Duration::from_millis(100);
+        let handler = handler.with_idle_timeout(idle_timeout);
+        let started = tokio::time::Instant::now();
+        let handle = tokio::spawn(handler.handle_connection());
+
+        // The peer never finishes the message, nor closes the connection
+        client.write_all(b"{\"BlockReq\":").await.unwrap();
+
+        tokio::time::timeout(idle_timeout * 3, handle)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(started.elapsed() < idle_timeout * 2);
+        let mut client = Connection::new(client);
+        assert_eq!(
+            client.read_message().await.unwrap(),
+            Some(Message::InvalidMessage(String::from("request timed out")))
+        );
+        assert_eq!(client.read_message().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_hung_peer_does_not_block_shutdown() {
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+
+        // Never sends a full message
+        let (silent_handler, mut silent) = handler(InMemoryDB::new()).await;
+        let silent_handler =
+            silent_handler.with_shutdown(notify_shutdown.subscribe(), shutdown_complete_tx.clone());
+        tokio::spawn(silent_handler.handle_connection());
+        silent.write_all(b"\"ChainInfo").await.unwrap();
+
+        // Never reads its responses, so the handler gets stuck writing them
+        let (stalled_handler, mut stalled) = handler(InMemoryDB::new()).await;
+        let stalled_handler =
+            stalled_handler.with_shutdown(notify_shutdown.subscribe(), shutdown_complete_tx);
+        tokio::spawn(stalled_handler.handle_connection());
+        let requests = b"\"LimitsReq\"\r\n".repeat(100_000);
+        let _ =
+            tokio::time::timeout(Duration::from_millis(500), stalled.write_all(&requests)).await;
+
+        drop(notify_shutdown);
+        let drained = tokio::time::timeout(SHUTDOWN_GRACE * 2, shutdown_complete_rx.recv()).await;
+        assert_eq!(drained.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let spec = ChainSpec::new(7);
+        let (handler, client) = handler(InMemoryDB::new()).await;
+        let handle = tokio::spawn(handler.with_handshake().handle_connection());
+        let mut client = Connection::new(client);
+
+        // Nothing is answered before the handshake
+        client.write_message(&Message::ChainInfoReq).await.unwrap();
+        assert!(matches!(
+            client.read_message().await.unwrap(),
+            Some(Message::InvalidMessage(_))
+        ));
+
+        client.write_message(&Message::hello(&spec)).await.unwrap();
+        assert_eq!(
+            client.read_message().await.unwrap(),
+            Some(Message::hello(&spec))
+        );
+        client.write_message(&Message::ChainInfoReq).await.unwrap();
+        assert!(matches!(
+            client.read_message().await.unwrap(),
+            Some(Message::ChainInfo(_))
+        ));
+
+        drop(client);
+        tokio::time::timeout(Duration::from_secs(1), handle)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_incompatible_peers_are_refused() {
+        let spec = ChainSpec::new(7);
+        let hellos = [
+            // Version skew
+            Message::Hello {
+                protocol_version: PROTOCOL_VERSION + 1,
+                chain_id: spec.chain_id(),
+                genesis_hash: spec.genesis_hash(),
+                hash_algo: spec.hash_algo(),
+            },
+            // Built before transactions carried a chain id
+            Message::Hello {
+                protocol_version: 1,
+                chain_id: spec.chain_id(),
+                genesis_hash: spec.genesis_hash(),
+                hash_algo: spec.hash_algo(),
+            },
+            // Another chain
+            Message::hello(&ChainSpec::new(8)),
+            // Same id, but started from other preallocations
+            Message::hello(&ChainSpec::new(7).with_account(Address::ZERO, Account::new(1, 0))),
+            // Same chain, but its blocks hashed with another algorithm
+            Message::hello(&ChainSpec::new(7).with_hash_algo(HashAlgo::Sha3)),
+        ];
+
+        for hello in hellos {
+            let (handler, client) = handler(InMemoryDB::new()).await;
+            let handle = tokio::spawn(handler.with_handshake().handle_connection());
+            let mut client = Connection::new(client);
+
+            client.write_message(&hello).await.unwrap();
+            assert!(matches!(
+                client.read_message().await.unwrap(),
+                Some(Message::IncompatiblePeer(_))
+            ));
+            // The node hangs up right after
+            assert_eq!(client.read_message().await.unwrap(), None);
+            tokio::time::timeout(Duration::from_secs(1), handle)
+                .await
+                .unwrap()
+                .unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_simulation() {
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+        let tx = transaction(0);
+
+        let response = handler
+            .handle_message(Message::SimulateTx(tx.clone()))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::InvalidMessage(_)));
+
+        let (simulation_tx, mut simulation_rx) = mpsc::channel(1);
+        handler = handler.with_simulations(simulation_tx);
+
+        // Stands in for the executor
+        let receipt = TransactionReceipt {
+            success: true,
+            ..Default::default()
+        };
+        let answer = receipt.clone();
+        tokio::spawn(async move {
+            let (simulated, reply): (Transaction, oneshot::Sender<TransactionReceipt>) =
+                simulation_rx.recv().await.unwrap();
+            assert_eq!(simulated, transaction(0));
+            reply.send(answer).unwrap();
+        });
+
+        let response = handler
+            .handle_message(Message::SimulateTx(tx))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::SimulationResult(receipt));
+    }
+
+    #[tokio::test]
+    async fn test_admin_requires_the_token() {
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+        let admin = |req, token: &str| Message::Admin(req, token.to_string());
+
+        // Without a token the admin surface is disabled
+        let response = handler
+            .handle_message(admin(AdminReq::ListBans, ""))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Unauthorized);
+
+        let black_list = Arc::new(RwLock::new(BlackList::default()));
+        let ip = IpAddr::from([127, 0, 0, 1]);
+        handler = handler.with_admin(black_list.clone(), Arc::from("secret"), ip);
+
+        for token in ["", "secreT", "secret2"] {
+            let response = handler
+                .handle_message(admin(AdminReq::Ban(String::from("10.0.0.0/8")), token))
+                .await
+                .unwrap();
+            assert_eq!(response, Message::Unauthorized);
+        }
+        assert_eq!(black_list.read().await.banned().count(), 0);
+
+        let response = handler
+            .handle_message(admin(AdminReq::Ban(String::from("10.0.0.0/8")), "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Bans(vec![String::from("10.0.0.0/8")]));
+        assert!(black_list
+            .read()
+            .await
+            .is_banned(&IpAddr::from([10, 1, 2, 3])));
+
+        let response = handler
+            .handle_message(admin(AdminReq::Ban(String::from("10.0.0.1/8")), "secret"))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::InvalidMessage(_)));
+
+        handler
+            .handle_message(admin(AdminReq::Ban(String::from("::1")), "secret"))
+            .await
+            .unwrap();
+        let response = handler
+            .handle_message(admin(AdminReq::Unban(String::from("10.0.0.0/8")), "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Bans(vec![String::from("::1")]));
+        let response = handler
+            .handle_message(admin(AdminReq::ListBans, "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Bans(vec![String::from("::1")]));
+    }
+
+    #[tokio::test]
+    async fn test_admin_answers_peer_stats() {
+        let (handler, _client) = handler(InMemoryDB::new()).await;
+        let peer_stats = Arc::new(PeerStats::new(16, Duration::from_secs(60)));
+        let (first, second) = (IpAddr::from([10, 0, 0, 1]), IpAddr::from([10, 0, 0, 2]));
+        peer_stats.record_connection(first).record_message("Ping");
+        peer_stats.record_connection(second);
+        peer_stats.record_connection(second);
+
+        let mut handler = handler
+            .with_admin(
+                Arc::new(RwLock::new(BlackList::default())),
+                Arc::from("secret"),
+                IpAddr::from([127, 0, 0, 1]),
+            )
+            .with_peer_stats(peer_stats);
+        let peers = |ip, token: &str| Message::Admin(AdminReq::PeerStats { ip }, token.into());
+
+        let response = handler.handle_message(peers(None, "wrong")).await.unwrap();
+        assert_eq!(response, Message::Unauthorized);
+
+        let response = handler.handle_message(peers(None, "secret")).await.unwrap();
+        let mut snapshots = match response {
+            Message::PeerStats(snapshots) => snapshots,
+            other => panic!("expected peer stats, got {:?}", other),
+        };
+        snapshots.sort_by_key(|peer| peer.ip);
+        assert_eq!(snapshots.len(), 2);
+        assert_eq!(snapshots[0].messages.get("Ping"), Some(&1));
+        assert_eq!(snapshots[1].connections, 2);
+
+        let response = handler
+            .handle_message(peers(Some(second), "secret"))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::PeerStats(s) if s.len() == 1 && s[0].ip == second));
+
+        let unknown = IpAddr::from([10, 0, 0, 3]);
+        let response = handler
+            .handle_message(peers(Some(unknown), "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::PeerStats(vec![]));
+    }
+
+    #[tokio::test]
+    async fn test_pause_and_resume_are_passed_on() {
+        let (handler, _client) = handler(InMemoryDB::new()).await;
+        let (server_control_tx, mut server_control_rx) = mpsc::channel(4);
+        let black_list = Arc::new(RwLock::new(BlackList::default()));
+        let mut handler = handler
+            .with_admin(
+                black_list,
+                Arc::from("secret"),
+                IpAddr::from([127, 0, 0, 1]),
+            )
+            .with_control(server_control_tx);
+
+        let response = handler
+            .handle_message(Message::PauseReq(String::from("wrong")))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Unauthorized);
+        assert!(server_control_rx.try_recv().is_err());
+
+        for msg in [
+            Message::PauseReq(String::from("secret")),
+            Message::ResumeReq(String::from("secret")),
+            Message::DemoteReq(String::from("secret")),
+            Message::PromoteReq(String::from("secret")),
+        ] {
+            assert_eq!(handler.handle_message(msg).await.unwrap(), Message::Ok);
+        }
+        assert_eq!(server_control_rx.try_recv().unwrap(), ControlMsg::Pause);
+        assert_eq!(server_control_rx.try_recv().unwrap(), ControlMsg::Resume);
+        assert_eq!(server_control_rx.try_recv().unwrap(), ControlMsg::Demote);
+        assert_eq!(server_control_rx.try_recv().unwrap(), ControlMsg::Promote);
+    }
+
+    #[tokio::test]
+    async fn test_followers_import_blocks() {
+        let status = Arc::new(NodeStatus::new());
+        let (import_tx, mut import_rx) = mpsc::channel(1);
+        let (handler, _client) = handler(InMemoryDB::new()).await;
+        let mut handler = handler
+            .with_admin(
+                Arc::new(RwLock::new(BlackList::default())),
+                Arc::from("secret"),
+                IpAddr::from([127, 0, 0, 1]),
+            )
+            .with_status(status.clone())
+            .with_follower_imports(import_tx);
+        let import = |block, token: &str| Message::ImportBlock(block, token.to_string());
+        let block = Block::new(
+            BlockHeader {
+                number: 1,
+                ..Default::default()
+            },
+            Default::default(),
+        )
+        .seal_slow(HashAlgo::Keccak256);
+
+        // A producer builds its own blocks
+        let response = handler
+            .handle_message(import(block.clone(), "secret"))
+            .await
+            .unwrap();
+        assert!(matches!(response, Message::InvalidMessage(_)));
+        assert!(import_rx.try_recv().is_err());
+
+        // Stands in for the executor
+        let expected = block.clone();
+        tokio::spawn(async move {
+            let (imported, reply): (SealedBlock, oneshot::Sender<Result<(), Error>>) =
+                import_rx.recv().await.unwrap();
+            assert_eq!(imported, expected);
+            reply.send(Ok(())).unwrap();
+        });
+
+        status.set_role(NodeRole::Follower);
+        // Not even a follower takes blocks from anyone but the operator
+        let response = handler
+            .handle_message(import(block.clone(), "wrong"))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Unauthorized);
+
+        let response = handler
+            .handle_message(import(block, "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Ok);
+    }
+
+    #[tokio::test]
+    async fn test_mempool_status() {
+        let (mut handler, _client) = handler(InMemoryDB::new()).await;
+        let (mempool_status_tx, mut mempool_status_rx) = mpsc::channel(1);
+        handler.mempool_status_tx = mempool_status_tx;
+
+        // Stands in for the mempool task
+        tokio::spawn(async move {
+            let reply: oneshot::Sender<MempoolStats> = mempool_status_rx.recv().await.unwrap();
+            reply
+                .send(MempoolStats {
+                    pending: 4,
+                    evicted: 1,
+                    expired: 2,
+                    oldest_pending: Some(1_700_000_000),
+                })
+                .unwrap();
+        });
+
+        let response = handler
+            .handle_message(Message::MempoolStatusReq)
+            .await
+            .unwrap();
+        assert_eq!(
+            response,
+            Message::MempoolStatus {
+                pending: 4,
+                evicted: 1,
+                expired: 2,
+                oldest_pending: Some(1_700_000_000),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allowance_req() {
+        let (owner, spender) = (Address::repeat_byte(1), Address::repeat_byte(2));
+        let mut db = InMemoryDB::new();
+        db.write_allowance(owner, spender, 70).unwrap();
+        let (mut handler, _client) = handler(db).await;
+
+        let response = handler
+            .handle_message(Message::AllowanceReq { owner, spender })
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Allowance(70));
+
+        // Approvals only go one way
+        let response = handler
+            .handle_message(Message::AllowanceReq {
+                owner: spender,
+                spender: owner,
+            })
+            .await
+            .unwrap();
+        assert_eq!(response, Message::Allowance(0));
+    }
+
+    #[tokio::test]
+    async fn test_status_req() {
+        let status = Arc::new(NodeStatus::new());
+        status.set_head(4, B256::repeat_byte(4));
+        let (handler, _client) = handler(InMemoryDB::new()).await;
+        let mut handler = handler.with_status(status.clone());
+
+        // Answered while the executor would be holding the write lock
+        let db = handler.db.clone();
+        let _executing = db.write().await;
+        match handler.handle_message(Message::StatusReq).await.unwrap() {
+            Message::Status {
+                head_number,
+                head_hash,
+                version,
+                ..
+            } => {
+                assert_eq!(head_number, 4);
+                assert_eq!(head_hash, B256::repeat_byte(4));
+                assert_eq!(version, env!("CARGO_PKG_VERSION"));
+            }
+            other => panic!("unexpected response {other:?}"),
+        }
+    }
 }
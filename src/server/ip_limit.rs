@@ -0,0 +1,87 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Open connections per ip, so a single host can't take up every connection of the node
+#[derive(Debug)]
+pub(crate) struct IpConnections {
+    max_per_ip: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl IpConnections {
+    pub(crate) fn new(max_per_ip: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_per_ip,
+            open: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes a slot of `ip` if it has one left, it is given back once the [IpSlot] is dropped
+    pub(crate) fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpSlot> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(ip).or_default();
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+
+        Some(IpSlot {
+            connections: self.clone(),
+            ip,
+        })
+    }
+
+    pub(crate) fn open(&self, ip: &IpAddr) -> usize {
+        self.open
+            .lock()
+            .unwrap()
+            .get(ip)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+/// One open connection of an ip, see [IpConnections::try_acquire]
+#[derive(Debug)]
+pub(crate) struct IpSlot {
+    connections: Arc<IpConnections>,
+    ip: IpAddr,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        let mut open = self.connections.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            // Hosts that left don't stay in the map
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_slots_are_given_back() {
+        let connections = IpConnections::new(2);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        let first = connections.try_acquire(ip).unwrap();
+        let _second = connections.try_acquire(ip).unwrap();
+        assert!(connections.try_acquire(ip).is_none());
+        assert!(connections.try_acquire(other).is_some());
+
+        drop(first);
+        assert_eq!(connections.open(&ip), 1);
+        assert!(connections.try_acquire(ip).is_some());
+    }
+}
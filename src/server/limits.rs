@@ -0,0 +1,394 @@
+use super::{message::MAX_TRANSACTIONS_PER_REQUEST, BlockReq, Message, TransactionReq};
+use crate::Error;
+use serde::{Deserialize, Serialize};
+
+/// Bounds on what the node accepts from a peer, so the cost of handling a message stays
+/// proportional to its size
+///
+/// The structure of every frame is checked before it is deserialized, the lengths of the
+/// individual fields right after. Clients can ask for them with [Message::LimitsReq]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct NodeLimits {
+    /// Bytes of a single frame, without the delimiter
+    pub max_frame_size: usize,
+    /// Nesting of arrays and objects
+    pub max_depth: usize,
+    /// Elements of any array
+    pub max_array_len: usize,
+    /// Bytes of any string, before unescaping
+    pub max_string_len: usize,
+    /// Hashes of a [TransactionReq::Many], entries of a [Message::Transactions] and
+    /// transactions of a [Message::SubmitTransactions]
+    pub max_transactions_per_request: usize,
+    /// Entries of a [Message::Blocks]
+    pub max_blocks_per_message: usize,
+    /// Headers a [Message::HeaderReq] for a range may ask for
+    pub max_headers_per_request: usize,
+    /// Requests of a [Message::Batch], each of which is checked against the other limits
+    #[serde(default = "default_max_batch_size")]
+    pub max_batch_size: usize,
+}
+
+impl Default for NodeLimits {
+    fn default() -> Self {
+        Self {
+            max_frame_size: 4 * 1024 * 1024,
+            max_depth: 16,
+            max_array_len: MAX_TRANSACTIONS_PER_REQUEST,
+            max_string_len: 1024,
+            max_transactions_per_request: MAX_TRANSACTIONS_PER_REQUEST,
+            max_blocks_per_message: 100,
+            max_headers_per_request: MAX_TRANSACTIONS_PER_REQUEST,
+            max_batch_size: default_max_batch_size(),
+        }
+    }
+}
+
+/// Also what nodes that predate batches are taken to allow
+fn default_max_batch_size() -> usize {
+    100
+}
+
+impl NodeLimits {
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_max_array_len(mut self, max_array_len: usize) -> Self {
+        self.max_array_len = max_array_len;
+        self
+    }
+
+    pub fn with_max_string_len(mut self, max_string_len: usize) -> Self {
+        self.max_string_len = max_string_len;
+        self
+    }
+
+    pub fn with_max_transactions_per_request(
+        mut self,
+        max_transactions_per_request: usize,
+    ) -> Self {
+        self.max_transactions_per_request = max_transactions_per_request;
+        self
+    }
+
+    pub fn with_max_blocks_per_message(mut self, max_blocks_per_message: usize) -> Self {
+        self.max_blocks_per_message = max_blocks_per_message;
+        self
+    }
+
+    pub fn with_max_headers_per_request(mut self, max_headers_per_request: usize) -> Self {
+        self.max_headers_per_request = max_headers_per_request;
+        self
+    }
+
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    /// Scans a JSON frame without allocating for it, so the deserializer never sees a frame
+    /// that is too deep, has too many elements or too long strings
+    ///
+    /// Malformed JSON is left for the deserializer to reject
+    pub fn check_structure(&self, json: &[u8]) -> Result<(), Error> {
+        if json.len() > self.max_frame_size {
+            return Err(Error::MessageTooLarge(self.max_frame_size));
+        }
+
+        // One entry per open array or object, counting the commas of arrays
+        let mut open: Vec<Option<usize>> = Vec::new();
+
+        let mut i = 0;
+        while i < json.len() {
+            match json[i] {
+                b'"' => {
+                    let start = i + 1;
+                    i = start;
+                    while i < json.len() && json[i] != b'"' {
+                        // Skips the escaped character, which may be a quote
+                        if json[i] == b'\\' {
+                            i += 1;
+                        }
+                        i += 1;
+                    }
+
+                    if i.min(json.len()) - start > self.max_string_len {
+                        return Err(exceeded("string length", self.max_string_len));
+                    }
+                }
+                open_bracket @ (b'[' | b'{') => {
+                    if open.len() >= self.max_depth {
+                        return Err(exceeded("depth", self.max_depth));
+                    }
+                    open.push((open_bracket == b'[').then_some(0));
+                }
+                b']' | b'}' => {
+                    open.pop();
+                }
+                b',' => {
+                    if let Some(Some(commas)) = open.last_mut() {
+                        *commas += 1;
+                        if *commas >= self.max_array_len {
+                            return Err(exceeded("array length", self.max_array_len));
+                        }
+                    }
+                }
+                _ => {}
+            }
+            i += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Checks the lengths that depend on what the fields of a message mean
+    pub fn check_message(&self, message: &Message) -> Result<(), Error> {
+        let (len, max, limit) = match message {
+            Message::Batch(messages) | Message::BatchResponse(messages) => {
+                if messages.len() > self.max_batch_size {
+                    return Err(exceeded("messages per batch", self.max_batch_size));
+                }
+                return messages.iter().try_for_each(|msg| self.check_message(msg));
+            }
+            Message::TransactionReq(TransactionReq::Many(hashes)) => (
+                hashes.len(),
+                self.max_transactions_per_request,
+                "transactions per request",
+            ),
+            Message::Bundle(transactions) => (
+                transactions.len(),
+                self.max_transactions_per_request,
+                "transactions per request",
+            ),
+            Message::Transactions(transactions) => (
+                transactions.len(),
+                self.max_transactions_per_request,
+                "transactions per request",
+            ),
+            Message::SubmitTransactions(transactions) => (
+                transactions.len(),
+                self.max_transactions_per_request,
+                "transactions per request",
+            ),
+            Message::SubmissionResults(results) => (
+                results.len(),
+                self.max_transactions_per_request,
+                "transactions per request",
+            ),
+            Message::Blocks(blocks) => (
+                blocks.len(),
+                self.max_blocks_per_message,
+                "blocks per message",
+            ),
+            Message::BlockReq(BlockReq::Range { start, end }) => (
+                range_len(*start, *end),
+                self.max_blocks_per_message,
+                "blocks per message",
+            ),
+            Message::HeaderReq(BlockReq::Range { start, end }) => (
+                range_len(*start, *end),
+                self.max_headers_per_request,
+                "headers per request",
+            ),
+            Message::Headers(headers) => (
+                headers.len(),
+                self.max_headers_per_request,
+                "headers per request",
+            ),
+            _ => return Ok(()),
+        };
+
+        if len > max {
+            return Err(exceeded(limit, max));
+        }
+
+        Ok(())
+    }
+}
+
+/// Blocks in `start..=end`
+pub(super) fn range_len(start: u64, end: u64) -> usize {
+    end.checked_sub(start)
+        .map_or(0, |len| len.saturating_add(1))
+        .try_into()
+        .unwrap_or(usize::MAX)
+}
+
+fn exceeded(limit: &'static str, max: usize) -> Error {
+    Error::LimitExceeded { limit, max }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{utils::HashAlgo, Block, BlockHeader, Transaction, TransactionEntry};
+    use alloy_primitives::B256;
+    use std::time::{Duration, Instant};
+
+    fn limit(result: Result<(), Error>) -> &'static str {
+        match result {
+            Err(Error::LimitExceeded { limit, .. }) => limit,
+            other => panic!("expected a limit to be exceeded, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_valid_messages_pass() {
+        let limits = NodeLimits::default();
+        let block = Block::new(
+            BlockHeader::default(),
+            vec![Transaction::default(); 100].into(),
+        )
+        .seal_slow(HashAlgo::Keccak256);
+        let messages = [
+            Message::Transaction(Transaction::default()),
+            Message::Block(block.clone()),
+            Message::Blocks(vec![block; 10]),
+            Message::TransactionReq(TransactionReq::Many(vec![B256::ZERO; 1_000])),
+            Message::Transactions(vec![TransactionEntry::Found(Transaction::default()); 1_000]),
+            Message::SubmitTransactions(vec![Transaction::default(); 1_000]),
+            Message::InvalidMessage("quote \" and backslash \\".repeat(10)),
+            Message::Ok,
+        ];
+
+        for message in messages {
+            let json = message.serialize().unwrap();
+            limits.check_structure(&json).unwrap();
+            limits.check_message(&message).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_huge_array_of_zero_hashes() {
+        let limits = NodeLimits {
+            max_frame_size: usize::MAX,
+            ..Default::default()
+        };
+        let hash = serde_json::to_string(&B256::ZERO).unwrap();
+        let json = format!(
+            r#"{{"TransactionReq":{{"Many":[{}]}}}}"#,
+            vec![hash; 1_000_000].join(",")
+        );
+
+        let started = Instant::now();
+        assert_eq!(
+            limit(limits.check_structure(json.as_bytes())),
+            "array length"
+        );
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Fits the structure but not the meaning of the field
+        let limits = NodeLimits {
+            max_transactions_per_request: 10,
+            ..Default::default()
+        };
+        let message = Message::TransactionReq(TransactionReq::Many(vec![B256::ZERO; 11]));
+        assert_eq!(
+            limit(limits.check_message(&message)),
+            "transactions per request"
+        );
+
+        // Ranges cost nothing to parse but a lot to answer
+        let message = Message::HeaderReq(BlockReq::Range {
+            start: 0,
+            end: u64::MAX,
+        });
+        assert_eq!(limit(limits.check_message(&message)), "headers per request");
+
+        let message = Message::BlockReq(BlockReq::Range {
+            start: 0,
+            end: u64::MAX,
+        });
+        assert_eq!(limit(limits.check_message(&message)), "blocks per message");
+    }
+
+    #[test]
+    fn test_batches_are_checked_request_by_request() {
+        let limits = NodeLimits {
+            max_batch_size: 2,
+            max_transactions_per_request: 10,
+            ..Default::default()
+        };
+        let batch = Message::Batch(vec![Message::ChainInfoReq, Message::LimitsReq]);
+        limits.check_message(&batch).unwrap();
+
+        let batch = Message::Batch(vec![Message::ChainInfoReq; 3]);
+        assert_eq!(limit(limits.check_message(&batch)), "messages per batch");
+
+        let many = TransactionReq::Many(vec![B256::ZERO; 11]);
+        let batch = Message::Batch(vec![Message::TransactionReq(many)]);
+        assert_eq!(
+            limit(limits.check_message(&batch)),
+            "transactions per request"
+        );
+
+        // Limits sent by nodes that predate batches
+        let mut json = serde_json::to_value(NodeLimits::default()).unwrap();
+        json.as_object_mut().unwrap().remove("max_batch_size");
+        let limits: NodeLimits = serde_json::from_value(json).unwrap();
+        assert_eq!(limits, NodeLimits::default());
+    }
+
+    #[test]
+    fn test_deeply_nested_garbage() {
+        let limits = NodeLimits::default();
+        let json = "[{".repeat(1_000_000);
+
+        let started = Instant::now();
+        assert_eq!(limit(limits.check_structure(json.as_bytes())), "depth");
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        // Brackets inside strings don't count
+        let json = format!(r#"{{"InvalidMessage":"{}"}}"#, "[".repeat(1_000));
+        limits.check_structure(json.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_long_string() {
+        let limits = NodeLimits::default();
+        let json = format!(r#"{{"InvalidMessage":"{}"}}"#, "a".repeat(1_025));
+        assert_eq!(
+            limit(limits.check_structure(json.as_bytes())),
+            "string length"
+        );
+
+        // Unterminated strings are measured up to the end of the frame
+        let json = format!(r#"{{"InvalidMessage":"{}"#, "a".repeat(1_025));
+        assert_eq!(
+            limit(limits.check_structure(json.as_bytes())),
+            "string length"
+        );
+    }
+
+    #[test]
+    fn test_random_bytes_never_panic() {
+        let limits = NodeLimits::default();
+
+        // Small xorshift so the input is scattered without pulling in a rng
+        let mut seed = 0x2545_f491_u64;
+        let alphabet = b"[]{}\",:\\ a0";
+        for _ in 0..1_000 {
+            let json: Vec<u8> = (0..256)
+                .map(|_| {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    alphabet[(seed % alphabet.len() as u64) as usize]
+                })
+                .collect();
+
+            if limits.check_structure(&json).is_ok() {
+                // Whatever passes is left for the deserializer, which must fail gracefully
+                let _ = serde_json::from_slice::<Message>(&json);
+            }
+        }
+    }
+}
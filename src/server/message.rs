@@ -1,38 +1,407 @@
-use std::io::Cursor;
+use std::{cell::Cell, io::Cursor, net::IpAddr, sync::OnceLock};
+
+use alloy_primitives::{Address, B256};
+use bincode::Options;
+use clap::ValueEnum;
+use serde::{de, Deserialize, Serialize};
+
+use super::{connection::LENGTH_PREFIX_SIZE, NodeLimits, PeerSnapshot};
+use crate::{
+    utils::HashAlgo, Account, BundleReceipt, ChainSpec, CoinbaseMaturity, Error, FailureReason,
+    NodeRole, SealedBlock, SealedHeader, Transaction, TransactionReceipt, TransactionSummary,
+};
+
+/// Most hashes a single [TransactionReq::Many] may ask for
+pub const MAX_TRANSACTIONS_PER_REQUEST: usize = 1_000;
+
+/// Version of the [Message] enum, bumped whenever a peer built against an older one could
+/// misread messages
+///
+/// Version 2 added the chain id to [Transaction], which peers on version 1 don't encode, and
+/// the hash algorithm to [Message::Hello]
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// How messages are encoded, both sides of a connection have to use the same one
+///
+/// For [Message::Blocks] with 100 blocks of 10 transactions each, measured with a release
+/// build on a single core:
+///
+/// | Format  | Size   | Serialize | Deserialize |
+/// |---------|--------|-----------|-------------|
+/// | Json    | 393 KB | 803 µs    | 1107 µs     |
+/// | Bincode | 160 KB | 94 µs     | 130 µs      |
+/// | Cbor    | 206 KB | 950 µs    | 557 µs      |
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum WireFormat {
+    #[default]
+    Json,
+    /// Compact binary encoding, only sent in [super::FrameMode::LengthPrefixed] frames since
+    /// the payload may contain `\r\n`
+    Bincode,
+    /// Self describing binary encoding for peers that aren't written in Rust, hashes and
+    /// 256 bit integers are byte strings
+    Cbor,
+}
 
-use alloy_primitives::B256;
-use serde::{Deserialize, Serialize};
+impl WireFormat {
+    /// Whether the encoded messages may contain the `\r\n` delimiter
+    pub fn is_binary(&self) -> bool {
+        matches!(self, WireFormat::Bincode | WireFormat::Cbor)
+    }
 
-use crate::{Error, SealedBlock, Transaction};
+    /// Bit of the format in the byte exchanged by [super::Connection::negotiate_encoding]
+    pub fn capability(&self) -> u8 {
+        match self {
+            WireFormat::Json => 0b01,
+            WireFormat::Bincode => 0b10,
+            WireFormat::Cbor => 0b100,
+        }
+    }
+}
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[non_exhaustive]
+#[allow(clippy::enum_variant_names)]
 pub enum Message {
+    /// First message on every connection, answered with the node's own [Message::Hello] or
+    /// with [Message::IncompatiblePeer] before the node closes the connection
+    ///
+    /// Kept as the first variant, so binary formats encode it the same in every version
+    Hello {
+        protocol_version: u32,
+        chain_id: u64,
+        genesis_hash: B256,
+        /// See [ChainSpec::hash_algo], peers hashing differently would refuse each other's
+        /// blocks
+        #[serde(default)]
+        hash_algo: HashAlgo,
+    },
+    /// Why the node refused the [Message::Hello]
+    IncompatiblePeer(String),
     Transaction(Transaction),
+    /// Transactions that are included in the same block and either all succeed or all revert,
+    /// answered with [Message::Ok] once the mempool has them
+    Bundle(Vec<Transaction>),
+    /// Response to [TransactionReq::Many], one entry per requested hash in the same order
+    Transactions(Vec<TransactionEntry>),
     Block(SealedBlock),
 
     Blocks(Vec<SealedBlock>),
-
+    /// Answered with [Message::Blocks] for [BlockReq::Range], skipping the blocks the node
+    /// doesn't have or has pruned
     BlockReq(BlockReq),
+    /// Only the header of a block, answered with [Message::Header] or with [Message::Headers]
+    /// for [BlockReq::Range]
+    HeaderReq(BlockReq),
+    Header(SealedHeader),
+    /// Headers of consecutive blocks, stopping at the first block the node doesn't have
+    Headers(Vec<SealedHeader>),
     TransactionReq(TransactionReq),
+    /// Receipt of the transaction with this hash, answered with [Message::Receipt] or
+    /// [Message::NonExistentTx] if the transaction isn't in a block yet
+    ///
+    /// The hash of a bundle is answered with [Message::BundleReceipt]
+    ReceiptReq(B256),
+    Receipt(TransactionReceipt),
+    BundleReceipt(BundleReceipt),
+    /// State of the account with this address, answered with [Message::Account] or
+    /// [Message::NonExistentAccount] if the address was never touched. A deleted account is
+    /// answered with no balance and the nonce it had, see [crate::DatabaseWriter::delete_account]
+    AccountReq(Address),
+    Account(Account),
+    /// Answer for blocks and transactions behind the node's retention horizon, whose full
+    /// body is no longer stored
+    Pruned(Pruned),
+    /// Which chain the node is on and its head, answered with [Message::ChainInfo]
+    ChainInfoReq,
+    ChainInfo(ChainInfo),
+    /// Statistics of the mempool, answered with [Message::MempoolStatus]
+    MempoolStatusReq,
+    MempoolStatus {
+        /// Transactions waiting to be included
+        pending: usize,
+        /// Transactions dropped because the mempool was full
+        evicted: u64,
+        /// Transactions dropped because they waited too long
+        expired: u64,
+        /// Unix timestamp at which the oldest pending transaction was submitted
+        #[serde(default)]
+        oldest_pending: Option<u64>,
+    },
+    /// Limits the node enforces on incoming messages, answered with [Message::Limits]
+    LimitsReq,
+    /// Answered with [Message::Ok], after which the node pushes updates over the same
+    /// connection until either side closes it
+    Subscribe(SubscriptionKind),
+    Limits(NodeLimits),
+    /// Answered right away with [Message::Pong] carrying the same nonce, keeps idle
+    /// connections alive and measures latency
+    Ping(u64),
+    Pong(u64),
 
     NonExistentBlock,
     NonExistentTx,
+    NonExistentAccount,
 
     InvalidMessage(String),
     InvalidTransaction,
 
     InternalError(String),
     Ok,
+    /// What `spender` may still send on behalf of `owner`, answered with [Message::Allowance],
+    /// which is zero if nothing is approved
+    AllowanceReq {
+        owner: Address,
+        spender: Address,
+    },
+    Allowance(u128),
+    /// Whether the node is healthy, answered with [Message::Status] from counters the node
+    /// keeps up to date, so even while the executor is busy building a block
+    StatusReq,
+    Status {
+        uptime_secs: u64,
+        head_number: u64,
+        head_hash: B256,
+        /// Transactions waiting in the mempool
+        pending_txs: usize,
+        /// Open connections, the one asking included
+        peer_count: usize,
+        /// Version of the node's build
+        version: String,
+        role: NodeRole,
+        /// Task that failed last and why, absent while every task runs as started
+        #[serde(default)]
+        last_failure: Option<String>,
+        /// Head the producer reported, absent unless the node followed one recently
+        #[serde(default)]
+        network_head: Option<u64>,
+        /// Blocks `head_number` trails the `network_head` by
+        #[serde(default)]
+        lag_blocks: Option<u64>,
+    },
+    /// Several requests in one round trip, answered with a [Message::BatchResponse] that has
+    /// the response of every request at its position
+    ///
+    /// Each request is handled and rate limited as if it was sent on its own, batches can't be
+    /// nested and hold at most [NodeLimits::max_batch_size] requests
+    Batch(Vec<Message>),
+    BatchResponse(Vec<Message>),
+    /// Answer to a [Message::Transaction] the mempool took, with the hash of the verified
+    /// transaction to poll its receipt with
+    TransactionAccepted {
+        hash: B256,
+    },
+    /// Many signed transactions at once, answered with a [Message::SubmissionResults] that
+    /// has the outcome of every transaction at its position
+    ///
+    /// Unlike a [Message::Bundle] the transactions are independent, the valid ones reach the
+    /// mempool whatever happens to the others
+    SubmitTransactions(Vec<Transaction>),
+    SubmissionResults(Vec<SubmissionResult>),
+    /// The mempool can't take more transactions right now, they may be submitted again after
+    /// `retry_after_ms`
+    Busy {
+        retry_after_ms: u64,
+    },
+    /// Statistics of the peers asked for with [AdminReq::PeerStats]
+    PeerStats(Vec<PeerSnapshot>),
+    /// Makes a follower produce blocks on top of its head, or stops a producer after its
+    /// current block. Carry the admin token like [Message::Admin] and are answered with
+    /// [Message::Ok]
+    PromoteReq(String),
+    DemoteReq(String),
+    /// Runs the transaction on top of the head without submitting it, answered with
+    /// [Message::SimulationResult] carrying the receipt it would get
+    SimulateTx(Transaction),
+    SimulationResult(TransactionReceipt),
+    /// Block of another node to add on top of the head, answered with [Message::Ok] once
+    /// it is written or [Message::InvalidMessage] telling why it was refused. Carries the
+    /// admin token like [Message::Admin]
+    ImportBlock(SealedBlock, String),
+    /// Manages the node, only handled if the token matches the node's `--admin-token`,
+    /// answered with [Message::Unauthorized] otherwise
+    Admin(AdminReq, String),
+    /// Entries of the black list in force, answered for every [AdminReq] managing it
+    Bans(Vec<String>),
+    /// Stops block production until [Message::ResumeReq], transactions are still accepted.
+    /// Carries the admin token like [Message::Admin] and is answered with [Message::Ok]
+    PauseReq(String),
+    ResumeReq(String),
+    /// Account of whoever signed the transaction, answered like [Message::AccountReq] or with
+    /// [Message::InvalidTransaction] if no signer can be recovered
+    RecoverSender(Transaction),
+    /// The peer ran out of requests, it may send the next one after `retry_after_ms`
+    RateLimited {
+        retry_after_ms: u64,
+    },
+    /// The token of an [Message::Admin] request is missing or wrong
+    Unauthorized,
+    /// A message of a type this build doesn't know, sent by a peer built against a newer
+    /// protocol, answered with [Message::InvalidMessage]
+    ///
+    /// Only ever decoded from the self describing formats, never sent
+    #[serde(skip)]
+    Unknown(String),
 }
 
 impl Message {
+    /// Name of the variant, used for per peer statistics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Hello { .. } => "Hello",
+            Message::IncompatiblePeer(_) => "IncompatiblePeer",
+            Message::Transaction(_) => "Transaction",
+            Message::Bundle(_) => "Bundle",
+            Message::Transactions(_) => "Transactions",
+            Message::Block(_) => "Block",
+            Message::Blocks(_) => "Blocks",
+            Message::BlockReq(_) => "BlockReq",
+            Message::HeaderReq(_) => "HeaderReq",
+            Message::Header(_) => "Header",
+            Message::Headers(_) => "Headers",
+            Message::TransactionReq(_) => "TransactionReq",
+            Message::ReceiptReq(_) => "ReceiptReq",
+            Message::Receipt(_) => "Receipt",
+            Message::BundleReceipt(_) => "BundleReceipt",
+            Message::AccountReq(_) => "AccountReq",
+            Message::Account(_) => "Account",
+            Message::Pruned(_) => "Pruned",
+            Message::ChainInfoReq => "ChainInfoReq",
+            Message::ChainInfo(_) => "ChainInfo",
+            Message::MempoolStatusReq => "MempoolStatusReq",
+            Message::MempoolStatus { .. } => "MempoolStatus",
+            Message::LimitsReq => "LimitsReq",
+            Message::Subscribe(_) => "Subscribe",
+            Message::Limits(_) => "Limits",
+            Message::Ping(_) => "Ping",
+            Message::Pong(_) => "Pong",
+            Message::NonExistentBlock => "NonExistentBlock",
+            Message::NonExistentTx => "NonExistentTx",
+            Message::NonExistentAccount => "NonExistentAccount",
+            Message::InvalidMessage(_) => "InvalidMessage",
+            Message::InvalidTransaction => "InvalidTransaction",
+            Message::InternalError(_) => "InternalError",
+            Message::Ok => "Ok",
+            Message::AllowanceReq { .. } => "AllowanceReq",
+            Message::Allowance(_) => "Allowance",
+            Message::StatusReq => "StatusReq",
+            Message::Status { .. } => "Status",
+            Message::Batch(_) => "Batch",
+            Message::BatchResponse(_) => "BatchResponse",
+            Message::TransactionAccepted { .. } => "TransactionAccepted",
+            Message::SubmitTransactions(_) => "SubmitTransactions",
+            Message::SubmissionResults(_) => "SubmissionResults",
+            Message::Busy { .. } => "Busy",
+            Message::PeerStats(_) => "PeerStats",
+            Message::PromoteReq(_) => "PromoteReq",
+            Message::DemoteReq(_) => "DemoteReq",
+            Message::SimulateTx(_) => "SimulateTx",
+            Message::SimulationResult(_) => "SimulationResult",
+            Message::ImportBlock(..) => "ImportBlock",
+            Message::Admin(..) => "Admin",
+            Message::Bans(_) => "Bans",
+            Message::PauseReq(_) => "PauseReq",
+            Message::ResumeReq(_) => "ResumeReq",
+            Message::RecoverSender(_) => "RecoverSender",
+            Message::RateLimited { .. } => "RateLimited",
+            Message::Unauthorized => "Unauthorized",
+            Message::Unknown(_) => "Unknown",
+        }
+    }
+
+    /// [Message::Unknown] if `tag` names no variant this build knows, `err` otherwise
+    fn unknown_or(tag: Option<&str>, err: Error) -> Result<Self, Error> {
+        match tag {
+            Some(tag) if !message_types().contains(&tag) => Ok(Message::Unknown(tag.to_string())),
+            _ => Err(err),
+        }
+    }
+
+    /// Decodes JSON, a message of an unknown type becomes [Message::Unknown]
+    fn deserialize_json(bytes: &[u8]) -> Result<Self, Error> {
+        serde_json::from_slice(bytes).or_else(|e| {
+            // Unit variants are plain strings, all others objects with the variant as only key
+            let value = serde_json::from_slice::<serde_json::Value>(bytes);
+            let tag = match &value {
+                Ok(serde_json::Value::String(tag)) => Some(tag),
+                Ok(serde_json::Value::Object(map)) if map.len() == 1 => map.keys().next(),
+                _ => None,
+            }
+            .map(String::as_str);
+            Self::unknown_or(tag, e.into())
+        })
+    }
+
+    /// Greeting of a peer running this protocol version on the chain of `spec`
+    pub fn hello(spec: &ChainSpec) -> Self {
+        Message::Hello {
+            protocol_version: PROTOCOL_VERSION,
+            chain_id: spec.chain_id(),
+            genesis_hash: spec.genesis_hash(),
+            hash_algo: spec.hash_algo(),
+        }
+    }
+
     pub fn serialize(&self) -> Result<Vec<u8>, Error> {
         serde_json::to_vec(self).map_err(|e| e.into())
     }
 
     pub fn _deserialize(bytes: &[u8]) -> Result<Self, Error> {
-        serde_json::from_slice(bytes).map_err(|e| e.into())
+        Self::deserialize_json(bytes)
+    }
+
+    pub fn serialize_bincode(&self) -> Result<Vec<u8>, Error> {
+        bincode::DefaultOptions::new()
+            .serialize(self)
+            .map_err(|e| e.into())
+    }
+
+    pub fn deserialize_bincode(bytes: &[u8]) -> Result<Self, Error> {
+        // Collections can't claim more memory than the payload has bytes
+        bincode::DefaultOptions::new()
+            .with_limit(bytes.len() as u64)
+            .deserialize(bytes)
+            .map_err(|e| e.into())
+    }
+
+    pub fn serialize_cbor(&self) -> Result<Vec<u8>, Error> {
+        // The streaming serializer claims to be human readable, which would turn hashes into
+        // hex strings the deserializer then doesn't accept
+        let value =
+            ciborium::Value::serialized(self).map_err(|e| Error::CborError(e.to_string()))?;
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).map_err(|e| Error::CborError(e.to_string()))?;
+        Ok(bytes)
+    }
+
+    pub fn deserialize_cbor(bytes: &[u8]) -> Result<Self, Error> {
+        ciborium::from_reader(bytes).or_else(|e| {
+            let value = ciborium::from_reader::<ciborium::Value, _>(bytes);
+            let tag = match &value {
+                Ok(ciborium::Value::Text(tag)) => Some(tag.as_str()),
+                Ok(ciborium::Value::Map(map)) if map.len() == 1 => map[0].0.as_text(),
+                _ => None,
+            };
+            Self::unknown_or(tag, Error::CborError(e.to_string()))
+        })
+    }
+
+    /// Same as [Message::serialize], in the given format
+    pub fn serialize_with(&self, format: WireFormat) -> Result<Vec<u8>, Error> {
+        match format {
+            WireFormat::Json => self.serialize(),
+            WireFormat::Bincode => self.serialize_bincode(),
+            WireFormat::Cbor => self.serialize_cbor(),
+        }
+    }
+
+    pub fn deserialize_with(bytes: &[u8], format: WireFormat) -> Result<Self, Error> {
+        match format {
+            WireFormat::Json => Self::deserialize_json(bytes),
+            WireFormat::Bincode => Self::deserialize_bincode(bytes),
+            WireFormat::Cbor => Self::deserialize_cbor(bytes),
+        }
     }
 
     pub fn check(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
@@ -42,10 +411,63 @@ impl Message {
 
     pub fn parse(src: &mut Cursor<&[u8]>) -> Result<Message, Error> {
         let line = Self::get_line(src)?;
-        let msg = serde_json::from_slice::<Message>(line)?;
+        Self::deserialize_json(line)
+    }
+
+    /// Same as [Message::parse], but rejects messages that break the limits
+    pub fn parse_with_limits(
+        src: &mut Cursor<&[u8]>,
+        limits: &NodeLimits,
+    ) -> Result<Message, Error> {
+        let line = Self::get_line(src)?;
+        limits.check_structure(line)?;
+        let msg = Self::deserialize_json(line)?;
+        limits.check_message(&msg)?;
+        Ok(msg)
+    }
+
+    /// Same as [Message::check], for [super::FrameMode::LengthPrefixed] frames
+    pub fn check_frame(src: &mut Cursor<&[u8]>) -> Result<(), Error> {
+        Self::get_frame(src)?;
+        Ok(())
+    }
+
+    /// Same as [Message::parse_with_limits], for [super::FrameMode::LengthPrefixed] frames
+    /// encoded in `format`
+    pub fn parse_frame_with_limits(
+        src: &mut Cursor<&[u8]>,
+        limits: &NodeLimits,
+        format: WireFormat,
+    ) -> Result<Message, Error> {
+        let payload = Self::get_frame(src)?;
+        // The structure checks only apply to JSON, the binary formats are decoded into the
+        // fixed shape of the message
+        if format == WireFormat::Json {
+            limits.check_structure(payload)?;
+        }
+        let msg = Self::deserialize_with(payload, format)?;
+        limits.check_message(&msg)?;
         Ok(msg)
     }
 
+    /// Payload of the length prefixed frame at the position of `src`, which is moved after it
+    pub fn get_frame<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
+        let start = src.position() as usize;
+        let buf = *src.get_ref();
+
+        let prefix = buf
+            .get(start..start + LENGTH_PREFIX_SIZE)
+            .ok_or(Error::IncompleteMessage)?;
+        let len = u32::from_be_bytes(prefix.try_into().unwrap()) as usize;
+
+        let payload_start = start + LENGTH_PREFIX_SIZE;
+        let payload = buf
+            .get(payload_start..payload_start + len)
+            .ok_or(Error::IncompleteMessage)?;
+        src.set_position((payload_start + len) as u64);
+        Ok(payload)
+    }
+
     pub fn get_line<'a>(src: &mut Cursor<&'a [u8]>) -> Result<&'a [u8], Error> {
         // Scan the bytes directly
         let start = src.position() as usize;
@@ -69,14 +491,144 @@ impl Message {
     }
 }
 
+/// Names of the [Message] variants this build decodes, as its derived [Deserialize] has them
+fn message_types() -> &'static [&'static str] {
+    static TYPES: OnceLock<&'static [&'static str]> = OnceLock::new();
+    TYPES.get_or_init(|| {
+        let types = Cell::new(&[][..]);
+        let _ = Message::deserialize(VariantNames(&types));
+        types.get()
+    })
+}
+
+/// Deserializer that only records the variant names the enum asks it for
+struct VariantNames<'a>(&'a Cell<&'static [&'static str]>);
+
+impl<'de> de::Deserializer<'de> for VariantNames<'_> {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("only enums have variant names"))
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        variants: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.0.set(variants);
+        Err(de::Error::custom("variant names recorded"))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        option unit unit_struct newtype_struct seq tuple tuple_struct map struct identifier
+        ignored_any
+    }
+}
+
+/// Node management, black list entries are single ips or networks like `10.0.0.0/8`, see
+/// [crate::BlackList::parse_entry]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum AdminReq {
+    /// Bans the entry for good
+    Ban(String),
+    /// Lifts the ban of exactly this entry
+    Unban(String),
+    ListBans,
+    /// Statistics of the peer at `ip`, or of every peer the node keeps track of, answered with
+    /// [Message::PeerStats]
+    PeerStats {
+        ip: Option<IpAddr>,
+    },
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum BlockReq {
-    Range { start: u64, end: u64 },
+    /// Blocks `start..=end`
+    Range {
+        start: u64,
+        end: u64,
+    },
     Number(u64),
     Hash(B256),
+    /// Latest block produced at or before the unix timestamp
+    Timestamp(u64),
+    /// Head of the chain
+    Latest,
+    /// First block of the chain, the genesis block if there is one
+    Earliest,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum SubscriptionKind {
+    /// Every block the node seals, sent as [Message::Block]
+    Blocks,
+    /// Every transaction the mempool accepts, sent as [Message::Transaction]
+    PendingTransactions,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct ChainInfo {
+    pub chain_id: u64,
+    /// Number of the latest block, 0 before the first block is produced
+    #[serde(alias = "head_number")]
+    pub head_block: u64,
+    pub head_hash: B256,
+    /// Seconds between blocks
+    pub block_time: u64,
+    pub role: NodeRole,
+    /// See [ChainSpec::coinbase_maturity]
+    pub coinbase_maturity: CoinbaseMaturity,
+    /// Head the producer reported, `None` unless the node followed one recently
+    #[serde(default)]
+    pub network_head: Option<u64>,
+    /// Blocks `head_block` trails the `network_head` by
+    #[serde(default)]
+    pub lag_blocks: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum Pruned {
+    /// Header of the block with a summary of each of its transactions
+    Block {
+        header: SealedHeader,
+        transactions: Vec<TransactionSummary>,
+    },
+    /// Answers both transaction and receipt requests
+    Transaction(TransactionSummary),
 }
 
+/// One transaction of a [Message::Transactions]
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub enum TransactionEntry {
+    Found(Transaction),
+    /// The block of the transaction is behind the retention horizon, see [Pruned::Transaction]
+    Pruned(TransactionSummary),
+    NotFound,
+}
+
+/// Outcome of one transaction of a [Message::SubmitTransactions]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SubmissionResult {
+    /// Handed to the mempool, with the hash to poll its receipt with
+    Accepted(B256),
+    Rejected(FailureReason),
+    /// Valid, but the mempool was full, it may be submitted again after `retry_after_ms`
+    Busy {
+        retry_after_ms: u64,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[non_exhaustive]
 pub enum TransactionReq {
     Many(Vec<B256>),
     Hash(B256),
@@ -85,77 +637,280 @@ pub enum TransactionReq {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use alloy_primitives::U256;
+
+    fn assert_round_trip(msg: &Message) {
+        for format in [WireFormat::Json, WireFormat::Bincode, WireFormat::Cbor] {
+            let bytes = msg.serialize_with(format).unwrap();
+            let de = Message::deserialize_with(&bytes, format).unwrap();
+            assert_eq!(msg, &de, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn test_unknown_types_are_decoded() {
+        let unknown = |kind: &str| Ok(Message::Unknown(String::from(kind)));
+        let json = |raw: &str| Message::deserialize_with(raw.as_bytes(), WireFormat::Json);
+        assert_eq!(
+            json(r#""FutureReq""#).map_err(|e| e.to_string()),
+            unknown("FutureReq")
+        );
+        assert_eq!(
+            json(r#"{"Future":{"field":1}}"#).map_err(|e| e.to_string()),
+            unknown("Future")
+        );
+        let line = b"{\"Future\":[1,2]}\r\n";
+        let msg = Message::parse(&mut Cursor::new(&line[..])).unwrap();
+        assert_eq!(msg, Message::Unknown(String::from("Future")));
+
+        // A known type with a broken payload stays an error
+        assert!(json(r#"{"Ping":"not a nonce"}"#).is_err());
+        assert!(json(r#"{"Ping":1,"Pong":2}"#).is_err());
+
+        let value = ciborium::Value::Map(vec![(
+            ciborium::Value::Text(String::from("Future")),
+            ciborium::Value::Integer(1.into()),
+        )]);
+        let mut bytes = Vec::new();
+        ciborium::into_writer(&value, &mut bytes).unwrap();
+        assert_eq!(
+            Message::deserialize_cbor(&bytes).map_err(|e| e.to_string()),
+            unknown("Future")
+        );
+
+        // Never sent, the node answers it instead
+        assert!(Message::Unknown(String::from("Future"))
+            .serialize()
+            .is_err());
+    }
+
+    #[test]
+    fn test_bincode_variant_indices_are_pinned() {
+        // bincode encodes the index of the variant, so new variants go at the end of
+        // [Message] and existing ones keep theirs
+        let index = |msg: Message| msg.serialize_bincode().unwrap()[0];
+        assert_eq!(index(Message::hello(&ChainSpec::default())), 0);
+        assert_eq!(index(Message::Ok), 33);
+    }
+
+    #[test]
+    fn test_cbor_uses_byte_strings() {
+        // Byte string header for 32 bytes followed by the bytes, instead of a hex string
+        let contains_byte_string = |bytes: &[u8], value: &[u8]| {
+            let expected = [&[0x58, 0x20], value].concat();
+            bytes
+                .windows(expected.len())
+                .any(|window| window == expected)
+        };
+
+        let hash = B256::repeat_byte(0xab);
+        let bytes = Message::ReceiptReq(hash).serialize_cbor().unwrap();
+        assert!(contains_byte_string(&bytes, hash.as_slice()));
+
+        let tx = Transaction {
+            r: U256::MAX,
+            ..Default::default()
+        };
+        let bytes = Message::Transaction(tx).serialize_cbor().unwrap();
+        assert!(contains_byte_string(&bytes, &[0xff; 32]));
+    }
 
     #[test]
     fn test_serialize_message() {
+        let msg = Message::hello(&ChainSpec::default());
+        assert_round_trip(&msg);
+
+        let msg = Message::IncompatiblePeer(String::new());
+        assert_round_trip(&msg);
+
         let msg = Message::Transaction(Transaction::default());
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
+
+        let msg = Message::Transactions(vec![
+            TransactionEntry::Found(Transaction::default()),
+            TransactionEntry::Pruned(TransactionSummary::default()),
+            TransactionEntry::NotFound,
+        ]);
+        assert_round_trip(&msg);
 
         let msg = Message::Block(SealedBlock::default());
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::Blocks(Vec::new());
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::BlockReq(BlockReq::Number(0));
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::BlockReq(BlockReq::Hash(B256::ZERO));
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
+
+        let msg = Message::BlockReq(BlockReq::Timestamp(0));
+        assert_round_trip(&msg);
+
+        let msg = Message::HeaderReq(BlockReq::Range { start: 0, end: 10 });
+        assert_round_trip(&msg);
+
+        let msg = Message::Headers(vec![SealedHeader::default()]);
+        assert_round_trip(&msg);
+
+        let msg = Message::Subscribe(SubscriptionKind::Blocks);
+        assert_round_trip(&msg);
+
+        let msg = Message::Limits(NodeLimits::default());
+        assert_round_trip(&msg);
+
+        let msg = Message::StatusReq;
+        assert_round_trip(&msg);
+
+        let msg = Message::Status {
+            uptime_secs: 3600,
+            head_number: 12,
+            head_hash: B256::repeat_byte(1),
+            pending_txs: 3,
+            peer_count: 2,
+            version: String::from("0.1.0"),
+            role: NodeRole::Follower,
+            last_failure: Some(String::from("mempool: Channel failure")),
+            network_head: Some(15),
+            lag_blocks: Some(3),
+        };
+        assert_round_trip(&msg);
+
+        let msg = Message::Batch(vec![Message::ChainInfoReq, Message::ReceiptReq(B256::ZERO)]);
+        assert_round_trip(&msg);
+
+        let msg = Message::BatchResponse(vec![Message::NonExistentTx, Message::Pong(1)]);
+        assert_round_trip(&msg);
+
+        let msg = Message::TransactionAccepted { hash: B256::ZERO };
+        assert_round_trip(&msg);
+
+        let msg = Message::SubmitTransactions(vec![Transaction::default()]);
+        assert_round_trip(&msg);
+
+        let msg = Message::SubmissionResults(vec![
+            SubmissionResult::Accepted(B256::ZERO),
+            SubmissionResult::Rejected(FailureReason::InvalidNonce),
+            SubmissionResult::Busy {
+                retry_after_ms: 100,
+            },
+        ]);
+        assert_round_trip(&msg);
+
+        let msg = Message::Busy {
+            retry_after_ms: 100,
+        };
+        assert_round_trip(&msg);
+
+        let msg = Message::BlockReq(BlockReq::Latest);
+        assert_round_trip(&msg);
 
         let msg = Message::BlockReq(BlockReq::Range { start: 0, end: 10 });
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::TransactionReq(TransactionReq::Hash(B256::ZERO));
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::TransactionReq(TransactionReq::Many(Vec::new()));
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
+
+        let msg = Message::ReceiptReq(B256::ZERO);
+        assert_round_trip(&msg);
+
+        let msg = Message::Receipt(TransactionReceipt::default());
+        assert_round_trip(&msg);
+
+        let msg = Message::Bundle(vec![Transaction::default()]);
+        assert_round_trip(&msg);
+
+        let msg = Message::BundleReceipt(BundleReceipt::default());
+        assert_round_trip(&msg);
+
+        let msg = Message::Ping(1_700_000_000_000);
+        assert_round_trip(&msg);
+
+        let msg = Message::Pong(1_700_000_000_000);
+        assert_round_trip(&msg);
+
+        let msg = Message::AccountReq(Address::ZERO);
+        assert_round_trip(&msg);
+
+        let msg = Message::Account(Account::new(100, 1));
+        assert_round_trip(&msg);
+
+        let msg = Message::RecoverSender(Transaction::default());
+        assert_round_trip(&msg);
+
+        let msg = Message::Pruned(Pruned::Block {
+            header: SealedHeader::default(),
+            transactions: vec![TransactionSummary::default()],
+        });
+        assert_round_trip(&msg);
+
+        let msg = Message::Pruned(Pruned::Transaction(TransactionSummary::default()));
+        assert_round_trip(&msg);
+
+        let msg = Message::ChainInfoReq;
+        assert_round_trip(&msg);
+
+        let info = ChainInfo {
+            chain_id: 1,
+            head_block: 10,
+            head_hash: B256::ZERO,
+            block_time: 10,
+            role: NodeRole::Follower,
+            coinbase_maturity: CoinbaseMaturity(1),
+            network_head: Some(12),
+            lag_blocks: Some(2),
+        };
+        assert_round_trip(&Message::ChainInfo(info));
+
+        // Nodes from before the rename still send `head_number`
+        let mut json = serde_json::to_value(info).unwrap();
+        let head = json.as_object_mut().unwrap().remove("head_block").unwrap();
+        json["head_number"] = head;
+        assert_eq!(serde_json::from_value::<ChainInfo>(json).unwrap(), info);
+
+        let msg = Message::MempoolStatusReq;
+        assert_round_trip(&msg);
+
+        let msg = Message::MempoolStatus {
+            pending: 3,
+            evicted: 2,
+            expired: 1,
+            oldest_pending: Some(1_700_000_000),
+        };
+        assert_round_trip(&msg);
 
         let msg = Message::NonExistentBlock;
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::NonExistentTx;
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
+
+        let msg = Message::NonExistentAccount;
+        assert_round_trip(&msg);
 
         let msg = Message::InvalidMessage(String::new());
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::InvalidTransaction;
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::InternalError(String::new());
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
 
         let msg = Message::Ok;
-        let bytes = serde_json::to_vec(&msg).unwrap();
-        let de: Message = serde_json::from_slice(&bytes).unwrap();
-        assert_eq!(msg, de);
+        assert_round_trip(&msg);
+
+        let msg = Message::AllowanceReq {
+            owner: Address::repeat_byte(1),
+            spender: Address::repeat_byte(2),
+        };
+        assert_round_trip(&msg);
+
+        let msg = Message::Allowance(u128::MAX);
+        assert_round_trip(&msg);
     }
 }
@@ -1,39 +1,190 @@
+mod black_list;
 mod connection;
 mod handler;
+mod ip_limit;
+mod limits;
 mod message;
+mod noise;
+mod peer_stats;
+mod rate_limit;
+mod rpc;
+mod status;
+mod supervisor;
+mod tls;
+mod unix;
+mod verifier;
+mod white_list;
+mod ws;
 
-use crate::executor::MempoolOrdering;
-pub use connection::Connection;
-pub use message::Message;
+use crate::executor::{
+    FailedBlockPolicy, ImportTx, MempoolOrdering, MempoolStatusTx, NodeRole, SimulationTx,
+    DEFAULT_BLOCK_GAS_LIMIT, DEFAULT_MEMPOOL_MAX_PER_SENDER, DEFAULT_MEMPOOL_MAX_SIZE,
+    DEFAULT_MEMPOOL_TTL,
+};
+pub use black_list::{BanPolicy, BlackList};
+pub use connection::{Connection, FrameMode};
+pub use limits::NodeLimits;
+pub use message::{
+    AdminReq, BlockReq, ChainInfo, Message, Pruned, SubmissionResult, SubscriptionKind,
+    TransactionEntry, TransactionReq, WireFormat, MAX_TRANSACTIONS_PER_REQUEST, PROTOCOL_VERSION,
+};
+pub use noise::{noise_accept, noise_connect, NoiseStream, PeerEncryption};
+pub use peer_stats::{PeerCounters, PeerSnapshot, PeerStats};
+pub use rate_limit::RateLimit;
+pub use status::{NodeStatus, StatusReport, SyncEvent, FELL_BEHIND_LAG};
+pub use supervisor::{OnTaskFailure, Task};
+pub use tls::{tls_acceptor, tls_connector, MaybeTlsStream, TlsConnection};
+pub use unix::UnixConnection;
+pub use white_list::WhiteList;
+pub use ws::WsStream;
+
+use ip_limit::{IpConnections, IpSlot};
+use rate_limit::{RateLimiter, RequestKind};
+use supervisor::Supervisor;
+use unix::{bind_unix, UNIX_PEER_ADDR};
+use verifier::{VerifierPool, VERIFIER_QUEUE_DEPTH};
 
 use crate::{
     database::{DatabaseReader, DatabaseWriter},
     executor::Mempool,
     server::handler::Handler,
-    Error, Executor,
+    utils::HashAlgo,
+    validation::{TxValidator, ValidationConfig, ValidationStage},
+    Bundle, ChainSpec, Error, Executor, SealedBlock, Shutdown, Transaction,
+};
+use alloy_primitives::{Address, U256};
+use k256::ecdsa::SigningKey;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
 };
-use alloy_primitives::Address;
-use std::sync::Arc;
 use tokio::{
-    net::TcpListener,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    select,
     sync::{
         broadcast,
         mpsc::{self, unbounded_channel},
-        RwLock,
+        oneshot, watch, OwnedSemaphorePermit, RwLock, Semaphore,
     },
+    time,
 };
-use tracing::{error, info};
+use tokio_rustls::{TlsAcceptor, TlsStream};
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tracing::{debug, error, info, warn};
+
+/// Interface the server listens on by default, only reachable from the same machine
+pub const DEFAULT_HOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+/// Amount of peers [PeerStats] keeps track of by default
+pub const DEFAULT_PEER_STATS_CAPACITY: usize = 1024;
+
+/// Amount of connections served at the same time by default
+pub const DEFAULT_MAX_CONNECTIONS: usize = 1024;
+
+/// Amount of connections a single ip may have open at the same time by default
+pub const DEFAULT_MAX_CONNECTIONS_PER_IP: usize = 8;
+
+/// How long a connection may go without a complete message by default
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long handling a single message may take by default
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Requests other than transactions a single ip may make by default
+pub const DEFAULT_RATE_LIMIT: RateLimit = RateLimit::new(100, 200);
+
+/// Transactions and bundles a single ip may submit by default
+pub const DEFAULT_TRANSACTION_RATE_LIMIT: RateLimit = RateLimit::new(20, 50);
+
+/// Connections a single ip may open by default
+pub const DEFAULT_CONNECTION_RATE_LIMIT: RateLimit = RateLimit::new(20, 100);
+
+/// Invalid transactions or malformed messages a single ip may send within a minute by default
+/// before it is banned
+pub const DEFAULT_BAN_POLICY: BanPolicy = BanPolicy::new(10, Duration::from_secs(60));
+
+/// How long a refused peer gets to take the busy message before it is disconnected
+const BUSY_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long a peer has to finish the TLS handshake or the WebSocket upgrade, it holds a
+/// connection slot in the meantime
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Sealed blocks a subscriber may fall behind before it is disconnected
+const NEW_BLOCKS_CAPACITY: usize = 16;
+
+/// Accepted transactions a subscriber may fall behind before it is disconnected
+const NEW_TRANSACTIONS_CAPACITY: usize = 1024;
+
+/// Peers idle for longer than this are the first to go once [PeerStats] is full
+const PEER_STATS_MAX_IDLE: Duration = Duration::from_secs(60 * 60);
+
+/// How often a follower that caught up asks the producer for new blocks
+const FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How long a follower waits before it tries to reach the producer again
+const FOLLOW_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Blocks a follower asks the producer for at once, below the default
+/// [NodeLimits::max_blocks_per_message]
+const FOLLOW_BATCH: u64 = 64;
+
+/// Connection to the producer of [Server::with_producer_addr], only kept open while the node
+/// follows it
+struct ProducerLink {
+    addr: SocketAddr,
+    role: watch::Receiver<NodeRole>,
+    connection: Option<TlsConnection>,
+}
+
+/// Requests handlers pass on to the [Server]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ControlMsg {
+    /// See [Server::pause]
+    Pause,
+    /// See [Server::resume]
+    Resume,
+    /// See [Server::promote]
+    Promote,
+    /// See [Server::demote]
+    Demote,
+}
+
+/// Steps [Server::run] goes through before it accepts connections, in order
+///
+/// The database has to be loaded or initialized with the spec before the [Server] is created,
+/// so nothing is admitted against a state that is still being written
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[non_exhaustive]
+pub enum StartupPhase {
+    /// The local chain is checked against the checkpoints of the [ChainSpec]
+    #[default]
+    Database,
+    /// The listeners are bound, the [Executor] and the [Mempool] are started
+    Tasks,
+    /// Every connection is served, the ones that queued up during startup included
+    Ready,
+}
 
 pub struct Server<DB> {
     /// Port on where the server will listen
     port: u16,
+
+    /// Addresses the server listens on, see [Server::with_addrs]
+    addrs: Vec<SocketAddr>,
     /// Arc copy to the database, database can be any data structure that implementes
     /// [DatabaseReader] and [DatabaseWriter]
     db: Arc<RwLock<DB>>,
 
-    /// Coinbase address of the executor
-    ///
-    /// This is technically not nesessary since we are not giving any rewards for mining a new
+    /// Spec the database was initialized with, shared with every [Handler]
+    spec: Arc<ChainSpec>,
+
+    /// Coinbase address of the executor, credited with [Server::with_block_reward] for every
     /// block
     coinbase: Address,
 
@@ -57,6 +208,136 @@ pub struct Server<DB> {
     /// [mpsc::Receiver] which will receive once all Senders are dropped thus ensuring all tasks
     /// were sutdown successfuly
     pub shutdown_complete_tx: mpsc::Sender<()>,
+
+    /// What to do when the [Mempool] or the [Executor] exits with an error
+    on_task_failure: OnTaskFailure,
+
+    /// Per ip statistics, updated by the accept loop and the handlers
+    peer_stats: Arc<PeerStats>,
+
+    /// Health of the node, answered with [Message::Status] without touching the database
+    status: Arc<NodeStatus>,
+
+    /// Whether the [Executor] produces blocks, can be flipped while the node is running
+    role: watch::Sender<NodeRole>,
+
+    /// Whether the [Executor] skips building blocks, can be flipped while the node is running
+    paused: watch::Sender<bool>,
+
+    /// What the [Executor] does with batches in which every transaction failed
+    failed_block_policy: FailedBlockPolicy,
+
+    /// Credited to the coinbase for every block the [Executor] seals
+    block_reward: u128,
+
+    /// Proof of work target of every block the [Executor] mines
+    difficulty: U256,
+
+    /// Most gas the transactions of a block may take up together
+    block_gas_limit: u64,
+
+    /// Whether the [Executor] runs transactions of unrelated accounts in parallel
+    parallel_execution: bool,
+
+    /// Whether the operator may hand the [Executor] blocks with [Message::ImportBlock] while
+    /// it produces, followers always take them
+    allow_import: bool,
+
+    /// Node a follower pulls its blocks from, see [Server::with_producer_addr]
+    producer_addr: Option<SocketAddr>,
+
+    /// Node the producer has to prove to be over Noise, see [Server::with_producer_node]
+    producer_node: Option<Address>,
+
+    /// Whether the links to and from other nodes are encrypted
+    peer_encryption: PeerEncryption,
+
+    /// Key the node proves its address with in Noise handshakes
+    node_key: Option<SigningKey>,
+
+    /// Port other nodes connect to, on every ip the server listens on
+    peer_port: Option<u16>,
+
+    /// Order in which the [Mempool] hands out transactions
+    mempool_ordering: MempoolOrdering,
+
+    /// Amount of pending transactions the [Mempool] holds before evicting
+    mempool_max_size: usize,
+
+    /// Amount of pending transactions a single sender may have in the [Mempool]
+    mempool_max_per_sender: usize,
+
+    /// How long a transaction may wait in the [Mempool]
+    mempool_ttl: Duration,
+
+    /// File the [Mempool] keeps its pending transactions in across restarts
+    mempool_persist_path: Option<PathBuf>,
+
+    /// Checks transactions on admission and on execution
+    validator: TxValidator,
+
+    /// Threads the admission checks of every connection run on
+    verifier_workers: usize,
+
+    /// What every connection accepts from its peer
+    limits: NodeLimits,
+
+    /// How far [Server::run] got, the listener is only bound once everything else runs
+    phase: watch::Sender<StartupPhase>,
+
+    /// Framing every connection of the listener uses
+    frame_mode: FrameMode,
+
+    /// Encoding every connection of the listener uses
+    wire_format: WireFormat,
+
+    /// Whether every connection starts with [Connection::negotiate_encoding]
+    negotiate_encoding: bool,
+
+    /// Connections that don't send a complete message for this long are closed
+    idle_timeout: Duration,
+
+    /// Messages that take longer than this to handle are answered with an error
+    request_timeout: Duration,
+
+    /// Connections beyond this many are told the server is busy and closed
+    max_connections: usize,
+
+    /// Connections of a single ip beyond this many are refused as well
+    max_connections_per_ip: usize,
+
+    /// Requests of a single ip beyond this rate are answered with [Message::RateLimited]
+    rate_limit: RateLimit,
+
+    /// Same for transactions and bundles, which have a bucket of their own
+    transaction_rate_limit: RateLimit,
+
+    /// Same for opening connections, over it a connection is closed before it is admitted
+    connection_rate_limit: RateLimit,
+
+    /// Ips whose connections are closed right away, filled by the handlers
+    black_list: Arc<RwLock<BlackList>>,
+
+    /// File the [BlackList] is restored from on startup and saved to on every change
+    black_list_path: Option<PathBuf>,
+
+    /// Ips that skip the [BlackList] and the rate limits
+    white_list: WhiteList,
+
+    /// Token [Message::Admin] requests have to carry, they are refused without one
+    admin_token: Option<Arc<str>>,
+
+    /// Terminates TLS on every connection, plain tcp without it
+    tls: Option<TlsAcceptor>,
+
+    /// Port JSON-RPC is served over HTTP on, on every ip the server listens on
+    http_port: Option<u16>,
+
+    /// Port messages are served over WebSocket on, on every ip the server listens on
+    ws_port: Option<u16>,
+
+    /// Unix domain socket local peers connect to, next to the tcp listeners
+    unix_socket: Option<PathBuf>,
 }
 
 impl<DB> Server<DB>
@@ -66,6 +347,7 @@ where
     /// Creates a new Server
     pub fn new(
         db: Arc<RwLock<DB>>,
+        spec: ChainSpec,
         port: u16,
         block_time: u64,
         coinbase: Address,
@@ -74,18 +356,483 @@ where
     ) -> Self {
         Self {
             port,
+            addrs: vec![SocketAddr::new(DEFAULT_HOST, port)],
             db,
+            spec: Arc::new(spec),
             block_time,
             coinbase,
             notify_shutdown,
             shutdown_complete_tx,
+            on_task_failure: OnTaskFailure::default(),
+            peer_stats: Arc::new(PeerStats::new(
+                DEFAULT_PEER_STATS_CAPACITY,
+                PEER_STATS_MAX_IDLE,
+            )),
+            status: Arc::new(NodeStatus::new()),
+            role: watch::channel(NodeRole::default()).0,
+            paused: watch::channel(false).0,
+            failed_block_policy: FailedBlockPolicy::default(),
+            block_reward: 0,
+            difficulty: U256::MAX,
+            block_gas_limit: DEFAULT_BLOCK_GAS_LIMIT,
+            parallel_execution: false,
+            allow_import: false,
+            producer_addr: None,
+            producer_node: None,
+            peer_encryption: PeerEncryption::default(),
+            node_key: None,
+            peer_port: None,
+            mempool_ordering: MempoolOrdering::default(),
+            mempool_max_size: DEFAULT_MEMPOOL_MAX_SIZE,
+            mempool_max_per_sender: DEFAULT_MEMPOOL_MAX_PER_SENDER,
+            mempool_ttl: DEFAULT_MEMPOOL_TTL,
+            mempool_persist_path: None,
+            validator: TxValidator::new(),
+            verifier_workers: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            limits: NodeLimits::default(),
+            phase: watch::channel(StartupPhase::default()).0,
+            frame_mode: FrameMode::default(),
+            wire_format: WireFormat::default(),
+            negotiate_encoding: false,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            request_timeout: DEFAULT_REQUEST_TIMEOUT,
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_connections_per_ip: DEFAULT_MAX_CONNECTIONS_PER_IP,
+            rate_limit: DEFAULT_RATE_LIMIT,
+            transaction_rate_limit: DEFAULT_TRANSACTION_RATE_LIMIT,
+            connection_rate_limit: DEFAULT_CONNECTION_RATE_LIMIT,
+            black_list: Arc::new(RwLock::new(BlackList::new(DEFAULT_BAN_POLICY))),
+            black_list_path: None,
+            white_list: WhiteList::default(),
+            admin_token: None,
+            tls: None,
+            http_port: None,
+            ws_port: None,
+            unix_socket: None,
+        }
+    }
+
+    pub fn with_limits(mut self, limits: NodeLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Sets how messages are delimited on every connection, clients have to use the same
+    pub fn with_frame_mode(mut self, frame_mode: FrameMode) -> Self {
+        self.frame_mode = frame_mode;
+        self
+    }
+
+    /// Sets how messages are encoded on every connection, binary formats imply
+    /// [FrameMode::LengthPrefixed]
+    pub fn with_wire_format(mut self, wire_format: WireFormat) -> Self {
+        self.wire_format = wire_format;
+        self
+    }
+
+    /// Agrees on the wire format with every peer, the one set by [Server::with_wire_format]
+    /// is only offered and the connection falls back to JSON if the peer doesn't offer it too
+    pub fn with_encoding_negotiation(mut self, negotiate_encoding: bool) -> Self {
+        self.negotiate_encoding = negotiate_encoding;
+        self
+    }
+
+    /// Sets how long a connection may wait between messages before it's closed, subscribed
+    /// connections are exempt
+    pub fn with_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Sets how long a single message may take to handle, independent of the idle timeout
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = request_timeout;
+        self
+    }
+
+    /// Listens on `host` instead of [DEFAULT_HOST], `0.0.0.0` or `::` make the node reachable
+    /// on every interface
+    pub fn with_host(mut self, host: IpAddr) -> Self {
+        self.addrs = vec![SocketAddr::new(host, self.port)];
+        self
+    }
+
+    /// Listens on every one of `addrs` instead, each with its own port, e.g. `0.0.0.0:8545`
+    /// next to `[::]:8545`
+    pub fn with_addrs(mut self, addrs: Vec<SocketAddr>) -> Self {
+        self.addrs = addrs;
+        self
+    }
+
+    /// Terminates TLS on every connection, see [tls_acceptor]
+    pub fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Also serves Ethereum style JSON-RPC over plain HTTP on `http_port`, see [rpc::serve]
+    pub fn with_http_port(mut self, http_port: u16) -> Self {
+        self.http_port = Some(http_port);
+        self
+    }
+
+    /// Also serves every [Message] over WebSocket on `ws_port`, one JSON message per
+    /// WebSocket message, for peers that can't open a tcp socket like browsers
+    ///
+    /// Upgraded over TLS with [Server::with_tls]
+    pub fn with_ws_port(mut self, ws_port: u16) -> Self {
+        self.ws_port = Some(ws_port);
+        self
+    }
+
+    /// Also serves [Message]s on the unix domain socket at `path`, like the native port but
+    /// without TLS, a socket a previous run left behind is replaced
+    ///
+    /// Combined with `with_addrs(vec![])` the node only listens on the socket
+    pub fn with_unix_socket(mut self, path: PathBuf) -> Self {
+        self.unix_socket = Some(path);
+        self
+    }
+
+    /// Sets how many connections are served at the same time
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Sets how many connections a single ip may have open at the same time
+    pub fn with_max_connections_per_ip(mut self, max_connections_per_ip: usize) -> Self {
+        self.max_connections_per_ip = max_connections_per_ip;
+        self
+    }
+
+    /// Sets how many requests other than transactions a single ip may make, across all of its
+    /// connections
+    pub fn with_rate_limit(mut self, rate_limit: RateLimit) -> Self {
+        self.rate_limit = rate_limit;
+        self
+    }
+
+    /// Sets how many transactions and bundles a single ip may submit
+    pub fn with_transaction_rate_limit(mut self, transaction_rate_limit: RateLimit) -> Self {
+        self.transaction_rate_limit = transaction_rate_limit;
+        self
+    }
+
+    /// Sets how many connections a single ip may open, further ones are closed right away
+    pub fn with_connection_rate_limit(mut self, connection_rate_limit: RateLimit) -> Self {
+        self.connection_rate_limit = connection_rate_limit;
+        self
+    }
+
+    /// Sets when peers are banned on their own, starts over with an empty [BlackList]
+    pub fn with_ban_policy(mut self, policy: BanPolicy) -> Self {
+        self.black_list = Arc::new(RwLock::new(BlackList::new(policy)));
+        self
+    }
+
+    /// See [BlackList::restore]
+    pub fn with_black_list_persistence(mut self, path: PathBuf) -> Self {
+        self.black_list_path = Some(path);
+        self
+    }
+
+    /// Never bans nor rate limits the peers in `white_list`
+    pub fn with_white_list(mut self, white_list: WhiteList) -> Self {
+        self.white_list = white_list;
+        self
+    }
+
+    /// Lets peers manage the [BlackList] with [Message::Admin] requests carrying `token`
+    pub fn with_admin_token(mut self, token: impl Into<Arc<str>>) -> Self {
+        self.admin_token = Some(token.into());
+        self
+    }
+
+    /// Banned ips, bans can be added and lifted while the node is running
+    pub fn black_list(&self) -> Arc<RwLock<BlackList>> {
+        self.black_list.clone()
+    }
+
+    /// Adds a check after the built in ones, applied both when a transaction is submitted and
+    /// when it is executed
+    pub fn with_validation_stage(mut self, stage: Arc<dyn ValidationStage>) -> Self {
+        self.validator = self.validator.with_stage(stage);
+        self
+    }
+
+    /// Verifies submitted transactions on `workers` threads instead of one per cpu, a full
+    /// queue of them answers [Message::Busy]
+    pub fn with_verifier_workers(mut self, workers: usize) -> Self {
+        self.verifier_workers = workers;
+        self
+    }
+
+    pub fn with_mempool_ttl(mut self, mempool_ttl: Duration) -> Self {
+        self.mempool_ttl = mempool_ttl;
+        self
+    }
+
+    /// See [Mempool::with_persistence]
+    pub fn with_mempool_persistence(mut self, path: PathBuf) -> Self {
+        self.mempool_persist_path = Some(path);
+        self
+    }
+
+    pub fn with_mempool_max_per_sender(mut self, mempool_max_per_sender: usize) -> Self {
+        self.mempool_max_per_sender = mempool_max_per_sender;
+        self
+    }
+
+    pub fn with_mempool_max_size(mut self, mempool_max_size: usize) -> Self {
+        self.mempool_max_size = mempool_max_size;
+        self
+    }
+
+    /// Sets which transactions the [Mempool] hands to the [Executor] first
+    pub fn with_mempool_ordering(mut self, mempool_ordering: MempoolOrdering) -> Self {
+        self.mempool_ordering = mempool_ordering;
+        self
+    }
+
+    /// Sets whether blocks made only of failed transactions are sealed
+    pub fn with_failed_block_policy(mut self, failed_block_policy: FailedBlockPolicy) -> Self {
+        self.failed_block_policy = failed_block_policy;
+        self
+    }
+
+    pub fn with_block_reward(mut self, block_reward: u128) -> Self {
+        self.block_reward = block_reward;
+        self
+    }
+
+    pub fn with_block_gas_limit(mut self, block_gas_limit: u64) -> Self {
+        self.block_gas_limit = block_gas_limit;
+        self
+    }
+
+    /// See [Executor::with_parallel_execution]
+    pub fn with_parallel_execution(mut self, parallel_execution: bool) -> Self {
+        self.parallel_execution = parallel_execution;
+        self
+    }
+
+    /// Accepts [Message::ImportBlock] while producing as well, followers always accept it, see
+    /// [Executor::import_block]
+    pub fn with_block_import(mut self, allow_import: bool) -> Self {
+        self.allow_import = allow_import;
+        self
+    }
+
+    /// Pulls the blocks of the node at `addr` and imports them for as long as this node follows
+    /// it, in the frame mode and wire format of this node
+    ///
+    /// The link is plain tcp unless it is encrypted with [Server::with_peer_encryption]
+    pub fn with_producer_addr(mut self, addr: SocketAddr) -> Self {
+        self.producer_addr = Some(addr);
+        self
+    }
+
+    /// Only pulls blocks over Noise from the producer that proves to be the node `address`
+    pub fn with_producer_node(mut self, address: Address) -> Self {
+        self.producer_node = Some(address);
+        self
+    }
+
+    /// Encrypts the links to and from other nodes, [PeerEncryption::Noise] takes a key set with
+    /// [Server::with_node_key] and, to follow a producer, [Server::with_producer_node]
+    ///
+    /// Clients keep connecting in plain tcp or TLS
+    pub fn with_peer_encryption(mut self, peer_encryption: PeerEncryption) -> Self {
+        self.peer_encryption = peer_encryption;
+        self
+    }
+
+    /// Sets the key the node proves its address with to other nodes, derived with the hash of
+    /// the spec
+    pub fn with_node_key(mut self, key: SigningKey) -> Self {
+        self.node_key = Some(key);
+        self
+    }
+
+    /// Also serves [Message]s to other nodes on `peer_port`, on every ip the server listens on,
+    /// encrypted with [Server::with_peer_encryption]
+    pub fn with_peer_port(mut self, peer_port: u16) -> Self {
+        self.peer_port = Some(peer_port);
+        self
+    }
+
+    /// Sets the target block hashes have to stay below, lower is harder to mine
+    pub fn with_difficulty(mut self, difficulty: U256) -> Self {
+        self.difficulty = difficulty;
+        self
+    }
+
+    /// Sets the role the node starts with
+    pub fn with_role(self, role: NodeRole) -> Self {
+        self.role.send_replace(role);
+        self.status.set_role(role);
+        self
+    }
+
+    pub fn role(&self) -> NodeRole {
+        *self.role.borrow()
+    }
+
+    /// Makes the node produce blocks, continuing from the latest block it has
+    pub fn promote(&self) {
+        self.role.send_replace(NodeRole::Producer);
+        self.status.set_role(NodeRole::Producer);
+    }
+
+    /// Stops block production, starting with the next block
+    pub fn demote(&self) {
+        self.role.send_replace(NodeRole::Follower);
+        self.status.set_role(NodeRole::Follower);
+    }
+
+    /// Skips building blocks until [Server::resume], unlike [Server::demote] the node stays
+    /// the producer and the transactions keep waiting in the [Mempool]
+    pub fn pause(&self) {
+        if !self.paused.send_replace(true) {
+            info!("Block production paused");
+        }
+    }
+
+    pub fn resume(&self) {
+        if self.paused.send_replace(false) {
+            info!("Block production resumed");
         }
     }
 
+    pub fn is_paused(&self) -> bool {
+        *self.paused.borrow()
+    }
+
+    /// Sets how many peers the statistics table keeps track of
+    pub fn with_peer_stats_capacity(mut self, capacity: usize) -> Self {
+        self.peer_stats = Arc::new(PeerStats::new(capacity, PEER_STATS_MAX_IDLE));
+        self
+    }
+
+    /// Per ip statistics of everyone that connected to this node
+    pub fn peer_stats(&self) -> Arc<PeerStats> {
+        self.peer_stats.clone()
+    }
+
+    /// Uptime, head and load of the node, as peers get it with [Message::StatusReq]
+    pub fn status(&self) -> Arc<NodeStatus> {
+        self.status.clone()
+    }
+
+    /// Follows the [StartupPhase] of the node, embedders can wait for
+    /// [StartupPhase::Ready] before they connect
+    pub fn phase(&self) -> watch::Receiver<StartupPhase> {
+        self.phase.subscribe()
+    }
+
+    /// Sets what happens when one of the spawned tasks fails
+    pub fn with_on_task_failure(mut self, on_task_failure: OnTaskFailure) -> Self {
+        self.on_task_failure = on_task_failure;
+        self
+    }
+
     /// Runs the server
+    ///
+    /// Returns an error if the local chain contradicts a checkpoint of the [ChainSpec], or if the
+    /// [Mempool] or the [Executor] failed and wasn't restarted, in which case the shutdown signal
+    /// was already sent
+    ///
+    /// Once [Server::notify_shutdown] is sent it returns after both tasks finished
     pub async fn run(&self) -> Result<(), Error> {
+        self.enter_phase(StartupPhase::Database);
+        // A node whose chain contradicts a checkpoint is on a fork and must not serve it
+        {
+            let db = self.db.read().await;
+            db.verify_checkpoints(self.spec.checkpoints())?;
+            // Handshakes and signatures use the spec's chain, so the database has to agree
+            match db.read_chain_id() {
+                Some(chain_id) if chain_id != self.spec.chain_id() => {
+                    return Err(Error::ChainIdMismatch {
+                        database: chain_id,
+                        spec: self.spec.chain_id(),
+                    })
+                }
+                _ => {}
+            }
+        }
+
+        if self.addrs.is_empty() && self.unix_socket.is_none() {
+            return Err(Error::NoListenAddr);
+        }
+        if self.peer_encryption == PeerEncryption::Noise {
+            if self.node_key.is_none() {
+                return Err(Error::Noise(String::from(
+                    "no node key to prove this node with",
+                )));
+            }
+            if self.producer_addr.is_some() && self.producer_node.is_none() {
+                return Err(Error::Noise(String::from(
+                    "no node address to authenticate the producer with",
+                )));
+            }
+        }
+
+        if let Some(path) = &self.black_list_path {
+            self.black_list.write().await.restore(path.clone())?;
+        }
+
+        // Every address has to be served, a single one missing is a misconfigured node, so that
+        // is found out before any task is started
+        let mut listeners = Vec::with_capacity(self.addrs.len());
+        for &addr in &self.addrs {
+            listeners.push((bind(addr, Transport::Native).await?, Transport::Native));
+        }
+        let mut ips: Vec<_> = self.addrs.iter().map(SocketAddr::ip).collect();
+        ips.sort();
+        ips.dedup();
+        let peer_transport = match self.peer_encryption {
+            PeerEncryption::None => Transport::Native,
+            PeerEncryption::Noise => Transport::Noise,
+        };
+        for (port, transport) in [
+            (self.http_port, Transport::Http),
+            (self.ws_port, Transport::WebSocket),
+            (self.peer_port, peer_transport),
+        ] {
+            if let Some(port) = port {
+                for &ip in &ips {
+                    let addr = SocketAddr::new(ip, port);
+                    listeners.push((bind(addr, transport).await?, transport));
+                }
+            }
+        }
+        let unix_listener = self.unix_socket.as_deref().map(bind_unix).transpose()?;
+
+        self.enter_phase(StartupPhase::Tasks);
+
         let (server_mempool_tx, server_mempool_rx) = mpsc::channel(1000);
+        let (server_bundle_tx, server_bundle_rx) = mpsc::channel(100);
         let (executor_mempool_tx, executor_mempool_rx) = unbounded_channel();
+        let (mempool_status_tx, mempool_status_rx) = mpsc::channel(100);
+        let (simulation_tx, simulation_rx) = mpsc::channel(100);
+        let (import_tx, import_rx) = mpsc::channel(100);
+        let (server_control_tx, server_control_rx) = mpsc::channel(16);
+        let validator = Arc::new(self.validator.clone());
+        let verifier = VerifierPool::spawn(
+            self.db.clone(),
+            validator.clone(),
+            ValidationConfig::from(self.spec.as_ref()),
+            self.verifier_workers,
+            VERIFIER_QUEUE_DEPTH,
+        )?;
+        let (new_blocks, _) = broadcast::channel(NEW_BLOCKS_CAPACITY);
+        let (new_transactions, _) = broadcast::channel(NEW_TRANSACTIONS_CAPACITY);
+        // Subscribed before the executor runs, so not even its first block is missed
+        let head_rx = new_blocks.subscribe();
+        if let Some(head) = self.db.read().await.latest_block() {
+            self.status.set_head(head.number(), *head.get_hash());
+        }
 
         let executor = Executor::new(
             self.db.clone(),
@@ -94,35 +841,1941 @@ where
             self.coinbase,
             self.notify_shutdown.subscribe(),
             self.shutdown_complete_tx.clone(),
-        );
+        )
+        .with_min_balance(self.spec.min_balance())
+        .with_coinbase_maturity(self.spec.coinbase_maturity())
+        .with_hash_algo(self.spec.hash_algo())
+        .with_chain_id(self.spec.chain_id())
+        .with_validator(validator.clone())
+        .with_new_blocks(new_blocks.clone())
+        .with_role(self.role.subscribe())
+        .with_paused(self.paused.subscribe())
+        .with_failed_block_policy(self.failed_block_policy)
+        .with_block_reward(self.block_reward)
+        .with_difficulty(self.difficulty)
+        .with_block_gas_limit(self.block_gas_limit)
+        .with_parallel_execution(self.parallel_execution)
+        .with_simulations(simulation_rx)
+        .with_imports(import_rx)
+        .with_checkpoints(self.spec.checkpoints().clone())
+        .with_producers(self.spec.producers().clone());
 
-        let mempool = Mempool::new(
+        let mut mempool = Mempool::new_with_capacity(
             server_mempool_rx,
             executor_mempool_rx,
-            MempoolOrdering::Fifo,
+            mempool_status_rx,
+            self.mempool_ordering,
             self.notify_shutdown.subscribe(),
             self.shutdown_complete_tx.clone(),
+            self.mempool_max_size,
+        )
+        .with_ttl(self.mempool_ttl)
+        .with_max_per_sender(self.mempool_max_per_sender)
+        .with_new_transactions(new_transactions.clone())
+        .with_pending_gauge(self.status.pending_txs())
+        .with_bundles(server_bundle_rx);
+        if let Some(path) = &self.mempool_persist_path {
+            let db = self.db.read().await;
+            let config = ValidationConfig::from(self.spec.as_ref());
+            mempool = mempool.with_persistence(path.clone(), &*db, &validator, config);
+        }
+
+        let mut supervisor = Supervisor::spawn(mempool, executor, self.on_task_failure)
+            .with_status(self.status.clone())
+            .with_notify_shutdown(self.notify_shutdown.clone());
+
+        info!(
+            listeners = listeners.len(),
+            unix_socket = unix_listener.is_some(),
+            "Rpc Server Initialized Successfuly"
         );
+        self.enter_phase(StartupPhase::Ready);
+
+        let res = select! {
+            res = supervisor.supervise() => return res,
+            res = self.control(server_control_rx) => res,
+            res = self.track_head(head_rx) => res,
+            res = self.follow(import_tx.clone()) => res,
+            res = self.track_network_head() => res,
+            res = self.accept(listeners, unix_listener, HandlerContext {
+                server_mempool_tx,
+                server_bundle_tx,
+                mempool_status_tx,
+                simulation_tx,
+                import_tx,
+                server_control_tx,
+                validator,
+                verifier,
+                new_blocks,
+                new_transactions,
+            }) => res,
+        };
+        res?;
+
+        // Accepting only stops once the shutdown signal is sent, which the tasks got as well
+        supervisor.supervise().await
+    }
 
-        tokio::spawn(mempool.run());
-        tokio::spawn(executor.run());
+    /// Applies the [ControlMsg] the handlers pass on
+    async fn control(
+        &self,
+        mut server_control_rx: mpsc::Receiver<ControlMsg>,
+    ) -> Result<(), Error> {
+        while let Some(msg) = server_control_rx.recv().await {
+            match msg {
+                ControlMsg::Pause => self.pause(),
+                ControlMsg::Resume => self.resume(),
+                ControlMsg::Promote => self.promote(),
+                ControlMsg::Demote => self.demote(),
+            }
+        }
+        // The accept loop holds a sender as long as it runs
+        std::future::pending().await
+    }
+
+    /// Keeps the head of the [NodeStatus] at the last block the [Executor] sealed or imported
+    async fn track_head(&self, mut head_rx: broadcast::Receiver<SealedBlock>) -> Result<(), Error> {
+        loop {
+            match head_rx.recv().await {
+                Ok(head) => self.status.set_head(head.number(), *head.get_hash()),
+                // Only the latest block matters, the next one received is newer anyway
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                // The executor is gone, the supervisor deals with that
+                Err(broadcast::error::RecvError::Closed) => std::future::pending().await,
+            }
+        }
+    }
 
-        let server = TcpListener::bind(format!("localhost:{}", self.port)).await?;
-        info!("Rpc Server Initialized Successfuly");
+    /// Imports the blocks of the producer set with [Server::with_producer_addr] while the node
+    /// follows it, the connection is opened again whenever the producer can't be reached
+    async fn follow(&self, import_tx: ImportTx) -> Result<(), Error> {
+        let Some(mut link) = self.producer_link() else {
+            return std::future::pending().await;
+        };
 
         loop {
-            let (stream, _) = match server.accept().await {
-                Ok(info) => info,
+            let producer = self.producer_connection(&mut link).await;
+            match self.pull_blocks(producer, &import_tx).await {
+                Ok(0) => time::sleep(FOLLOW_INTERVAL).await,
+                Ok(_) => {}
                 Err(e) => {
-                    error!(err = %e, "Couldn't accept connection, skipping");
-                    continue;
+                    warn!(
+                        addr = %link.addr,
+                        err = %e,
+                        "Couldn't follow the producer, reconnecting"
+                    );
+                    link.connection = None;
+                    time::sleep(FOLLOW_RETRY_DELAY).await;
                 }
-            };
+            }
+        }
+    }
+
+    /// Keeps the network head of the [NodeStatus] at the head the producer reports while the
+    /// node follows it
+    ///
+    /// Asks over a connection of its own, so the lag keeps growing while imports are stuck
+    async fn track_network_head(&self) -> Result<(), Error> {
+        let Some(mut link) = self.producer_link() else {
+            return std::future::pending().await;
+        };
+
+        loop {
+            let producer = self.producer_connection(&mut link).await;
+            match Self::producer_head(producer).await {
+                Ok(head) => {
+                    self.status.set_network_head(head);
+                    time::sleep(FOLLOW_INTERVAL).await;
+                }
+                Err(e) => {
+                    warn!(addr = %link.addr, err = %e, "Couldn't ask the producer for its head");
+                    link.connection = None;
+                    time::sleep(FOLLOW_RETRY_DELAY).await;
+                }
+            }
+        }
+    }
+
+    /// `None` unless the node has a producer to follow
+    fn producer_link(&self) -> Option<ProducerLink> {
+        Some(ProducerLink {
+            addr: self.producer_addr?,
+            role: self.role.subscribe(),
+            connection: None,
+        })
+    }
+
+    /// Waits until the node follows the producer of `link` and returns the connection to it,
+    /// which is opened again whenever it was dropped
+    async fn producer_connection<'a>(&self, link: &'a mut ProducerLink) -> &'a mut TlsConnection {
+        loop {
+            if *link.role.borrow_and_update() != NodeRole::Follower {
+                // A producer makes its own blocks, it may be demoted again later
+                link.connection = None;
+                if link.role.changed().await.is_err() {
+                    return std::future::pending().await;
+                }
+                continue;
+            }
+
+            if link.connection.is_none() {
+                match self.connect_producer(link.addr).await {
+                    Ok(connection) => link.connection = Some(connection),
+                    Err(e) => {
+                        warn!(addr = %link.addr, err = %e, "Couldn't reach the producer, retrying");
+                        time::sleep(FOLLOW_RETRY_DELAY).await;
+                        continue;
+                    }
+                }
+            }
+            // Opened right above if it wasn't already
+            break link.connection.as_mut().unwrap();
+        }
+    }
+
+    /// Opens a connection to the producer and greets it like a client would
+    async fn connect_producer(&self, addr: SocketAddr) -> Result<TlsConnection, Error> {
+        let stream = TcpStream::connect(addr).await?;
+        let stream: MaybeTlsStream = match (&self.node_key, self.producer_node) {
+            (Some(key), Some(expected)) if self.peer_encryption == PeerEncryption::Noise => {
+                let handshake = noise_connect(stream, key, self.spec.hash_algo(), expected);
+                time::timeout(HANDSHAKE_TIMEOUT, handshake)
+                    .await
+                    .map_err(|_| Error::Noise(String::from("handshake timed out")))??
+                    .into()
+            }
+            // [Server::run] doesn't start with Noise but without either
+            _ => MaybeTlsStream::Plain(stream),
+        };
+        let mut connection = Connection::new(stream).with_frame_mode(self.frame_mode);
+        if self.negotiate_encoding {
+            connection.negotiate_encoding(self.wire_format).await?;
+        } else {
+            connection = connection.with_wire_format(self.wire_format);
+        }
+
+        connection
+            .write_message(&Message::hello(&self.spec))
+            .await?;
+        match connection.read_message().await? {
+            Some(Message::Hello { .. }) => Ok(connection),
+            Some(Message::IncompatiblePeer(reason)) => Err(Error::IncompatiblePeer(reason)),
+            Some(other) => Err(Error::UnexpectedResponse(Box::new(other))),
+            None => Err(Error::ConnectionEnded),
+        }
+    }
 
-            let connection = Connection::new(stream);
-            let handler = Handler::new(self.db.clone(), connection, server_mempool_tx.clone());
+    /// Head the producer reports in its [ChainInfo]
+    async fn producer_head(producer: &mut TlsConnection) -> Result<u64, Error> {
+        producer.write_message(&Message::ChainInfoReq).await?;
+        match producer.read_message().await? {
+            Some(Message::ChainInfo(info)) => Ok(info.head_block),
+            Some(other) => Err(Error::UnexpectedResponse(Box::new(other))),
+            None => Err(Error::ConnectionEnded),
+        }
+    }
+
+    /// Imports the blocks the producer has on top of the local head, returns how many
+    async fn pull_blocks(
+        &self,
+        producer: &mut TlsConnection,
+        import_tx: &ImportTx,
+    ) -> Result<usize, Error> {
+        let start = self
+            .db
+            .read()
+            .await
+            .latest_block()
+            .map_or(1, |head| head.number() + 1);
+        // Nothing to ask for until the producer reported a head beyond ours
+        let end = match self.status.network_head() {
+            Some(network_head) if network_head < start => return Ok(0),
+            Some(network_head) => network_head.min(start + FOLLOW_BATCH - 1),
+            None => start + FOLLOW_BATCH - 1,
+        };
+        let req = BlockReq::Range { start, end };
+        producer.write_message(&Message::BlockReq(req)).await?;
+        let blocks = match producer.read_message().await? {
+            Some(Message::Blocks(blocks)) => blocks,
+            Some(other) => return Err(Error::UnexpectedResponse(Box::new(other))),
+            None => return Err(Error::ConnectionEnded),
+        };
+
+        let count = blocks.len();
+        for block in blocks {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            import_tx
+                .send((block, reply_tx))
+                .await
+                .map_err(|_| Error::ChannelFailure)?;
+            reply_rx.await.map_err(|_| Error::ChannelFailure)??;
+        }
+        Ok(count)
+    }
+
+    fn enter_phase(&self, phase: StartupPhase) {
+        info!(?phase, "Entering startup phase");
+        self.phase.send_replace(phase);
+    }
 
-            tokio::spawn(handler.handle_connection());
+    /// Accepts new connections and spawns a [Handler] for each one of them
+    async fn accept(
+        &self,
+        listeners: Vec<(TcpListener, Transport)>,
+        unix_listener: Option<UnixListener>,
+        context: HandlerContext,
+    ) -> Result<(), Error> {
+        // Every handler holds a permit and a slot of its ip until its connection is closed
+        let connections = Arc::new(Semaphore::new(self.max_connections));
+        let ip_connections = IpConnections::new(self.max_connections_per_ip);
+        let rate_limiter = Arc::new(RateLimiter::new(
+            self.rate_limit,
+            self.transaction_rate_limit,
+            self.connection_rate_limit,
+        ));
+
+        // One loop per listener, all of them share the limits below
+        let (accepted_tx, mut accepted_rx) = mpsc::channel(listeners.len() + 1);
+        for (listener, transport) in listeners {
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            tokio::spawn(listen(listener, transport, accepted_tx.clone(), shutdown));
+        }
+        if let Some(listener) = unix_listener {
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            tokio::spawn(listen_unix(listener, accepted_tx.clone(), shutdown));
+        }
+        drop(accepted_tx);
+
+        // Connections back from their TLS handshake, only with [Server::with_tls]
+        let (handshaken_tx, mut handshaken_rx) = mpsc::channel(16);
+        // Connections back from their WebSocket upgrade
+        let (upgraded_tx, mut upgraded_rx) = mpsc::channel(16);
+
+        loop {
+            select! {
+                accepted = accepted_rx.recv() => {
+                    let (stream, addr, transport) = match accepted {
+                        Some(Accepted::Tcp(stream, addr, transport)) => (stream, addr, transport),
+                        Some(Accepted::Unix(stream)) => {
+                            let admitted = self.admit(
+                                stream,
+                                UNIX_PEER_ADDR,
+                                &connections,
+                                &ip_connections,
+                                &rate_limiter,
+                                true,
+                            );
+                            if let Some(admitted) = admitted.await {
+                                self.serve(admitted, &context, &rate_limiter);
+                            }
+                            continue;
+                        }
+                        None => break,
+                    };
+                    let native = transport == Transport::Native;
+                    let admitted = self.admit(
+                        stream,
+                        addr,
+                        &connections,
+                        &ip_connections,
+                        &rate_limiter,
+                        native,
+                    );
+                    let admitted = match admitted.await {
+                        Some(admitted) => admitted,
+                        None => continue,
+                    };
+                    // Handshakes take a few round trips, other peers don't wait for them
+                    match (transport, &self.tls) {
+                        (Transport::Native, Some(acceptor)) => {
+                            let handshaken_tx = handshaken_tx.clone();
+                            tokio::spawn(handshake(acceptor.clone(), admitted, handshaken_tx));
+                        }
+                        (Transport::Native, None) => {
+                            let admitted = admitted.map_stream(MaybeTlsStream::Plain);
+                            self.serve(admitted, &context, &rate_limiter);
+                        }
+                        (Transport::Noise, _) => {
+                            // Only bound with a node key, see [Server::run]
+                            if let Some(key) = &self.node_key {
+                                let hash_algo = self.spec.hash_algo();
+                                let handshake = noise_handshake(
+                                    key.clone(),
+                                    hash_algo,
+                                    admitted,
+                                    handshaken_tx.clone(),
+                                );
+                                tokio::spawn(handshake);
+                            }
+                        }
+                        (Transport::Http, _) => self.serve_http(admitted, &context, &rate_limiter),
+                        (Transport::WebSocket, tls) => {
+                            let (tls, max_size) = (tls.clone(), self.limits.max_frame_size);
+                            tokio::spawn(upgrade(tls, admitted, max_size, upgraded_tx.clone()));
+                        }
+                    }
+                }
+                Some(admitted) = handshaken_rx.recv() => {
+                    self.serve(admitted, &context, &rate_limiter);
+                }
+                Some(admitted) = upgraded_rx.recv() => {
+                    self.serve_ws(admitted, &context, &rate_limiter);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks `stream` against the black list, the connection rate and limits, the connection is
+    /// closed if it doesn't pass, after telling the peer why if it speaks the native protocol
+    ///
+    /// Whitelisted peers are only held to the limits
+    async fn admit<S>(
+        &self,
+        stream: S,
+        addr: SocketAddr,
+        connections: &Arc<Semaphore>,
+        ip_connections: &Arc<IpConnections>,
+        rate_limiter: &RateLimiter,
+        native: bool,
+    ) -> Option<Admitted<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let peer = self.peer_stats.record_connection(addr.ip());
+
+        if !self.white_list.contains(&addr) {
+            if self.black_list.read().await.is_banned(&addr.ip()) {
+                debug!(%addr, "Peer is banned, closing connection");
+                peer.record_rejection("banned");
+                return None;
+            }
+
+            // Not even told why, answering a flood would only feed it
+            if rate_limiter
+                .check(addr.ip(), RequestKind::Connection)
+                .is_err()
+            {
+                debug!(%addr, "Peer opens connections too fast, closing connection");
+                peer.record_rejection("connection_rate_limited");
+                return None;
+            }
+        }
+
+        // Checked first, so a single host can't even briefly hold the permits of others
+        let slot = match ip_connections.try_acquire(addr.ip()) {
+            Some(slot) => slot,
+            None => {
+                warn!(
+                    %addr,
+                    connections = self.max_connections_per_ip,
+                    "Too many connections from this ip, refusing connection"
+                );
+                peer.record_rejection("too_many_connections_from_ip");
+                if native {
+                    self.refuse(stream, "too many connections from this ip");
+                }
+                return None;
+            }
+        };
+        let permit = match connections.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                warn!(
+                    %addr,
+                    connections = self.max_connections,
+                    "Server busy, refusing connection"
+                );
+                peer.record_rejection("server_busy");
+                if native {
+                    self.refuse(stream, "server busy");
+                }
+                return None;
+            }
+        };
+        debug!(
+            %addr,
+            connections = self.max_connections - connections.available_permits(),
+            from_ip = ip_connections.open(&addr.ip()),
+            "Accepted connection"
+        );
+
+        Some(Admitted {
+            stream,
+            addr,
+            peer,
+            permit,
+            slot,
+        })
+    }
+
+    /// Spawns the [Handler] of an admitted connection
+    fn serve<S>(
+        &self,
+        admitted: Admitted<S>,
+        context: &HandlerContext,
+        rate_limiter: &Arc<RateLimiter>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let admitted = admitted.map_stream(|stream| {
+            let connection = Connection::new(stream)
+                .with_limits(self.limits)
+                .with_frame_mode(self.frame_mode);
+            // Otherwise the format is only known once the peer answered
+            match self.negotiate_encoding {
+                true => connection,
+                false => connection.with_wire_format(self.wire_format),
+            }
+        });
+        self.spawn_handler(admitted, self.negotiate_encoding, context, rate_limiter);
+    }
+
+    /// Spawns the [Handler] of an upgraded WebSocket connection, which always speaks JSON
+    fn serve_ws(
+        &self,
+        admitted: Admitted<WsStream<MaybeTlsStream>>,
+        context: &HandlerContext,
+        rate_limiter: &Arc<RateLimiter>,
+    ) {
+        let admitted = admitted.map_stream(|stream| {
+            Connection::new(stream)
+                .with_limits(self.limits)
+                .with_frame_mode(FrameMode::LengthPrefixed)
+        });
+        self.spawn_handler(admitted, false, context, rate_limiter);
+    }
+
+    /// Spawns a [Handler] answering the messages of `admitted` until its connection is closed
+    fn spawn_handler<S>(
+        &self,
+        admitted: Admitted<Connection<S>>,
+        negotiate_encoding: bool,
+        context: &HandlerContext,
+        rate_limiter: &Arc<RateLimiter>,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + Sync + 'static,
+    {
+        let Admitted {
+            stream: connection,
+            addr,
+            peer,
+            permit,
+            slot,
+        } = admitted;
+
+        let mut handler = self
+            .handler(connection, addr, peer, context, rate_limiter)
+            .with_handshake()
+            .with_subscriptions(context.new_blocks.clone(), context.new_transactions.clone())
+            .with_shutdown(
+                self.notify_shutdown.subscribe(),
+                self.shutdown_complete_tx.clone(),
+            );
+        if negotiate_encoding {
+            handler = handler.with_encoding_negotiation(self.wire_format);
+        }
+        let connected = self.status.connected();
+
+        tokio::spawn(async move {
+            handler.handle_connection().await;
+            drop((permit, slot, connected));
+        });
+    }
+
+    /// Spawns the JSON-RPC server of an admitted HTTP connection
+    fn serve_http(
+        &self,
+        admitted: Admitted<TcpStream>,
+        context: &HandlerContext,
+        rate_limiter: &Arc<RateLimiter>,
+    ) {
+        let Admitted {
+            stream,
+            addr,
+            peer,
+            permit,
+            slot,
+        } = admitted;
+
+        // Calls are handed to the handler one by one, it only reads the limits off its connection
+        let connection = Connection::new(tokio::io::empty()).with_limits(self.limits);
+        let handler = self.handler(connection, addr, peer, context, rate_limiter);
+        let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+        let shutdown_complete_tx = self.shutdown_complete_tx.clone();
+        let (limits, idle_timeout) = (self.limits, self.idle_timeout);
+        let connected = self.status.connected();
+
+        tokio::spawn(async move {
+            rpc::serve(stream, handler, limits, idle_timeout, shutdown).await;
+            drop((permit, slot, connected, shutdown_complete_tx));
+        });
+    }
+
+    /// [Handler] of a connection from `addr`, with everything both protocols share
+    fn handler<S>(
+        &self,
+        connection: Connection<S>,
+        addr: SocketAddr,
+        peer: Arc<PeerCounters>,
+        context: &HandlerContext,
+        rate_limiter: &Arc<RateLimiter>,
+    ) -> Handler<DB, S>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send,
+    {
+        let mut handler = Handler::new(
+            self.db.clone(),
+            connection,
+            context.server_mempool_tx.clone(),
+            context.mempool_status_tx.clone(),
+            self.spec.clone(),
+            self.block_time,
+            peer,
+        )
+        .with_validator(context.validator.clone())
+        .with_verifier(context.verifier.clone())
+        .with_bundles(context.server_bundle_tx.clone())
+        .with_simulations(context.simulation_tx.clone())
+        .with_status(self.status.clone())
+        .with_idle_timeout(self.idle_timeout)
+        .with_request_timeout(self.request_timeout);
+        if !self.white_list.contains(&addr) {
+            handler = handler
+                .with_rate_limiter(rate_limiter.clone(), addr.ip())
+                .with_black_list(self.black_list.clone(), addr.ip());
+        }
+        handler = match self.allow_import {
+            true => handler.with_imports(context.import_tx.clone()),
+            false => handler.with_follower_imports(context.import_tx.clone()),
+        };
+        if let Some(token) = &self.admin_token {
+            handler = handler
+                .with_admin(self.black_list.clone(), token.clone(), addr.ip())
+                .with_peer_stats(self.peer_stats.clone())
+                .with_control(context.server_control_tx.clone());
+        }
+        handler
+    }
+
+    /// Tells the peer why it isn't served and closes the connection
+    fn refuse<S>(&self, stream: S, reason: &str)
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        // A negotiating peer expects the capability byte first and a TLS peer the handshake, so
+        // they are only closed
+        if self.negotiate_encoding || self.tls.is_some() {
+            return;
+        }
+
+        let mut connection = Connection::new(stream)
+            .with_frame_mode(self.frame_mode)
+            .with_wire_format(self.wire_format);
+        let refusal = Message::InternalError(String::from(reason));
+        tokio::spawn(async move {
+            let write = connection.write_message(&refusal);
+            let _ = tokio::time::timeout(BUSY_WRITE_TIMEOUT, write).await;
+            connection.shutdown().await;
+        });
+    }
+}
+
+/// What the peers of a listener speak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Transport {
+    /// [Message]s over tcp, or TLS with [Server::with_tls]
+    Native,
+    /// JSON-RPC over HTTP, see [Server::with_http_port]
+    Http,
+    /// [Message]s over WebSocket, see [Server::with_ws_port]
+    WebSocket,
+    /// [Message]s over Noise sessions with other nodes, see [Server::with_peer_encryption]
+    Noise,
+}
+
+/// A connection one of the listeners of [Server::accept] took
+enum Accepted {
+    Tcp(TcpStream, SocketAddr, Transport),
+    Unix(UnixStream),
+}
+
+/// Binds a listener on `addr`, a failure names the address
+async fn bind(addr: SocketAddr, transport: Transport) -> Result<TcpListener, Error> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|source| Error::Bind { addr, source })?;
+    info!(addr = %listener.local_addr()?, ?transport, "Listening");
+    Ok(listener)
+}
+
+/// Accepts connections on `listener` and passes them on, until shutdown or until nobody takes
+/// them anymore
+async fn listen(
+    listener: TcpListener,
+    transport: Transport,
+    accepted: mpsc::Sender<Accepted>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let res = select! {
+            res = listener.accept() => res,
+            _ = shutdown.recv() => return,
+            _ = accepted.closed() => return,
+        };
+        match res {
+            Ok((stream, addr)) => {
+                if accepted
+                    .send(Accepted::Tcp(stream, addr, transport))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Err(e) => error!(err = %e, "Couldn't accept connection, skipping"),
+        }
+    }
+}
+
+/// [listen] for the unix domain socket of [Server::with_unix_socket]
+async fn listen_unix(
+    listener: UnixListener,
+    accepted: mpsc::Sender<Accepted>,
+    mut shutdown: Shutdown,
+) {
+    loop {
+        let res = select! {
+            res = listener.accept() => res,
+            _ = shutdown.recv() => return,
+            _ = accepted.closed() => return,
+        };
+        match res {
+            Ok((stream, _)) => {
+                if accepted.send(Accepted::Unix(stream)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => error!(err = %e, "Couldn't accept connection, skipping"),
+        }
+    }
+}
+
+/// A connection that passed [Server::admit], it is counted against the limits until it is
+/// dropped
+struct Admitted<S> {
+    stream: S,
+    addr: SocketAddr,
+    peer: Arc<PeerCounters>,
+    permit: OwnedSemaphorePermit,
+    slot: IpSlot,
+}
+
+impl<S> Admitted<S> {
+    fn map_stream<T>(self, f: impl FnOnce(S) -> T) -> Admitted<T> {
+        Admitted {
+            stream: f(self.stream),
+            addr: self.addr,
+            peer: self.peer,
+            permit: self.permit,
+            slot: self.slot,
+        }
+    }
+}
+
+/// Runs the TLS handshake of `admitted` and passes it back to [Server::accept], the connection
+/// is closed if the handshake fails
+async fn handshake(
+    acceptor: TlsAcceptor,
+    admitted: Admitted<TcpStream>,
+    handshaken: mpsc::Sender<Admitted<MaybeTlsStream>>,
+) {
+    let Admitted {
+        stream,
+        addr,
+        peer,
+        permit,
+        slot,
+    } = admitted;
+
+    match time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+        Ok(Ok(stream)) => {
+            let admitted = Admitted {
+                stream: TlsStream::from(stream).into(),
+                addr,
+                peer,
+                permit,
+                slot,
+            };
+            let _ = handshaken.send(admitted).await;
+        }
+        Ok(Err(e)) => {
+            debug!(%addr, err = %e, "TLS handshake failed, closing connection");
+            peer.record_rejection("tls_handshake_failed");
+        }
+        Err(_) => {
+            debug!(%addr, "TLS handshake timed out, closing connection");
+            peer.record_rejection("tls_handshake_timeout");
+        }
+    }
+}
+
+/// Runs the Noise handshake of `admitted` and passes it back to [Server::accept], the connection
+/// is closed if the peer can't prove its node address
+async fn noise_handshake(
+    node_key: SigningKey,
+    hash_algo: HashAlgo,
+    admitted: Admitted<TcpStream>,
+    handshaken: mpsc::Sender<Admitted<MaybeTlsStream>>,
+) {
+    let Admitted {
+        stream,
+        addr,
+        peer,
+        permit,
+        slot,
+    } = admitted;
+
+    let handshake = noise_accept(stream, &node_key, hash_algo);
+    match time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+        Ok(Ok(stream)) => {
+            debug!(%addr, node = %stream.peer_node(), "Noise handshake done");
+            let admitted = Admitted {
+                stream: stream.into(),
+                addr,
+                peer,
+                permit,
+                slot,
+            };
+            let _ = handshaken.send(admitted).await;
+        }
+        Ok(Err(e)) => {
+            debug!(%addr, err = %e, "Noise handshake failed, closing connection");
+            peer.record_rejection("noise_handshake_failed");
+        }
+        Err(_) => {
+            debug!(%addr, "Noise handshake timed out, closing connection");
+            peer.record_rejection("noise_handshake_timeout");
+        }
+    }
+}
+
+/// Runs the TLS handshake if the node terminates TLS, then the WebSocket upgrade of `admitted`
+/// and passes it back to [Server::accept], the connection is closed if either fails
+async fn upgrade(
+    tls: Option<TlsAcceptor>,
+    admitted: Admitted<TcpStream>,
+    max_message_size: usize,
+    upgraded: mpsc::Sender<Admitted<WsStream<MaybeTlsStream>>>,
+) {
+    let Admitted {
+        stream,
+        addr,
+        peer,
+        permit,
+        slot,
+    } = admitted;
+
+    let upgrade = async {
+        let stream = match tls {
+            Some(acceptor) => TlsStream::from(acceptor.accept(stream).await?).into(),
+            None => MaybeTlsStream::Plain(stream),
+        };
+        let config = WebSocketConfig::default().max_message_size(Some(max_message_size));
+        tokio_tungstenite::accept_async_with_config(stream, Some(config))
+            .await
+            .map_err(io::Error::other)
+    };
+    match time::timeout(HANDSHAKE_TIMEOUT, upgrade).await {
+        Ok(Ok(ws)) => {
+            let admitted = Admitted {
+                stream: WsStream::new(ws),
+                addr,
+                peer,
+                permit,
+                slot,
+            };
+            let _ = upgraded.send(admitted).await;
+        }
+        Ok(Err(e)) => {
+            debug!(%addr, err = %e, "WebSocket upgrade failed, closing connection");
+            peer.record_rejection("ws_upgrade_failed");
+        }
+        Err(_) => {
+            debug!(%addr, "WebSocket upgrade timed out, closing connection");
+            peer.record_rejection("ws_upgrade_timeout");
+        }
+    }
+}
+
+/// Channels every [Handler] gets a clone of
+struct HandlerContext {
+    server_mempool_tx: mpsc::Sender<Transaction>,
+    server_bundle_tx: mpsc::Sender<Bundle>,
+    mempool_status_tx: MempoolStatusTx,
+    simulation_tx: SimulationTx,
+    /// Only taken while following, unless [Server::with_block_import]
+    import_tx: ImportTx,
+    server_control_tx: mpsc::Sender<ControlMsg>,
+    validator: Arc<TxValidator>,
+    verifier: VerifierPool,
+    new_blocks: broadcast::Sender<SealedBlock>,
+    new_transactions: broadcast::Sender<Transaction>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        fixtures::connect_when_up,
+        utils::{addr, funded_account, random_keypair, sign_transaction, HashAlgo},
+        Block, BlockHeader, InMemoryDB,
+    };
+    use alloy_primitives::B256;
+
+    #[tokio::test]
+    async fn test_bind_failure_names_the_address() {
+        let port = 47_524;
+        let _taken = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(InMemoryDB::new())),
+            ChainSpec::default(),
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        );
+        assert_eq!(server.addrs, vec![SocketAddr::new(DEFAULT_HOST, port)]);
+
+        let phase = server.phase();
+        let err = server.run().await.unwrap_err();
+        assert!(matches!(err, Error::Bind { addr, .. } if addr.port() == port));
+        assert!(err.to_string().contains("127.0.0.1:47524"), "{err}");
+        // Nothing was started for a node that can't be reached
+        assert_eq!(*phase.borrow(), StartupPhase::Database);
+
+        // Binding the others doesn't make up for it
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(InMemoryDB::new())),
+            ChainSpec::default(),
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_addrs(vec![
+            "127.0.0.1:0".parse().unwrap(),
+            SocketAddr::new(DEFAULT_HOST, port),
+        ]);
+        let err = server.run().await.unwrap_err();
+        assert!(matches!(err, Error::Bind { addr, .. } if addr.port() == port));
+
+        let server = server.with_addrs(Vec::new());
+        assert!(matches!(server.run().await, Err(Error::NoListenAddr)));
+    }
+
+    #[tokio::test]
+    async fn test_every_listener_is_served() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let addrs: Vec<SocketAddr> = vec![
+            "127.0.0.1:47525".parse().unwrap(),
+            "127.0.0.1:47526".parse().unwrap(),
+        ];
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            0,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_addrs(addrs.clone());
+        let node = tokio::spawn(async move { server.run().await });
+
+        for addr in addrs {
+            let mut connection = Connection::new(connect_when_up(addr.port()).await.unwrap());
+            connection.write_message(&hello).await.unwrap();
+            let greeting = connection.read_message().await.unwrap();
+            assert!(matches!(greeting, Some(Message::Hello { .. })), "{addr}");
+        }
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connections_are_served_over_tls() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let tls = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/tls");
+        let acceptor = tls_acceptor(&tls.join("cert.pem"), &tls.join("key.pem")).unwrap();
+        let connector = tls_connector(Some(&tls.join("ca.pem"))).unwrap();
+
+        let port = 47_527;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_tls(acceptor);
+        let node = tokio::spawn(async move { server.run().await });
+
+        let connect = || async move { connect_when_up(port).await.unwrap() };
+
+        let name = tokio_rustls::rustls::pki_types::ServerName::try_from("localhost").unwrap();
+        let stream = connector.connect(name, connect().await).await.unwrap();
+        let mut connection = TlsConnection::new_tls(stream.into());
+        connection.write_message(&hello).await.unwrap();
+        let greeting = connection.read_message().await.unwrap();
+        assert!(matches!(greeting, Some(Message::Hello { .. })));
+
+        // A plain hello isn't a TLS handshake
+        let mut connection = Connection::new(connect().await);
+        connection.write_message(&hello).await.unwrap();
+        assert!(!matches!(
+            connection.read_message().await,
+            Ok(Some(Message::Hello { .. }))
+        ));
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_json_rpc_is_served_over_http() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+
+        let port = 47_528;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            0,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_host("127.0.0.1".parse().unwrap())
+        .with_http_port(port);
+        let node = tokio::spawn(async move { server.run().await });
+
+        let mut stream = connect_when_up(port).await.unwrap();
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"}"#;
+        let request = format!(
+            "POST / HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\n\
+             Content-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{response}");
+        assert!(
+            response.ends_with(r#"{"jsonrpc":"2.0","id":1,"result":"0x0"}"#),
+            "{response}"
+        );
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_websocket_peers_get_pushed_transactions() {
+        use futures_util::{SinkExt, StreamExt};
+        use tokio_tungstenite::{tungstenite::Message as WsMessage, WebSocketStream};
+
+        let (key, from, account) = funded_account(1000);
+        let mut tx = Transaction {
+            from,
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &key, HashAlgo::Keccak256);
+
+        let spec = ChainSpec::new(1).with_account(from, account);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_529;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            0,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_host("127.0.0.1".parse().unwrap())
+        .with_ws_port(port);
+        let node = tokio::spawn(async move { server.run().await });
+
+        // Every message is the JSON of a [Message], like a browser would send it
+        let url = format!("ws://127.0.0.1:{port}");
+        let mut peers = Vec::new();
+        for _ in 0..2 {
+            let mut ws = loop {
+                match tokio_tungstenite::connect_async(&url).await {
+                    Ok((ws, _)) => break ws,
+                    Err(_) => tokio::time::sleep(Duration::from_millis(1)).await,
+                }
+            };
+            let text = serde_json::to_string(&hello).unwrap();
+            ws.send(WsMessage::text(text)).await.unwrap();
+            peers.push(ws);
+        }
+        // Sends `msg` and returns the answer, past the greeting of the server
+        async fn exchange<S>(ws: &mut WebSocketStream<S>, msg: &Message) -> Message
+        where
+            S: AsyncRead + AsyncWrite + Unpin,
+        {
+            let text = serde_json::to_string(msg).unwrap();
+            ws.send(WsMessage::text(text)).await.unwrap();
+            loop {
+                match ws.next().await.unwrap().unwrap() {
+                    WsMessage::Text(text) => {
+                        let reply: Message = serde_json::from_str(&text).unwrap();
+                        if !matches!(reply, Message::Hello { .. }) {
+                            return reply;
+                        }
+                    }
+                    other => panic!("unexpected message {other:?}"),
+                }
+            }
+        }
+        let (mut subscriber, mut sender) = (peers.remove(0), peers.remove(0));
+
+        let subscribe = Message::Subscribe(SubscriptionKind::PendingTransactions);
+        assert_eq!(exchange(&mut subscriber, &subscribe).await, Message::Ok);
+        let submit = Message::Transaction(tx.clone());
+        let accepted = Message::TransactionAccepted { hash: tx.hash };
+        assert_eq!(exchange(&mut sender, &submit).await, accepted);
+
+        match subscriber.next().await.unwrap().unwrap() {
+            WsMessage::Text(text) => {
+                let pushed: Message = serde_json::from_str(&text).unwrap();
+                assert_eq!(pushed, Message::Transaction(tx));
+            }
+            other => panic!("unexpected message {other:?}"),
+        }
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_submitted_over_unix_socket() {
+        let (key, from, account) = funded_account(1000);
+        let mut tx = Transaction {
+            from,
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &key, HashAlgo::Keccak256);
+
+        let spec = ChainSpec::new(1).with_account(from, account);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let path = std::env::temp_dir().join(format!("mini-node-{}.sock", std::process::id()));
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            0,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_addrs(Vec::new())
+        .with_unix_socket(path.clone());
+        let node = tokio::spawn(async move { server.run().await });
+
+        let mut connection = loop {
+            match UnixConnection::connect_unix(&path).await {
+                Ok(connection) => break connection,
+                Err(_) => tokio::time::sleep(Duration::from_millis(1)).await,
+            }
+        };
+        connection.write_message(&hello).await.unwrap();
+        let greeting = connection.read_message().await.unwrap();
+        assert!(matches!(greeting, Some(Message::Hello { .. })));
+        connection
+            .write_message(&Message::Transaction(tx.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.read_message().await.unwrap(),
+            Some(Message::TransactionAccepted { hash: tx.hash })
+        );
+
+        node.abort();
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_connections_wait_for_startup() {
+        let (key, from, account) = funded_account(1000);
+        let mut tx = Transaction {
+            from,
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &key, HashAlgo::Keccak256);
+        let hash = tx.hash;
+
+        let spec = ChainSpec::new(1).with_account(from, account);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_513;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        );
+        let phase = server.phase();
+
+        // The client starts knocking before the server does anything
+        let client = tokio::spawn(async move {
+            let stream = connect_when_up(port).await.unwrap();
+            let mut connection = Connection::new(stream);
+            connection.write_message(&hello).await.unwrap();
+            let greeting = connection.read_message().await.unwrap();
+            assert!(matches!(greeting, Some(Message::Hello { .. })));
+
+            connection
+                .write_message(&Message::Transaction(tx))
+                .await
+                .unwrap();
+            connection.read_message().await.unwrap()
+        });
+        let node = tokio::spawn(async move { server.run().await });
+
+        assert_eq!(
+            client.await.unwrap(),
+            Some(Message::TransactionAccepted { hash })
+        );
+        assert_eq!(*phase.borrow(), StartupPhase::Ready);
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connections_beyond_the_limit_are_refused() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_520;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_max_connections(1);
+        let node = tokio::spawn(async move { server.run().await });
+
+        let connect = || async move { Connection::new(connect_when_up(port).await.unwrap()) };
+
+        let mut first = connect().await;
+        first.write_message(&hello).await.unwrap();
+        assert!(matches!(
+            first.read_message().await.unwrap(),
+            Some(Message::Hello { .. })
+        ));
+
+        let mut second = connect().await;
+        assert_eq!(
+            second.read_message().await.unwrap(),
+            Some(Message::InternalError(String::from("server busy")))
+        );
+        assert_eq!(second.read_message().await.unwrap(), None);
+
+        // The permit comes back once the first connection is gone
+        drop(first);
+        let greeting = loop {
+            let mut third = connect().await;
+            third.write_message(&hello).await.unwrap();
+            match third.read_message().await.unwrap() {
+                Some(Message::InternalError(_)) => {
+                    tokio::time::sleep(Duration::from_millis(10)).await
+                }
+                greeting => break greeting,
+            }
+        };
+        assert!(matches!(greeting, Some(Message::Hello { .. })));
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_hundred_and_first_connection_is_refused() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_530;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        // All from the same ip, which would otherwise hit the per ip limits first
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_max_connections(100)
+        .with_max_connections_per_ip(200)
+        .with_connection_rate_limit(RateLimit::new(0, 0));
+        let node = tokio::spawn(async move { server.run().await });
+
+        let mut open = Vec::new();
+        for _ in 0..100 {
+            let stream = connect_when_up(port).await.unwrap();
+            // Answered, so the connection holds a permit
+            let mut connection = Connection::new(stream);
+            connection.write_message(&hello).await.unwrap();
+            assert!(matches!(
+                connection.read_message().await.unwrap(),
+                Some(Message::Hello { .. })
+            ));
+            open.push(connection);
+        }
+
+        let stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+        let mut refused = Connection::new(stream);
+        assert_eq!(
+            refused.read_message().await.unwrap(),
+            Some(Message::InternalError(String::from("server busy")))
+        );
+        assert_eq!(refused.read_message().await.unwrap(), None);
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_connections_per_ip_are_capped() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_521;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_max_connections_per_ip(3);
+        let node = tokio::spawn(async move { server.run().await });
+
+        let mut open = Vec::new();
+        let mut handshakes = 0;
+        for _ in 0..10 {
+            let stream = connect_when_up(port).await.unwrap();
+            let mut connection = Connection::new(stream);
+            // Refused connections may already be closed
+            let _ = connection.write_message(&hello).await;
+            match connection.read_message().await {
+                Ok(Some(Message::Hello { .. })) => handshakes += 1,
+                Ok(Some(Message::InternalError(_))) => {}
+                other => panic!("Unexpected answer {:?}", other),
+            }
+            open.push(connection);
+        }
+
+        assert_eq!(handshakes, 3);
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_requests_are_rate_limited() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_522;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_rate_limit(RateLimit::new(1, 2));
+        let node = tokio::spawn(async move { server.run().await });
+
+        let stream = connect_when_up(port).await.unwrap();
+        let mut connection = Connection::new(stream);
+        connection.write_message(&hello).await.unwrap();
+        assert!(matches!(
+            connection.read_message().await.unwrap(),
+            Some(Message::Hello { .. })
+        ));
+
+        // The handshake and keepalives don't take tokens
+        connection.write_message(&Message::Ping(1)).await.unwrap();
+        assert_eq!(
+            connection.read_message().await.unwrap(),
+            Some(Message::Pong(1))
+        );
+        for _ in 0..2 {
+            connection
+                .write_message(&Message::ChainInfoReq)
+                .await
+                .unwrap();
+            assert!(matches!(
+                connection.read_message().await.unwrap(),
+                Some(Message::ChainInfo(_))
+            ));
+        }
+        connection
+            .write_message(&Message::ChainInfoReq)
+            .await
+            .unwrap();
+        match connection.read_message().await.unwrap() {
+            Some(Message::RateLimited { retry_after_ms }) => {
+                assert!(retry_after_ms > 0 && retry_after_ms <= 1000)
+            }
+            other => panic!("Unexpected answer {:?}", other),
+        }
+
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_invalid_transactions_get_the_peer_banned() {
+        let spec = ChainSpec::new(1);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_523;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_ban_policy(BanPolicy::new(2, Duration::from_secs(60)));
+        let black_list = server.black_list();
+        let node = tokio::spawn(async move { server.run().await });
+
+        let connect = || async { Connection::new(connect_when_up(port).await.unwrap()) };
+
+        let mut connection = connect().await;
+        connection.write_message(&hello).await.unwrap();
+        assert!(matches!(
+            connection.read_message().await.unwrap(),
+            Some(Message::Hello { .. })
+        ));
+
+        // Unsigned, so it never makes it into the mempool
+        let invalid = Message::Transaction(Transaction::default());
+        for _ in 0..2 {
+            connection.write_message(&invalid).await.unwrap();
+            assert_eq!(
+                connection.read_message().await.unwrap(),
+                Some(Message::InvalidTransaction)
+            );
+        }
+        assert_eq!(connection.read_message().await.unwrap(), None);
+
+        let ip = "127.0.0.1".parse().unwrap();
+        assert!(black_list.read().await.is_banned(&ip));
+        let mut connection = connect().await;
+        let _ = connection.write_message(&hello).await;
+        assert!(!matches!(connection.read_message().await, Ok(Some(_))));
+
+        // Lifting the ban lets the peer back in
+        black_list.write().await.remove(ip);
+        let mut connection = connect().await;
+        connection.write_message(&hello).await.unwrap();
+        assert!(matches!(
+            connection.read_message().await.unwrap(),
+            Some(Message::Hello { .. })
+        ));
+
+        node.abort();
+    }
+
+    #[tokio::test]
+    async fn test_refuses_to_start_on_checkpoint_mismatch() {
+        let mut db = InMemoryDB::new();
+        let header = BlockHeader {
+            number: 1,
+            ..Default::default()
+        };
+        let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+        db.write_block(*block.get_hash(), block).unwrap();
+
+        let spec = ChainSpec::new(1).with_checkpoint(1, B256::repeat_byte(1));
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            0,
+            1,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        );
+
+        let err = server.run().await.unwrap_err();
+        assert!(matches!(err, Error::CheckpointMismatch { number: 1, .. }));
+    }
+
+    #[tokio::test]
+    async fn test_refuses_to_start_on_chain_id_mismatch() {
+        let mut db = InMemoryDB::new();
+        db.write_spec(&ChainSpec::new(9)).unwrap();
+
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            ChainSpec::new(1),
+            0,
+            1,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        );
+
+        let err = server.run().await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ChainIdMismatch {
+                database: 9,
+                spec: 1
+            }
+        ));
+    }
+
+    /// Number of the latest block in `db`, 0 for a chain that only has its genesis block
+    async fn head_number(db: &RwLock<InMemoryDB>) -> u64 {
+        db.read()
+            .await
+            .latest_block()
+            .map_or(0, |head| head.number())
+    }
+
+    /// Waits until the chain in `db` reaches block `number`
+    async fn wait_for_block(db: &RwLock<InMemoryDB>, number: u64) {
+        let reached = async {
+            while head_number(db).await < number {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), reached)
+            .await
+            .unwrap_or_else(|_| panic!("block {number} never came"));
+    }
+
+    #[tokio::test]
+    async fn test_promoted_follower_continues_the_chain() {
+        let (producer_coinbase, follower_coinbase) =
+            (Address::repeat_byte(1), Address::repeat_byte(2));
+        let spec = ChainSpec::new(1)
+            .with_producer(producer_coinbase)
+            .with_producer(follower_coinbase);
+        let spec_db = || {
+            let mut db = InMemoryDB::new();
+            db.write_spec(&spec).unwrap();
+            Arc::new(RwLock::new(db))
+        };
+        let (producer_port, follower_port) = (47_533, 47_534);
+
+        let producer_db = spec_db();
+        let (producer_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let producer = Server::new(
+            producer_db.clone(),
+            spec.clone(),
+            producer_port,
+            1,
+            producer_coinbase,
+            producer_shutdown.clone(),
+            shutdown_complete_tx,
+        );
+        let producer = tokio::spawn(async move { producer.run().await });
+
+        let follower_db = spec_db();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let follower = Server::new(
+            follower_db.clone(),
+            spec.clone(),
+            follower_port,
+            1,
+            follower_coinbase,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_role(NodeRole::Follower)
+        .with_admin_token("secret")
+        .with_producer_addr(SocketAddr::new(DEFAULT_HOST, producer_port));
+        let follower = tokio::spawn(async move { follower.run().await });
+
+        wait_for_block(&follower_db, 2).await;
+
+        // The producer goes down, whatever it sealed last may never reach the follower
+        let _ = producer_shutdown.send(());
+        producer.abort();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        let imported = head_number(&follower_db).await;
+        assert!(head_number(&producer_db).await >= imported);
+
+        let mut connection = Connection::new(connect_when_up(follower_port).await.unwrap());
+        connection
+            .write_message(&Message::hello(&spec))
+            .await
+            .unwrap();
+        assert!(matches!(
+            connection.read_message().await.unwrap(),
+            Some(Message::Hello { .. })
+        ));
+        connection
+            .write_message(&Message::PromoteReq(String::from("secret")))
+            .await
+            .unwrap();
+        assert_eq!(connection.read_message().await.unwrap(), Some(Message::Ok));
+
+        wait_for_block(&follower_db, imported + 2).await;
+        follower.abort();
+
+        // Every height exists once and builds on the one before it, the blocks up to the
+        // promotion are the ones of the producer
+        let follower_db = follower_db.read().await;
+        let producer_db = producer_db.read().await;
+        let head = follower_db.latest_block().unwrap().number();
+        for number in 1..=head {
+            let block = follower_db.read_block_by_number(number).unwrap();
+            let parent = follower_db.read_block_by_number(number - 1).unwrap();
+            assert_eq!(block.number(), number);
+            assert_eq!(block.parent_hash(), parent.get_hash());
+            if number <= imported {
+                assert_eq!(Some(block), producer_db.read_block_by_number(number));
+            } else {
+                assert_eq!(*block.header().coinbase(), follower_coinbase);
+            }
+        }
+        assert_eq!(follower_db.block_count() as u64, head + 1);
+    }
+
+    #[tokio::test]
+    async fn test_stalled_follower_reports_its_lag() {
+        let (producer_coinbase, follower_coinbase) =
+            (Address::repeat_byte(1), Address::repeat_byte(2));
+        let spec = ChainSpec::new(1)
+            .with_producer(producer_coinbase)
+            .with_producer(follower_coinbase);
+        let spec_db = || {
+            let mut db = InMemoryDB::new();
+            db.write_spec(&spec).unwrap();
+            Arc::new(RwLock::new(db))
+        };
+        let (producer_port, follower_port) = (47_535, 47_536);
+
+        let (producer_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let producer = Server::new(
+            spec_db(),
+            spec.clone(),
+            producer_port,
+            1,
+            producer_coinbase,
+            producer_shutdown,
+            shutdown_complete_tx,
+        );
+        let producer = tokio::spawn(async move { producer.run().await });
+
+        let follower_db = spec_db();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let follower = Server::new(
+            follower_db.clone(),
+            spec.clone(),
+            follower_port,
+            1,
+            follower_coinbase,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_role(NodeRole::Follower)
+        .with_producer_addr(SocketAddr::new(DEFAULT_HOST, producer_port));
+        let mut sync_events = follower.status().sync_events();
+        let follower = tokio::spawn(async move { follower.run().await });
+
+        wait_for_block(&follower_db, 1).await;
+        let mut connection = Connection::new(connect_when_up(follower_port).await.unwrap());
+        connection
+            .write_message(&Message::hello(&spec))
+            .await
+            .unwrap();
+        assert!(matches!(
+            connection.read_message().await.unwrap(),
+            Some(Message::Hello { .. })
+        ));
+
+        // Holding the database stalls every import while the producer keeps sealing blocks
+        let stalled = follower_db.write().await;
+        let lag = tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                connection.write_message(&Message::StatusReq).await.unwrap();
+                match connection.read_message().await.unwrap() {
+                    Some(Message::Status {
+                        lag_blocks: Some(lag),
+                        ..
+                    }) if lag > FELL_BEHIND_LAG => break lag,
+                    Some(Message::Status { .. }) => {
+                        tokio::time::sleep(Duration::from_millis(100)).await
+                    }
+                    other => panic!("unexpected message {other:?}"),
+                }
+            }
+        })
+        .await
+        .expect("the lag never grew");
+        assert!(lag > FELL_BEHIND_LAG);
+        assert!(matches!(
+            sync_events.recv().await,
+            Ok(SyncEvent::FellBehind { .. })
+        ));
+
+        drop(stalled);
+        let event = tokio::time::timeout(Duration::from_secs(10), sync_events.recv())
+            .await
+            .expect("the follower never caught up");
+        assert!(matches!(event, Ok(SyncEvent::CaughtUp { .. })));
+
+        producer.abort();
+        follower.abort();
+    }
+
+    #[tokio::test]
+    async fn test_follower_syncs_over_noise() {
+        let (producer_coinbase, follower_coinbase) =
+            (Address::repeat_byte(1), Address::repeat_byte(2));
+        let spec = ChainSpec::new(1)
+            .with_producer(producer_coinbase)
+            .with_producer(follower_coinbase);
+        let spec_db = || {
+            let mut db = InMemoryDB::new();
+            db.write_spec(&spec).unwrap();
+            Arc::new(RwLock::new(db))
+        };
+        let (producer_key, follower_key) = (random_keypair(), random_keypair());
+        let producer_node = addr(&producer_key, spec.hash_algo());
+        let (producer_port, peer_port, follower_port) = (47_537, 47_538, 47_539);
+
+        let (producer_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let producer = Server::new(
+            spec_db(),
+            spec.clone(),
+            producer_port,
+            1,
+            producer_coinbase,
+            producer_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_peer_encryption(PeerEncryption::Noise)
+        .with_node_key(producer_key)
+        .with_peer_port(peer_port);
+        let peer_stats = producer.peer_stats();
+        let producer = tokio::spawn(async move { producer.run().await });
+
+        let follower_db = spec_db();
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let follower = Server::new(
+            follower_db.clone(),
+            spec.clone(),
+            follower_port,
+            1,
+            follower_coinbase,
+            notify_shutdown,
+            shutdown_complete_tx,
+        )
+        .with_role(NodeRole::Follower)
+        .with_peer_encryption(PeerEncryption::Noise)
+        .with_node_key(follower_key)
+        .with_producer_addr(SocketAddr::new(DEFAULT_HOST, peer_port))
+        .with_producer_node(producer_node);
+        let follower = tokio::spawn(async move { follower.run().await });
+
+        wait_for_block(&follower_db, 2).await;
+
+        // A node that expects another producer gives up halfway, the producer doesn't let it in
+        let stream = connect_when_up(peer_port).await.unwrap();
+        let expected = Address::repeat_byte(3);
+        match noise_connect(stream, &random_keypair(), spec.hash_algo(), expected).await {
+            Err(Error::UnexpectedNode { found, .. }) => assert_eq!(found, producer_node),
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => panic!("the handshake passed"),
+        }
+        let rejected = async {
+            while !peer_stats
+                .snapshot(None)
+                .iter()
+                .any(|peer| peer.rejections.contains_key("noise_handshake_failed"))
+            {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), rejected)
+            .await
+            .expect("the producer never rejected the handshake");
+
+        // Nor does it answer peers that skip the handshake
+        let mut plain = Connection::new(connect_when_up(peer_port).await.unwrap());
+        plain.write_message(&Message::hello(&spec)).await.unwrap();
+        assert!(!matches!(plain.read_message().await, Ok(Some(_))));
+
+        producer.abort();
+        follower.abort();
+    }
+
+    #[tokio::test]
+    async fn test_status_follows_the_node() {
+        let (key, from, account) = funded_account(1000);
+        let mut tx = Transaction {
+            from,
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &key, HashAlgo::Keccak256);
+
+        let spec = ChainSpec::new(1).with_account(from, account);
+        let mut db = InMemoryDB::new();
+        db.write_spec(&spec).unwrap();
+        let header = BlockHeader {
+            number: 1,
+            ..Default::default()
+        };
+        let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+        let head_hash = *block.get_hash();
+        db.write_block(head_hash, block).unwrap();
+        let hello = Message::hello(&spec);
+
+        let port = 47_532;
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, _) = mpsc::channel(1);
+        let server = Server::new(
+            Arc::new(RwLock::new(db)),
+            spec,
+            port,
+            60,
+            Address::ZERO,
+            notify_shutdown,
+            shutdown_complete_tx,
+        );
+        let node = tokio::spawn(async move { server.run().await });
+
+        let mut connections = Vec::new();
+        for _ in 0..2 {
+            let mut connection = Connection::new(connect_when_up(port).await.unwrap());
+            connection.write_message(&hello).await.unwrap();
+            assert!(matches!(
+                connection.read_message().await.unwrap(),
+                Some(Message::Hello { .. })
+            ));
+            connections.push(connection);
+        }
+        let connection = &mut connections[0];
+        connection
+            .write_message(&Message::Transaction(tx.clone()))
+            .await
+            .unwrap();
+        assert_eq!(
+            connection.read_message().await.unwrap(),
+            Some(Message::TransactionAccepted { hash: tx.hash })
+        );
+
+        // The mempool stores its count once it took the transaction off the channel
+        let status = loop {
+            connection.write_message(&Message::StatusReq).await.unwrap();
+            match connection.read_message().await.unwrap() {
+                Some(Message::Status { pending_txs: 0, .. }) => {
+                    tokio::time::sleep(Duration::from_millis(1)).await
+                }
+                status => break status,
+            }
+        };
+        match status {
+            Some(Message::Status {
+                head_number,
+                head_hash: hash,
+                pending_txs,
+                peer_count,
+                version,
+                ..
+            }) => {
+                assert_eq!((head_number, hash), (1, head_hash));
+                assert_eq!(pending_txs, 1);
+                assert_eq!(peer_count, 2);
+                assert_eq!(version, env!("CARGO_PKG_VERSION"));
+            }
+            other => panic!("unexpected message {other:?}"),
         }
+        node.abort();
     }
 }
@@ -0,0 +1,424 @@
+use super::connection::LENGTH_PREFIX_SIZE;
+use crate::{
+    utils::{self, HashAlgo},
+    Error,
+};
+use alloy_primitives::{Address, B256, U256};
+use bytes::{Buf, BufMut, BytesMut};
+use clap::ValueEnum;
+use k256::ecdsa::SigningKey;
+use snow::{params::NoiseParams, Builder, HandshakeState, TransportState};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+
+/// XX sends the static keys of both sides during the handshake, neither has to know the other
+/// one up front
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message, authentication tag included
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+/// Authentication tag of every encrypted Noise message
+const TAG_SIZE: usize = 16;
+
+/// Hashed in front of the static key a node signs, so the signature can't pass for anything
+/// else signed with the node key
+const IDENTITY_DOMAIN: &[u8] = b"mini-blockchain noise identity";
+
+/// `v`, `r` and `s` of the signature over the static key
+const IDENTITY_PROOF_SIZE: usize = 65;
+
+const BUFFER_SIZE: usize = 1024 * 4;
+
+/// Whether the links between nodes are encrypted, see [super::Server::with_peer_encryption]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum PeerEncryption {
+    /// Plain tcp, or TLS with [super::Server::with_tls]
+    #[default]
+    None,
+    /// Noise sessions in which both nodes prove their address with their node key
+    Noise,
+}
+
+/// Stream of a [super::Connection] between two nodes, encrypted with the keys of a Noise
+/// session
+///
+/// Every Noise message travels as a [super::FrameMode::LengthPrefixed] frame
+pub struct NoiseStream<S> {
+    stream: S,
+    session: TransportState,
+    /// Node the peer proved to be during the handshake
+    peer_node: Address,
+    /// Ciphertext read from the stream, up to an incomplete frame
+    read: BytesMut,
+    /// Plaintext of the frames the reader didn't take yet
+    plaintext: BytesMut,
+    /// Encrypted frames not yet written to the stream
+    write: BytesMut,
+}
+
+impl<S> NoiseStream<S> {
+    fn new(stream: S, session: TransportState, peer_node: Address) -> Self {
+        Self {
+            stream,
+            session,
+            peer_node,
+            read: BytesMut::with_capacity(BUFFER_SIZE),
+            plaintext: BytesMut::new(),
+            write: BytesMut::new(),
+        }
+    }
+
+    /// Address the peer signed its static key with
+    pub fn peer_node(&self) -> Address {
+        self.peer_node
+    }
+
+    /// Takes the next complete frame off the read buffer and decrypts it
+    fn decrypt_frame(&mut self) -> io::Result<bool> {
+        if self.read.len() < LENGTH_PREFIX_SIZE {
+            return Ok(false);
+        }
+        let len = (&self.read[..LENGTH_PREFIX_SIZE]).get_u32() as usize;
+        if len > MAX_NOISE_MESSAGE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Noise message too large",
+            ));
+        }
+        if self.read.len() < LENGTH_PREFIX_SIZE + len {
+            return Ok(false);
+        }
+
+        self.read.advance(LENGTH_PREFIX_SIZE);
+        let frame = self.read.split_to(len);
+        let mut plaintext = vec![0; len];
+        let len = self
+            .session
+            .read_message(&frame, &mut plaintext)
+            .map_err(io::Error::other)?;
+        self.plaintext.extend_from_slice(&plaintext[..len]);
+        Ok(true)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> NoiseStream<S> {
+    /// Writes every encrypted frame to the stream
+    fn poll_write_frames(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while !self.write.is_empty() {
+            let written = ready!(Pin::new(&mut self.stream).poll_write(cx, &self.write))?;
+            if written == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            self.write.advance(written);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for NoiseStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.plaintext.is_empty() {
+            if this.decrypt_frame()? {
+                continue;
+            }
+
+            let mut chunk = [0; BUFFER_SIZE];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.stream).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                // Only a clean end if the peer didn't stop in the middle of a frame
+                if this.read.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+            }
+            this.read.extend_from_slice(chunk.filled());
+        }
+
+        let len = buf.remaining().min(this.plaintext.len());
+        buf.put_slice(&this.plaintext.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for NoiseStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Earlier frames go out first, so a slow peer holds up the writer instead of the memory
+        ready!(this.poll_write_frames(cx))?;
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let len = buf.len().min(MAX_NOISE_MESSAGE - TAG_SIZE);
+        let mut frame = vec![0; len + TAG_SIZE];
+        let frame_len = this
+            .session
+            .write_message(&buf[..len], &mut frame)
+            .map_err(io::Error::other)?;
+        this.write.put_u32(frame_len as u32);
+        this.write.extend_from_slice(&frame[..frame_len]);
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frames(cx))?;
+        Pin::new(&mut this.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_write_frames(cx))?;
+        Pin::new(&mut this.stream).poll_shutdown(cx)
+    }
+}
+
+/// Runs the handshake as the node that opened `stream`, the peer has to prove it is the node
+/// `expected`, otherwise the handshake is abandoned before this node proves who it is
+pub async fn noise_connect<S>(
+    mut stream: S,
+    node_key: &SigningKey,
+    hash_algo: HashAlgo,
+    expected: Address,
+) -> Result<NoiseStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut handshake, static_key) = handshake_state(true)?;
+    let mut message = vec![0; MAX_NOISE_MESSAGE];
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+
+    // -> e
+    let len = handshake
+        .write_message(&[], &mut message)
+        .map_err(noise_error)?;
+    write_frame(&mut stream, &message[..len]).await?;
+
+    // <- e, ee, s, es
+    let frame = read_frame(&mut stream).await?;
+    let len = handshake
+        .read_message(&frame, &mut payload)
+        .map_err(noise_error)?;
+    let found = peer_node(&handshake, &payload[..len], hash_algo)?;
+    if found != expected {
+        return Err(Error::UnexpectedNode { expected, found });
+    }
+
+    // -> s, se
+    let proof = prove_identity(&static_key, node_key);
+    let len = handshake
+        .write_message(&proof, &mut message)
+        .map_err(noise_error)?;
+    write_frame(&mut stream, &message[..len]).await?;
+
+    let session = handshake.into_transport_mode().map_err(noise_error)?;
+    Ok(NoiseStream::new(stream, session, found))
+}
+
+/// Runs the handshake as the node that accepted `stream`, any node that proves its address is
+/// let in
+pub async fn noise_accept<S>(
+    mut stream: S,
+    node_key: &SigningKey,
+    hash_algo: HashAlgo,
+) -> Result<NoiseStream<S>, Error>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut handshake, static_key) = handshake_state(false)?;
+    let mut message = vec![0; MAX_NOISE_MESSAGE];
+    let mut payload = vec![0; MAX_NOISE_MESSAGE];
+
+    // -> e
+    let frame = read_frame(&mut stream).await?;
+    handshake
+        .read_message(&frame, &mut payload)
+        .map_err(noise_error)?;
+
+    // <- e, ee, s, es
+    let proof = prove_identity(&static_key, node_key);
+    let len = handshake
+        .write_message(&proof, &mut message)
+        .map_err(noise_error)?;
+    write_frame(&mut stream, &message[..len]).await?;
+
+    // -> s, se
+    let frame = read_frame(&mut stream).await?;
+    let len = handshake
+        .read_message(&frame, &mut payload)
+        .map_err(noise_error)?;
+    let found = peer_node(&handshake, &payload[..len], hash_algo)?;
+
+    let session = handshake.into_transport_mode().map_err(noise_error)?;
+    Ok(NoiseStream::new(stream, session, found))
+}
+
+/// Handshake with a static key of its own, which the node key vouches for instead of being
+/// the node key itself, secp256k1 keys can't take part in a Noise handshake
+fn handshake_state(initiator: bool) -> Result<(HandshakeState, Vec<u8>), Error> {
+    let params: NoiseParams = NOISE_PARAMS.parse().map_err(noise_error)?;
+    let builder = Builder::new(params);
+    let keypair = builder.generate_keypair().map_err(noise_error)?;
+    let builder = builder.local_private_key(&keypair.private);
+    let handshake = if initiator {
+        builder.build_initiator()
+    } else {
+        builder.build_responder()
+    };
+    Ok((handshake.map_err(noise_error)?, keypair.public))
+}
+
+/// Signature of the node key over the static key of the handshake
+fn prove_identity(static_key: &[u8], node_key: &SigningKey) -> [u8; IDENTITY_PROOF_SIZE] {
+    let (v, r, s) = utils::sign_hash(identity_hash(static_key), node_key);
+    let mut proof = [0; IDENTITY_PROOF_SIZE];
+    proof[0] = v;
+    proof[1..33].copy_from_slice(&r.to_be_bytes::<32>());
+    proof[33..].copy_from_slice(&s.to_be_bytes::<32>());
+    proof
+}
+
+/// Address of the node that signed the static key the peer sent along with `proof`
+fn peer_node(
+    handshake: &HandshakeState,
+    proof: &[u8],
+    hash_algo: HashAlgo,
+) -> Result<Address, Error> {
+    // Both of the messages that carry a proof carry the static key as well
+    let static_key = handshake
+        .get_remote_static()
+        .ok_or(Error::InvalidSignature("no static key to verify"))?;
+    if proof.len() != IDENTITY_PROOF_SIZE {
+        return Err(Error::InvalidSignature("malformed node identity proof"));
+    }
+    let r = U256::from_be_slice(&proof[1..33]);
+    let s = U256::from_be_slice(&proof[33..]);
+    utils::recover_address(identity_hash(static_key), proof[0], r, s, hash_algo)
+}
+
+fn identity_hash(static_key: &[u8]) -> B256 {
+    utils::keccak256([IDENTITY_DOMAIN, static_key].concat())
+}
+
+async fn write_frame<S: AsyncWrite + Unpin>(stream: &mut S, frame: &[u8]) -> io::Result<()> {
+    stream.write_u32(frame.len() as u32).await?;
+    stream.write_all(frame).await?;
+    stream.flush().await
+}
+
+async fn read_frame<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Vec<u8>> {
+    let len = stream.read_u32().await? as usize;
+    if len > MAX_NOISE_MESSAGE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Noise message too large",
+        ));
+    }
+    let mut frame = vec![0; len];
+    stream.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+fn noise_error(e: snow::Error) -> Error {
+    Error::Noise(e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::{Connection, FrameMode, Message},
+        utils::random_keypair,
+    };
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_messages_cross_the_session() {
+        let (initiator_key, responder_key) = (random_keypair(), random_keypair());
+        let responder_node = utils::addr(&responder_key, HashAlgo::default());
+        let (initiator, responder) = tokio::io::duplex(4096);
+        let session = async {
+            let (initiator, responder) = tokio::join!(
+                noise_connect(
+                    initiator,
+                    &initiator_key,
+                    HashAlgo::default(),
+                    responder_node
+                ),
+                noise_accept(responder, &responder_key, HashAlgo::default())
+            );
+            let (initiator, responder) = (initiator.unwrap(), responder.unwrap());
+            assert_eq!(initiator.peer_node(), responder_node);
+            assert_eq!(
+                responder.peer_node(),
+                utils::addr(&initiator_key, HashAlgo::default())
+            );
+
+            let mut initiator =
+                Connection::new(initiator).with_frame_mode(FrameMode::LengthPrefixed);
+            let mut responder =
+                Connection::new(responder).with_frame_mode(FrameMode::LengthPrefixed);
+            initiator.write_message(&Message::Ping(7)).await.unwrap();
+            assert_eq!(
+                responder.read_message().await.unwrap(),
+                Some(Message::Ping(7))
+            );
+
+            // Takes more than one Noise message, far more than the duplex holds, so it is only
+            // written as fast as it is read
+            let large = Message::InternalError("x".repeat(3 * MAX_NOISE_MESSAGE));
+            let (written, read) =
+                tokio::join!(responder.write_message(&large), initiator.read_message());
+            written.unwrap();
+            assert_eq!(read.unwrap(), Some(large));
+
+            drop(responder);
+            assert_eq!(initiator.read_message().await.unwrap(), None);
+        };
+        tokio::time::timeout(Duration::from_secs(10), session)
+            .await
+            .expect("the session got stuck");
+    }
+
+    #[tokio::test]
+    async fn test_wrong_node_is_rejected_during_the_handshake() {
+        let (initiator_key, responder_key) = (random_keypair(), random_keypair());
+        let expected = utils::addr(&random_keypair(), HashAlgo::default());
+        let (initiator, responder) = tokio::io::duplex(4096);
+        // The initiator drops the stream once the responder proved who it is, before it
+        // proves who it is itself
+        let (initiator, responder) = tokio::join!(
+            noise_connect(initiator, &initiator_key, HashAlgo::default(), expected),
+            noise_accept(responder, &responder_key, HashAlgo::default())
+        );
+
+        match initiator {
+            Err(Error::UnexpectedNode {
+                expected: node,
+                found,
+            }) => {
+                assert_eq!(node, expected);
+                assert_eq!(found, utils::addr(&responder_key, HashAlgo::default()));
+            }
+            Err(e) => panic!("unexpected error {e}"),
+            Ok(_) => panic!("the handshake passed"),
+        }
+        assert!(responder.is_err());
+    }
+}
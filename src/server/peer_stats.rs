@@ -0,0 +1,232 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Counters of a single peer
+///
+/// Handlers keep an [Arc] to the counters of their peer, so updating them never touches the
+/// lock of the [PeerStats] table
+#[derive(Debug, Default)]
+pub struct PeerCounters {
+    connections: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    /// Unix timestamp of the last activity
+    last_seen: AtomicU64,
+    /// Messages received by variant, only ever locked by the handlers of this peer
+    messages: Mutex<HashMap<&'static str, u64>>,
+    /// Rejections by reason, only ever locked by the handlers of this peer
+    rejections: Mutex<HashMap<&'static str, u64>>,
+}
+
+impl PeerCounters {
+    pub fn record_message(&self, kind: &'static str) {
+        self.touch();
+        *self.messages.lock().unwrap().entry(kind).or_default() += 1;
+    }
+
+    pub fn record_rejection(&self, reason: &'static str) {
+        self.touch();
+        *self.rejections.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn record_traffic(&self, bytes_in: u64, bytes_out: u64) {
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+
+    fn touch(&self) {
+        self.last_seen.store(now(), Ordering::Relaxed);
+    }
+
+    fn last_seen(&self) -> u64 {
+        self.last_seen.load(Ordering::Relaxed)
+    }
+
+    fn snapshot(&self, ip: IpAddr) -> PeerSnapshot {
+        let collect = |map: &Mutex<HashMap<&'static str, u64>>| {
+            map.lock()
+                .unwrap()
+                .iter()
+                .map(|(kind, count)| (kind.to_string(), *count))
+                .collect()
+        };
+
+        PeerSnapshot {
+            ip,
+            connections: self.connections.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            last_seen: self.last_seen(),
+            messages: collect(&self.messages),
+            rejections: collect(&self.rejections),
+        }
+    }
+}
+
+/// Point in time copy of the counters of a peer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerSnapshot {
+    pub ip: IpAddr,
+    pub connections: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub last_seen: u64,
+    pub messages: BTreeMap<String, u64>,
+    pub rejections: BTreeMap<String, u64>,
+}
+
+/// Bounded table of per ip statistics, used when investigating abuse
+///
+/// Once the table is full, peers idle for longer than `max_idle` are dropped first and then
+/// the least recently seen one
+#[derive(Debug)]
+pub struct PeerStats {
+    peers: Mutex<HashMap<IpAddr, Arc<PeerCounters>>>,
+    capacity: usize,
+    max_idle: Duration,
+}
+
+impl PeerStats {
+    pub fn new(capacity: usize, max_idle: Duration) -> Self {
+        Self {
+            peers: Mutex::new(HashMap::new()),
+            capacity,
+            max_idle,
+        }
+    }
+
+    /// Records a new connection from `ip` and returns the counters of that peer
+    pub fn record_connection(&self, ip: IpAddr) -> Arc<PeerCounters> {
+        let mut peers = self.peers.lock().unwrap();
+
+        if !peers.contains_key(&ip) && peers.len() >= self.capacity {
+            self.evict(&mut peers);
+        }
+
+        let counters = peers.entry(ip).or_default().clone();
+        counters.connections.fetch_add(1, Ordering::Relaxed);
+        counters.touch();
+        counters
+    }
+
+    /// Returns the statistics of `ip`, or of every known peer if `ip` is `None`
+    pub fn snapshot(&self, ip: Option<IpAddr>) -> Vec<PeerSnapshot> {
+        let peers = self.peers.lock().unwrap();
+        match ip {
+            Some(ip) => peers
+                .get(&ip)
+                .map(|counters| counters.snapshot(ip))
+                .into_iter()
+                .collect(),
+            None => peers
+                .iter()
+                .map(|(ip, counters)| counters.snapshot(*ip))
+                .collect(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Makes room for at least one more peer
+    fn evict(&self, peers: &mut HashMap<IpAddr, Arc<PeerCounters>>) {
+        let idle_since = now().saturating_sub(self.max_idle.as_secs());
+        peers.retain(|_, counters| counters.last_seen() >= idle_since);
+
+        if peers.len() < self.capacity {
+            return;
+        }
+
+        if let Some(ip) = peers
+            .iter()
+            .min_by_key(|(_, counters)| counters.last_seen())
+            .map(|(ip, _)| *ip)
+        {
+            peers.remove(&ip);
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_table_stays_bounded() {
+        let stats = PeerStats::new(100, Duration::from_secs(3600));
+
+        for i in 0..10_000u32 {
+            let counters = stats.record_connection(IpAddr::V4(Ipv4Addr::from(i)));
+            counters.record_message("Transaction");
+            assert!(stats.len() <= 100);
+        }
+
+        assert_eq!(stats.len(), 100);
+    }
+
+    #[test]
+    fn test_counters_are_aggregated_per_ip() {
+        let stats = PeerStats::new(10, Duration::from_secs(3600));
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        let first = stats.record_connection(ip);
+        first.record_message("Transaction");
+        first.record_rejection("invalid_transaction");
+        first.record_traffic(100, 20);
+
+        let second = stats.record_connection(ip);
+        second.record_message("Transaction");
+        second.record_message("BlockReq");
+        second.record_traffic(50, 10);
+
+        let snapshot = stats.snapshot(Some(ip)).pop().unwrap();
+        assert_eq!(snapshot.connections, 2);
+        assert_eq!(snapshot.bytes_in, 150);
+        assert_eq!(snapshot.bytes_out, 30);
+        assert_eq!(snapshot.messages["Transaction"], 2);
+        assert_eq!(snapshot.messages["BlockReq"], 1);
+        assert_eq!(snapshot.rejections["invalid_transaction"], 1);
+
+        assert!(stats
+            .snapshot(Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_idle_peers_are_evicted_first() {
+        let stats = PeerStats::new(2, Duration::from_secs(60));
+        let idle = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let active = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        stats
+            .record_connection(idle)
+            .last_seen
+            .store(0, Ordering::Relaxed);
+        stats.record_connection(active);
+        stats.record_connection(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)));
+
+        assert!(stats.snapshot(Some(idle)).is_empty());
+        assert_eq!(stats.snapshot(Some(active)).len(), 1);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Buckets the [RateLimiter] keeps before it drops the ones that refilled completely
+const PRUNE_THRESHOLD: usize = 4096;
+
+/// How many requests a peer can make, refilled at `per_second` up to `burst`
+///
+/// A `per_second` of 0 disables the limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RateLimit {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+impl RateLimit {
+    pub const fn new(per_second: u32, burst: u32) -> Self {
+        Self { per_second, burst }
+    }
+
+    fn is_disabled(&self) -> bool {
+        self.per_second == 0
+    }
+}
+
+/// Which bucket a request is taken from, so flooding reads doesn't keep a peer from
+/// submitting transactions and the other way around
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RequestKind {
+    Read,
+    Transaction,
+    /// Opening a connection, so a single peer can't exhaust the file descriptors of the node
+    Connection,
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn full(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst.max(1) as f64,
+            refilled: now,
+        }
+    }
+
+    fn refill(&mut self, limit: RateLimit, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed * limit.per_second as f64).min(limit.burst.max(1) as f64);
+        self.refilled = now;
+    }
+
+    fn is_full(&self, limit: RateLimit) -> bool {
+        self.tokens >= limit.burst.max(1) as f64
+    }
+
+    /// Takes a token, or tells how long until the next one is available
+    fn try_take(&mut self, limit: RateLimit, now: Instant) -> Result<(), Duration> {
+        self.refill(limit, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return Ok(());
+        }
+
+        Err(Duration::from_secs_f64(
+            (1.0 - self.tokens) / limit.per_second as f64,
+        ))
+    }
+}
+
+#[derive(Debug)]
+struct PeerBuckets {
+    reads: TokenBucket,
+    transactions: TokenBucket,
+    connections: TokenBucket,
+}
+
+/// Token buckets of every peer by ip, shared by all the handlers of the server so opening
+/// more connections doesn't raise the limit
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    reads: RateLimit,
+    transactions: RateLimit,
+    connections: RateLimit,
+    buckets: Mutex<HashMap<IpAddr, PeerBuckets>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(reads: RateLimit, transactions: RateLimit, connections: RateLimit) -> Self {
+        Self {
+            reads,
+            transactions,
+            connections,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a token of `ip` for a request of `kind`, or tells how long the peer should wait
+    /// before retrying
+    pub(crate) fn check(&self, ip: IpAddr, kind: RequestKind) -> Result<(), Duration> {
+        self.check_at(ip, kind, Instant::now())
+    }
+
+    fn check_at(&self, ip: IpAddr, kind: RequestKind, now: Instant) -> Result<(), Duration> {
+        let limit = match kind {
+            RequestKind::Read => self.reads,
+            RequestKind::Transaction => self.transactions,
+            RequestKind::Connection => self.connections,
+        };
+        if limit.is_disabled() {
+            return Ok(());
+        }
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= PRUNE_THRESHOLD && !buckets.contains_key(&ip) {
+            // Peers whose buckets refilled are indistinguishable from peers never seen
+            buckets.retain(|_, peer| {
+                peer.reads.refill(self.reads, now);
+                peer.transactions.refill(self.transactions, now);
+                peer.connections.refill(self.connections, now);
+                !(peer.reads.is_full(self.reads)
+                    && peer.transactions.is_full(self.transactions)
+                    && peer.connections.is_full(self.connections))
+            });
+        }
+
+        let peer = buckets.entry(ip).or_insert_with(|| PeerBuckets {
+            reads: TokenBucket::full(self.reads, now),
+            transactions: TokenBucket::full(self.transactions, now),
+            connections: TokenBucket::full(self.connections, now),
+        });
+        match kind {
+            RequestKind::Read => peer.reads.try_take(limit, now),
+            RequestKind::Transaction => peer.transactions.try_take(limit, now),
+            RequestKind::Connection => peer.connections.try_take(limit, now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_bucket_refills_up_to_burst() {
+        let limiter = RateLimiter::new(
+            RateLimit::new(10, 3),
+            RateLimit::new(1, 1),
+            RateLimit::new(1, 1),
+        );
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip, RequestKind::Read, start).is_ok());
+        }
+        let retry_after = limiter.check_at(ip, RequestKind::Read, start).unwrap_err();
+        assert_eq!(retry_after, Duration::from_millis(100));
+
+        // Other peers and the transaction bucket are untouched
+        assert!(limiter.check_at(other, RequestKind::Read, start).is_ok());
+        assert!(limiter
+            .check_at(ip, RequestKind::Transaction, start)
+            .is_ok());
+        assert!(limiter
+            .check_at(ip, RequestKind::Transaction, start)
+            .is_err());
+
+        // A second refills 10 tokens, but only 3 fit in the bucket
+        let later = start + Duration::from_secs(1);
+        for _ in 0..3 {
+            assert!(limiter.check_at(ip, RequestKind::Read, later).is_ok());
+        }
+        assert!(limiter.check_at(ip, RequestKind::Read, later).is_err());
+    }
+
+    #[test]
+    fn test_connection_flood_is_throttled_after_burst() {
+        let limit = RateLimit::new(100, 50);
+        let limiter = RateLimiter::new(limit, limit, limit);
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let start = Instant::now();
+
+        // 200 connections within a millisecond, which refills a tenth of a token
+        let admitted = (0..200)
+            .map(|i| start + Duration::from_micros(i * 5))
+            .filter(|&now| limiter.check_at(ip, RequestKind::Connection, now).is_ok())
+            .count();
+        assert_eq!(admitted, 50);
+
+        // Requests over the connections that did get through aren't affected
+        assert!(limiter.check_at(ip, RequestKind::Read, start).is_ok());
+    }
+
+    #[test]
+    fn test_disabled_limit() {
+        let limiter = RateLimiter::new(
+            RateLimit::new(0, 0),
+            RateLimit::new(0, 0),
+            RateLimit::new(0, 0),
+        );
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+        for _ in 0..1000 {
+            assert!(limiter.check(ip, RequestKind::Read).is_ok());
+        }
+    }
+}
@@ -0,0 +1,628 @@
+use super::{handler::Handler, message::Pruned, BlockReq, Message, NodeLimits, TransactionReq};
+use crate::{
+    database::{DatabaseReader, DatabaseWriter},
+    SealedHeader, Shutdown, Transaction,
+};
+use alloy_primitives::{Address, B256};
+use bytes::Bytes;
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::{
+    body::Incoming, header, server::conn::http1, service::service_fn, Method, Request, Response,
+    StatusCode,
+};
+use hyper_util::rt::{TokioIo, TokioTimer};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::{json, Value};
+use std::{convert::Infallible, sync::Arc, time::Duration};
+use tokio::{io::Empty, net::TcpStream, select, sync::Mutex};
+use tracing::debug;
+
+/// The body isn't JSON
+const PARSE_ERROR: i64 = -32700;
+/// The JSON isn't a request object
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const INTERNAL_ERROR: i64 = -32603;
+/// The node refused the transaction
+const TRANSACTION_REJECTED: i64 = -32000;
+/// The peer ran out of tokens, see [super::RateLimit]
+const LIMIT_EXCEEDED: i64 = -32005;
+
+/// Serves JSON-RPC over HTTP on `stream` until the peer disconnects or the node shuts down,
+/// every call is answered by `handler`
+pub(crate) async fn serve<DB>(
+    stream: TcpStream,
+    handler: Handler<DB, Empty>,
+    limits: NodeLimits,
+    idle_timeout: Duration,
+    mut shutdown: Shutdown,
+) where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    let handler = Arc::new(Mutex::new(handler));
+    let service = service_fn(move |req| {
+        let handler = handler.clone();
+        async move { Ok::<_, Infallible>(respond(&handler, req, &limits).await) }
+    });
+
+    let connection = http1::Builder::new()
+        .timer(TokioTimer::new())
+        .header_read_timeout(idle_timeout)
+        .serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(connection);
+
+    let served = select! {
+        served = connection.as_mut() => served,
+        _ = shutdown.recv() => {
+            // Requests in flight are still answered
+            connection.as_mut().graceful_shutdown();
+            connection.await
+        }
+    };
+    if let Err(e) = served {
+        debug!(err = %e, "HTTP connection closed");
+    }
+}
+
+/// Answers a single HTTP request, only `POST` with a body of at most
+/// [NodeLimits::max_frame_size] is accepted
+async fn respond<DB>(
+    handler: &Mutex<Handler<DB, Empty>>,
+    req: Request<Incoming>,
+    limits: &NodeLimits,
+) -> Response<Full<Bytes>>
+where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    if req.method() != Method::POST {
+        let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+        response
+            .headers_mut()
+            .insert(header::ALLOW, header::HeaderValue::from_static("POST"));
+        return response;
+    }
+
+    let body = match Limited::new(req.into_body(), limits.max_frame_size)
+        .collect()
+        .await
+    {
+        Ok(body) => body.to_bytes(),
+        Err(e) if e.is::<LengthLimitError>() => return status(StatusCode::PAYLOAD_TOO_LARGE),
+        Err(_) => return status(StatusCode::BAD_REQUEST),
+    };
+
+    let reply = match limits.check_structure(&body) {
+        Ok(()) => handle_body(&mut *handler.lock().await, &body).await,
+        Err(e) => Some(Reply::Single(RpcResponse::error(
+            Value::Null,
+            RpcError::new(INVALID_REQUEST, e),
+        ))),
+    };
+    let reply = match reply {
+        Some(reply) => reply,
+        // Only notifications, which aren't answered
+        None => return status(StatusCode::NO_CONTENT),
+    };
+
+    match serde_json::to_vec(&reply) {
+        Ok(json) => {
+            let mut response = Response::new(Full::new(Bytes::from(json)));
+            response.headers_mut().insert(
+                header::CONTENT_TYPE,
+                header::HeaderValue::from_static("application/json"),
+            );
+            response
+        }
+        Err(_) => status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// A single request object or a batch of them, `None` if there is nothing to answer
+async fn handle_body<DB>(handler: &mut Handler<DB, Empty>, body: &[u8]) -> Option<Reply>
+where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    let body: Value = match serde_json::from_slice(body) {
+        Ok(body) => body,
+        Err(e) => {
+            let error = RpcError::new(PARSE_ERROR, e);
+            return Some(Reply::Single(RpcResponse::error(Value::Null, error)));
+        }
+    };
+
+    match body {
+        Value::Array(calls) if calls.is_empty() => {
+            let error = RpcError::new(INVALID_REQUEST, "empty batch");
+            Some(Reply::Single(RpcResponse::error(Value::Null, error)))
+        }
+        Value::Array(calls) => {
+            let mut responses = Vec::with_capacity(calls.len());
+            for call in calls {
+                responses.extend(handle_call(handler, call).await);
+            }
+            (!responses.is_empty()).then_some(Reply::Batch(responses))
+        }
+        call => handle_call(handler, call).await.map(Reply::Single),
+    }
+}
+
+/// Answers `call`, notifications are handled but not answered
+async fn handle_call<DB>(handler: &mut Handler<DB, Empty>, call: Value) -> Option<RpcResponse>
+where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    // Invalid requests are still answered with their id if they have one
+    let id = call.get("id").cloned().unwrap_or_default();
+    let call = match serde_json::from_value::<Call>(call) {
+        Ok(call) if call.jsonrpc == "2.0" => call,
+        Ok(_) => {
+            let error = RpcError::new(INVALID_REQUEST, "jsonrpc has to be \"2.0\"");
+            return Some(RpcResponse::error(id, error));
+        }
+        Err(e) => return Some(RpcResponse::error(id, RpcError::new(INVALID_REQUEST, e))),
+    };
+
+    let outcome = dispatch(handler, &call.method, &call.params).await;
+    Some(RpcResponse::new(call.id?, outcome))
+}
+
+/// Translates a call into the [Message] that answers it
+async fn dispatch<DB>(
+    handler: &mut Handler<DB, Empty>,
+    method: &str,
+    params: &[Value],
+) -> Result<Value, RpcError>
+where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    match method {
+        "eth_blockNumber" => match call(handler, Message::ChainInfoReq).await? {
+            Message::ChainInfo(info) => Ok(quantity(info.head_block.into())),
+            other => Err(unexpected(other)),
+        },
+        "eth_getBlockByNumber" => {
+            let block_req = block_tag(&required::<String>(params, 0)?)?;
+            let full = param::<bool>(params, 1)?.unwrap_or(false);
+            match call(handler, Message::BlockReq(block_req)).await? {
+                Message::Block(block) => {
+                    let transactions = block.transactions().into_iter().map(|tx| match full {
+                        true => to_value(tx),
+                        false => to_value(tx.hash),
+                    });
+                    block_json(block.header(), transactions)
+                }
+                // Pruned blocks only have a summary of their transactions left
+                Message::Pruned(Pruned::Block {
+                    header,
+                    transactions,
+                }) => {
+                    let transactions = transactions.iter().map(|summary| match full {
+                        true => to_value(summary),
+                        false => to_value(summary.hash),
+                    });
+                    block_json(&header, transactions)
+                }
+                Message::NonExistentBlock => Ok(Value::Null),
+                other => Err(unexpected(other)),
+            }
+        }
+        "eth_getBalance" => {
+            let address = required::<Address>(params, 0)?;
+            if let Some(tag) = param::<String>(params, 1)? {
+                if !matches!(tag.as_str(), "latest" | "pending") {
+                    return Err(RpcError::new(
+                        INVALID_PARAMS,
+                        "only the latest state is kept",
+                    ));
+                }
+            }
+            match call(handler, Message::AccountReq(address)).await? {
+                Message::Account(account) => Ok(quantity(account.balance())),
+                Message::NonExistentAccount => Ok(quantity(0)),
+                other => Err(unexpected(other)),
+            }
+        }
+        "eth_getTransactionByHash" => {
+            let hash = required::<B256>(params, 0)?;
+            match call(handler, Message::TransactionReq(TransactionReq::Hash(hash))).await? {
+                Message::Transaction(tx) => to_value(tx),
+                Message::Pruned(Pruned::Transaction(summary)) => to_value(summary),
+                Message::NonExistentTx => Ok(Value::Null),
+                other => Err(unexpected(other)),
+            }
+        }
+        "eth_getTransactionReceipt" => {
+            let hash = required::<B256>(params, 0)?;
+            match call(handler, Message::ReceiptReq(hash)).await? {
+                Message::Receipt(receipt) => to_value(receipt),
+                Message::BundleReceipt(receipt) => to_value(receipt),
+                Message::Pruned(Pruned::Transaction(summary)) => to_value(summary),
+                Message::NonExistentTx => Ok(Value::Null),
+                other => Err(unexpected(other)),
+            }
+        }
+        "eth_sendRawTransaction" => {
+            // The crate's own transaction JSON, either as an object or as a string of it
+            let tx = match required::<Value>(params, 0)? {
+                Value::String(raw) => serde_json::from_str::<Transaction>(&raw),
+                tx => serde_json::from_value::<Transaction>(tx),
+            }
+            .map_err(|e| invalid_param(0, e))?;
+            match call(handler, Message::Transaction(tx)).await? {
+                Message::TransactionAccepted { hash } => to_value(hash),
+                Message::InvalidTransaction => {
+                    Err(RpcError::new(TRANSACTION_REJECTED, "transaction rejected"))
+                }
+                other => Err(unexpected(other)),
+            }
+        }
+        _ => Err(RpcError::new(
+            METHOD_NOT_FOUND,
+            format!("method {method} not found"),
+        )),
+    }
+}
+
+/// Answers `msg` like any other connection would, errors every method shares are mapped here
+async fn call<DB>(handler: &mut Handler<DB, Empty>, msg: Message) -> Result<Message, RpcError>
+where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    match handler.handle_rpc(msg).await {
+        Ok(Message::RateLimited { retry_after_ms }) => Err(RpcError::new(
+            LIMIT_EXCEEDED,
+            format!("rate limited, retry after {retry_after_ms}ms"),
+        )),
+        Ok(Message::Busy { retry_after_ms }) => Err(RpcError::new(
+            LIMIT_EXCEEDED,
+            format!("node busy, retry after {retry_after_ms}ms"),
+        )),
+        Ok(Message::InternalError(e)) => Err(RpcError::new(INTERNAL_ERROR, e)),
+        Ok(msg) => Ok(msg),
+        Err(e) => Err(RpcError::new(INTERNAL_ERROR, e)),
+    }
+}
+
+fn unexpected(msg: Message) -> RpcError {
+    RpcError::new(
+        INTERNAL_ERROR,
+        format!("unexpected response {}", msg.kind()),
+    )
+}
+
+/// `latest` and `pending` both mean the head, the node has no pending block
+fn block_tag(tag: &str) -> Result<BlockReq, RpcError> {
+    match tag {
+        "latest" | "pending" => Ok(BlockReq::Latest),
+        "earliest" => Ok(BlockReq::Earliest),
+        number => number
+            .strip_prefix("0x")
+            .and_then(|hex| u64::from_str_radix(hex, 16).ok())
+            .map(BlockReq::Number)
+            .ok_or_else(|| invalid_param(0, format!("unknown block {tag}"))),
+    }
+}
+
+fn block_json(
+    header: &SealedHeader,
+    transactions: impl Iterator<Item = Result<Value, RpcError>>,
+) -> Result<Value, RpcError> {
+    Ok(json!({
+        "header": to_value(header)?,
+        "transactions": transactions.collect::<Result<Vec<_>, _>>()?,
+    }))
+}
+
+/// Hex encoded number, as Ethereum answers them
+fn quantity(n: u128) -> Value {
+    Value::String(format!("{n:#x}"))
+}
+
+fn to_value(value: impl Serialize) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e))
+}
+
+/// Positional parameter `index`, `None` if it was left out
+fn param<T: DeserializeOwned>(params: &[Value], index: usize) -> Result<Option<T>, RpcError> {
+    params
+        .get(index)
+        .map(|param| serde_json::from_value(param.clone()))
+        .transpose()
+        .map_err(|e| invalid_param(index, e))
+}
+
+fn required<T: DeserializeOwned>(params: &[Value], index: usize) -> Result<T, RpcError> {
+    param(params, index)?.ok_or_else(|| invalid_param(index, "missing"))
+}
+
+fn invalid_param(index: usize, err: impl std::fmt::Display) -> RpcError {
+    RpcError::new(INVALID_PARAMS, format!("parameter {index}: {err}"))
+}
+
+/// A request object, only positional parameters are supported
+#[derive(Debug, Deserialize)]
+struct Call {
+    jsonrpc: String,
+    /// `None` for notifications, `Some(Value::Null)` for an explicit `null`
+    #[serde(default, deserialize_with = "present")]
+    id: Option<Value>,
+    method: String,
+    #[serde(default)]
+    params: Vec<Value>,
+}
+
+fn present<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Value>, D::Error> {
+    Value::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Reply {
+    Single(RpcResponse),
+    Batch(Vec<RpcResponse>),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct RpcResponse {
+    jsonrpc: String,
+    id: Value,
+    #[serde(flatten)]
+    outcome: Outcome,
+}
+
+impl RpcResponse {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> Self {
+        let outcome = match outcome {
+            Ok(result) => Outcome::Result(result),
+            Err(error) => Outcome::Error(error),
+        };
+        Self {
+            jsonrpc: String::from("2.0"),
+            id,
+            outcome,
+        }
+    }
+
+    fn error(id: Value, error: RpcError) -> Self {
+        Self::new(id, Err(error))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Outcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl ToString) -> Self {
+        Self {
+            code,
+            message: message.to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        server::Connection,
+        utils::{addr, random_keypair, sign_transaction, HashAlgo},
+        Account, Block, BlockHeader, ChainSpec, InMemoryDB, TransactionReceipt, Transactions,
+    };
+    use tokio::sync::{mpsc, RwLock};
+
+    /// Handler answering calls on a chain with id 7, the receiver gets what reaches the mempool
+    fn handler(db: InMemoryDB) -> (Handler<InMemoryDB, Empty>, mpsc::Receiver<Transaction>) {
+        let (server_mempool_tx, mempool_rx) = mpsc::channel(1);
+        let (mempool_status_tx, _) = mpsc::channel(1);
+        let handler = Handler::new(
+            Arc::new(RwLock::new(db)),
+            Connection::new(tokio::io::empty()),
+            server_mempool_tx,
+            mempool_status_tx,
+            Arc::new(ChainSpec::new(7)),
+            10,
+            Arc::default(),
+        );
+        (handler, mempool_rx)
+    }
+
+    async fn answer(handler: &mut Handler<InMemoryDB, Empty>, body: &str) -> Value {
+        serde_json::to_value(handle_body(handler, body.as_bytes()).await).unwrap()
+    }
+
+    /// Result of calling `method` with `params`, or the code of the error
+    async fn call(
+        handler: &mut Handler<InMemoryDB, Empty>,
+        method: &str,
+        params: Value,
+    ) -> Result<Value, i64> {
+        let body = json!({"jsonrpc": "2.0", "id": 1, "method": method, "params": params});
+        let reply = answer(handler, &body.to_string()).await;
+        match reply.get("error") {
+            Some(error) => Err(error["code"].as_i64().unwrap()),
+            None => Ok(reply["result"].clone()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_envelope() {
+        let (mut handler, _mempool_rx) = handler(InMemoryDB::new());
+
+        let reply = answer(&mut handler, "{").await;
+        assert_eq!(reply["error"]["code"], PARSE_ERROR);
+        assert_eq!(reply["id"], Value::Null);
+
+        let reply = answer(&mut handler, r#"{"id":2,"method":"eth_blockNumber"}"#).await;
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+        assert_eq!(reply["id"], 2);
+
+        let body = r#"{"jsonrpc":"1.0","id":3,"method":"eth_chainId"}"#;
+        let reply = answer(&mut handler, body).await;
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+        assert_eq!(reply["id"], 3);
+
+        // Only positional parameters are supported
+        let body = r#"{"jsonrpc":"2.0","id":4,"method":"eth_getBalance","params":{"a":"0x1"}}"#;
+        let reply = answer(&mut handler, body).await;
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+        assert_eq!(reply["id"], 4);
+
+        let reply = answer(&mut handler, "[]").await;
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+
+        // Each member of a batch that isn't a request object gets its own error
+        let reply = answer(&mut handler, "[1,\"a\"]").await;
+        assert_eq!(reply.as_array().unwrap().len(), 2);
+        assert_eq!(reply[0]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(reply[1]["id"], Value::Null);
+
+        let body = r#"{"jsonrpc":"2.0","id":"a","method":"eth_mine"}"#;
+        assert_eq!(
+            answer(&mut handler, body).await,
+            json!({"jsonrpc": "2.0", "id": "a", "error": {
+                "code": METHOD_NOT_FOUND,
+                "message": "method eth_mine not found",
+            }})
+        );
+
+        // Notifications are handled, but not answered
+        let notification = r#"{"jsonrpc":"2.0","method":"eth_blockNumber"}"#;
+        assert_eq!(answer(&mut handler, notification).await, Value::Null);
+
+        let batch = format!(
+            r#"[{{"jsonrpc":"2.0","id":1,"method":"eth_blockNumber"}},{notification},
+                {{"jsonrpc":"2.0","id":null,"method":"eth_getBalance","params":["0x1"]}}]"#
+        );
+        let reply = answer(&mut handler, &batch).await;
+        assert_eq!(reply.as_array().unwrap().len(), 2);
+        assert_eq!(
+            reply[0],
+            json!({"jsonrpc": "2.0", "id": 1, "result": "0x0"})
+        );
+        assert_eq!(reply[1]["id"], Value::Null);
+        assert_eq!(reply[1]["error"]["code"], INVALID_PARAMS);
+    }
+
+    #[tokio::test]
+    async fn test_chain_is_read() {
+        let mut tx = Transaction {
+            nonce: 3,
+            ..Default::default()
+        };
+        tx.hash = tx.hash(HashAlgo::Keccak256);
+        let receipt = TransactionReceipt {
+            success: true,
+            block_number: 2,
+            ..Default::default()
+        };
+        let address = Address::repeat_byte(1);
+
+        let mut db = InMemoryDB::new();
+        for number in 1..=2 {
+            let header = BlockHeader {
+                number,
+                ..Default::default()
+            };
+            let transactions = match number {
+                2 => Transactions::from(vec![tx.clone()]),
+                _ => Transactions::default(),
+            };
+            let block = Block::new(header, transactions).seal_slow(HashAlgo::Keccak256);
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+        db.write_transaction(tx.clone()).unwrap();
+        db.write_transaction_receipt(tx.hash, receipt.clone())
+            .unwrap();
+        db.write_account(address, Account::new(500, 0)).unwrap();
+        let (mut handler, _mempool_rx) = handler(db);
+        let h = &mut handler;
+
+        assert_eq!(
+            call(h, "eth_blockNumber", json!([])).await,
+            Ok(json!("0x2"))
+        );
+
+        let block = call(h, "eth_getBlockByNumber", json!(["latest", false])).await;
+        let block = block.unwrap();
+        assert_eq!(block["header"]["number"], 2);
+        assert_eq!(block["transactions"], json!([tx.hash]));
+        let block = call(h, "eth_getBlockByNumber", json!(["0x2", true])).await;
+        assert_eq!(block.unwrap()["transactions"], json!([tx]));
+        let block = call(h, "eth_getBlockByNumber", json!(["earliest"])).await;
+        assert_eq!(block.unwrap()["header"]["number"], 1);
+        let missing = call(h, "eth_getBlockByNumber", json!(["0x9", false])).await;
+        assert_eq!(missing, Ok(Value::Null));
+        let unknown = call(h, "eth_getBlockByNumber", json!(["safe", false])).await;
+        assert_eq!(unknown, Err(INVALID_PARAMS));
+
+        let balance = call(h, "eth_getBalance", json!([address, "latest"])).await;
+        assert_eq!(balance, Ok(json!("0x1f4")));
+        let untouched = call(h, "eth_getBalance", json!([Address::ZERO])).await;
+        assert_eq!(untouched, Ok(json!("0x0")));
+        let historic = call(h, "eth_getBalance", json!([address, "0x1"])).await;
+        assert_eq!(historic, Err(INVALID_PARAMS));
+
+        let found = call(h, "eth_getTransactionByHash", json!([tx.hash])).await;
+        assert_eq!(found, Ok(json!(tx)));
+        let found = call(h, "eth_getTransactionReceipt", json!([tx.hash])).await;
+        assert_eq!(found, Ok(json!(receipt)));
+        for method in ["eth_getTransactionByHash", "eth_getTransactionReceipt"] {
+            let missing = call(h, method, json!([B256::ZERO])).await;
+            assert_eq!(missing, Ok(Value::Null), "{method}");
+            assert_eq!(call(h, method, json!([])).await, Err(INVALID_PARAMS));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_submitted() {
+        let key = random_keypair();
+        let signed = |nonce| {
+            let mut tx = Transaction {
+                from: addr(&key, HashAlgo::Keccak256),
+                nonce,
+                ..Default::default()
+            };
+            sign_transaction(&mut tx, 7, &key, HashAlgo::Keccak256);
+            tx
+        };
+        let (mut handler, mut mempool_rx) = handler(InMemoryDB::new());
+        let h = &mut handler;
+
+        let tx = signed(0);
+        let sent = call(h, "eth_sendRawTransaction", json!([tx])).await;
+        assert_eq!(sent, Ok(json!(tx.hash)));
+        assert_eq!(mempool_rx.recv().await.unwrap(), tx);
+
+        // The JSON may come as a string as well
+        let tx = signed(1);
+        let raw = serde_json::to_string(&tx).unwrap();
+        let sent = call(h, "eth_sendRawTransaction", json!([raw])).await;
+        assert_eq!(sent, Ok(json!(tx.hash)));
+        assert_eq!(mempool_rx.recv().await.unwrap(), tx);
+
+        let mut unsigned = signed(2);
+        unsigned.v = 4;
+        let sent = call(h, "eth_sendRawTransaction", json!([unsigned])).await;
+        assert_eq!(sent, Err(TRANSACTION_REJECTED));
+        let sent = call(h, "eth_sendRawTransaction", json!(["0xf86c"])).await;
+        assert_eq!(sent, Err(INVALID_PARAMS));
+        assert!(mempool_rx.try_recv().is_err());
+    }
+}
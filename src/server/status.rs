@@ -0,0 +1,264 @@
+use super::Task;
+use crate::NodeRole;
+use alloy_primitives::B256;
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast;
+use tracing::{info, warn};
+
+/// A follower counts as behind once it trails the network head by more than this many blocks,
+/// and as caught up again only once it reached the network head
+pub const FELL_BEHIND_LAG: u64 = 2;
+
+/// How long the head the producer reported counts as the network head, after that the node no
+/// longer knows how far behind it is
+const NETWORK_HEAD_TTL: Duration = Duration::from_secs(30);
+
+/// [SyncEvent]s nobody received yet, only transitions are sent so few ever pile up
+const SYNC_EVENTS_CAPACITY: usize = 16;
+
+/// Transition of a node relative to the network head, see [NodeStatus::sync_events]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SyncEvent {
+    /// Trails the network head by more than [FELL_BEHIND_LAG] blocks
+    FellBehind { lag_blocks: u64 },
+    /// Reached the network head again after falling behind
+    CaughtUp { head: u64 },
+}
+
+/// What the node knows about the head of the network
+#[derive(Debug, Default)]
+struct SyncState {
+    /// Head the producer reported last and when
+    network_head: Option<(u64, Instant)>,
+    /// Whether the last [SyncEvent] was [SyncEvent::FellBehind]
+    behind: bool,
+}
+
+/// Health of a running node, kept up to date by the [super::Server] and its tasks
+///
+/// Handlers answer [super::Message::StatusReq] from it, so they never wait for the database
+/// lock the [crate::Executor] holds while it builds a block
+#[derive(Debug)]
+pub struct NodeStatus {
+    started: Instant,
+    /// Number and hash of the head, always updated together
+    head: Mutex<(u64, B256)>,
+    /// Shared with the [crate::Mempool], which stores its length after every change
+    pending_txs: Arc<AtomicUsize>,
+    /// Connections currently served, of every transport
+    peer_count: AtomicUsize,
+    /// Set by the [super::Server] whenever it is promoted or demoted
+    role: Mutex<NodeRole>,
+    /// Task that failed last and why, whether it was restarted or not
+    last_failure: Mutex<Option<String>>,
+    /// Locked before `head` whenever both are needed
+    sync: Mutex<SyncState>,
+    sync_events: broadcast::Sender<SyncEvent>,
+}
+
+/// Point in time copy of the [NodeStatus]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct StatusReport {
+    pub uptime_secs: u64,
+    pub head_number: u64,
+    pub head_hash: B256,
+    /// Transactions waiting in the mempool
+    pub pending_txs: usize,
+    /// Open connections, the one asking included
+    pub peer_count: usize,
+    /// Version of the node's build
+    pub version: String,
+    pub role: NodeRole,
+    /// Task that failed last and why, `None` while every task runs as started
+    pub last_failure: Option<String>,
+    /// Head the producer reported, `None` unless the node followed one recently
+    pub network_head: Option<u64>,
+    /// Blocks the head trails the `network_head` by
+    pub lag_blocks: Option<u64>,
+}
+
+/// Counts a connection as served until it is dropped
+pub(crate) struct ConnectedPeer(Arc<NodeStatus>);
+
+impl Drop for ConnectedPeer {
+    fn drop(&mut self) {
+        self.0.peer_count.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl NodeStatus {
+    /// Uptime is counted from here on
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            head: Mutex::new((0, B256::ZERO)),
+            pending_txs: Arc::new(AtomicUsize::new(0)),
+            peer_count: AtomicUsize::new(0),
+            role: Mutex::new(NodeRole::default()),
+            last_failure: Mutex::new(None),
+            sync: Mutex::new(SyncState::default()),
+            sync_events: broadcast::channel(SYNC_EVENTS_CAPACITY).0,
+        }
+    }
+
+    pub fn set_head(&self, number: u64, hash: B256) {
+        *self.head.lock().unwrap() = (number, hash);
+        self.check_sync();
+    }
+
+    /// Records the head the producer reported, the node is as far behind as it is ahead of
+    /// the local head
+    pub fn set_network_head(&self, number: u64) {
+        self.sync.lock().unwrap().network_head = Some((number, Instant::now()));
+        self.check_sync();
+    }
+
+    /// Head of the network as the producer reported it, `None` if it didn't recently
+    pub fn network_head(&self) -> Option<u64> {
+        let sync = self.sync.lock().unwrap();
+        sync.network_head
+            .filter(|(_, at)| at.elapsed() < NETWORK_HEAD_TTL)
+            .map(|(number, _)| number)
+    }
+
+    /// Every time the node falls behind the network head or catches up with it again
+    pub fn sync_events(&self) -> broadcast::Receiver<SyncEvent> {
+        self.sync_events.subscribe()
+    }
+
+    /// Sends a [SyncEvent] if the lag crossed one of the thresholds
+    ///
+    /// Falling behind takes more than [FELL_BEHIND_LAG] blocks but catching up takes reaching
+    /// the network head, so a node that trails by a block or two doesn't flap between both
+    fn check_sync(&self) {
+        let mut sync = self.sync.lock().unwrap();
+        let Some((network_head, _)) = sync.network_head else {
+            return;
+        };
+        let head = self.head.lock().unwrap().0;
+        let lag_blocks = network_head.saturating_sub(head);
+
+        let event = if !sync.behind && lag_blocks > FELL_BEHIND_LAG {
+            warn!(lag_blocks, network_head, "Fell behind the network");
+            SyncEvent::FellBehind { lag_blocks }
+        } else if sync.behind && lag_blocks == 0 {
+            info!(head, "Caught up with the network");
+            SyncEvent::CaughtUp { head }
+        } else {
+            return;
+        };
+        sync.behind = matches!(event, SyncEvent::FellBehind { .. });
+        // Nobody has to be listening
+        let _ = self.sync_events.send(event);
+    }
+
+    pub fn set_role(&self, role: NodeRole) {
+        *self.role.lock().unwrap() = role;
+    }
+
+    pub fn role(&self) -> NodeRole {
+        *self.role.lock().unwrap()
+    }
+
+    pub(crate) fn set_last_failure(&self, task: Task, reason: &str) {
+        *self.last_failure.lock().unwrap() = Some(format!("{task}: {reason}"));
+    }
+
+    /// Counter the [crate::Mempool] keeps its amount of pending transactions in
+    pub(crate) fn pending_txs(&self) -> Arc<AtomicUsize> {
+        self.pending_txs.clone()
+    }
+
+    pub(crate) fn connected(self: &Arc<Self>) -> ConnectedPeer {
+        self.peer_count.fetch_add(1, Ordering::Relaxed);
+        ConnectedPeer(self.clone())
+    }
+
+    pub fn report(&self) -> StatusReport {
+        let network_head = self.network_head();
+        let (head_number, head_hash) = *self.head.lock().unwrap();
+        StatusReport {
+            uptime_secs: self.started.elapsed().as_secs(),
+            head_number,
+            head_hash,
+            pending_txs: self.pending_txs.load(Ordering::Relaxed),
+            peer_count: self.peer_count.load(Ordering::Relaxed),
+            version: String::from(env!("CARGO_PKG_VERSION")),
+            role: self.role(),
+            last_failure: self.last_failure.lock().unwrap().clone(),
+            network_head,
+            lag_blocks: network_head.map(|network_head| network_head.saturating_sub(head_number)),
+        }
+    }
+}
+
+impl Default for NodeStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_follows_the_node() {
+        let status = Arc::new(NodeStatus::new());
+        status.set_head(7, B256::repeat_byte(7));
+        status.pending_txs().store(3, Ordering::Relaxed);
+        status.set_role(NodeRole::Follower);
+
+        let first = status.connected();
+        let second = status.connected();
+        let report = status.report();
+        assert_eq!(report.head_number, 7);
+        assert_eq!(report.head_hash, B256::repeat_byte(7));
+        assert_eq!(report.pending_txs, 3);
+        assert_eq!(report.peer_count, 2);
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(report.role, NodeRole::Follower);
+
+        drop((first, second));
+        assert_eq!(status.report().peer_count, 0);
+    }
+
+    #[test]
+    fn test_lag_follows_the_network_head() {
+        let status = NodeStatus::new();
+        let mut events = status.sync_events();
+        status.set_head(5, B256::repeat_byte(5));
+        assert_eq!(status.report().lag_blocks, None);
+
+        // Stalled at block 5 while the network moves on
+        for network_head in 6..=9 {
+            status.set_network_head(network_head);
+            assert_eq!(status.report().lag_blocks, Some(network_head - 5));
+        }
+        assert_eq!(status.report().network_head, Some(9));
+        assert_eq!(
+            events.try_recv(),
+            Ok(SyncEvent::FellBehind { lag_blocks: 3 })
+        );
+        assert!(events.try_recv().is_err());
+
+        // A block short of the network head doesn't count as caught up yet
+        status.set_head(8, B256::repeat_byte(8));
+        assert!(events.try_recv().is_err());
+        status.set_head(9, B256::repeat_byte(9));
+        assert_eq!(status.report().lag_blocks, Some(0));
+        assert_eq!(events.try_recv(), Ok(SyncEvent::CaughtUp { head: 9 }));
+
+        // Trailing by a block or two again isn't falling behind
+        status.set_network_head(11);
+        assert!(events.try_recv().is_err());
+    }
+}
@@ -0,0 +1,423 @@
+use super::NodeStatus;
+use crate::{
+    database::{DatabaseReader, DatabaseWriter},
+    executor::Mempool,
+    Error, Executor,
+};
+use clap::ValueEnum;
+use std::{fmt, sync::Arc};
+use tokio::{
+    sync::broadcast,
+    task::{JoinError, JoinHandle},
+};
+use tracing::{error, info, warn};
+
+/// How many times the [Supervisor] restarts failed tasks before giving up and shutting down
+const MAX_TASK_RESTARTS: usize = 3;
+
+/// Every task hands itself back once it exits, so it can be restarted with the state it had
+type TaskHandle<T> = JoinHandle<(T, Result<(), Error>)>;
+
+/// Long running tasks spawned by the [crate::Server] next to the rpc listener
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Task {
+    Mempool,
+    Executor,
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Task::Mempool => write!(f, "mempool"),
+            Task::Executor => write!(f, "executor"),
+        }
+    }
+}
+
+/// What the [Supervisor] does when one of the tasks exits with an error
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+#[non_exhaustive]
+pub enum OnTaskFailure {
+    /// Stop the node, the same as pressing ctrl-c
+    #[default]
+    Shutdown,
+    /// Spawn the task again, keeping the state it had when it failed
+    ///
+    /// For the mempool this means the transactions it had drained from its channels stay
+    /// pending. Failures a restart can't fix, like a closed channel or a panic, still stop the
+    /// node
+    Restart,
+}
+
+/// Owns the [JoinHandle]s of the mempool and the executor, so the node notices
+/// when one of them stops instead of accepting transactions it can't process
+pub struct Supervisor<DB> {
+    /// `None` once the task finished
+    mempool: Option<TaskHandle<Mempool>>,
+    executor: Option<TaskHandle<Executor<DB>>>,
+    on_failure: OnTaskFailure,
+    restarts: usize,
+    /// Told why a task failed, so the status report shows it
+    status: Option<Arc<NodeStatus>>,
+    /// Sent once a task failed for good, so the other task and the listeners stop as well
+    notify_shutdown: Option<broadcast::Sender<()>>,
+}
+
+impl<DB> Supervisor<DB>
+where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    /// Spawns both tasks and starts tracking them
+    pub fn spawn(mempool: Mempool, executor: Executor<DB>, on_failure: OnTaskFailure) -> Self {
+        Self {
+            mempool: Some(spawn_mempool(mempool)),
+            executor: Some(spawn_executor(executor)),
+            on_failure,
+            restarts: 0,
+            status: None,
+            notify_shutdown: None,
+        }
+    }
+
+    pub fn with_status(mut self, status: Arc<NodeStatus>) -> Self {
+        self.status = Some(status);
+        self
+    }
+
+    pub fn with_notify_shutdown(mut self, notify_shutdown: broadcast::Sender<()>) -> Self {
+        self.notify_shutdown = Some(notify_shutdown);
+        self
+    }
+
+    /// Waits on the tasks until both finished, restarting them according to [OnTaskFailure]
+    ///
+    /// Resolves with `Ok` once both tasks finished because they received the shutdown signal,
+    /// and with [Error::TaskFailed] as soon as one of them fails and isn't restarted, during
+    /// shutdown as well
+    pub async fn supervise(&mut self) -> Result<(), Error> {
+        loop {
+            let (mempool_running, executor_running) =
+                (self.mempool.is_some(), self.executor.is_some());
+            let (mempool, executor) = (&mut self.mempool, &mut self.executor);
+            // A finished handle must not be polled again, so it is only waited on while set
+            let exit = tokio::select! {
+                exit = async move { mempool.as_mut().unwrap().await },
+                    if mempool_running => TaskExit::Mempool(exit),
+                exit = async move { executor.as_mut().unwrap().await },
+                    if executor_running => TaskExit::Executor(exit),
+                else => return Ok(()),
+            };
+
+            // The handle is cleared before the exit is handled, a failure returns right away and
+            // the next call must not poll the finished handle
+            match exit {
+                TaskExit::Mempool(exit) => {
+                    self.mempool = None;
+                    self.mempool = self.handle_exit(Task::Mempool, exit)?.map(spawn_mempool);
+                }
+                TaskExit::Executor(exit) => {
+                    self.executor = None;
+                    self.executor = self.handle_exit(Task::Executor, exit)?.map(spawn_executor);
+                }
+            }
+        }
+    }
+
+    /// Returns the task back if it should be restarted, `None` if it finished gracefully
+    fn handle_exit<T>(
+        &mut self,
+        task: Task,
+        exit: Result<(T, Result<(), Error>), JoinError>,
+    ) -> Result<Option<T>, Error> {
+        let (inner, reason) = match exit {
+            Ok((_, Ok(()))) => {
+                info!(%task, "Task finished");
+                return Ok(None);
+            }
+            // A restarted task would run into the same error again
+            Ok((inner, Err(e))) => (is_recoverable(&e).then_some(inner), e.to_string()),
+            // The task panicked, so there is nothing left to restart
+            Err(e) => (None, e.to_string()),
+        };
+
+        error!(%task, err = %reason, "Task exited with an error");
+        if let Some(status) = &self.status {
+            status.set_last_failure(task, &reason);
+        }
+
+        match (self.on_failure, inner) {
+            (OnTaskFailure::Restart, Some(inner)) if self.restarts < MAX_TASK_RESTARTS => {
+                self.restarts += 1;
+                warn!(%task, restarts = self.restarts, "Restarting task");
+                Ok(Some(inner))
+            }
+            _ => {
+                if let Some(notify_shutdown) = &self.notify_shutdown {
+                    // Nobody left to tell if every receiver is already gone
+                    let _ = notify_shutdown.send(());
+                }
+                Err(Error::TaskFailed(task, reason))
+            }
+        }
+    }
+}
+
+/// Whether a task that failed with `e` can still do its work once restarted, the other end of
+/// a closed channel never comes back
+fn is_recoverable(e: &Error) -> bool {
+    !matches!(e, Error::ChannelFailure)
+}
+
+enum TaskExit<DB> {
+    Mempool(Result<(Mempool, Result<(), Error>), JoinError>),
+    Executor(Result<(Executor<DB>, Result<(), Error>), JoinError>),
+}
+
+fn spawn_mempool(mut mempool: Mempool) -> TaskHandle<Mempool> {
+    tokio::spawn(async move {
+        let res = mempool.run().await;
+        (mempool, res)
+    })
+}
+
+fn spawn_executor<DB>(mut executor: Executor<DB>) -> TaskHandle<Executor<DB>>
+where
+    DB: DatabaseReader + DatabaseWriter + Send + Sync + 'static,
+{
+    tokio::spawn(async move {
+        let res = executor.run().await;
+        (executor, res)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        executor::{MempoolOrdering, MempoolStatusTx},
+        InMemoryDB, Transaction,
+    };
+    use alloy_primitives::{Address, B256};
+    use std::{collections::BTreeMap, time::Duration};
+    use tokio::sync::{broadcast, mpsc, oneshot, RwLock};
+
+    /// Senders the server would hold while the node runs, the tasks stop once they are gone
+    struct Node {
+        supervisor: Supervisor<InMemoryDB>,
+        status: Arc<NodeStatus>,
+        notify_shutdown: broadcast::Sender<()>,
+        shutdown_complete_rx: mpsc::Receiver<()>,
+        mempool_status_tx: MempoolStatusTx,
+        _server_mempool_tx: mpsc::Sender<Transaction>,
+    }
+
+    /// Builds a node producing a block every `block_time` seconds, which must match the
+    /// `checkpoints`
+    ///
+    /// With a `fault` the mempool exits with it as soon as it is spawned, holding one pending
+    /// transaction
+    fn node(
+        block_time: u64,
+        checkpoints: BTreeMap<u64, B256>,
+        on_failure: OnTaskFailure,
+        fault: Option<Error>,
+    ) -> Node {
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+        let (server_mempool_tx, server_mempool_rx) = mpsc::channel(1);
+        let (executor_mempool_tx, executor_mempool_rx) = mpsc::unbounded_channel();
+        let (mempool_status_tx, status_rx) = mpsc::channel(1);
+
+        let db = Arc::new(RwLock::new(InMemoryDB::default()));
+        let executor = Executor::new(
+            db,
+            block_time,
+            executor_mempool_tx,
+            Address::ZERO,
+            notify_shutdown.subscribe(),
+            shutdown_complete_tx.clone(),
+        )
+        .with_checkpoints(checkpoints);
+
+        let mut mempool = Mempool::new(
+            server_mempool_rx,
+            executor_mempool_rx,
+            status_rx,
+            MempoolOrdering::Fifo,
+            notify_shutdown.subscribe(),
+            shutdown_complete_tx,
+        );
+
+        let mempool = match fault {
+            Some(fault) => {
+                mempool.push(Transaction::default()).unwrap();
+                tokio::spawn(async move { (mempool, Err(fault)) })
+            }
+            None => spawn_mempool(mempool),
+        };
+        let status = Arc::new(NodeStatus::new());
+        let supervisor = Supervisor {
+            mempool: Some(mempool),
+            executor: Some(spawn_executor(executor)),
+            on_failure,
+            restarts: 0,
+            status: Some(status.clone()),
+            notify_shutdown: Some(notify_shutdown.clone()),
+        };
+        Node {
+            supervisor,
+            status,
+            notify_shutdown,
+            shutdown_complete_rx,
+            mempool_status_tx,
+            _server_mempool_tx: server_mempool_tx,
+        }
+    }
+
+    fn disk_full() -> Error {
+        Error::IOError(std::io::Error::other("disk full"))
+    }
+
+    /// Same sequence as the ctrl-c path in main, every task has to let go of its sender
+    async fn assert_shut_down(node: Node) {
+        let Node {
+            supervisor,
+            notify_shutdown,
+            mut shutdown_complete_rx,
+            ..
+        } = node;
+        drop(supervisor);
+        drop(notify_shutdown);
+        assert!(shutdown_complete_rx.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_failed_mempool_shuts_down_node() {
+        let mut node = node(
+            60,
+            BTreeMap::new(),
+            OnTaskFailure::Shutdown,
+            Some(disk_full()),
+        );
+        let mut shutdown = node.notify_shutdown.subscribe();
+
+        let res = node.supervisor.supervise().await;
+        assert!(
+            matches!(res, Err(Error::TaskFailed(Task::Mempool, _))),
+            "{res:?}"
+        );
+        assert_eq!(
+            node.status.report().last_failure.as_deref(),
+            Some("mempool: I/O Error: disk full")
+        );
+        assert_eq!(node.supervisor.restarts, 0);
+        assert!(node.supervisor.mempool.is_none());
+
+        // The failure told every task to stop, so the executor finishes on its own
+        assert_eq!(shutdown.try_recv(), Ok(()));
+        node.supervisor.supervise().await.unwrap();
+        assert!(node.supervisor.executor.is_none());
+
+        assert_shut_down(node).await;
+    }
+
+    #[tokio::test]
+    async fn test_failed_mempool_is_restarted() {
+        let mut node = node(
+            60,
+            BTreeMap::new(),
+            OnTaskFailure::Restart,
+            Some(disk_full()),
+        );
+        let mut shutdown = node.notify_shutdown.subscribe();
+
+        // Keeps supervising the restarted mempool
+        let supervise = node.supervisor.supervise();
+        assert!(tokio::time::timeout(Duration::from_millis(50), supervise)
+            .await
+            .is_err());
+        assert_eq!(node.supervisor.restarts, 1);
+        assert!(node.status.report().last_failure.is_some());
+        assert!(shutdown.try_recv().is_err());
+
+        // With the transaction it had before it failed
+        let (reply, stats) = oneshot::channel();
+        node.mempool_status_tx.send(reply).await.unwrap();
+        assert_eq!(stats.await.unwrap().pending, 1);
+
+        node.notify_shutdown.send(()).unwrap();
+        node.supervisor.supervise().await.unwrap();
+        assert_shut_down(node).await;
+    }
+
+    #[tokio::test]
+    async fn test_closed_channel_is_not_restarted() {
+        let fault = Some(Error::ChannelFailure);
+        let mut node = node(60, BTreeMap::new(), OnTaskFailure::Restart, fault);
+
+        let res = node.supervisor.supervise().await;
+        assert!(matches!(res, Err(Error::TaskFailed(Task::Mempool, _))));
+        assert_eq!(node.supervisor.restarts, 0);
+        assert_eq!(
+            node.status.report().last_failure.as_deref(),
+            Some("mempool: Channel failure")
+        );
+
+        assert_shut_down(node).await;
+    }
+
+    #[tokio::test]
+    async fn test_restarts_are_limited() {
+        let mut node = node(60, BTreeMap::new(), OnTaskFailure::Restart, None);
+        let failure = || Ok(((), Err(disk_full())));
+
+        for _ in 0..MAX_TASK_RESTARTS {
+            assert!(matches!(
+                node.supervisor.handle_exit(Task::Executor, failure()),
+                Ok(Some(()))
+            ));
+        }
+        // Gives up once the restarts are used up
+        assert!(matches!(
+            node.supervisor.handle_exit(Task::Executor, failure()),
+            Err(Error::TaskFailed(Task::Executor, _))
+        ));
+        assert_eq!(node.supervisor.restarts, MAX_TASK_RESTARTS);
+
+        assert_shut_down(node).await;
+    }
+
+    #[tokio::test]
+    async fn test_contradicted_checkpoint_shuts_down_node() {
+        // The first block can't have this hash, so the executor fails as it seals it
+        let checkpoints = BTreeMap::from([(1, B256::repeat_byte(1))]);
+        let mut node = node(1, checkpoints, OnTaskFailure::Shutdown, None);
+
+        let res = node.supervisor.supervise().await;
+        assert!(
+            matches!(res, Err(Error::TaskFailed(Task::Executor, _))),
+            "{res:?}"
+        );
+        let failure = node.status.report().last_failure.unwrap();
+        assert!(
+            failure.starts_with("executor: Block 1 contradicts the checkpoint"),
+            "{failure}"
+        );
+
+        assert_shut_down(node).await;
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_both_tasks() {
+        let mut node = node(60, BTreeMap::new(), OnTaskFailure::Shutdown, None);
+
+        node.notify_shutdown.send(()).unwrap();
+        node.supervisor.supervise().await.unwrap();
+        assert!(node.supervisor.mempool.is_none());
+        assert!(node.supervisor.executor.is_none());
+        assert_eq!(node.status.report().last_failure, None);
+
+        assert_shut_down(node).await;
+    }
+}
@@ -0,0 +1,215 @@
+use super::{noise::NoiseStream, Connection};
+use crate::Error;
+use std::{
+    io,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{
+        pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer},
+        ClientConfig, RootCertStore, ServerConfig,
+    },
+    TlsAcceptor, TlsConnector, TlsStream,
+};
+
+/// Stream of a [super::Connection] that may or may not be encrypted, plain tcp unless the node
+/// is started with [super::Server::with_tls] or the peer is a node with
+/// [super::Server::with_peer_encryption]
+pub enum MaybeTlsStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Noise(Box<NoiseStream<TcpStream>>),
+}
+
+/// [Connection] that may be encrypted, what the node and the client speak over
+pub type TlsConnection = Connection<MaybeTlsStream>;
+
+impl From<TcpStream> for MaybeTlsStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Plain(stream)
+    }
+}
+
+impl From<TlsStream<TcpStream>> for MaybeTlsStream {
+    fn from(stream: TlsStream<TcpStream>) -> Self {
+        Self::Tls(Box::new(stream))
+    }
+}
+
+impl From<NoiseStream<TcpStream>> for MaybeTlsStream {
+    fn from(stream: NoiseStream<TcpStream>) -> Self {
+        Self::Noise(Box::new(stream))
+    }
+}
+
+impl TlsConnection {
+    /// Messages over an established TLS session, buffered and framed like over plain tcp
+    pub fn new_tls(stream: TlsStream<TcpStream>) -> Self {
+        Self::new(stream.into())
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            Self::Noise(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            Self::Noise(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            Self::Noise(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            Self::Noise(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Terminates TLS with the certificate chain and the private key of the PEM files `cert` and
+/// `key`
+pub fn tls_acceptor(cert: &Path, key: &Path) -> Result<TlsAcceptor, Error> {
+    let certs = read_certs(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| tls_error(key, e))?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| tls_error(cert, e))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Verifies the node against the certificates of the PEM file `ca`, or against the web's root
+/// CAs without one
+pub fn tls_connector(ca: Option<&Path>) -> Result<TlsConnector, Error> {
+    let mut roots = RootCertStore::empty();
+    match ca {
+        Some(ca) => {
+            for cert in read_certs(ca)? {
+                roots.add(cert).map_err(|e| tls_error(ca, e))?;
+            }
+        }
+        None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+fn read_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| tls_error(path, e))?;
+    if certs.is_empty() {
+        return Err(tls_error(path, "no certificate"));
+    }
+    Ok(certs)
+}
+
+fn tls_error(path: &Path, err: impl std::fmt::Display) -> Error {
+    Error::Tls(format!("{}: {err}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Message;
+    use std::path::PathBuf;
+    use tokio::net::TcpListener;
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    /// `cert.pem` is valid for `localhost` and `127.0.0.1`, signed by the CA of `ca.pem`
+    fn test_file(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/tls")
+            .join(name)
+    }
+
+    /// Runs both sides of a handshake, the client verifies the node with `connector`
+    async fn handshake(
+        connector: TlsConnector,
+    ) -> (
+        io::Result<TlsStream<TcpStream>>,
+        io::Result<TlsStream<TcpStream>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let name = ServerName::try_from("localhost").unwrap();
+        let (server, client) = tokio::join!(
+            tls_acceptor(&test_file("cert.pem"), &test_file("key.pem"))
+                .unwrap()
+                .accept(server),
+            connector.connect(name, client)
+        );
+        (server.map(TlsStream::from), client.map(TlsStream::from))
+    }
+
+    #[tokio::test]
+    async fn test_messages_are_exchanged_over_tls() {
+        let connector = tls_connector(Some(&test_file("ca.pem"))).unwrap();
+        let (server, client) = handshake(connector).await;
+        let mut server = TlsConnection::new_tls(server.unwrap());
+        let mut client = TlsConnection::new_tls(client.unwrap());
+
+        client.write_message(&Message::Ping(7)).await.unwrap();
+        assert_eq!(server.read_message().await.unwrap(), Some(Message::Ping(7)));
+        server.write_message(&Message::Pong(7)).await.unwrap();
+        assert_eq!(client.read_message().await.unwrap(), Some(Message::Pong(7)));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_certificates_are_rejected() {
+        // The test CA isn't one of the web's root CAs
+        let (_, client) = handshake(tls_connector(None).unwrap()).await;
+        assert!(client.is_err());
+    }
+
+    #[test]
+    fn test_errors_name_the_file() {
+        let missing = test_file("missing.pem");
+        let err = tls_acceptor(&missing, &test_file("key.pem")).err().unwrap();
+        assert!(err.to_string().contains("missing.pem"), "{err}");
+
+        // A key isn't a certificate
+        let err = tls_connector(Some(&test_file("key.pem"))).err().unwrap();
+        assert!(err.to_string().contains("no certificate"), "{err}");
+    }
+}
@@ -0,0 +1,112 @@
+use super::Connection;
+use crate::Error;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    path::Path,
+};
+use tokio::net::{UnixListener, UnixStream};
+use tracing::info;
+
+/// [Connection] over a unix domain socket, for tooling on the same machine as the node
+pub type UnixConnection = Connection<UnixStream>;
+
+/// What peers on a unix domain socket count as, they have no ip of their own
+pub(crate) const UNIX_PEER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+
+impl UnixConnection {
+    /// Connects to the socket of a node started with [super::Server::with_unix_socket]
+    pub async fn connect_unix(path: impl AsRef<Path>) -> Result<Self, Error> {
+        Ok(Self::new(UnixStream::connect(path).await?))
+    }
+}
+
+/// Binds a listener on `path`, replacing the socket a previous run left behind
+///
+/// Anything else at `path`, a file or a socket another node still listens on, is left alone
+/// and an error
+pub(crate) fn bind_unix(path: &Path) -> Result<UnixListener, Error> {
+    let bind = || {
+        remove_stale_socket(path)?;
+        UnixListener::bind(path)
+    };
+    let listener = bind().map_err(|source| Error::BindSocket {
+        path: path.to_path_buf(),
+        source,
+    })?;
+    info!(path = %path.display(), "Listening");
+    Ok(listener)
+}
+
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        metadata => metadata?,
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            "the path exists and is not a socket",
+        ));
+    }
+
+    // Only a socket nobody listens on anymore refuses the connection
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(
+            io::ErrorKind::AddrInUse,
+            "another process is listening on the socket",
+        )),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => std::fs::remove_file(path),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::Message;
+
+    #[tokio::test]
+    async fn test_messages_cross_the_socket() {
+        let path =
+            std::env::temp_dir().join(format!("mini-blockchain-{}.sock", std::process::id()));
+        // Stale sockets are replaced
+        drop(bind_unix(&path).unwrap());
+        let listener = bind_unix(&path).unwrap();
+
+        let (client, server) = tokio::join!(UnixConnection::connect_unix(&path), listener.accept());
+        let (mut client, mut server) = (client.unwrap(), UnixConnection::new(server.unwrap().0));
+
+        client.write_message(&Message::Ping(3)).await.unwrap();
+        assert_eq!(server.read_message().await.unwrap(), Some(Message::Ping(3)));
+        server.write_message(&Message::Pong(3)).await.unwrap();
+        assert_eq!(client.read_message().await.unwrap(), Some(Message::Pong(3)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_only_stale_sockets_are_replaced() {
+        let path = std::env::temp_dir().join(format!(
+            "mini-blockchain-{}-in-use.sock",
+            std::process::id()
+        ));
+
+        let _listener = bind_unix(&path).unwrap();
+        assert!(matches!(
+            bind_unix(&path),
+            Err(Error::BindSocket { source, .. }) if source.kind() == io::ErrorKind::AddrInUse
+        ));
+        assert!(path.exists());
+        std::fs::remove_file(&path).unwrap();
+
+        std::fs::write(&path, b"not a socket").unwrap();
+        assert!(matches!(
+            bind_unix(&path),
+            Err(Error::BindSocket { source, .. }) if source.kind() == io::ErrorKind::AlreadyExists
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), b"not a socket");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
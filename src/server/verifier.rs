@@ -0,0 +1,176 @@
+use crate::{
+    database::DatabaseReader,
+    validation::{
+        TxValidator, ValidationConfig, ValidationContext, ValidationOutcome, ValidationPhase,
+    },
+    Error, Transaction,
+};
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+    time::Instant,
+};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot, RwLock,
+};
+use tracing::debug;
+
+/// Verification jobs that may wait for a worker before submitters are told the node is busy,
+/// a job is a whole submission however many transactions it has
+pub(crate) const VERIFIER_QUEUE_DEPTH: usize = 256;
+
+/// Transactions with the outcome of their admission checks, in the order they were given
+pub(crate) type Verified = Vec<(Transaction, ValidationOutcome)>;
+
+struct Job {
+    transactions: Vec<Transaction>,
+    reply: oneshot::Sender<Verified>,
+}
+
+/// Fixed set of threads running the admission checks of the [TxValidator], so a burst of
+/// submissions recovering signatures can't take over the blocking pool of the runtime
+///
+/// Every handler of a node shares the same pool, the workers stop once it is dropped
+#[derive(Clone)]
+pub(crate) struct VerifierPool {
+    jobs: mpsc::Sender<Job>,
+}
+
+impl VerifierPool {
+    pub(crate) fn spawn<DB>(
+        db: Arc<RwLock<DB>>,
+        validator: Arc<TxValidator>,
+        config: ValidationConfig,
+        workers: usize,
+        queue_depth: usize,
+    ) -> Result<Self, Error>
+    where
+        DB: DatabaseReader + Send + Sync + 'static,
+    {
+        let (jobs, rx) = mpsc::channel::<Job>(queue_depth.max(1));
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..workers.max(1) {
+            let (db, validator, rx) = (db.clone(), validator.clone(), rx.clone());
+            thread::Builder::new()
+                .name(format!("verifier-{i}"))
+                .spawn(move || loop {
+                    // The lock is released before the job is worked on
+                    let job = match rx.lock().unwrap().blocking_recv() {
+                        Some(job) => job,
+                        None => break,
+                    };
+
+                    let started = Instant::now();
+                    let transactions = job.transactions.len();
+                    let verified =
+                        validate_all(&*db.blocking_read(), &validator, config, job.transactions);
+                    let elapsed = started.elapsed();
+                    debug!(transactions, ?elapsed, "Verified transactions");
+                    // The handler may have given up on the answer already
+                    let _ = job.reply.send(verified);
+                })?;
+        }
+
+        Ok(Self { jobs })
+    }
+
+    /// Verifies `transactions` as a single job, `None` if the queue is full
+    pub(crate) async fn verify(
+        &self,
+        transactions: Vec<Transaction>,
+    ) -> Result<Option<Verified>, Error> {
+        let (reply, verified) = oneshot::channel();
+        match self.jobs.try_send(Job {
+            transactions,
+            reply,
+        }) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => return Ok(None),
+            Err(TrySendError::Closed(_)) => return Err(Error::ChannelFailure),
+        }
+
+        verified.await.map(Some).map_err(|_| Error::ChannelFailure)
+    }
+}
+
+/// Runs the admission checks on every one of `transactions` against the latest written state
+pub(crate) fn validate_all<DB: DatabaseReader>(
+    db: &DB,
+    validator: &TxValidator,
+    config: ValidationConfig,
+    transactions: Vec<Transaction>,
+) -> Verified {
+    let ctx = ValidationContext {
+        state: db,
+        config,
+        head: db.latest_block().map(|block| block.number()).unwrap_or(0),
+        phase: ValidationPhase::Admission,
+    };
+    transactions
+        .into_iter()
+        .map(|tx| {
+            let outcome = validator.validate(&tx, &ctx);
+            (tx, outcome)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{utils::HashAlgo, CoinbaseMaturity, FailureReason, InMemoryDB};
+    use std::time::Duration;
+
+    fn transaction(nonce: u64) -> Transaction {
+        let mut tx = Transaction {
+            nonce,
+            ..Default::default()
+        };
+        tx.hash = tx.hash(HashAlgo::Keccak256);
+        tx
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_is_refused() {
+        let db = Arc::new(RwLock::new(InMemoryDB::new()));
+        let config = ValidationConfig {
+            chain_id: 0,
+            min_balance: None,
+            coinbase_maturity: CoinbaseMaturity::default(),
+            hash_algo: HashAlgo::default(),
+        };
+        let validator = Arc::new(TxValidator::new());
+        let pool = VerifierPool::spawn(db.clone(), validator, config, 1, 1).unwrap();
+
+        // The only worker waits for the state with the first job
+        let state = db.write().await;
+        let (reply, first) = oneshot::channel();
+        let job = Job {
+            transactions: vec![transaction(0), transaction(1)],
+            reply,
+        };
+        assert!(pool.jobs.try_send(job).is_ok());
+        while pool.jobs.capacity() == 0 {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        let (reply, second) = oneshot::channel();
+        let job = Job {
+            transactions: Vec::new(),
+            reply,
+        };
+        assert!(pool.jobs.try_send(job).is_ok());
+
+        assert_eq!(pool.verify(vec![transaction(2)]).await.unwrap(), None);
+
+        drop(state);
+        let outcomes: Vec<_> = first.await.unwrap().into_iter().map(|(_, o)| o).collect();
+        // Unsigned, but judged in the order they were given
+        assert_eq!(
+            outcomes,
+            vec![ValidationOutcome::Invalid(FailureReason::InvalidSignature); 2]
+        );
+        assert!(second.await.unwrap().is_empty());
+        assert_eq!(pool.verify(Vec::new()).await.unwrap(), Some(Vec::new()));
+    }
+}
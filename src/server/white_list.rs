@@ -0,0 +1,71 @@
+use super::black_list::{normalize, normalize_net};
+use ipnet::IpNet;
+use std::net::{IpAddr, SocketAddr};
+
+/// Ips that are never banned nor rate limited, like those of local monitoring nodes
+///
+/// Connections from them skip the [super::BlackList] and the rate limits, the connection
+/// limits still hold
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WhiteList {
+    allowed: Vec<IpAddr>,
+    allowed_networks: Vec<IpNet>,
+}
+
+impl WhiteList {
+    pub fn new(allowed: Vec<IpAddr>, allowed_networks: Vec<IpNet>) -> Self {
+        Self {
+            allowed: allowed.into_iter().map(normalize).collect(),
+            allowed_networks: allowed_networks.into_iter().map(normalize_net).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.allowed.is_empty() && self.allowed_networks.is_empty()
+    }
+
+    /// Whether the peer at `addr` is whitelisted, whatever port it connects from
+    pub fn contains(&self, addr: &SocketAddr) -> bool {
+        let ip = normalize(addr.ip());
+        self.allowed.contains(&ip)
+            || self
+                .allowed_networks
+                .iter()
+                .any(|network| network.contains(&ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::BlackList;
+
+    #[test]
+    fn test_contains_ips_and_networks() {
+        let white_list = WhiteList::new(
+            vec![
+                "10.0.0.1".parse().unwrap(),
+                "::ffff:10.0.0.2".parse().unwrap(),
+            ],
+            vec![
+                BlackList::parse_entry("192.168.1.0/24").unwrap(),
+                BlackList::parse_entry("2001:db8::/32").unwrap(),
+            ],
+        );
+        assert!(!white_list.is_empty());
+
+        let contains = |addr: &str| white_list.contains(&addr.parse().unwrap());
+        assert!(contains("10.0.0.1:30303"));
+        assert!(contains("10.0.0.1:1"));
+        assert!(contains("10.0.0.2:30303"));
+        assert!(contains("[::ffff:10.0.0.1]:30303"));
+        assert!(contains("192.168.1.77:30303"));
+        assert!(contains("[::ffff:192.168.1.77]:30303"));
+        assert!(contains("[2001:db8::1]:30303"));
+
+        assert!(!contains("10.0.0.3:30303"));
+        assert!(!contains("192.168.2.1:30303"));
+        assert!(!contains("[2001:db9::1]:30303"));
+        assert!(!WhiteList::default().contains(&"10.0.0.1:30303".parse().unwrap()));
+    }
+}
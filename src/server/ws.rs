@@ -0,0 +1,158 @@
+use super::connection::LENGTH_PREFIX_SIZE;
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures_util::{Sink, Stream};
+use std::{
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{Message as WsMessage, Utf8Bytes},
+    WebSocketStream,
+};
+
+/// WebSocket as the stream of a [super::Connection] in [super::FrameMode::LengthPrefixed],
+/// every frame travels as a WebSocket message of its own
+///
+/// Frames that are valid UTF-8 are sent as text messages, so browsers get the JSON of every
+/// [super::Message] as is
+pub struct WsStream<S> {
+    ws: WebSocketStream<S>,
+    /// Rest of the frame being read, length prefix included
+    read: Bytes,
+    /// Bytes written since the last complete frame
+    write: BytesMut,
+}
+
+impl<S> WsStream<S> {
+    pub fn new(ws: WebSocketStream<S>) -> Self {
+        Self {
+            ws,
+            read: Bytes::new(),
+            write: BytesMut::new(),
+        }
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WsStream<S> {
+    /// Hands every complete frame written so far to the WebSocket
+    fn poll_send_frames(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        while self.write.len() >= LENGTH_PREFIX_SIZE {
+            let len = (&self.write[..LENGTH_PREFIX_SIZE]).get_u32() as usize;
+            if self.write.len() < LENGTH_PREFIX_SIZE + len {
+                break;
+            }
+            ready!(Pin::new(&mut self.ws).poll_ready(cx)).map_err(io::Error::other)?;
+
+            self.write.advance(LENGTH_PREFIX_SIZE);
+            let payload = self.write.split_to(len).freeze();
+            let msg = match Utf8Bytes::try_from(payload.clone()) {
+                Ok(text) => WsMessage::Text(text),
+                Err(_) => WsMessage::Binary(payload),
+            };
+            Pin::new(&mut self.ws)
+                .start_send(msg)
+                .map_err(io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        while this.read.is_empty() {
+            let payload = match ready!(Pin::new(&mut this.ws).poll_next(cx)) {
+                Some(Ok(WsMessage::Text(text))) => Bytes::from(text),
+                Some(Ok(WsMessage::Binary(payload))) => payload,
+                // Pings are answered by the WebSocket itself
+                Some(Ok(WsMessage::Ping(_) | WsMessage::Pong(_) | WsMessage::Frame(_))) => continue,
+                Some(Ok(WsMessage::Close(_))) | None => return Poll::Ready(Ok(())),
+                Some(Err(e)) => return Poll::Ready(Err(io::Error::other(e))),
+            };
+
+            let mut frame = BytesMut::with_capacity(LENGTH_PREFIX_SIZE + payload.len());
+            frame.put_u32(payload.len() as u32);
+            frame.put(payload);
+            this.read = frame.freeze();
+        }
+
+        let len = buf.remaining().min(this.read.len());
+        buf.put_slice(&this.read.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        // Earlier frames go out first, so a slow peer holds up the writer instead of the memory
+        ready!(this.poll_send_frames(cx))?;
+        this.write.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_frames(cx))?;
+        Pin::new(&mut this.ws)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_frames(cx))?;
+        Pin::new(&mut this.ws)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{Connection, FrameMode, Message};
+    use futures_util::{SinkExt, StreamExt};
+
+    #[tokio::test]
+    async fn test_frames_are_websocket_messages() {
+        let (client, server) = tokio::io::duplex(4096);
+        let (server, client) = tokio::join!(
+            tokio_tungstenite::accept_async(server),
+            tokio_tungstenite::client_async("ws://localhost/", client)
+        );
+        let mut server = Connection::new(WsStream::new(server.unwrap()))
+            .with_frame_mode(FrameMode::LengthPrefixed);
+        let (mut client, _) = client.unwrap();
+
+        // What a browser sends
+        let ping = serde_json::to_string(&Message::Ping(7)).unwrap();
+        client.send(WsMessage::text(ping)).await.unwrap();
+        assert_eq!(server.read_message().await.unwrap(), Some(Message::Ping(7)));
+
+        server.write_message(&Message::Pong(7)).await.unwrap();
+        server.write_message(&Message::Pong(8)).await.unwrap();
+        for nonce in [7, 8] {
+            match client.next().await.unwrap().unwrap() {
+                WsMessage::Text(text) => {
+                    let msg: Message = serde_json::from_str(&text).unwrap();
+                    assert_eq!(msg, Message::Pong(nonce));
+                }
+                other => panic!("unexpected message {other:?}"),
+            }
+        }
+
+        client.close(None).await.unwrap();
+        assert_eq!(server.read_message().await.unwrap(), None);
+    }
+}
@@ -1,12 +1,72 @@
-use crate::Error;
-use alloy_primitives::{Address, FixedBytes, B256, U256};
+use crate::{Error, Transaction};
+use alloy_primitives::{Address, B256, U256};
+use clap::ValueEnum;
 use k256::{
-    ecdsa::{signature::hazmat::PrehashSigner, SigningKey},
+    ecdsa::{signature::hazmat::PrehashSigner, RecoveryId, Signature, SigningKey, VerifyingKey},
     elliptic_curve::sec1::ToEncodedPoint,
     elliptic_curve::FieldBytes,
     EncodedPoint, PublicKey, Secp256k1,
 };
-use tiny_keccak::{Hasher, Sha3};
+use serde::{Deserialize, Serialize};
+use tiny_keccak::{Hasher, Keccak, Sha3};
+
+/// Hash function transactions, blocks and addresses are derived with, part of the
+/// [crate::ChainSpec] so every node and client of a chain uses the same one
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashAlgo {
+    /// What Ethereum uses, addresses match the ones of MetaMask or Foundry
+    #[default]
+    Keccak256,
+    /// Standardised SHA3-256, for chains created before Keccak-256 was the default
+    Sha3,
+}
+
+/// Incremental hasher of a [HashAlgo]
+pub(crate) enum ChainHasher {
+    Keccak(Keccak),
+    Sha3(Sha3),
+}
+
+impl ChainHasher {
+    pub(crate) fn new(algo: HashAlgo) -> Self {
+        match algo {
+            HashAlgo::Keccak256 => Self::Keccak(Keccak::v256()),
+            HashAlgo::Sha3 => Self::Sha3(Sha3::v256()),
+        }
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Keccak(hasher) => hasher.update(data),
+            Self::Sha3(hasher) => hasher.update(data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> B256 {
+        let mut output = [0u8; 32];
+        match self {
+            Self::Keccak(hasher) => hasher.finalize(&mut output),
+            Self::Sha3(hasher) => hasher.finalize(&mut output),
+        }
+        B256::from(output)
+    }
+}
+
+/// Hashes `data` with `algo`
+pub fn chain_hash<T: AsRef<[u8]>>(data: T, algo: HashAlgo) -> B256 {
+    let mut hasher = ChainHasher::new(algo);
+    hasher.update(data.as_ref());
+    hasher.finalize()
+}
+
+pub fn keccak256<T: AsRef<[u8]>>(data: T) -> B256 {
+    let mut hasher = Keccak::v256();
+    hasher.update(data.as_ref());
+    let mut output = [0u8; 32];
+    hasher.finalize(&mut output);
+    B256::from_slice(&output)
+}
 
 pub fn sha3<T: AsRef<[u8]>>(data: T) -> B256 {
     let mut hasher = Sha3::v256();
@@ -20,15 +80,39 @@ pub fn u256_to_signing_key(pk: &U256) -> Result<SigningKey, Error> {
     SigningKey::from_slice(pk.as_le_slice()).map_err(|e| e.into())
 }
 
-pub fn addr(private_key: &SigningKey) -> Address {
+pub fn addr(private_key: &SigningKey, algo: HashAlgo) -> Address {
     let verifying_key = private_key.verifying_key();
     let encoded_point: EncodedPoint = PublicKey::from(verifying_key).to_encoded_point(false);
     let pub_key_uncompressed = encoded_point.as_bytes();
-    let mut hasher = Sha3::v256();
-    let mut buf = [0u8; 32];
-    hasher.update(&pub_key_uncompressed[1..]);
-    hasher.finalize(&mut buf);
-    Address::from_word(FixedBytes::from_slice(&buf))
+    Address::from_word(chain_hash(&pub_key_uncompressed[1..], algo))
+}
+
+/// Key of a fresh account nobody else signs with
+#[cfg(any(test, feature = "test-utils"))]
+pub fn random_keypair() -> SigningKey {
+    SigningKey::random(&mut rand_core::OsRng)
+}
+
+/// [random_keypair] with its address and an account holding `balance`, ready to be written to
+/// a database or a [crate::ChainSpec] of the default [HashAlgo]
+#[cfg(any(test, feature = "test-utils"))]
+pub fn funded_account(balance: u128) -> (SigningKey, Address, crate::Account) {
+    let key = random_keypair();
+    let address = addr(&key, HashAlgo::default());
+    (key, address, crate::Account::new(balance, 0))
+}
+
+/// Commits `tx` to `chain_id`, then hashes it with `algo` and signs it, the signature only
+/// holds on that chain
+pub fn sign_transaction(
+    tx: &mut Transaction,
+    chain_id: u64,
+    private_key: &SigningKey,
+    algo: HashAlgo,
+) {
+    tx.chain_id = chain_id;
+    tx.hash = tx.hash(algo);
+    (tx.v, tx.r, tx.s) = sign_hash(tx.hash, private_key);
 }
 
 /// Utility function mainly used for testing
@@ -45,6 +129,29 @@ pub fn sign_hash(hash: B256, private_key: &SigningKey) -> (u8, U256, U256) {
     (v, r, s)
 }
 
+/// Address of the key that signed `hash` with [sign_hash], derived with `algo`
+pub fn recover_address(
+    hash: B256,
+    v: u8,
+    r: U256,
+    s: U256,
+    algo: HashAlgo,
+) -> Result<Address, Error> {
+    let recovery_id =
+        RecoveryId::from_byte(v).ok_or(Error::InvalidSignature("recovery id out of range"))?;
+    let r: FieldBytes<Secp256k1> = r.to_be_bytes::<32>().into();
+    let s: FieldBytes<Secp256k1> = s.to_be_bytes::<32>().into();
+    let signature = Signature::from_scalars(r, s)?;
+
+    let key = VerifyingKey::recover_from_prehash(&hash[..], &signature, recovery_id)?;
+    let encoded_point = PublicKey::from(&key).to_encoded_point(false);
+    let pub_key_uncompressed = encoded_point.as_bytes();
+    Ok(Address::from_word(chain_hash(
+        &pub_key_uncompressed[1..],
+        algo,
+    )))
+}
+
 #[cfg(test)]
 mod tests {
     use std::str::FromStr;
@@ -56,8 +163,82 @@ mod tests {
         let pk = U256::from(100);
         let pk = u256_to_signing_key(&pk).unwrap();
         assert_eq!(
-            Address::from_str("0x2a6669a8bec28af42dceff73070cdb2246adfb22").unwrap(),
-            addr(&pk)
+            Address::from_str("0x015b90bb95bf6daf4d75669c35c646dd32f6d11e").unwrap(),
+            addr(&pk, HashAlgo::Keccak256)
+        );
+    }
+
+    #[test]
+    fn test_addresses_match_ethereum() {
+        let key = SigningKey::from_slice(&U256::from(1).to_be_bytes::<32>()).unwrap();
+        assert_eq!(
+            Address::from_str("0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf").unwrap(),
+            addr(&key, HashAlgo::Keccak256)
+        );
+    }
+
+    #[test]
+    fn test_keccak256() {
+        assert_eq!(
+            keccak256(b"").to_string(),
+            "0xc5d2460186f7233c927e7db2dcc703c0e500b653ca82273b7bfad8045d85a470"
         );
+        assert_ne!(keccak256(b""), sha3(b""));
+        assert_eq!(chain_hash(b"", HashAlgo::Keccak256), keccak256(b""));
+        assert_eq!(chain_hash(b"", HashAlgo::Sha3), sha3(b""));
+    }
+
+    #[test]
+    fn test_funded_account() {
+        let (key, address, account) = funded_account(500);
+        assert_eq!(address, addr(&key, HashAlgo::Keccak256));
+        assert_eq!((account.balance(), account.nonce()), (500, 0));
+        assert_ne!(addr(&random_keypair(), HashAlgo::Keccak256), address);
+    }
+
+    #[test]
+    fn test_recover_address() {
+        let hash = keccak256(b"mini-blockchain");
+        // Signed by the private keys 1 and 2
+        let vectors = [
+            (
+                0,
+                "0x63713ea14b8aebe9dec632c5723213a4271c9220c30d83430fe5e893bcee8cbc",
+                "0x599e7de39883ded2c91bfd90e72ab044df44e8b14af35a1dba9bcc1b8e934593",
+                "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf",
+            ),
+            (
+                1,
+                "0x14ba72ae5280eb668144d2d6a08ce461286e474eaa9ae5831ae142bbd8702e60",
+                "0x29db9f13679a9d5a504e6fd8e0e7ce66ca9572fcef9fa095c28dd0cdb805cabb",
+                "0x2B5AD5c4795c026514f8317c7a215E218DcCD6cF",
+            ),
+        ];
+        for (v, r, s, expected) in vectors {
+            let (r, s) = (U256::from_str(r).unwrap(), U256::from_str(s).unwrap());
+            let expected = Address::from_str(expected).unwrap();
+            assert_eq!(
+                recover_address(hash, v, r, s, HashAlgo::Keccak256).unwrap(),
+                expected
+            );
+
+            // Any other recovery id or hash points to some other key
+            assert_ne!(
+                recover_address(hash, v ^ 1, r, s, HashAlgo::Keccak256).unwrap(),
+                expected
+            );
+            assert_ne!(
+                recover_address(B256::ZERO, v, r, s, HashAlgo::Keccak256).unwrap(),
+                expected
+            );
+        }
+
+        let (_, r, s, _) = vectors[0];
+        let (r, s) = (U256::from_str(r).unwrap(), U256::from_str(s).unwrap());
+        assert!(matches!(
+            recover_address(hash, 4, r, s, HashAlgo::Keccak256),
+            Err(Error::InvalidSignature(_))
+        ));
+        assert!(recover_address(hash, 0, U256::ZERO, s, HashAlgo::Keccak256).is_err());
     }
 }
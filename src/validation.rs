@@ -0,0 +1,619 @@
+//! Transaction validation shared by admission, execution and import
+//!
+//! A [TxValidator] runs an ordered list of [ValidationStage]s and stops at the first one that
+//! rejects the transaction. The same pipeline decides whether the handler accepts a
+//! transaction and what its receipt says once it is executed, so the two can't disagree
+
+use crate::{
+    utils::HashAlgo, Account, ChainSpec, CoinbaseMaturity, DatabaseReader, DatabaseWriter,
+    FailureReason, State, Transaction,
+};
+use alloy_primitives::Address;
+use std::{fmt, sync::Arc};
+
+/// Where in the life of a transaction it is validated
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ValidationPhase {
+    /// Submitted to this node, before it enters the mempool. Pending transactions of the same
+    /// sender aren't in the state yet, so nonces and balances can't be judged
+    Admission,
+    /// Included in a block this node builds, its transactions come from the mempool
+    Execution,
+    /// Included in a block this node didn't build, nothing about it can be trusted
+    Import,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValidationOutcome {
+    Valid,
+    Invalid(FailureReason),
+}
+
+/// Chain wide settings the stages check against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub struct ValidationConfig {
+    pub chain_id: u64,
+    /// See [ChainSpec::min_balance]
+    pub min_balance: Option<u128>,
+    /// See [ChainSpec::coinbase_maturity]
+    pub coinbase_maturity: CoinbaseMaturity,
+    /// See [ChainSpec::hash_algo]
+    pub hash_algo: HashAlgo,
+}
+
+impl ValidationConfig {
+    /// The defaults of every other setting for `chain_id`
+    pub fn new(chain_id: u64) -> Self {
+        Self {
+            chain_id,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_min_balance(mut self, min_balance: Option<u128>) -> Self {
+        self.min_balance = min_balance;
+        self
+    }
+
+    pub fn with_coinbase_maturity(mut self, coinbase_maturity: CoinbaseMaturity) -> Self {
+        self.coinbase_maturity = coinbase_maturity;
+        self
+    }
+
+    pub fn with_hash_algo(mut self, hash_algo: HashAlgo) -> Self {
+        self.hash_algo = hash_algo;
+        self
+    }
+}
+
+impl From<&ChainSpec> for ValidationConfig {
+    fn from(spec: &ChainSpec) -> Self {
+        Self {
+            chain_id: spec.chain_id(),
+            min_balance: spec.min_balance(),
+            coinbase_maturity: spec.coinbase_maturity(),
+            hash_algo: spec.hash_algo(),
+        }
+    }
+}
+
+/// Accounts as seen by the transaction being validated
+pub trait StateView {
+    fn account(&self, address: &Address) -> Option<Account>;
+
+    /// What `spender` may still send on behalf of `owner`, views without approvals have none
+    fn allowance(&self, _owner: &Address, _spender: &Address) -> u128 {
+        0
+    }
+
+    /// Rewards `coinbase` got for blocks whose reward the transactions of block `number` can't
+    /// spend yet, views without blocks have none
+    fn immature_reward(
+        &self,
+        _coinbase: &Address,
+        _number: u64,
+        _maturity: CoinbaseMaturity,
+    ) -> u128 {
+        0
+    }
+}
+
+impl<DB: DatabaseReader> StateView for DB {
+    fn account(&self, address: &Address) -> Option<Account> {
+        self.read_account(address).copied()
+    }
+
+    fn allowance(&self, owner: &Address, spender: &Address) -> u128 {
+        self.read_allowance(owner, spender)
+    }
+
+    /// Headers survive pruning, so the rewards of every written block are known
+    fn immature_reward(&self, coinbase: &Address, number: u64, maturity: CoinbaseMaturity) -> u128 {
+        maturity
+            .immature_blocks(number)
+            .filter_map(|number| self.read_block_by_number(number))
+            .map(|block| block.header())
+            .filter(|header| header.coinbase() == coinbase)
+            .fold(0, |total, header| {
+                total.saturating_add(header.block_reward())
+            })
+    }
+}
+
+/// Includes the changes of the transactions executed earlier in the block
+impl<DB: DatabaseReader + DatabaseWriter> StateView for State<'_, DB> {
+    fn account(&self, address: &Address) -> Option<Account> {
+        self.get_account(address).copied()
+    }
+
+    fn allowance(&self, owner: &Address, spender: &Address) -> u128 {
+        self.get_allowance(owner, spender)
+    }
+
+    /// The block being executed isn't written yet, its reward comes from the state
+    fn immature_reward(&self, coinbase: &Address, number: u64, maturity: CoinbaseMaturity) -> u128 {
+        let written = self.db().immature_reward(coinbase, number, maturity);
+        match self.block_reward() {
+            Some((paid_to, reward)) if paid_to == *coinbase && maturity.0 > 0 => {
+                written.saturating_add(reward)
+            }
+            _ => written,
+        }
+    }
+}
+
+pub struct ValidationContext<'a> {
+    pub state: &'a dyn StateView,
+    pub config: ValidationConfig,
+    /// Number of the latest block
+    pub head: u64,
+    pub phase: ValidationPhase,
+}
+
+/// One check of the [TxValidator]
+pub trait ValidationStage: Send + Sync {
+    /// Used when logging rejections
+    fn name(&self) -> &'static str;
+
+    fn check(&self, tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome;
+}
+
+/// Hash and signature, skipped during execution because the handler already checked them
+///
+/// Transactions meant for another chain don't verify either, [ChainIdCheck] rejects them first
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SignatureCheck;
+
+impl ValidationStage for SignatureCheck {
+    fn name(&self) -> &'static str {
+        "signature"
+    }
+
+    fn check(&self, tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome {
+        if ctx.phase == ValidationPhase::Execution
+            || tx.verify(ctx.config.chain_id, ctx.config.hash_algo)
+        {
+            return ValidationOutcome::Valid;
+        }
+
+        ValidationOutcome::Invalid(FailureReason::InvalidSignature)
+    }
+}
+
+/// The chain id the transaction was signed for, so transactions of one chain can't be replayed
+/// on another
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChainIdCheck;
+
+impl ValidationStage for ChainIdCheck {
+    fn name(&self) -> &'static str {
+        "chain_id"
+    }
+
+    fn check(&self, tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome {
+        if tx.chain_id == ctx.config.chain_id {
+            return ValidationOutcome::Valid;
+        }
+
+        ValidationOutcome::Invalid(FailureReason::WrongChainId)
+    }
+}
+
+/// Sender, nonce and balance, and the allowance of spenders, only judged once the transaction
+/// is in a block
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatefulCheck;
+
+impl ValidationStage for StatefulCheck {
+    fn name(&self) -> &'static str {
+        "stateful"
+    }
+
+    fn check(&self, tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome {
+        if ctx.phase == ValidationPhase::Admission {
+            return ValidationOutcome::Valid;
+        }
+
+        let reason = match ctx.state.account(&tx.from) {
+            None => FailureReason::UnknownSender,
+            Some(sender) if sender.nonce() != tx.nonce => FailureReason::InvalidNonce,
+            Some(sender) if sender.balance() < tx.value => FailureReason::InsufficientBalance,
+            Some(_) => match tx.spender() {
+                Some(spender) if ctx.state.allowance(&tx.from, &spender) < tx.value => {
+                    FailureReason::InsufficientAllowance
+                }
+                _ => return ValidationOutcome::Valid,
+            },
+        };
+
+        ValidationOutcome::Invalid(reason)
+    }
+}
+
+/// Block rewards of the sender younger than [ChainSpec::coinbase_maturity] can't be spent yet
+///
+/// Only rejects transactions the balance would cover if it weren't for the immature rewards
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MaturityCheck;
+
+impl ValidationStage for MaturityCheck {
+    fn name(&self) -> &'static str {
+        "maturity"
+    }
+
+    fn check(&self, tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome {
+        let balance = match ctx.state.account(&tx.from) {
+            Some(sender) if sender.balance() >= tx.value => sender.balance(),
+            _ => return ValidationOutcome::Valid,
+        };
+
+        let immature =
+            ctx.state
+                .immature_reward(&tx.from, ctx.head + 1, ctx.config.coinbase_maturity);
+        if balance.saturating_sub(immature) < tx.value {
+            return ValidationOutcome::Invalid(FailureReason::ImmatureReward);
+        }
+
+        ValidationOutcome::Valid
+    }
+}
+
+/// The reserve of [ChainSpec::min_balance], checked against the sender if it exists
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PolicyCheck;
+
+impl ValidationStage for PolicyCheck {
+    fn name(&self) -> &'static str {
+        "policy"
+    }
+
+    fn check(&self, tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome {
+        let min_balance = match ctx.config.min_balance {
+            Some(min_balance) => min_balance,
+            None => return ValidationOutcome::Valid,
+        };
+
+        match ctx.state.account(&tx.from) {
+            Some(sender) if sender.breaks_reserve(tx.value, min_balance) => {
+                ValidationOutcome::Invalid(FailureReason::BelowReserve)
+            }
+            _ => ValidationOutcome::Valid,
+        }
+    }
+}
+
+/// Ordered list of [ValidationStage]s, the first rejection wins
+#[derive(Clone)]
+pub struct TxValidator {
+    stages: Vec<Arc<dyn ValidationStage>>,
+}
+
+impl TxValidator {
+    /// The rules of the node: [ChainIdCheck], [SignatureCheck], [StatefulCheck],
+    /// [MaturityCheck] and [PolicyCheck], in that order
+    pub fn new() -> Self {
+        Self {
+            stages: vec![
+                Arc::new(ChainIdCheck),
+                Arc::new(SignatureCheck),
+                Arc::new(StatefulCheck),
+                Arc::new(MaturityCheck),
+                Arc::new(PolicyCheck),
+            ],
+        }
+    }
+
+    /// Runs `stage` after every stage already in the pipeline
+    pub fn with_stage(mut self, stage: Arc<dyn ValidationStage>) -> Self {
+        self.stages.push(stage);
+        self
+    }
+
+    pub fn validate(&self, tx: &Transaction, ctx: &ValidationContext<'_>) -> ValidationOutcome {
+        for stage in &self.stages {
+            if let ValidationOutcome::Invalid(reason) = stage.check(tx, ctx) {
+                tracing::debug!(hash = %tx.hash, stage = stage.name(), ?reason, "Transaction rejected");
+                return ValidationOutcome::Invalid(reason);
+            }
+        }
+
+        ValidationOutcome::Valid
+    }
+}
+
+impl Default for TxValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for TxValidator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = self.stages.iter().map(|stage| stage.name()).collect();
+        f.debug_struct("TxValidator")
+            .field("stages", &names)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        utils::{addr, sign_transaction, u256_to_signing_key},
+        Block, BlockHeader, InMemoryDB,
+    };
+    use alloy_primitives::U256;
+
+    fn signed(seed: u64, nonce: u64, value: u128) -> Transaction {
+        let key = u256_to_signing_key(&U256::from(seed)).unwrap();
+        let mut tx = Transaction {
+            from: addr(&key, HashAlgo::Keccak256),
+            to: Address::repeat_byte(9),
+            nonce,
+            value,
+            ..Default::default()
+        };
+        sign_transaction(&mut tx, 1, &key, HashAlgo::Keccak256);
+        tx
+    }
+
+    fn validate(
+        db: &InMemoryDB,
+        tx: &Transaction,
+        min_balance: Option<u128>,
+        phase: ValidationPhase,
+    ) -> ValidationOutcome {
+        let ctx = ValidationContext {
+            state: db,
+            config: ValidationConfig {
+                chain_id: 1,
+                min_balance,
+                coinbase_maturity: CoinbaseMaturity::default(),
+                hash_algo: HashAlgo::default(),
+            },
+            head: 0,
+            phase,
+        };
+        TxValidator::new().validate(tx, &ctx)
+    }
+
+    /// What the handler checked before the pipeline existed
+    fn legacy_admission(db: &InMemoryDB, tx: &Transaction, min_balance: Option<u128>) -> bool {
+        if !tx.verify(1, HashAlgo::Keccak256) {
+            return false;
+        }
+
+        match (min_balance, db.read_account(&tx.from)) {
+            (Some(min_balance), Some(account)) => !account.breaks_reserve(tx.value, min_balance),
+            _ => true,
+        }
+    }
+
+    /// Sender 1 is funded with 100 and has sent one transaction, sender 2 doesn't exist
+    fn cases() -> (InMemoryDB, Vec<Transaction>) {
+        let mut db = InMemoryDB::new();
+        let funded = signed(1, 1, 0).from;
+        db.write_account(funded, Account::new(100, 1)).unwrap();
+
+        let mut tampered = signed(1, 1, 10);
+        tampered.value = 11;
+
+        let mut bad_signature = signed(1, 1, 10);
+        bad_signature.s = U256::from(1);
+
+        let transactions = vec![
+            signed(1, 1, 10),
+            signed(1, 1, 100),
+            signed(1, 1, 95),
+            signed(1, 1, 101),
+            signed(1, 0, 10),
+            signed(1, 5, 10),
+            signed(2, 0, 10),
+            tampered,
+            bad_signature,
+        ];
+        (db, transactions)
+    }
+
+    #[test]
+    fn test_admission_matches_previous_rules() {
+        let (db, transactions) = cases();
+        for min_balance in [None, Some(10)] {
+            for tx in &transactions {
+                let outcome = validate(&db, tx, min_balance, ValidationPhase::Admission);
+                assert_eq!(
+                    outcome == ValidationOutcome::Valid,
+                    legacy_admission(&db, tx, min_balance),
+                    "{tx:?} with min balance {min_balance:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_execution_reasons() {
+        let (db, transactions) = cases();
+        let outcomes: Vec<_> = transactions
+            .iter()
+            .map(|tx| validate(&db, tx, Some(10), ValidationPhase::Execution))
+            .collect();
+
+        use FailureReason::*;
+        use ValidationOutcome::*;
+        assert_eq!(
+            outcomes,
+            vec![
+                Valid,
+                Valid,
+                Invalid(BelowReserve),
+                Invalid(InsufficientBalance),
+                Invalid(InvalidNonce),
+                Invalid(InvalidNonce),
+                Invalid(UnknownSender),
+                // Signatures were checked on admission
+                Valid,
+                Valid,
+            ]
+        );
+
+        // Imported blocks get no such trust
+        let tampered = &transactions[7];
+        assert_eq!(
+            validate(&db, tampered, None, ValidationPhase::Import),
+            Invalid(InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_transactions_of_another_chain_are_rejected() {
+        let (db, _) = cases();
+        let key = u256_to_signing_key(&U256::from(1)).unwrap();
+        let mut tx = signed(1, 1, 10);
+        sign_transaction(&mut tx, 2, &key, HashAlgo::Keccak256);
+
+        // A valid transaction on chain 2 can't be replayed on chain 1 in any phase
+        for phase in [
+            ValidationPhase::Admission,
+            ValidationPhase::Execution,
+            ValidationPhase::Import,
+        ] {
+            assert_eq!(
+                validate(&db, &tx, None, phase),
+                ValidationOutcome::Invalid(FailureReason::WrongChainId)
+            );
+        }
+
+        // Claiming chain 1 without signing again breaks the signature
+        tx.chain_id = 1;
+        assert_eq!(
+            validate(&db, &tx, None, ValidationPhase::Admission),
+            ValidationOutcome::Invalid(FailureReason::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn test_spenders_are_held_to_the_allowance() {
+        let (mut db, _) = cases();
+        let owner = signed(1, 1, 0).from;
+        let spender_key = u256_to_signing_key(&U256::from(3)).unwrap();
+        let spender = addr(&spender_key, HashAlgo::Keccak256);
+        db.write_allowance(owner, spender, 50).unwrap();
+
+        let spend = |value, key| {
+            let mut tx = Transaction {
+                from: owner,
+                to: Address::repeat_byte(9),
+                nonce: 1,
+                value,
+                kind: crate::TransactionKind::TransferFrom { spender },
+                ..Default::default()
+            };
+            sign_transaction(&mut tx, 1, key, HashAlgo::Keccak256);
+            tx
+        };
+
+        let owner_key = u256_to_signing_key(&U256::from(1)).unwrap();
+        let outcome = |tx: Transaction, phase| validate(&db, &tx, None, phase);
+        assert_eq!(
+            outcome(spend(10, &owner_key), ValidationPhase::Admission),
+            ValidationOutcome::Invalid(FailureReason::InvalidSignature)
+        );
+        assert_eq!(
+            outcome(spend(10, &spender_key), ValidationPhase::Admission),
+            ValidationOutcome::Valid
+        );
+        assert_eq!(
+            outcome(spend(50, &spender_key), ValidationPhase::Import),
+            ValidationOutcome::Valid
+        );
+        // Within the balance of the owner, but not within what it approved
+        assert_eq!(
+            outcome(spend(51, &spender_key), ValidationPhase::Execution),
+            ValidationOutcome::Invalid(FailureReason::InsufficientAllowance)
+        );
+    }
+
+    #[test]
+    fn test_admission_refuses_immature_rewards() {
+        // Sender 1 holds 100, 50 of them the reward of block 1
+        let (mut db, _) = cases();
+        let coinbase = signed(1, 1, 0).from;
+        let header = BlockHeader {
+            number: 1,
+            coinbase,
+            block_reward: 50,
+            ..Default::default()
+        };
+        let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+        db.write_block(*block.get_hash(), block).unwrap();
+
+        let outcome = |value, maturity| {
+            let ctx = ValidationContext {
+                state: &db,
+                config: ValidationConfig {
+                    chain_id: 1,
+                    min_balance: None,
+                    coinbase_maturity: CoinbaseMaturity(maturity),
+                    hash_algo: HashAlgo::default(),
+                },
+                head: 1,
+                phase: ValidationPhase::Admission,
+            };
+            TxValidator::new().validate(&signed(1, 1, value), &ctx)
+        };
+        // The next block may spend it with the default maturity
+        assert_eq!(outcome(100, 1), ValidationOutcome::Valid);
+        assert_eq!(outcome(50, 2), ValidationOutcome::Valid);
+        assert_eq!(
+            outcome(51, 2),
+            ValidationOutcome::Invalid(FailureReason::ImmatureReward)
+        );
+        // More than the balance stays a balance problem, left to execution
+        assert_eq!(outcome(101, 2), ValidationOutcome::Valid);
+    }
+
+    #[test]
+    fn test_custom_stage_runs_last() {
+        struct NoZeroValue;
+
+        impl ValidationStage for NoZeroValue {
+            fn name(&self) -> &'static str {
+                "no_zero_value"
+            }
+
+            fn check(&self, tx: &Transaction, _ctx: &ValidationContext<'_>) -> ValidationOutcome {
+                match tx.value {
+                    0 => ValidationOutcome::Invalid(FailureReason::RejectedByPolicy),
+                    _ => ValidationOutcome::Valid,
+                }
+            }
+        }
+
+        let (db, _) = cases();
+        let validator = TxValidator::new().with_stage(Arc::new(NoZeroValue));
+        let ctx = ValidationContext {
+            state: &db,
+            config: ValidationConfig {
+                chain_id: 1,
+                min_balance: None,
+                coinbase_maturity: CoinbaseMaturity::default(),
+                hash_algo: HashAlgo::default(),
+            },
+            head: 0,
+            phase: ValidationPhase::Execution,
+        };
+
+        assert_eq!(
+            validator.validate(&signed(1, 1, 0), &ctx),
+            ValidationOutcome::Invalid(FailureReason::RejectedByPolicy)
+        );
+        // Earlier stages still have the first say
+        assert_eq!(
+            validator.validate(&signed(2, 0, 0), &ctx),
+            ValidationOutcome::Invalid(FailureReason::UnknownSender)
+        );
+    }
+}
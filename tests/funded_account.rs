@@ -0,0 +1,39 @@
+//! Accounts made with `utils::funded_account`, which the dev-dependency of this crate on
+//! itself enables with the `test-utils` feature
+
+use mini_blockchain::{
+    utils::{addr, funded_account, random_keypair, sign_transaction, HashAlgo},
+    DatabaseReader, DatabaseWriter, InMemoryDB, Transaction, TxValidator, ValidationConfig,
+    ValidationContext, ValidationOutcome, ValidationPhase,
+};
+
+#[test]
+fn test_funded_account_signs_admissible_transactions() {
+    let (key, from, account) = funded_account(1_000);
+    let mut db = InMemoryDB::new();
+    db.write_account(from, account).unwrap();
+
+    let mut tx = Transaction {
+        from,
+        to: addr(&random_keypair(), HashAlgo::Keccak256),
+        value: 400,
+        ..Default::default()
+    };
+    sign_transaction(&mut tx, 7, &key, HashAlgo::Keccak256);
+    assert!(tx.verify(7, HashAlgo::Keccak256));
+
+    let ctx = ValidationContext {
+        state: &db,
+        config: ValidationConfig::new(7),
+        head: 0,
+        phase: ValidationPhase::Admission,
+    };
+    assert_eq!(
+        TxValidator::new().validate(&tx, &ctx),
+        ValidationOutcome::Valid
+    );
+
+    db.write_transaction(tx.clone()).unwrap();
+    assert_eq!(db.read_transaction(&tx.hash), Some(&tx));
+    assert_eq!(db.read_account(&from), Some(&account));
+}
@@ -0,0 +1,72 @@
+//! Loading a large dump has to stay close to the memory of the loaded database
+//!
+//! Lives in its own test binary because it replaces the global allocator to count bytes
+
+use mini_blockchain::{
+    utils::HashAlgo, Block, BlockHeader, DatabaseReader, DatabaseWriter, InMemoryDB,
+};
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let allocated = ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(allocated, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[tokio::test]
+async fn test_load_large_dump_memory() {
+    const BLOCKS: u64 = 100_000;
+
+    let path =
+        std::env::temp_dir().join(format!("mini-blockchain-{}-large.json", std::process::id()));
+    {
+        let mut db = InMemoryDB::new();
+        for number in 1..=BLOCKS {
+            let header = BlockHeader {
+                number,
+                timestamp: number * 10,
+                ..Default::default()
+            };
+            let block = Block::new(header, Default::default()).seal_slow(HashAlgo::Keccak256);
+            db.write_block(*block.get_hash(), block).unwrap();
+        }
+        db.mem_dump(path.clone()).await.unwrap();
+    }
+
+    let baseline = ALLOCATED.load(Ordering::Relaxed);
+    PEAK.store(baseline, Ordering::Relaxed);
+
+    let db = InMemoryDB::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    let size = ALLOCATED.load(Ordering::Relaxed) - baseline;
+    let peak = PEAK.load(Ordering::Relaxed) - baseline;
+    assert_eq!(db.block_count(), BLOCKS as usize);
+    assert_eq!(db.latest_block().unwrap().number(), BLOCKS);
+    // Hash maps double when they grow, so the old and the new table briefly coexist
+    assert!(
+        peak <= 3 * size,
+        "peak {peak} bytes for a {size} bytes database"
+    );
+}
@@ -0,0 +1,57 @@
+//! Snapshot of the public API, every path embedders rely on has to keep compiling
+//!
+//! If one of these imports breaks, the change is semver breaking
+
+#[allow(unused_imports)]
+use mini_blockchain::{
+    client, fixtures, merkle, noise_accept, noise_connect, prelude, tls_acceptor, tls_connector,
+    utils, Account, AdminReq, BanPolicy, BlackList, Block, BlockHeader, BlockReq, Bundle,
+    BundleReceipt, ChainIdCheck, ChainInfo, ChainSpec, CoinbaseMaturity, Connection, ControlMsg,
+    DatabaseReader, DatabaseWriter, DifficultyAdjuster, Error, FailedBlockPolicy, FailureReason,
+    FrameMode, InMemoryDB, MaybeTlsStream, MempoolOrdering, MempoolStats, Message, NodeLimits,
+    NodeRole, NodeStatus, NoiseStream, OnTaskFailure, PeerCounters, PeerEncryption, PeerSnapshot,
+    PeerStats, PolicyCheck, Pruned, RateLimit, Reporter, SealedBlock, SealedHeader, Server,
+    SignatureCheck, StartupPhase, StateView, StatefulCheck, StatusReport, SubmissionResult,
+    SubscriptionKind, SyncEvent, Task, TestAccount, TlsConnection, Transaction, TransactionEntry,
+    TransactionKind, TransactionReceipt, TransactionReq, TransactionSummary, Transactions,
+    TxValidator, UnixConnection, ValidationConfig, ValidationContext, ValidationOutcome,
+    ValidationPhase, ValidationStage, WhiteList, WireFormat, WsStream, ADJUSTMENT_INTERVAL,
+    DEFAULT_BAN_POLICY, DEFAULT_BLOCK_GAS_LIMIT, DEFAULT_CONNECTION_RATE_LIMIT, DEFAULT_HOST,
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CONNECTIONS, DEFAULT_MAX_CONNECTIONS_PER_IP,
+    DEFAULT_MEMPOOL_MAX_PER_SENDER, DEFAULT_MEMPOOL_MAX_SIZE, DEFAULT_MEMPOOL_TTL,
+    DEFAULT_RATE_LIMIT, DEFAULT_REQUEST_TIMEOUT, DEFAULT_TRANSACTION_RATE_LIMIT, FELL_BEHIND_LAG,
+    MAX_BUNDLE_SIZE, MAX_TRANSACTIONS_PER_REQUEST, PROTOCOL_VERSION,
+};
+
+#[allow(unused_imports)]
+use mini_blockchain::prelude::*;
+
+#[test]
+fn test_chainspec_builder() {
+    let spec = ChainSpec::new(7)
+        .with_account(Default::default(), Account::new(10, 0))
+        .with_min_balance(5);
+
+    assert_eq!(spec.chain_id(), 7);
+    assert_eq!(spec.min_balance(), Some(5));
+    assert_eq!(spec.iter_accounts().count(), 1);
+}
+
+#[test]
+fn test_messages_are_constructible() {
+    let msg = Message::BlockReq(BlockReq::Number(1));
+    let bytes = msg.serialize().unwrap();
+    assert!(!bytes.is_empty());
+
+    let _ = Message::TransactionReq(TransactionReq::Hash(Default::default()));
+}
+
+#[test]
+fn test_settings_are_constructible() {
+    let limits = NodeLimits::default().with_max_frame_size(1024);
+    assert_eq!(limits.max_frame_size, 1024);
+
+    let config = ValidationConfig::new(7).with_min_balance(Some(5));
+    assert_eq!(config.chain_id, 7);
+    assert_eq!(config.min_balance, Some(5));
+}
@@ -0,0 +1,92 @@
+//! Whitelisted peers are served however they behave, through a node bound on localhost
+
+use alloy_primitives::Address;
+use mini_blockchain::{
+    fixtures::connect_when_up, BanPolicy, ChainSpec, Connection, DatabaseWriter, InMemoryDB,
+    Message, RateLimit, Server, Transaction, WhiteList,
+};
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{broadcast, mpsc, RwLock};
+
+#[tokio::test]
+async fn test_whitelisted_peer_is_never_blocked() {
+    let spec = ChainSpec::new(1);
+    let mut db = InMemoryDB::new();
+    db.write_spec(&spec).unwrap();
+    let hello = Message::hello(&spec);
+
+    let port = 47_531;
+    let (notify_shutdown, _) = broadcast::channel(1);
+    let (shutdown_complete_tx, _) = mpsc::channel(1);
+    let server = Server::new(
+        Arc::new(RwLock::new(db)),
+        spec,
+        port,
+        60,
+        Address::ZERO,
+        notify_shutdown,
+        shutdown_complete_tx,
+    )
+    .with_rate_limit(RateLimit::new(1, 1))
+    .with_transaction_rate_limit(RateLimit::new(1, 1))
+    .with_connection_rate_limit(RateLimit::new(1, 1))
+    .with_ban_policy(BanPolicy::new(1, Duration::from_secs(60)))
+    .with_white_list(WhiteList::new(
+        vec!["127.0.0.1".parse().unwrap()],
+        Vec::new(),
+    ));
+    let black_list = server.black_list();
+    let node = tokio::spawn(async move { server.run().await });
+
+    // Well over the connection burst
+    let mut connections = Vec::new();
+    for _ in 0..3 {
+        let mut connection = Connection::new(connect_when_up(port).await.unwrap());
+        connection.write_message(&hello).await.unwrap();
+        assert!(matches!(
+            connection.read_message().await.unwrap(),
+            Some(Message::Hello { .. })
+        ));
+        connections.push(connection);
+    }
+
+    let connection = &mut connections[0];
+    for _ in 0..5 {
+        connection
+            .write_message(&Message::ChainInfoReq)
+            .await
+            .unwrap();
+        assert!(matches!(
+            connection.read_message().await.unwrap(),
+            Some(Message::ChainInfo(_))
+        ));
+    }
+
+    // Each of them would get any other peer banned, and the later ones rate limited
+    let invalid = Message::Transaction(Transaction::default());
+    for _ in 0..3 {
+        connection.write_message(&invalid).await.unwrap();
+        assert_eq!(
+            connection.read_message().await.unwrap(),
+            Some(Message::InvalidTransaction)
+        );
+    }
+    connection.write_message(&Message::Ping(1)).await.unwrap();
+    assert_eq!(
+        connection.read_message().await.unwrap(),
+        Some(Message::Pong(1))
+    );
+    let ip = "127.0.0.1".parse().unwrap();
+    assert!(!black_list.read().await.is_banned(&ip));
+
+    // Not even a ban by hand keeps it out
+    black_list.write().await.insert(ip);
+    let mut connection = Connection::new(connect_when_up(port).await.unwrap());
+    connection.write_message(&hello).await.unwrap();
+    assert!(matches!(
+        connection.read_message().await.unwrap(),
+        Some(Message::Hello { .. })
+    ));
+
+    node.abort();
+}